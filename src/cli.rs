@@ -0,0 +1,69 @@
+//! Command line interface of the exporter
+//!
+//! The global flags ([`Overrides`]) override the env variables and the configuration file
+
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+use crate::config::Overrides;
+use crate::scan::OutputFormat;
+use crate::web::ListenAddress;
+
+/// Command line arguments
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Subcommand to run, `serve` if none is given
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Global settings
+    #[command(flatten)]
+    pub overrides: Overrides,
+}
+
+/// Subcommands
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Validate the configuration and print it, with the tokens redacted
+    CheckConfig,
+    /// Scan the gitlab instances once and print the tokens
+    Scan(ScanArgs),
+    /// Serve the metrics over HTTP, refreshing them periodically (default)
+    Serve(ServeArgs),
+    /// Write the metrics to a file read by the `node_exporter` textfile collector after each refresh,
+    /// instead of serving them over HTTP
+    Textfile(TextfileArgs),
+}
+
+/// Arguments of the `scan` subcommand
+#[derive(Args, Debug)]
+pub struct ScanArgs {
+    /// Only print the tokens expiring within this number of days, and exit with status 1
+    /// if there is any
+    #[arg(long, value_name = "DAYS")]
+    pub expires_within_days: Option<u32>,
+    /// Output format
+    #[arg(long, short, value_enum, default_value_t = OutputFormat::Table)]
+    pub format: OutputFormat,
+}
+
+/// Arguments of the `serve` subcommand
+#[derive(Args, Debug, Default)]
+pub struct ServeArgs {
+    /// Address the HTTP server listens on: `<ip>:<port>` or `unix:<path>`
+    /// (overrides `LISTEN_ADDRESS`, `0.0.0.0:3000` by default)
+    #[arg(long, value_name = "ADDRESS")]
+    pub listen: Option<ListenAddress>,
+    /// Web configuration file (TLS and authentication), in the Prometheus exporters format
+    /// (overrides `WEB_CONFIG_FILE`)
+    #[arg(long, value_name = "FILE")]
+    pub web_config_file: Option<PathBuf>,
+}
+
+/// Arguments of the `textfile` subcommand
+#[derive(Args, Debug)]
+pub struct TextfileArgs {
+    /// File written after each successful refresh. Its name must end with `.prom`
+    #[arg(long, value_name = "FILE")]
+    pub path: PathBuf,
+}
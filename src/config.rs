@@ -1,131 +1,1091 @@
 //! Creates the exporter's [`Config`] in the static variable [`CONFIG`]
 
-use core::time::Duration;
-use std::{collections::HashSet, env, sync::LazyLock};
+use core::{error::Error, fmt, ops::RangeInclusive, str::FromStr, time::Duration};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex, OnceLock},
+};
 
 use anyhow::{Context as _, anyhow};
+use arc_swap::ArcSwap;
+use clap::{Args, ValueEnum};
 use dotenvy::dotenv_override;
 use regex::Regex;
-use tracing::{instrument, warn};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument, warn};
 
-use crate::gitlab::connection::Connection;
+use crate::config_file::{
+    self, ConfigFile, FiltersSection, InstanceSection, PatternsSection, RefreshSchedulesSection,
+};
+use crate::filter::{Filters, NameFilter, StateFilter};
+use crate::gitlab::{
+    cache::HttpCache,
+    client::Client,
+    connection::{Connection, TlsOptions},
+};
+use crate::inventory::{ResourceType, ScanScope};
+use crate::prometheus_metrics;
+use crate::schedule::{self, RefreshSchedule};
+
+/// Default value for `history_retention_days`
+const HISTORY_RETENTION_DAYS_DEFAULT: u16 = 365;
+
+/// Accepted values for `history_retention_days`
+const HISTORY_RETENTION_DAYS_RANGE: RangeInclusive<u16> = 1..=3650;
 
 /// Default value for `max_concurrent_requests`
 const MAX_CONCURRENT_REQUESTS_DEFAULT: u16 = 10;
 
+/// Accepted values for `max_concurrent_requests`
+const MAX_CONCURRENT_REQUESTS_RANGE: RangeInclusive<u16> = 1..=u16::MAX;
+
 /// Default value for `data_refresh_hours`
 const DATA_REFRESH_HOURS_DEFAULT: u8 = 6;
 
+/// Accepted values for `data_refresh_hours`
+const DATA_REFRESH_HOURS_RANGE: RangeInclusive<u8> = 1..=24;
+
+/// Name of the credential defined by `GITLAB_TOKEN`
+const DEFAULT_CREDENTIAL_NAME: &str = "default";
+
 /// Default number of times a transient gitlab API error is retried
 const MAX_RETRIES_DEFAULT: u32 = 4;
 
+/// Accepted values for `max_retries`
+const MAX_RETRIES_RANGE: RangeInclusive<u32> = 0..=20;
+
+/// Placeholder printed instead of the tokens by `--check-config`
+const REDACTED: &str = "<redacted>";
+
 /// Default base delay (in milliseconds) for the retry exponential backoff
 const RETRY_BACKOFF_MS_DEFAULT: u64 = 500;
 
-/// This config will be available to all tasks
+/// Accepted values for `retry_backoff_ms`
+const RETRY_BACKOFF_MS_RANGE: RangeInclusive<u64> = 1..=60_000;
+
+/// Settings given on the command line (cf [`set_overrides`])
+static OVERRIDES: OnceLock<Overrides> = OnceLock::new();
+
+/// Configuration validated by [`init`], taken as the initial value of [`CONFIG`]
+static VALIDATED_CONFIG: Mutex<Option<Config>> = Mutex::new(None);
+
+/// This config will be available to all tasks. It is the one validated by [`init`], if any, and
+/// is replaced by [`reload`]
 #[expect(clippy::unwrap_used, reason = "we *want* to crash if this fails")]
-pub static CONFIG: LazyLock<Config> = LazyLock::new(|| Config::new().unwrap());
+pub static CONFIG: LazyLock<ArcSwap<Config>> = LazyLock::new(|| {
+    let validated_config = VALIDATED_CONFIG.lock().unwrap().take();
+    ArcSwap::from_pointee(validated_config.unwrap_or_else(|| Config::new().unwrap()))
+});
+
+/// Backend used to collect projects and groups tokens
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum CollectionBackend {
+    /// GitLab GraphQL API (cf [`graphql`](crate::gitlab::graphql))
+    #[value(name = "graphql", help = "GitLab GraphQL API")]
+    GraphQl,
+    /// GitLab REST API
+    #[default]
+    Rest,
+}
 
 /// Defines the exporter's configuration
 #[derive(Clone)]
 pub struct Config {
     /// Regex to filter group or project bot tokens
     pub bot_users_re: Regex,
-    /// Connection to gitlab
-    pub connection: Connection,
-    /// Time interval between updates
-    pub data_refresh_hours: u8,
-    /// Total (for **all** tasks) number of concurrent requests
+    /// Backend used to collect projects and groups tokens
+    pub collection_backend: CollectionBackend,
+    /// Settings shared by the connections of all instances
+    pub connection_settings: ConnectionSettings,
+    /// `SQLite` database keeping the history of the tokens, written after each scan
+    pub history_database: Option<PathBuf>,
+    /// Number of days the tokens which are no longer seen are kept in `history_database`
+    pub history_retention_days: u16,
+    /// gitlab instances to scan
+    pub instances: Vec<Arc<Instance>>,
+    /// File where the tokens are saved after each successful scan, and loaded at startup
+    pub inventory_file: Option<PathBuf>,
+    /// Total (for **all** tasks) number of concurrent requests, per instance
     pub max_concurrent_requests: u16,
+    /// Maximum random delay added to each scheduled scan
+    pub refresh_jitter: Duration,
+    /// When the scans are run
+    pub refresh_schedule: RefreshSchedule,
+    /// When the resource types which don't use `refresh_schedule` are scanned
+    pub refresh_schedules: BTreeMap<ResourceType, RefreshSchedule>,
+}
+
+/// Errors found while reading the configuration, so that they are all reported at once
+#[derive(Debug, Default)]
+pub struct ConfigErrors {
+    /// One message per invalid setting
+    messages: Vec<String>,
+}
+
+/// Defines a gitlab token used by the exporter to scan an [`Instance`]
+#[derive(Clone)]
+pub struct Credential {
+    /// gitlab API client, authenticated with this credential
+    pub client: Client,
+    /// Credential name, used as the `credentials` label of the metrics
+    pub name: String,
+}
+
+/// Group subtrees to scan (or not) on an [`Instance`]
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GroupSubtrees {
+    /// Full paths of the groups whose subtrees must not be scanned
+    pub exclude: Vec<String>,
+    /// Full paths of the groups whose subtrees are scanned. All the visible projects and groups
+    /// are scanned if it is empty
+    pub include: Vec<String>,
+}
+
+impl GroupSubtrees {
+    /// Returns `true` if the group or project at `full_path` is in an excluded subtree
+    pub fn is_excluded(&self, full_path: &str) -> bool {
+        self.exclude
+            .iter()
+            .any(|group_path| is_in_subtree(full_path, group_path))
+    }
+}
+
+/// Defines a gitlab instance and the filters applied to its tokens
+#[derive(Clone)]
+pub struct Instance {
+    /// Credentials used to scan the instance. Each one sees its own projects, groups and users
+    pub credentials: Vec<Arc<Credential>>,
+    /// Filters on project paths, group paths, usernames, token names, token scopes and token state
+    pub filters: Filters,
+    /// Group subtrees to scan (or not)
+    pub group_subtrees: GroupSubtrees,
+    /// Hostname of the instance
+    pub hostname: String,
+    /// Additional labels added to the metrics of the instance
+    pub labels: BTreeMap<String, String>,
+    /// Instance name, used as the `instance` label of the metrics
+    pub name: String,
     /// Only handle owned tokens if set to `true`
     pub owned_entities_only: bool,
-    /// Skip non expiring tokens if set to `true`
-    pub skip_non_expiring_tokens: bool,
     /// Skip users tokens if set to `true`
     pub skip_users_tokens: bool,
-    /// Filter users tokens by username
-    pub usernames_filter: Option<HashSet<String>>,
+    /// TLS options of the connections to the instance
+    pub tls: TlsOptions,
+}
+
+/// Global settings given on the command line. They override the env variables
+/// and the configuration file
+#[derive(Args, Clone, Debug, Default)]
+pub struct Overrides {
+    /// Backend used to collect projects and groups tokens (`COLLECTION_BACKEND`)
+    #[arg(long, global = true, value_enum)]
+    pub collection_backend: Option<CollectionBackend>,
+    /// TOML configuration file (`CONFIG_FILE`)
+    #[arg(long, global = true)]
+    pub config_file: Option<PathBuf>,
+    /// Time interval between updates, in hours (`DATA_REFRESH_HOURS`)
+    #[arg(long, global = true)]
+    pub data_refresh_hours: Option<u8>,
+    /// Disables the in-memory cache of gitlab API responses (`DISABLE_HTTP_CACHE`)
+    #[arg(long, global = true)]
+    pub disable_http_cache: bool,
+    /// `SQLite` database keeping the history of the tokens, written after each scan
+    /// (`HISTORY_DATABASE`)
+    #[arg(long, global = true)]
+    pub history_database: Option<PathBuf>,
+    /// Number of days the tokens which are no longer seen are kept in the history database
+    /// (`HISTORY_RETENTION_DAYS`)
+    #[arg(long, global = true)]
+    pub history_retention_days: Option<u16>,
+    /// File where the tokens are saved after each successful scan, and loaded at startup
+    /// (`INVENTORY_FILE`)
+    #[arg(long, global = true)]
+    pub inventory_file: Option<PathBuf>,
+    /// Total number of concurrent requests, per instance (`MAX_CONCURRENT_REQUESTS`)
+    #[arg(long, global = true)]
+    pub max_concurrent_requests: Option<u16>,
+    /// Number of times a transient gitlab API error is retried (`MAX_RETRIES`)
+    #[arg(long, global = true)]
+    pub max_retries: Option<u32>,
+    /// Maximum random delay added to each scheduled scan, such as `5m` (`REFRESH_JITTER`)
+    #[arg(long, global = true, value_name = "DURATION", value_parser = schedule::parse_jitter)]
+    pub refresh_jitter: Option<Duration>,
+    /// Interval between updates (such as `15m`) or cron expression (such as `0 2 * * *`),
+    /// overrides `--data-refresh-hours` (`REFRESH_SCHEDULE`)
+    #[arg(long, global = true, value_name = "SCHEDULE")]
+    pub refresh_schedule: Option<RefreshSchedule>,
+    /// Base delay for the retry exponential backoff, in milliseconds (`RETRY_BACKOFF_MS`)
+    #[arg(long, global = true)]
+    pub retry_backoff_ms: Option<u64>,
+}
+
+/// Settings shared by the connections of all instances
+#[derive(Clone)]
+pub struct ConnectionSettings {
+    /// Cache gitlab API responses if set to `true`
+    pub http_cache: bool,
+    /// Number of times a transient gitlab API error is retried
+    pub max_retries: u32,
+    /// Base delay for the retry exponential backoff
+    pub retry_backoff: Duration,
 }
 
 impl Config {
+    /// Creates a new [`Config`] from the configuration file at `path` (cf [`config_file`])
+    fn from_file(path: &Path) -> Result<Self, anyhow::Error> {
+        let ConfigFile {
+            collection_backend,
+            data_refresh_hours: data_refresh_hours_value,
+            disable_http_cache,
+            history_database: history_database_value,
+            history_retention_days: history_retention_days_value,
+            instances: instance_sections,
+            inventory_file: inventory_file_value,
+            max_concurrent_requests: max_concurrent_requests_value,
+            max_retries,
+            refresh_jitter: refresh_jitter_value,
+            refresh_schedule: refresh_schedule_value,
+            refresh_schedules: refresh_schedules_section,
+            retry_backoff_ms,
+        } = config_file::read(path)?;
+        let overrides = overrides();
+        let mut errors = ConfigErrors::default();
+
+        let refresh_schedule = errors.check(refresh_schedule(
+            ("refresh_schedule", refresh_schedule_value.as_deref()),
+            ("data_refresh_hours", data_refresh_hours_value),
+        ));
+        let refresh_schedules = errors.check(section_refresh_schedules(refresh_schedules_section));
+        let refresh_jitter = errors.check(refresh_jitter(
+            "refresh_jitter",
+            refresh_jitter_value.as_deref(),
+        ));
+        let inventory_file = errors.check(data_file(
+            "inventory_file",
+            overrides.inventory_file.clone().or(inventory_file_value),
+        ));
+        let history_database = errors.check(data_file(
+            "history_database",
+            overrides
+                .history_database
+                .clone()
+                .or(history_database_value),
+        ));
+        let history_retention_days = errors.check(check_range(
+            "history_retention_days",
+            overrides
+                .history_retention_days
+                .or(history_retention_days_value)
+                .unwrap_or(HISTORY_RETENTION_DAYS_DEFAULT),
+            &HISTORY_RETENTION_DAYS_RANGE,
+        ));
+        let max_concurrent_requests = errors.check(check_range(
+            "max_concurrent_requests",
+            overrides
+                .max_concurrent_requests
+                .or(max_concurrent_requests_value)
+                .unwrap_or(MAX_CONCURRENT_REQUESTS_DEFAULT),
+            &MAX_CONCURRENT_REQUESTS_RANGE,
+        ));
+
+        let connection_settings = ConnectionSettings {
+            http_cache: !(overrides.disable_http_cache || disable_http_cache),
+            max_retries: errors.check(check_range(
+                "max_retries",
+                overrides
+                    .max_retries
+                    .or(max_retries)
+                    .unwrap_or(MAX_RETRIES_DEFAULT),
+                &MAX_RETRIES_RANGE,
+            )),
+            retry_backoff: Duration::from_millis(
+                errors.check(check_range(
+                    "retry_backoff_ms",
+                    overrides
+                        .retry_backoff_ms
+                        .or(retry_backoff_ms)
+                        .unwrap_or(RETRY_BACKOFF_MS_DEFAULT),
+                    &RETRY_BACKOFF_MS_RANGE,
+                )),
+            ),
+        };
+
+        let instances =
+            instances_from_sections(path, instance_sections, &connection_settings, &mut errors);
+        errors.finish()?;
+
+        Ok(Self {
+            bot_users_re: new_bot_users_re()?,
+            collection_backend: overrides.collection_backend.unwrap_or(collection_backend),
+            connection_settings,
+            history_database,
+            history_retention_days,
+            instances,
+            inventory_file,
+            max_concurrent_requests,
+            refresh_jitter,
+            refresh_schedule,
+            refresh_schedules,
+        })
+    }
+
     #[instrument(skip_all, err)]
-    /// Creates a new [`Config`]
+    /// Creates a new [`Config`], from the configuration file if `CONFIG_FILE` is defined,
+    /// or else from the env variables
+    ///
+    /// Invalid values are never replaced by their default: all the errors are reported
+    /// at once (cf [`ConfigErrors`]). The command line [`Overrides`] take precedence
+    /// over the env variables and the configuration file
     pub fn new() -> Result<Self, anyhow::Error> {
         let _res = dotenv_override();
+        let overrides = overrides();
 
-        let Ok(token) = env::var("GITLAB_TOKEN") else {
-            return Err(anyhow!("env variable GITLAB_TOKEN is not defined"));
-        };
-        let Ok(hostname) = env::var("GITLAB_HOSTNAME") else {
-            return Err(anyhow!("env variable GITLAB_HOSTNAME is not defined"));
+        // Checking CONFIG_FILE env variable
+        if let Some(config_file) = config_file_path() {
+            return Self::from_file(&config_file);
+        }
+        let mut errors = ConfigErrors::default();
+
+        // Checking COLLECTION_BACKEND env variable
+        let collection_backend = match overrides.collection_backend {
+            Some(collection_backend) => collection_backend,
+            None => errors.check(get_collection_backend()),
         };
 
-        // Checking ACCEPT_INVALID_CERTS env variable
-        let accept_invalid_certs = get_bool_or_false("ACCEPT_INVALID_CERTS")?;
+        // Checking DISABLE_HTTP_CACHE env variable
+        let disable_http_cache =
+            overrides.disable_http_cache || errors.check(get_bool_or_false("DISABLE_HTTP_CACHE"));
 
-        // Checking OWNED_ENTITIES_ONLY env variable
-        let owned_entities_only = get_bool_or_false("OWNED_ENTITIES_ONLY")?;
+        // Checking HISTORY_DATABASE env variable
+        let history_database =
+            errors.check(get_optional_var("HISTORY_DATABASE").and_then(|value| {
+                data_file(
+                    "HISTORY_DATABASE",
+                    overrides
+                        .history_database
+                        .clone()
+                        .or_else(|| value.map(PathBuf::from)),
+                )
+            }));
 
-        // Checking MAX_CONCURRENT_REQUESTS env variable
-        let max_concurrent_requests = env::var("MAX_CONCURRENT_REQUESTS")
-            .ok()
-            .and_then(|value| value.parse().ok())
-            .unwrap_or(MAX_CONCURRENT_REQUESTS_DEFAULT);
+        // Checking HISTORY_RETENTION_DAYS env variable
+        let history_retention_days = errors.check(get_number(
+            "HISTORY_RETENTION_DAYS",
+            overrides.history_retention_days,
+            HISTORY_RETENTION_DAYS_DEFAULT,
+            &HISTORY_RETENTION_DAYS_RANGE,
+        ));
 
-        // Checking SKIP_USERS_TOKENS env variable
-        let skip_users_tokens = get_bool_or_false("SKIP_USERS_TOKENS")?;
+        // Checking INVENTORY_FILE env variable
+        let inventory_file = errors.check(get_optional_var("INVENTORY_FILE").and_then(|value| {
+            data_file(
+                "INVENTORY_FILE",
+                overrides
+                    .inventory_file
+                    .clone()
+                    .or_else(|| value.map(PathBuf::from)),
+            )
+        }));
 
-        // Checking USERNAMES_FILTER env variable
-        let usernames_filter = get_usernames_filter()?;
+        // Checking MAX_CONCURRENT_REQUESTS env variable
+        let max_concurrent_requests = errors.check(get_number(
+            "MAX_CONCURRENT_REQUESTS",
+            overrides.max_concurrent_requests,
+            MAX_CONCURRENT_REQUESTS_DEFAULT,
+            &MAX_CONCURRENT_REQUESTS_RANGE,
+        ));
 
-        if skip_users_tokens && usernames_filter.is_some() {
-            warn!("USERNAMES_FILTER is ignored because SKIP_USERS_TOKENS is set to yes");
-        }
+        // Checking REFRESH_SCHEDULE and DATA_REFRESH_HOURS env variables
+        let refresh_schedule = errors.check(get_refresh_schedule());
 
-        // Checking SKIP_NON_EXPIRING_TOKENS env variable
-        let skip_non_expiring_tokens = get_bool_or_false("SKIP_NON_EXPIRING_TOKENS")?;
+        // Checking GROUPS_REFRESH_SCHEDULE, PROJECTS_REFRESH_SCHEDULE and
+        // USERS_REFRESH_SCHEDULE env variables
+        let refresh_schedules = errors.check(get_refresh_schedules());
 
-        let data_refresh_hours = env::var("DATA_REFRESH_HOURS")
-            .ok()
-            .and_then(|env_value| env_value.parse().ok())
-            .filter(|env_value_u8| *env_value_u8 > 0 && *env_value_u8 <= 24)
-            .unwrap_or(DATA_REFRESH_HOURS_DEFAULT);
+        // Checking REFRESH_JITTER env variable
+        let refresh_jitter = errors.check(
+            get_optional_var("REFRESH_JITTER")
+                .and_then(|value| refresh_jitter("REFRESH_JITTER", value.as_deref())),
+        );
 
         // Checking MAX_RETRIES env variable
-        let max_retries = env::var("MAX_RETRIES")
-            .ok()
-            .and_then(|value| value.parse().ok())
-            .unwrap_or(MAX_RETRIES_DEFAULT);
+        let max_retries = errors.check(get_number(
+            "MAX_RETRIES",
+            overrides.max_retries,
+            MAX_RETRIES_DEFAULT,
+            &MAX_RETRIES_RANGE,
+        ));
 
         // Checking RETRY_BACKOFF_MS env variable
-        let retry_backoff_ms = env::var("RETRY_BACKOFF_MS")
-            .ok()
-            .and_then(|value| value.parse().ok())
-            .unwrap_or(RETRY_BACKOFF_MS_DEFAULT);
+        let retry_backoff_ms = errors.check(get_number(
+            "RETRY_BACKOFF_MS",
+            overrides.retry_backoff_ms,
+            RETRY_BACKOFF_MS_DEFAULT,
+            &RETRY_BACKOFF_MS_RANGE,
+        ));
 
-        let connection = Connection::new(
-            hostname,
-            token,
-            accept_invalid_certs,
+        let connection_settings = ConnectionSettings {
+            http_cache: !disable_http_cache,
             max_retries,
-            Duration::from_millis(retry_backoff_ms),
-        )
-        .context("failed to create gitlab_connection")?;
+            retry_backoff: Duration::from_millis(retry_backoff_ms),
+        };
 
-        let bot_users_re = Regex::new("(project|group)_[0-9]+_bot_[0-9a-f]{32,}")
-            .context("failed to compile bot_users_re regex")?;
+        // Checking GITLAB_INSTANCES env variable
+        let instances = get_instances(&connection_settings, &mut errors);
+        errors.finish()?;
 
         Ok(Self {
-            bot_users_re,
-            connection,
-            data_refresh_hours,
+            bot_users_re: new_bot_users_re()?,
+            collection_backend,
+            connection_settings,
+            history_database,
+            history_retention_days,
+            instances,
+            inventory_file,
             max_concurrent_requests,
+            refresh_jitter,
+            refresh_schedule,
+            refresh_schedules,
+        })
+    }
+
+    /// Returns the refresh schedule of `resource_type`: its own schedule if it has one, or else
+    /// `refresh_schedule`
+    pub fn refresh_schedule_of(&self, resource_type: ResourceType) -> &RefreshSchedule {
+        self.refresh_schedules
+            .get(&resource_type)
+            .unwrap_or(&self.refresh_schedule)
+    }
+
+    /// Returns the own schedule of `resource_type`, if any, as written in the configuration file
+    fn refresh_schedule_string(&self, resource_type: ResourceType) -> Option<String> {
+        self.refresh_schedules
+            .get(&resource_type)
+            .map(ToString::to_string)
+    }
+
+    /// Returns what has to be scanned again when the configuration `previous` is replaced by this
+    /// one, or `None` if it didn't change
+    ///
+    /// Only the resource types whose settings changed are rescanned, on the instances they belong
+    /// to. A new instance is fully scanned. The scope is empty if no setting affecting the tokens
+    /// changed: the metrics only have to be rendered again
+    pub fn rescan_scope(&self, previous: &Self) -> Option<ScanScope> {
+        let unchanged = self.to_config_file() == previous.to_config_file()
+            && self
+                .instances
+                .iter()
+                .map(|instance| instance.credential_tokens())
+                .eq(previous
+                    .instances
+                    .iter()
+                    .map(|instance| instance.credential_tokens()));
+        if unchanged {
+            return None;
+        }
+
+        let mut scope = ScanScope::default();
+        if self.collection_backend != previous.collection_backend {
+            scope
+                .types
+                .extend([ResourceType::Group, ResourceType::Project]);
+        }
+        for instance in &self.instances {
+            let changed_types = previous
+                .instances
+                .iter()
+                .find(|previous_instance| previous_instance.name == instance.name)
+                .map_or_else(
+                    || BTreeSet::from(ResourceType::ALL),
+                    |previous_instance| instance.changed_types(previous_instance),
+                );
+            if !changed_types.is_empty() {
+                scope.instances.insert(instance.name.clone(), changed_types);
+            }
+        }
+        Some(scope)
+    }
+
+    /// Returns the effective configuration as a [`ConfigFile`], with the tokens redacted.
+    /// Used by `--check-config`
+    pub fn to_config_file(&self) -> ConfigFile {
+        ConfigFile {
+            collection_backend: self.collection_backend,
+            data_refresh_hours: None,
+            disable_http_cache: !self.connection_settings.http_cache,
+            history_database: self.history_database.clone(),
+            history_retention_days: Some(self.history_retention_days),
+            instances: self
+                .instances
+                .iter()
+                .map(|instance| instance.to_section())
+                .collect(),
+            inventory_file: self.inventory_file.clone(),
+            max_concurrent_requests: Some(self.max_concurrent_requests),
+            max_retries: Some(self.connection_settings.max_retries),
+            refresh_jitter: Some(humantime::format_duration(self.refresh_jitter).to_string()),
+            refresh_schedule: Some(self.refresh_schedule.to_string()),
+            refresh_schedules: RefreshSchedulesSection {
+                groups: self.refresh_schedule_string(ResourceType::Group),
+                projects: self.refresh_schedule_string(ResourceType::Project),
+                users: self.refresh_schedule_string(ResourceType::User),
+            },
+            retry_backoff_ms: Some(
+                u64::try_from(self.connection_settings.retry_backoff.as_millis())
+                    .unwrap_or(u64::MAX),
+            ),
+        }
+    }
+}
+
+impl ConfigErrors {
+    /// Adds the errors of the instance `instance_name` to these errors
+    fn append(&mut self, instance_name: Option<&str>, other: Self) {
+        self.messages.extend(
+            other
+                .messages
+                .into_iter()
+                .map(|message| match instance_name {
+                    Some(name) => format!("instance '{name}': {message}"),
+                    None => message,
+                }),
+        );
+    }
+
+    /// Returns the value of `result`, or records its error and returns a default value
+    fn check<T: Default>(&mut self, result: Result<T, anyhow::Error>) -> T {
+        result.unwrap_or_else(|err| {
+            self.messages.push(format!("{err:#}"));
+            T::default()
+        })
+    }
+
+    /// Returns these errors if there is at least one
+    fn finish(self) -> Result<(), Self> {
+        if self.messages.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Records an error
+    fn push(&mut self, message: String) {
+        self.messages.push(message);
+    }
+}
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} configuration error(s):", self.messages.len())?;
+        for message in &self.messages {
+            write!(f, "\n  - {message}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConfigErrors {}
+
+impl Instance {
+    /// Returns the resource types whose tokens may change when the settings of `previous`
+    /// (the same instance, before the configuration was reloaded) are replaced by these ones
+    fn changed_types(&self, previous: &Self) -> BTreeSet<ResourceType> {
+        let mut section = self.to_section();
+        let mut previous_section = previous.to_section();
+        let mut changed_types = BTreeSet::new();
+
+        if section.filters.group_paths != previous_section.filters.group_paths {
+            changed_types.insert(ResourceType::Group);
+        }
+        if section.filters.project_paths != previous_section.filters.project_paths {
+            changed_types.insert(ResourceType::Project);
+        }
+        if section.filters.usernames != previous_section.filters.usernames
+            || section.skip_users_tokens != previous_section.skip_users_tokens
+        {
+            changed_types.insert(ResourceType::User);
+        }
+
+        // The other settings apply to every resource type, except the labels which are only
+        // added to the metrics
+        for instance_section in [&mut section, &mut previous_section] {
+            instance_section.filters.group_paths = PatternsSection::default();
+            instance_section.filters.project_paths = PatternsSection::default();
+            instance_section.filters.usernames = PatternsSection::default();
+            instance_section.labels.clear();
+            instance_section.skip_users_tokens = false;
+        }
+        if section != previous_section || self.credential_tokens() != previous.credential_tokens() {
+            changed_types.extend(ResourceType::ALL);
+        }
+        changed_types
+    }
+
+    /// Returns the name and the token of each credential
+    fn credential_tokens(&self) -> Vec<(&str, &str)> {
+        self.credentials
+            .iter()
+            .map(|credential| (credential.name.as_str(), credential.client.token()))
+            .collect()
+    }
+
+    /// Creates a new [`Instance`] from a `[[instances]]` table of the configuration file
+    fn from_section(
+        section: InstanceSection,
+        connection_settings: &ConnectionSettings,
+    ) -> Result<Self, ConfigErrors> {
+        let mut errors = ConfigErrors::default();
+
+        let name = section.name.unwrap_or_else(|| section.hostname.clone());
+        if name.is_empty() || section.hostname.is_empty() {
+            errors.push("instance names and hostnames can't be empty".to_owned());
+        }
+
+        let tokens = match (section.token, section.tokens.is_empty()) {
+            (Some(token), true) => vec![(DEFAULT_CREDENTIAL_NAME.to_owned(), token)],
+            (None, false) => section.tokens.into_iter().collect(),
+            (Some(_), false) => {
+                errors.push("'token' and 'tokens' can't be both defined".to_owned());
+                Vec::new()
+            }
+            (None, true) => {
+                errors.push("'token' or 'tokens' must be defined".to_owned());
+                Vec::new()
+            }
+        };
+        if tokens
+            .iter()
+            .any(|(credential_name, token)| credential_name.is_empty() || token.is_empty())
+        {
+            errors.push("credential names and tokens can't be empty".to_owned());
+        }
+
+        errors.check(prometheus_metrics::check_labels(&section.labels).context("invalid labels"));
+
+        let filters_section = section.filters;
+        let filters = Filters {
+            group_paths: errors.check(section_name_filter(
+                "group_paths",
+                &filters_section.group_paths,
+            )),
+            project_paths: errors.check(section_name_filter(
+                "project_paths",
+                &filters_section.project_paths,
+            )),
+            state: StateFilter {
+                expired_for_days_max: filters_section.skip_tokens_expired_for_days,
+                skip_inactive: filters_section.skip_inactive_tokens,
+                skip_non_expiring: filters_section.skip_non_expiring_tokens,
+                skip_revoked: filters_section.skip_revoked_tokens,
+            },
+            token_names: errors.check(section_name_filter(
+                "token_names",
+                &filters_section.token_names,
+            )),
+            token_scopes: errors.check(section_name_filter(
+                "token_scopes",
+                &filters_section.token_scopes,
+            )),
+            usernames: errors.check(section_name_filter("usernames", &filters_section.usernames)),
+        };
+
+        if section.skip_users_tokens && !filters.usernames.is_empty() {
+            warn!("{name}: usernames filters are ignored because skip_users_tokens is set to true");
+        }
+
+        let tls = TlsOptions {
+            accept_invalid_certs: section.accept_invalid_certs,
+            ca_cert_file: section.ca_cert_file,
+        };
+        let credentials = errors.check(new_credentials(
+            &name,
+            &section.hostname,
+            tokens,
+            &tls,
+            connection_settings,
+        ));
+        errors.finish()?;
+
+        Ok(Self {
+            credentials,
+            filters,
+            group_subtrees: GroupSubtrees {
+                exclude: normalize_group_paths(
+                    section.group_subtrees.exclude.iter().map(String::as_str),
+                ),
+                include: normalize_group_paths(
+                    section.group_subtrees.include.iter().map(String::as_str),
+                ),
+            },
+            hostname: section.hostname,
+            labels: section.labels,
+            name,
+            owned_entities_only: section.owned_entities_only,
+            skip_users_tokens: section.skip_users_tokens,
+            tls,
+        })
+    }
+
+    /// Creates a new [`Instance`]
+    ///
+    /// If `prefix` is set, the instance settings are read from the `<prefix>_*` env variables,
+    /// and default to the global env variables (except `<prefix>_GITLAB_HOSTNAME`
+    /// and `<prefix>_GITLAB_TOKEN`/`<prefix>_GITLAB_TOKENS` which are mandatory)
+    fn new(
+        name: String,
+        prefix: Option<&str>,
+        connection_settings: &ConnectionSettings,
+    ) -> Result<Self, ConfigErrors> {
+        let mut errors = ConfigErrors::default();
+
+        let hostname_var = prefixed(prefix, "GITLAB_HOSTNAME");
+        let hostname = errors.check(
+            env::var(&hostname_var)
+                .with_context(|| format!("env variable {hostname_var} is not defined")),
+        );
+        let tokens = errors.check(get_tokens(prefix));
+
+        // Checking ACCEPT_INVALID_CERTS env variable
+        let accept_invalid_certs = errors.check(get_bool_or_false(&instance_var(
+            prefix,
+            "ACCEPT_INVALID_CERTS",
+        )));
+
+        // Checking CA_CERT_FILE env variable
+        let ca_cert_file = env::var_os(instance_var(prefix, "CA_CERT_FILE")).map(PathBuf::from);
+
+        // Checking GROUP_SUBTREES_INCLUDE and GROUP_SUBTREES_EXCLUDE env variables
+        let group_subtrees = GroupSubtrees {
+            exclude: errors.check(get_group_paths(&instance_var(
+                prefix,
+                "GROUP_SUBTREES_EXCLUDE",
+            ))),
+            include: errors.check(get_group_paths(&instance_var(
+                prefix,
+                "GROUP_SUBTREES_INCLUDE",
+            ))),
+        };
+
+        // Checking OWNED_ENTITIES_ONLY env variable
+        let owned_entities_only = errors.check(get_bool_or_false(&instance_var(
+            prefix,
+            "OWNED_ENTITIES_ONLY",
+        )));
+
+        // Checking SKIP_USERS_TOKENS env variable
+        let skip_users_tokens = errors.check(get_bool_or_false(&instance_var(
+            prefix,
+            "SKIP_USERS_TOKENS",
+        )));
+
+        // Checking SKIP_*_TOKENS and SKIP_TOKENS_EXPIRED_FOR_DAYS env variables
+        let state = StateFilter {
+            expired_for_days_max: errors.check(get_expired_for_days_max(&instance_var(
+                prefix,
+                "SKIP_TOKENS_EXPIRED_FOR_DAYS",
+            ))),
+            skip_inactive: errors.check(get_bool_or_false(&instance_var(
+                prefix,
+                "SKIP_INACTIVE_TOKENS",
+            ))),
+            skip_non_expiring: errors.check(get_bool_or_false(&instance_var(
+                prefix,
+                "SKIP_NON_EXPIRING_TOKENS",
+            ))),
+            skip_revoked: errors.check(get_bool_or_false(&instance_var(
+                prefix,
+                "SKIP_REVOKED_TOKENS",
+            ))),
+        };
+
+        // Checking *_INCLUDE and *_EXCLUDE filters env variables
+        let mut filters = Filters {
+            group_paths: errors.check(get_name_filter(prefix, "GROUP_PATHS")),
+            project_paths: errors.check(get_name_filter(prefix, "PROJECT_PATHS")),
+            state,
+            token_names: errors.check(get_name_filter(prefix, "TOKEN_NAMES")),
+            token_scopes: errors.check(get_name_filter(prefix, "TOKEN_SCOPES")),
+            usernames: errors.check(get_name_filter(prefix, "USERNAMES")),
+        };
+
+        // Checking USERNAMES_FILTER env variable (exact matches, kept for compatibility)
+        if let Some(usernames) = errors.check(get_usernames_filter(&instance_var(
+            prefix,
+            "USERNAMES_FILTER",
+        ))) {
+            errors.check(filters.usernames.include_exact(&usernames));
+        }
+
+        if skip_users_tokens && !filters.usernames.is_empty() {
+            warn!("{name}: usernames filters are ignored because SKIP_USERS_TOKENS is set to yes");
+        }
+
+        let tls = TlsOptions {
+            accept_invalid_certs,
+            ca_cert_file,
+        };
+        let credentials = errors.check(new_credentials(
+            &name,
+            &hostname,
+            tokens,
+            &tls,
+            connection_settings,
+        ));
+        errors.finish()?;
+
+        Ok(Self {
+            credentials,
+            filters,
+            group_subtrees,
+            hostname,
+            labels: BTreeMap::new(),
+            name,
             owned_entities_only,
-            skip_non_expiring_tokens,
             skip_users_tokens,
-            usernames_filter,
+            tls,
         })
     }
+
+    /// Returns the instance as a `[[instances]]` table, with the tokens redacted
+    fn to_section(&self) -> InstanceSection {
+        InstanceSection {
+            accept_invalid_certs: self.tls.accept_invalid_certs,
+            ca_cert_file: self.tls.ca_cert_file.clone(),
+            filters: FiltersSection {
+                group_paths: patterns_section(&self.filters.group_paths),
+                project_paths: patterns_section(&self.filters.project_paths),
+                skip_inactive_tokens: self.filters.state.skip_inactive,
+                skip_non_expiring_tokens: self.filters.state.skip_non_expiring,
+                skip_revoked_tokens: self.filters.state.skip_revoked,
+                skip_tokens_expired_for_days: self.filters.state.expired_for_days_max,
+                token_names: patterns_section(&self.filters.token_names),
+                token_scopes: patterns_section(&self.filters.token_scopes),
+                usernames: patterns_section(&self.filters.usernames),
+            },
+            group_subtrees: self.group_subtrees.clone(),
+            hostname: self.hostname.clone(),
+            labels: self.labels.clone(),
+            name: Some(self.name.clone()),
+            owned_entities_only: self.owned_entities_only,
+            skip_users_tokens: self.skip_users_tokens,
+            token: None,
+            tokens: self
+                .credentials
+                .iter()
+                .map(|credential| (credential.name.clone(), REDACTED.to_owned()))
+                .collect(),
+        }
+    }
+}
+
+/// Returns the [`NameFilter`] configured in the `kind` table of `[instances.filters]`
+fn section_name_filter(
+    kind: &str,
+    patterns: &PatternsSection,
+) -> Result<NameFilter, anyhow::Error> {
+    NameFilter::from_patterns(&patterns.include, &patterns.exclude)
+        .with_context(|| format!("invalid {kind} filter"))
+}
+
+/// Returns the patterns of `filter` as a [`PatternsSection`]
+fn patterns_section(filter: &NameFilter) -> PatternsSection {
+    PatternsSection {
+        exclude: filter.exclude_patterns(),
+        include: filter.include_patterns(),
+    }
+}
+
+/// Creates the [`Credential`]s of the instance `name`, from their names and tokens
+fn new_credentials(
+    name: &str,
+    hostname: &str,
+    tokens: Vec<(String, String)>,
+    tls: &TlsOptions,
+    connection_settings: &ConnectionSettings,
+) -> Result<Vec<Arc<Credential>>, anyhow::Error> {
+    let mut credentials = Vec::new();
+    for (credential_name, token) in tokens {
+        // The cache is kept across the reloads which don't change the hostname or the token
+        let http_cache = connection_settings
+            .http_cache
+            .then(|| HttpCache::shared(name, &credential_name, hostname, &token));
+        let connection = Connection::new(
+            name.to_owned(),
+            hostname.to_owned(),
+            token,
+            tls,
+            http_cache,
+            connection_settings.max_retries,
+            connection_settings.retry_backoff,
+        )
+        .with_context(|| {
+            format!("failed to create gitlab_connection for credential '{credential_name}'")
+        })?;
+
+        credentials.push(Arc::new(Credential {
+            client: Client::new(connection),
+            name: credential_name,
+        }));
+    }
+    Ok(credentials)
+}
+
+/// Creates the regex matching the group or project bot users
+fn new_bot_users_re() -> Result<Regex, anyhow::Error> {
+    Regex::new("(project|group)_[0-9]+_bot_[0-9a-f]{32,}")
+        .context("failed to compile bot_users_re regex")
+}
+
+/// Returns the instances defined by the `GITLAB_INSTANCES` env variable, or the single instance
+/// defined by `GITLAB_HOSTNAME` if it isn't set. The invalid instances are recorded in `errors`
+fn get_instances(
+    connection_settings: &ConnectionSettings,
+    errors: &mut ConfigErrors,
+) -> Vec<Arc<Instance>> {
+    let mut instances: Vec<Arc<Instance>> = Vec::new();
+    match env::var("GITLAB_INSTANCES") {
+        Ok(value) => {
+            for name in value
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+            {
+                if instances.iter().any(|instance| instance.name == name) {
+                    errors.push(format!(
+                        "instance '{name}' is defined twice in GITLAB_INSTANCES"
+                    ));
+                    continue;
+                }
+                let prefix = env_var_prefix(name);
+                match Instance::new(name.to_owned(), Some(&prefix), connection_settings) {
+                    Ok(instance) => instances.push(Arc::new(instance)),
+                    Err(instance_errors) => errors.append(Some(name), instance_errors),
+                }
+            }
+            if value.split(',').all(|name| name.trim().is_empty()) {
+                errors.push("env variable GITLAB_INSTANCES is empty".to_owned());
+            }
+        }
+        Err(env::VarError::NotPresent) => {
+            let name = env::var("GITLAB_INSTANCE_NAME")
+                .or_else(|_| env::var("GITLAB_HOSTNAME"))
+                .unwrap_or_default();
+            match Instance::new(name, None, connection_settings) {
+                Ok(instance) => instances.push(Arc::new(instance)),
+                Err(instance_errors) => errors.append(None, instance_errors),
+            }
+        }
+        Err(env::VarError::NotUnicode(value)) => {
+            errors.push(format!(
+                "invalid value for 'GITLAB_INSTANCES': '{}'.",
+                value.display()
+            ));
+        }
+    }
+    instances
+}
+
+/// Returns the instances defined by the `[[instances]]` sections of the configuration file at
+/// `path`. The invalid sections are recorded in `errors`
+fn instances_from_sections(
+    path: &Path,
+    instance_sections: Vec<InstanceSection>,
+    connection_settings: &ConnectionSettings,
+    errors: &mut ConfigErrors,
+) -> Vec<Arc<Instance>> {
+    if instance_sections.is_empty() {
+        errors.push(format!("no instance is defined in {}", path.display()));
+    }
+    let mut instances: Vec<Arc<Instance>> = Vec::new();
+    for section in instance_sections {
+        let section_name = section
+            .name
+            .clone()
+            .unwrap_or_else(|| section.hostname.clone());
+        match Instance::from_section(section, connection_settings) {
+            Ok(instance) if instances.iter().any(|other| other.name == instance.name) => {
+                errors.push(format!("instance '{}' is defined twice", instance.name));
+            }
+            Ok(instance) => instances.push(Arc::new(instance)),
+            Err(instance_errors) => errors.append(Some(&section_name), instance_errors),
+        }
+    }
+    instances
+}
+
+/// Returns the path of the configuration file, given on the command line or in `CONFIG_FILE`
+pub fn config_file_path() -> Option<PathBuf> {
+    overrides()
+        .config_file
+        .clone()
+        .or_else(|| env::var_os("CONFIG_FILE").map(PathBuf::from))
+}
+
+/// Creates the configuration and makes it the value of [`CONFIG`], or returns the error of the
+/// invalid configuration instead of crashing when [`CONFIG`] is first used
+pub fn init() -> Result<(), anyhow::Error> {
+    let config = Config::new()?;
+    *VALIDATED_CONFIG
+        .lock()
+        .map_err(|err| anyhow!("failed to store the configuration: {err}"))? = Some(config);
+    LazyLock::force(&CONFIG);
+    Ok(())
+}
+
+/// Returns the settings given on the command line, or the default ones if
+/// [`set_overrides`] has not been called
+fn overrides() -> &'static Overrides {
+    OVERRIDES.get_or_init(Overrides::default)
+}
+
+/// Sets the settings given on the command line. Must be called before [`CONFIG`] is used
+pub fn set_overrides(overrides: Overrides) {
+    if OVERRIDES.set(overrides).is_err() {
+        warn!("the command line settings have already been set");
+    }
+}
+
+/// Reads the configuration again and replaces [`CONFIG`] if it is valid and changed, and returns
+/// what has to be scanned again (cf [`Config::rescan_scope`]), or `None` if it didn't change.
+/// The current configuration is kept if the new one is invalid
+pub fn reload() -> Result<Option<ScanScope>, anyhow::Error> {
+    let config = Config::new().context("invalid configuration")?;
+    let Some(scope) = config.rescan_scope(&CONFIG.load()) else {
+        info!("the configuration didn't change");
+        return Ok(None);
+    };
+    CONFIG.store(Arc::new(config));
+    info!("configuration reloaded");
+    Ok(Some(scope))
+}
+
+/// Returns the prefix of the env variables of the instance `name`
+/// (uppercased, with `-` and `.` replaced by `_`)
+fn env_var_prefix(name: &str) -> String {
+    name.to_uppercase().replace(['-', '.'], "_")
+}
+
+/// Returns `<prefix>_<env_var_name>`, or `env_var_name` if there is no prefix
+fn prefixed(prefix: Option<&str>, env_var_name: &str) -> String {
+    match prefix {
+        Some(instance_prefix) => format!("{instance_prefix}_{env_var_name}"),
+        None => env_var_name.to_owned(),
+    }
+}
+
+/// Returns `<prefix>_<env_var_name>` if it is defined, or `env_var_name` otherwise
+fn instance_var(prefix: Option<&str>, env_var_name: &str) -> String {
+    let prefixed_name = prefixed(prefix, env_var_name);
+    if env::var_os(&prefixed_name).is_some() {
+        prefixed_name
+    } else {
+        env_var_name.to_owned()
+    }
 }
 
 /// Returns the boolean value of `env_var_name`, or `false` if the
@@ -147,10 +1107,330 @@ fn get_bool_or_false(env_var_name: &str) -> Result<bool, anyhow::Error> {
     }
 }
 
-/// Returns the usernames configured in `USERNAMES_FILTER`,
+/// Returns the backend configured in `COLLECTION_BACKEND`,
+/// or [`CollectionBackend::Rest`] if the environment variable is not defined.
+fn get_collection_backend() -> Result<CollectionBackend, anyhow::Error> {
+    match env::var("COLLECTION_BACKEND").as_deref() {
+        Ok("graphql") => Ok(CollectionBackend::GraphQl),
+        Ok("rest") | Err(env::VarError::NotPresent) => Ok(CollectionBackend::Rest),
+        Ok(value) => Err(anyhow!(
+            "invalid value for 'COLLECTION_BACKEND': '{value}'. expected 'rest' or 'graphql'.",
+        )),
+        Err(env::VarError::NotUnicode(value)) => Err(anyhow!(
+            "invalid value for 'COLLECTION_BACKEND': '{}'. expected 'rest' or 'graphql'.",
+            value.display()
+        )),
+    }
+}
+
+/// Returns the credentials (name and token) of an instance
+///
+/// They are read from `GITLAB_TOKENS` (cf [`parse_tokens`]) if it is defined, or else from
+/// `GITLAB_TOKEN`, with the credential name [`DEFAULT_CREDENTIAL_NAME`]
+fn get_tokens(prefix: Option<&str>) -> Result<Vec<(String, String)>, anyhow::Error> {
+    let tokens_var = prefixed(prefix, "GITLAB_TOKENS");
+    if let Ok(value) = env::var(&tokens_var) {
+        return parse_tokens(&value).with_context(|| format!("invalid value for '{tokens_var}'"));
+    }
+
+    let single_token_var = prefixed(prefix, "GITLAB_TOKEN");
+    let Ok(token) = env::var(&single_token_var) else {
+        return Err(anyhow!(
+            "env variable {single_token_var} (or {tokens_var}) is not defined"
+        ));
+    };
+    Ok(vec![(DEFAULT_CREDENTIAL_NAME.to_owned(), token)])
+}
+
+/// Parses a comma separated list of `<credential name>:<token>`
+fn parse_tokens(value: &str) -> Result<Vec<(String, String)>, anyhow::Error> {
+    let mut tokens: Vec<(String, String)> = Vec::new();
+
+    for item in value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+    {
+        let Some((name, token)) = item.split_once(':') else {
+            return Err(anyhow!(
+                "expected '<credential name>:<token>', got an item without ':'"
+            ));
+        };
+        let (credential_name, credential_token) = (name.trim(), token.trim());
+        if credential_name.is_empty() || credential_token.is_empty() {
+            return Err(anyhow!("credential names and tokens can't be empty"));
+        }
+        if tokens.iter().any(|(other, _)| other == credential_name) {
+            return Err(anyhow!("credential '{credential_name}' is defined twice"));
+        }
+        tokens.push((credential_name.to_owned(), credential_token.to_owned()));
+    }
+
+    if tokens.is_empty() {
+        return Err(anyhow!("no credential is defined"));
+    }
+    Ok(tokens)
+}
+
+/// Returns the group full paths configured in `env_var_name` (cf [`parse_group_paths`])
+fn get_group_paths(env_var_name: &str) -> Result<Vec<String>, anyhow::Error> {
+    match env::var(env_var_name) {
+        Ok(value) => Ok(parse_group_paths(&value)),
+        Err(env::VarError::NotPresent) => Ok(Vec::new()),
+        Err(env::VarError::NotUnicode(value)) => Err(anyhow!(
+            "invalid value for '{env_var_name}': '{}'.",
+            value.display()
+        )),
+    }
+}
+
+/// Parses a comma separated list of group full paths (cf [`normalize_group_paths`])
+fn parse_group_paths(value: &str) -> Vec<String> {
+    normalize_group_paths(value.split(','))
+}
+
+/// Sorts and trims group full paths, dropping the groups already in the subtree
+/// of another group of the list
+fn normalize_group_paths<'path, I>(paths: I) -> Vec<String>
+where
+    I: IntoIterator<Item = &'path str>,
+{
+    let mut group_paths: Vec<&str> = paths
+        .into_iter()
+        .map(|item| item.trim().trim_matches('/'))
+        .filter(|group_path| !group_path.is_empty())
+        .collect();
+    group_paths.sort_unstable();
+    group_paths.dedup();
+
+    group_paths
+        .iter()
+        .filter(|group_path| {
+            !group_paths
+                .iter()
+                .any(|other| other != *group_path && is_in_subtree(group_path, other))
+        })
+        .map(|group_path| (*group_path).to_owned())
+        .collect()
+}
+
+/// Returns `true` if `full_path` is the group `group_path` or one of its descendants
+pub fn is_in_subtree(full_path: &str, group_path: &str) -> bool {
+    full_path
+        .strip_prefix(group_path)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Returns the [`NameFilter`] configured in `<kind>_INCLUDE` and `<kind>_EXCLUDE`
+fn get_name_filter(prefix: Option<&str>, kind: &str) -> Result<NameFilter, anyhow::Error> {
+    let include = get_optional_var(&instance_var(prefix, &format!("{kind}_INCLUDE")))?;
+    let exclude = get_optional_var(&instance_var(prefix, &format!("{kind}_EXCLUDE")))?;
+
+    NameFilter::new(include.as_deref(), exclude.as_deref())
+        .with_context(|| format!("invalid {kind} filter"))
+}
+
+/// Returns the number of days configured in `env_var_name` (`SKIP_TOKENS_EXPIRED_FOR_DAYS` or its
+/// per instance variant), or `None` if the environment variable is not defined.
+fn get_expired_for_days_max(env_var_name: &str) -> Result<Option<u32>, anyhow::Error> {
+    get_optional_var(env_var_name)?
+        .map(|value| {
+            value.trim().parse().with_context(|| {
+                format!("invalid value for '{env_var_name}': '{value}'. expected a number of days.")
+            })
+        })
+        .transpose()
+}
+
+/// Returns `cli_value` if it is set, or else the number configured in `env_var_name`,
+/// or `default` if the environment variable is not defined. The number must be in `range`
+fn get_number<T>(
+    env_var_name: &str,
+    cli_value: Option<T>,
+    default: T,
+    range: &RangeInclusive<T>,
+) -> Result<T, anyhow::Error>
+where
+    T: FromStr + PartialOrd + fmt::Display,
+    T::Err: fmt::Display,
+{
+    if let Some(number) = cli_value {
+        return check_range(env_var_name, number, range);
+    }
+    let Some(value) = get_optional_var(env_var_name)? else {
+        return Ok(default);
+    };
+    let number = value
+        .trim()
+        .parse()
+        .map_err(|err| anyhow!("invalid value for '{env_var_name}': '{value}'. {err}."))?;
+    check_range(env_var_name, number, range)
+}
+
+/// Returns `value` if it is in `range`, or an error mentioning the setting `name`
+fn check_range<T>(name: &str, value: T, range: &RangeInclusive<T>) -> Result<T, anyhow::Error>
+where
+    T: PartialOrd + fmt::Display,
+{
+    if range.contains(&value) {
+        Ok(value)
+    } else {
+        Err(anyhow!(
+            "invalid value for '{name}': {value}. expected a value >= {} and <= {}.",
+            range.start(),
+            range.end()
+        ))
+    }
+}
+
+/// Returns the refresh schedule configured in `REFRESH_SCHEDULE` and `DATA_REFRESH_HOURS`
+/// (cf [`refresh_schedule`])
+fn get_refresh_schedule() -> Result<RefreshSchedule, anyhow::Error> {
+    let schedule = get_optional_var("REFRESH_SCHEDULE")?;
+    let data_refresh_hours = get_optional_var("DATA_REFRESH_HOURS")?
+        .map(|value| {
+            value
+                .trim()
+                .parse()
+                .map_err(|err| anyhow!("invalid value for 'DATA_REFRESH_HOURS': '{value}'. {err}."))
+        })
+        .transpose()?;
+    refresh_schedule(
+        ("REFRESH_SCHEDULE", schedule.as_deref()),
+        ("DATA_REFRESH_HOURS", data_refresh_hours),
+    )
+}
+
+/// Returns the refresh schedules configured in `GROUPS_REFRESH_SCHEDULE`,
+/// `PROJECTS_REFRESH_SCHEDULE` and `USERS_REFRESH_SCHEDULE` (cf [`refresh_schedules`])
+fn get_refresh_schedules() -> Result<BTreeMap<ResourceType, RefreshSchedule>, anyhow::Error> {
+    refresh_schedules([
+        (
+            ResourceType::Group,
+            "GROUPS_REFRESH_SCHEDULE",
+            get_optional_var("GROUPS_REFRESH_SCHEDULE")?,
+        ),
+        (
+            ResourceType::Project,
+            "PROJECTS_REFRESH_SCHEDULE",
+            get_optional_var("PROJECTS_REFRESH_SCHEDULE")?,
+        ),
+        (
+            ResourceType::User,
+            "USERS_REFRESH_SCHEDULE",
+            get_optional_var("USERS_REFRESH_SCHEDULE")?,
+        ),
+    ])
+}
+
+/// Returns the refresh schedules configured in the `[refresh_schedules]` section of the
+/// configuration file (cf [`refresh_schedules`])
+fn section_refresh_schedules(
+    section: RefreshSchedulesSection,
+) -> Result<BTreeMap<ResourceType, RefreshSchedule>, anyhow::Error> {
+    refresh_schedules([
+        (
+            ResourceType::Group,
+            "refresh_schedules.groups",
+            section.groups,
+        ),
+        (
+            ResourceType::Project,
+            "refresh_schedules.projects",
+            section.projects,
+        ),
+        (ResourceType::User, "refresh_schedules.users", section.users),
+    ])
+}
+
+/// Checks the file written by the exporter given in the setting `name`, such as the inventory
+/// file: its directory must exist
+fn data_file(name: &str, value: Option<PathBuf>) -> Result<Option<PathBuf>, anyhow::Error> {
+    let Some(path) = value else {
+        return Ok(None);
+    };
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() && !parent.is_dir() => Err(anyhow!(
+            "invalid value for '{name}': {} is not a directory",
+            parent.display()
+        )),
+        _ => Ok(Some(path)),
+    }
+}
+
+/// Returns the refresh jitter given on the command line, or else parsed from `value`, or zero
+/// if neither is set. `name` is the name of the setting, used in the errors
+fn refresh_jitter(name: &str, value: Option<&str>) -> Result<Duration, anyhow::Error> {
+    if let Some(jitter) = overrides().refresh_jitter {
+        return Ok(jitter);
+    }
+    value.map_or(Ok(Duration::ZERO), |jitter| {
+        schedule::parse_jitter(jitter).with_context(|| format!("invalid value for '{name}'"))
+    })
+}
+
+/// Returns the refresh schedules of the resource types which have their own, parsed from the
+/// settings, with their names
+fn refresh_schedules(
+    settings: [(ResourceType, &str, Option<String>); 3],
+) -> Result<BTreeMap<ResourceType, RefreshSchedule>, anyhow::Error> {
+    let mut schedules = BTreeMap::new();
+    for (resource_type, name, value) in settings {
+        if let Some(schedule) = value {
+            schedules.insert(
+                resource_type,
+                schedule
+                    .parse()
+                    .with_context(|| format!("invalid value for '{name}'"))?,
+            );
+        }
+    }
+    Ok(schedules)
+}
+
+/// Returns the refresh schedule. The first one set is used, among `--refresh-schedule`,
+/// `--data-refresh-hours`, the `schedule` setting and the `data_refresh_hours` setting, with
+/// their names. The default is every [`DATA_REFRESH_HOURS_DEFAULT`] hours
+fn refresh_schedule(
+    (schedule_name, schedule): (&str, Option<&str>),
+    (hours_name, data_refresh_hours): (&str, Option<u8>),
+) -> Result<RefreshSchedule, anyhow::Error> {
+    let cli_settings = overrides();
+    if let Some(cli_schedule) = &cli_settings.refresh_schedule {
+        return Ok(cli_schedule.clone());
+    }
+    let hours = match (cli_settings.data_refresh_hours, schedule) {
+        (Some(cli_hours), _) => cli_hours,
+        (None, Some(value)) => {
+            return value
+                .parse()
+                .with_context(|| format!("invalid value for '{schedule_name}'"));
+        }
+        (None, None) => data_refresh_hours.unwrap_or(DATA_REFRESH_HOURS_DEFAULT),
+    };
+    Ok(RefreshSchedule::hours(check_range(
+        hours_name,
+        hours,
+        &DATA_REFRESH_HOURS_RANGE,
+    )?))
+}
+
+/// Returns the value of `env_var_name`, or `None` if the environment variable is not defined.
+fn get_optional_var(env_var_name: &str) -> Result<Option<String>, anyhow::Error> {
+    match env::var(env_var_name) {
+        Ok(value) => Ok(Some(value)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(value)) => Err(anyhow!(
+            "invalid value for '{env_var_name}': '{}'.",
+            value.display()
+        )),
+    }
+}
+
+/// Returns the usernames configured in `env_var_name` (`USERNAMES_FILTER` or its per instance variant),
 /// or `None` if the environment variable is not defined.
-fn get_usernames_filter() -> Result<Option<HashSet<String>>, anyhow::Error> {
-    match env::var("USERNAMES_FILTER") {
+fn get_usernames_filter(env_var_name: &str) -> Result<Option<BTreeSet<String>>, anyhow::Error> {
+    match env::var(env_var_name) {
         Ok(value) => {
             let users = value
                 .split(',')
@@ -163,9 +1443,250 @@ fn get_usernames_filter() -> Result<Option<HashSet<String>>, anyhow::Error> {
         Err(err) => match err {
             env::VarError::NotPresent => Ok(None),
             env::VarError::NotUnicode(value) => Err(anyhow!(
-                "invalid value for 'USERNAMES_FILTER': '{}'.",
+                "invalid value for '{env_var_name}': '{}'.",
                 value.display()
             )),
         },
     }
 }
+
+//-------------------------------------------
+//
+// Unit tests
+//
+// ------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use crate::config::{
+        ConfigErrors, ConnectionSettings, DATA_REFRESH_HOURS_RANGE, GroupSubtrees, Instance,
+        MAX_CONCURRENT_REQUESTS_RANGE, check_range, is_in_subtree, parse_group_paths, parse_tokens,
+    };
+    use crate::config_file::InstanceSection;
+    use crate::inventory::ResourceType;
+
+    fn instance_from_toml(value: &str) -> Result<Instance, ConfigErrors> {
+        let section: InstanceSection = toml::from_str(value).unwrap();
+        let connection_settings = ConnectionSettings {
+            http_cache: false,
+            max_retries: 0,
+            retry_backoff: Duration::from_millis(1),
+        };
+        Instance::from_section(section, &connection_settings)
+    }
+
+    #[test]
+    fn nested_group_paths_are_dropped() {
+        assert_eq!(
+            parse_group_paths("business/unit, /business/,other,business/unit/team,other"),
+            vec!["business".to_owned(), "other".to_owned()]
+        );
+    }
+
+    #[test]
+    fn subtree_membership() {
+        assert!(is_in_subtree("business/unit", "business/unit"));
+        assert!(is_in_subtree("business/unit/project", "business/unit"));
+        assert!(!is_in_subtree("business/unit-b", "business/unit"));
+        assert!(!is_in_subtree("business", "business/unit"));
+    }
+
+    #[test]
+    fn excluded_subtrees() {
+        let group_subtrees = GroupSubtrees {
+            exclude: vec!["business/sandbox".to_owned()],
+            include: vec!["business".to_owned()],
+        };
+
+        assert!(group_subtrees.is_excluded("business/sandbox/project"));
+        assert!(!group_subtrees.is_excluded("business/unit/project"));
+    }
+
+    #[test]
+    fn tokens_are_parsed() {
+        assert_eq!(
+            parse_tokens(" team-a:glpat-aaa, team-b:glpat-bbb,").unwrap(),
+            vec![
+                ("team-a".to_owned(), "glpat-aaa".to_owned()),
+                ("team-b".to_owned(), "glpat-bbb".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_tokens() {
+        assert!(parse_tokens("glpat-aaa").is_err());
+        assert!(parse_tokens("team-a:").is_err());
+        assert!(parse_tokens("team-a:glpat-aaa,team-a:glpat-bbb").is_err());
+        assert!(parse_tokens(" , ").is_err());
+    }
+
+    #[test]
+    fn instance_sections() {
+        let instance = instance_from_toml(
+            r#"
+            hostname = "gitlab.example.com"
+            token = "glpat-aaa"
+            labels = { env = "prod" }
+            group_subtrees = { include = ["business/unit", "/business/"] }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(instance.name, "gitlab.example.com");
+        assert_eq!(instance.credentials[0].name, "default");
+        assert_eq!(instance.labels["env"], "prod");
+        assert_eq!(instance.group_subtrees.include, vec!["business".to_owned()]);
+    }
+
+    #[test]
+    fn changed_types() {
+        let previous = instance_from_toml(
+            r#"
+            hostname = "gitlab.example.com"
+            token = "glpat-aaa"
+            filters = { project_paths = { include = ["business/**"] } }
+            "#,
+        )
+        .unwrap();
+        let changed_types = |value: &str| {
+            let instance =
+                instance_from_toml(&format!("hostname = \"gitlab.example.com\"\n{value}")).unwrap();
+            instance
+                .changed_types(&previous)
+                .into_iter()
+                .collect::<Vec<_>>()
+        };
+
+        assert!(
+            changed_types(
+                r#"
+                token = "glpat-aaa"
+                filters = { project_paths = { include = ["business/**"] } }
+                labels = { env = "prod" }
+                "#
+            )
+            .is_empty()
+        );
+        assert_eq!(
+            changed_types(r#"token = "glpat-aaa""#),
+            vec![ResourceType::Project]
+        );
+        assert_eq!(
+            changed_types(
+                r#"
+                token = "glpat-aaa"
+                skip_users_tokens = true
+                filters = { project_paths = { include = ["business/**"] }, usernames = { exclude = ["bot"] } }
+                "#
+            ),
+            vec![ResourceType::User]
+        );
+        assert_eq!(
+            changed_types(
+                r#"
+                token = "glpat-bbb"
+                filters = { project_paths = { include = ["business/**"] } }
+                "#
+            ),
+            vec![
+                ResourceType::Group,
+                ResourceType::Project,
+                ResourceType::User
+            ]
+        );
+        assert_eq!(
+            changed_types(
+                r#"
+                token = "glpat-aaa"
+                filters = { project_paths = { include = ["business/**"] }, skip_revoked_tokens = true }
+                "#
+            ),
+            vec![
+                ResourceType::Group,
+                ResourceType::Project,
+                ResourceType::User
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_instance_sections() {
+        assert!(instance_from_toml(r#"hostname = "gitlab.example.com""#).is_err());
+        assert!(
+            instance_from_toml(
+                r#"
+                hostname = "gitlab.example.com"
+                token = "glpat-aaa"
+                tokens = { team-a = "glpat-bbb" }
+                "#
+            )
+            .is_err()
+        );
+        assert!(
+            instance_from_toml(
+                r#"
+                hostname = "gitlab.example.com"
+                token = "glpat-aaa"
+                labels = { instance = "prod" }
+                "#
+            )
+            .is_err()
+        );
+        assert!(
+            instance_from_toml(
+                r#"
+                hostname = "gitlab.example.com"
+                token = "glpat-aaa"
+                filters = { token_names = { include = ["re:("] } }
+                "#
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn instance_errors_are_aggregated() {
+        let errors = instance_from_toml(
+            r#"
+            hostname = "gitlab.example.com"
+            token = "glpat-aaa"
+            tokens = { team-a = "glpat-bbb" }
+            labels = { instance = "prod" }
+            filters = { token_names = { include = ["re:("] } }
+            "#,
+        )
+        .err()
+        .unwrap();
+
+        assert_eq!(errors.messages.len(), 3);
+        assert!(errors.to_string().starts_with("3 configuration error(s):"));
+    }
+
+    #[test]
+    fn numbers_are_checked() {
+        assert_eq!(check_range("n", 24, &DATA_REFRESH_HOURS_RANGE).unwrap(), 24);
+        assert!(check_range("n", 0, &DATA_REFRESH_HOURS_RANGE).is_err());
+        assert!(check_range("n", 25, &DATA_REFRESH_HOURS_RANGE).is_err());
+        assert!(check_range("n", 0, &MAX_CONCURRENT_REQUESTS_RANGE).is_err());
+    }
+
+    #[test]
+    fn tokens_are_redacted() {
+        let instance = instance_from_toml(
+            r#"
+            hostname = "gitlab.example.com"
+            tokens = { team-a = "glpat-aaa" }
+            filters = { usernames = { include = ["alice"] } }
+            "#,
+        )
+        .unwrap();
+        let section = toml::to_string(&instance.to_section()).unwrap();
+
+        assert!(section.contains(r#"team-a = "<redacted>""#));
+        assert!(!section.contains("glpat-aaa"));
+        assert!(section.contains(r#"include = ["alice"]"#));
+    }
+}
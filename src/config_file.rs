@@ -0,0 +1,231 @@
+//! Structure of the TOML configuration file (`CONFIG_FILE`), converted into a [`Config`](crate::config::Config)
+//!
+//! The same structure is used to print the effective configuration (cf `--check-config`)
+//!
+//! The file covers the same settings as the env variables, with one `[[instances]]` table per
+//! gitlab instance:
+//!
+//! ```toml
+//! refresh_schedule = "6h"
+//!
+//! [refresh_schedules]
+//! users = "1h"
+//!
+//! [[instances]]
+//! name = "self-managed"
+//! hostname = "gitlab.example.com"
+//! tokens = { team-a = "<team a token>", team-b = "<team b token>" }
+//! labels = { env = "prod" }
+//!
+//! [instances.filters]
+//! project_paths = { include = ["business/**"], exclude = ["**/archive-*"] }
+//! skip_revoked_tokens = true
+//! ```
+
+use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::Path, path::PathBuf};
+
+use crate::config::{CollectionBackend, GroupSubtrees};
+
+/// Root of the configuration file
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Backend used to collect projects and groups tokens (`COLLECTION_BACKEND`)
+    #[serde(default)]
+    pub collection_backend: CollectionBackend,
+    /// Time interval between updates, in hours (`DATA_REFRESH_HOURS`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_refresh_hours: Option<u8>,
+    /// Disables the in-memory cache of gitlab API responses (`DISABLE_HTTP_CACHE`)
+    #[serde(default)]
+    pub disable_http_cache: bool,
+    /// `SQLite` database keeping the history of the tokens (`HISTORY_DATABASE`)
+    pub history_database: Option<PathBuf>,
+    /// Number of days the tokens which are no longer seen are kept in the history database
+    /// (`HISTORY_RETENTION_DAYS`)
+    pub history_retention_days: Option<u16>,
+    /// gitlab instances to scan
+    pub instances: Vec<InstanceSection>,
+    /// File where the tokens are saved after each successful scan, and loaded at startup
+    /// (`INVENTORY_FILE`)
+    pub inventory_file: Option<PathBuf>,
+    /// Total number of concurrent requests, per instance (`MAX_CONCURRENT_REQUESTS`)
+    pub max_concurrent_requests: Option<u16>,
+    /// Number of times a transient gitlab API error is retried (`MAX_RETRIES`)
+    pub max_retries: Option<u32>,
+    /// Maximum random delay added to each scheduled scan (`REFRESH_JITTER`)
+    pub refresh_jitter: Option<String>,
+    /// Interval between updates or cron expression, overrides `data_refresh_hours`
+    /// (`REFRESH_SCHEDULE`)
+    pub refresh_schedule: Option<String>,
+    /// Refresh schedules of the resource types which don't use `refresh_schedule`
+    #[serde(default, skip_serializing_if = "RefreshSchedulesSection::is_empty")]
+    pub refresh_schedules: RefreshSchedulesSection,
+    /// Base delay for the retry exponential backoff (`RETRY_BACKOFF_MS`)
+    pub retry_backoff_ms: Option<u64>,
+}
+
+/// Filters applied to the tokens of an instance (`[instances.filters]`)
+#[derive(Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FiltersSection {
+    /// `GROUP_PATHS_INCLUDE` and `GROUP_PATHS_EXCLUDE`
+    pub group_paths: PatternsSection,
+    /// `PROJECT_PATHS_INCLUDE` and `PROJECT_PATHS_EXCLUDE`
+    pub project_paths: PatternsSection,
+    /// `SKIP_INACTIVE_TOKENS`
+    pub skip_inactive_tokens: bool,
+    /// `SKIP_NON_EXPIRING_TOKENS`
+    pub skip_non_expiring_tokens: bool,
+    /// `SKIP_REVOKED_TOKENS`
+    pub skip_revoked_tokens: bool,
+    /// `SKIP_TOKENS_EXPIRED_FOR_DAYS`
+    pub skip_tokens_expired_for_days: Option<u32>,
+    /// `TOKEN_NAMES_INCLUDE` and `TOKEN_NAMES_EXCLUDE`
+    pub token_names: PatternsSection,
+    /// `TOKEN_SCOPES_INCLUDE` and `TOKEN_SCOPES_EXCLUDE`
+    pub token_scopes: PatternsSection,
+    /// `USERNAMES_INCLUDE` and `USERNAMES_EXCLUDE`
+    pub usernames: PatternsSection,
+}
+
+/// A gitlab instance (`[[instances]]`)
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstanceSection {
+    /// `ACCEPT_INVALID_CERTS`
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+    /// `CA_CERT_FILE`
+    pub ca_cert_file: Option<PathBuf>,
+    /// Filters applied to the tokens
+    #[serde(default)]
+    pub filters: FiltersSection,
+    /// `GROUP_SUBTREES_INCLUDE` and `GROUP_SUBTREES_EXCLUDE`
+    #[serde(default)]
+    pub group_subtrees: GroupSubtrees,
+    /// `GITLAB_HOSTNAME`
+    pub hostname: String,
+    /// Additional labels added to the metrics of the instance
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Value of the `instance` label (`GITLAB_INSTANCE_NAME`), defaults to `hostname`
+    pub name: Option<String>,
+    /// `OWNED_ENTITIES_ONLY`
+    #[serde(default)]
+    pub owned_entities_only: bool,
+    /// `SKIP_USERS_TOKENS`
+    #[serde(default)]
+    pub skip_users_tokens: bool,
+    /// `GITLAB_TOKEN`
+    pub token: Option<String>,
+    /// `GITLAB_TOKENS`, by credential name
+    #[serde(default)]
+    pub tokens: BTreeMap<String, String>,
+}
+
+/// Include and exclude patterns of a [`NameFilter`](crate::filter::NameFilter)
+#[derive(Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PatternsSection {
+    /// Names matching any of these patterns are filtered out
+    pub exclude: Vec<String>,
+    /// If not empty, names must match at least one of these patterns
+    pub include: Vec<String>,
+}
+
+/// Refresh schedules of the resource types (`[refresh_schedules]`)
+#[derive(Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RefreshSchedulesSection {
+    /// `GROUPS_REFRESH_SCHEDULE`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub groups: Option<String>,
+    /// `PROJECTS_REFRESH_SCHEDULE`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub projects: Option<String>,
+    /// `USERS_REFRESH_SCHEDULE`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub users: Option<String>,
+}
+
+impl RefreshSchedulesSection {
+    /// Returns `true` if no resource type has its own schedule
+    pub const fn is_empty(&self) -> bool {
+        self.groups.is_none() && self.projects.is_none() && self.users.is_none()
+    }
+}
+
+/// Reads and parses the configuration file at `path`
+pub fn read(path: &Path) -> Result<ConfigFile, anyhow::Error> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("failed to read configuration file {}", path.display()))?;
+
+    toml::from_str(&content)
+        .with_context(|| format!("failed to parse configuration file {}", path.display()))
+}
+
+//-------------------------------------------
+//
+// Unit tests
+//
+// ------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::config::CollectionBackend;
+    use crate::config_file::ConfigFile;
+
+    #[test]
+    fn config_file_is_parsed() {
+        let config_file: ConfigFile = toml::from_str(
+            r#"
+            collection_backend = "graphql"
+
+            [refresh_schedules]
+            users = "1h"
+
+            [[instances]]
+            hostname = "gitlab.example.com"
+            tokens = { team-a = "glpat-aaa" }
+            labels = { env = "prod" }
+            group_subtrees = { include = ["business"] }
+
+            [instances.filters]
+            project_paths = { include = ["business/**"] }
+            skip_revoked_tokens = true
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config_file.collection_backend, CollectionBackend::GraphQl);
+        assert_eq!(config_file.data_refresh_hours, None);
+        assert_eq!(config_file.refresh_schedules.users.as_deref(), Some("1h"));
+        assert_eq!(config_file.refresh_schedules.projects, None);
+        let instance = &config_file.instances[0];
+        assert_eq!(instance.tokens["team-a"], "glpat-aaa");
+        assert_eq!(instance.labels["env"], "prod");
+        assert_eq!(instance.group_subtrees.include, vec!["business".to_owned()]);
+        assert_eq!(
+            instance.filters.project_paths.include,
+            vec!["business/**".to_owned()]
+        );
+        assert!(instance.filters.skip_revoked_tokens);
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert!(
+            toml::from_str::<ConfigFile>(
+                r#"
+                [[instances]]
+                hostname = "gitlab.example.com"
+                skip_user_tokens = true
+                "#,
+            )
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,383 @@
+//! Token change events, found by comparing the tokens of successive scans
+//!
+//! When a scan replaces tokens of the [inventory](crate::inventory), the previous tokens are
+//! compared with the scanned ones (cf [`diff`]): a token is created, revoked, rotated (a new token
+//! replaces an active token with the same name, which is revoked or gone), expired, removed, or
+//! its expiration date changed. The first scan of a resource type is the baseline, without events.
+//!
+//! The events are counted in `gitlab_tokens_exporter_token_events_total`, logged, and the last
+//! [`MAX_EVENTS`] are served as JSON on `/events`.
+
+use axum::{extract::Query, http::StatusCode, response::Response};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::{Mutex, MutexGuard},
+};
+use tracing::info;
+
+use crate::exporter_metrics;
+use crate::gitlab::token::Token;
+use crate::health::json_response;
+
+/// Number of events kept for `/events`
+const MAX_EVENTS: usize = 1000;
+
+/// Last events, served on `/events`
+static FEED: Mutex<Feed> = Mutex::new(Feed {
+    events: VecDeque::new(),
+    last_seq: 0,
+});
+
+/// Token type and id
+type TokenKey = (&'static str, usize);
+
+/// Kind of change of a token
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// A new token
+    Created,
+    /// An active token became inactive without being revoked
+    Expired,
+    /// The expiration date of the token changed
+    ExpiryChanged,
+    /// The token is no longer found (its project, group or user may have been deleted)
+    Removed,
+    /// The token has been revoked
+    Revoked,
+    /// A new token replaces an active token with the same name, which is revoked or gone
+    Rotated,
+}
+
+/// Response of `/events`
+#[derive(Debug, Serialize)]
+struct EventsFeed {
+    /// Events after the `since` query parameter, oldest first
+    events: Vec<TokenEvent>,
+    /// Sequence number of the last event, to pass as `since` to the next request
+    last_seq: u64,
+}
+
+/// Query parameters of `/events`
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EventsQuery {
+    /// Only returns the events whose sequence number is greater
+    since: Option<u64>,
+}
+
+/// Last events, with the sequence number of the last one
+struct Feed {
+    /// Up to [`MAX_EVENTS`] events, oldest first
+    events: VecDeque<TokenEvent>,
+    /// Sequence number of the last event recorded since the exporter started
+    last_seq: u64,
+}
+
+/// Change of a token between two scans
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct TokenEvent {
+    /// End of the scan which found the change
+    at: DateTime<Utc>,
+    /// Expiration date of the token
+    expires_at: Option<NaiveDate>,
+    /// Token id
+    id: usize,
+    /// Instance name
+    instance: String,
+    /// Kind of change
+    #[serde(rename = "event")]
+    kind: EventKind,
+    /// Token name
+    name: String,
+    /// Full path of the project or group, or username of the user owning the token
+    path: String,
+    /// Previous expiration date, for [`EventKind::ExpiryChanged`]
+    previous_expires_at: Option<NaiveDate>,
+    /// Id of the replaced token, for [`EventKind::Rotated`]
+    previous_id: Option<usize>,
+    /// Sequence number, assigned by [`record`]
+    seq: u64,
+    /// `project`, `group` or `user`
+    #[serde(rename = "type")]
+    token_type: &'static str,
+}
+
+impl EventKind {
+    /// Name of the kind, as used by the metrics and the logs
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Created => "created",
+            Self::Expired => "expired",
+            Self::ExpiryChanged => "expiry_changed",
+            Self::Removed => "removed",
+            Self::Revoked => "revoked",
+            Self::Rotated => "rotated",
+        }
+    }
+}
+
+impl TokenEvent {
+    /// Returns an event of `token`, before [`record`] assigns its sequence number
+    fn new(instance: &str, at: DateTime<Utc>, kind: EventKind, token: &Token) -> Self {
+        Self {
+            at,
+            expires_at: token.expires_at(),
+            id: token.id(),
+            instance: instance.to_owned(),
+            kind,
+            name: token.name().to_owned(),
+            path: token.full_path().to_owned(),
+            previous_expires_at: None,
+            previous_id: None,
+            seq: 0,
+            token_type: token.type_name(),
+        }
+    }
+}
+
+/// Returns `tokens` by type and id
+fn by_key<'token>(tokens: &[&'token Token]) -> BTreeMap<TokenKey, &'token Token> {
+    tokens
+        .iter()
+        .map(|token| ((token.type_name(), token.id()), *token))
+        .collect()
+}
+
+/// Returns the changes between the `previous` tokens of `instance` and the `current` ones,
+/// found by a scan finished `at`
+pub fn diff(
+    instance: &str,
+    at: DateTime<Utc>,
+    previous: &[&Token],
+    current: &[&Token],
+) -> Vec<TokenEvent> {
+    let previous_tokens = by_key(previous);
+    let current_tokens = by_key(current);
+    let mut events = Vec::new();
+    // Previous tokens replaced by a rotation
+    let mut rotated: BTreeSet<TokenKey> = BTreeSet::new();
+
+    for (key, token) in &current_tokens {
+        if previous_tokens.contains_key(key) {
+            continue;
+        }
+        // The last active token with the same name which is now inactive or gone
+        let replaced = previous_tokens
+            .iter()
+            .filter(|&(previous_key, previous_token)| {
+                previous_key.0 == key.0
+                    && previous_token.is_active()
+                    && previous_token.full_path() == token.full_path()
+                    && previous_token.name() == token.name()
+                    && !rotated.contains(previous_key)
+                    && current_tokens
+                        .get(previous_key)
+                        .is_none_or(|current_token| !current_token.is_active())
+            })
+            .map(|(previous_key, _)| *previous_key)
+            .next_back();
+        if let Some(replaced_key) = replaced {
+            rotated.insert(replaced_key);
+            events.push(TokenEvent {
+                previous_id: Some(replaced_key.1),
+                ..TokenEvent::new(instance, at, EventKind::Rotated, token)
+            });
+        } else {
+            events.push(TokenEvent::new(instance, at, EventKind::Created, token));
+        }
+    }
+
+    for (key, previous_token) in &previous_tokens {
+        let Some(token) = current_tokens.get(key) else {
+            if !rotated.contains(key) {
+                events.push(TokenEvent::new(
+                    instance,
+                    at,
+                    EventKind::Removed,
+                    previous_token,
+                ));
+            }
+            continue;
+        };
+        if rotated.contains(key) {
+            continue;
+        }
+        if token.is_revoked() && !previous_token.is_revoked() {
+            events.push(TokenEvent::new(instance, at, EventKind::Revoked, token));
+        } else if previous_token.is_active() && !token.is_active() {
+            events.push(TokenEvent::new(instance, at, EventKind::Expired, token));
+        } else {
+            // The token status didn't change
+        }
+        if token.expires_at() != previous_token.expires_at() {
+            events.push(TokenEvent {
+                previous_expires_at: previous_token.expires_at(),
+                ..TokenEvent::new(instance, at, EventKind::ExpiryChanged, token)
+            });
+        }
+    }
+    events
+}
+
+/// Handles `/events` requests
+pub async fn events_handler(Query(query): Query<EventsQuery>) -> Response {
+    json_response(StatusCode::OK, &events_since(query.since.unwrap_or(0)))
+}
+
+/// Returns the events whose sequence number is greater than `since`
+fn events_since(since: u64) -> EventsFeed {
+    let feed = lock();
+    let events_feed = EventsFeed {
+        events: feed
+            .events
+            .iter()
+            .filter(|event| event.seq > since)
+            .cloned()
+            .collect(),
+        last_seq: feed.last_seq,
+    };
+    drop(feed);
+    events_feed
+}
+
+/// Locks [`FEED`]
+#[expect(
+    clippy::unwrap_used,
+    reason = "crashing on a poisoned mutex is ok in our case"
+)]
+fn lock() -> MutexGuard<'static, Feed> {
+    FEED.lock().unwrap()
+}
+
+/// Records `events`: they are numbered, logged, counted, and added to the feed of `/events`
+pub fn record(events: Vec<TokenEvent>) {
+    if events.is_empty() {
+        return;
+    }
+    let mut feed = lock();
+    for mut event in events {
+        feed.last_seq = feed.last_seq.saturating_add(1);
+        event.seq = feed.last_seq;
+        info!(
+            instance = event.instance,
+            event = event.kind.name(),
+            token_type = event.token_type,
+            path = event.path,
+            name = event.name,
+            id = event.id,
+            previous_id = event.previous_id,
+            "token {}",
+            event.kind.name()
+        );
+        exporter_metrics::record_token_event(&event.instance, event.token_type, event.kind.name());
+        if feed.events.len() >= MAX_EVENTS {
+            feed.events.pop_front();
+        }
+        feed.events.push_back(event);
+    }
+    drop(feed);
+}
+
+//-------------------------------------------
+//
+// Unit tests
+//
+// ------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, TimeZone as _, Utc};
+
+    use crate::events::{EventKind, diff, events_since, record};
+    use crate::gitlab::token::{AccessLevel, AccessToken, Token};
+
+    fn token(id: usize, name: &str, active: bool, revoked: bool, expires_at: &str) -> Token {
+        Token::Project {
+            token: AccessToken {
+                access_level: AccessLevel::Maintainer,
+                active,
+                expires_at: Some(expires_at.parse().unwrap()),
+                id,
+                name: name.to_owned(),
+                revoked,
+                scopes: Vec::new(),
+            },
+            full_path: "business/app".to_owned(),
+            web_url: "https://gitlab.example.com/business/app".to_owned(),
+        }
+    }
+
+    #[test]
+    fn token_changes() {
+        let at = Utc.with_ymd_and_hms(2026, 3, 10, 14, 30, 0).unwrap();
+        let previous = [
+            token(1, "deploy", true, false, "2026-04-01"),
+            token(2, "ci", true, false, "2026-04-01"),
+            token(3, "backup", true, false, "2026-03-10"),
+            token(4, "renovate", true, false, "2026-04-01"),
+            token(5, "old", true, false, "2026-04-01"),
+        ];
+        let current = [
+            // deploy has been rotated
+            token(1, "deploy", false, true, "2026-04-01"),
+            token(6, "deploy", true, false, "2026-06-01"),
+            token(2, "ci", false, true, "2026-04-01"),
+            token(3, "backup", false, false, "2026-03-10"),
+            token(4, "renovate", true, false, "2026-05-01"),
+            token(7, "release", true, false, "2026-05-01"),
+        ];
+        let events = diff(
+            "gitlab.example.com",
+            at,
+            &previous.iter().collect::<Vec<_>>(),
+            &current.iter().collect::<Vec<_>>(),
+        );
+
+        let changes: Vec<(EventKind, usize)> =
+            events.iter().map(|event| (event.kind, event.id)).collect();
+        assert_eq!(
+            changes,
+            vec![
+                (EventKind::Rotated, 6),
+                (EventKind::Created, 7),
+                (EventKind::Revoked, 2),
+                (EventKind::Expired, 3),
+                (EventKind::ExpiryChanged, 4),
+                (EventKind::Removed, 5),
+            ]
+        );
+        assert_eq!(events[0].previous_id, Some(1));
+        assert_eq!(
+            events[4].previous_expires_at,
+            NaiveDate::from_ymd_opt(2026, 4, 1)
+        );
+
+        // Nothing changed
+        assert!(
+            diff(
+                "gitlab.example.com",
+                at,
+                &current.iter().collect::<Vec<_>>(),
+                &current.iter().collect::<Vec<_>>(),
+            )
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn events_are_numbered() {
+        let at = Utc.with_ymd_and_hms(2026, 3, 10, 14, 30, 0).unwrap();
+        let created = token(1, "deploy", true, false, "2026-04-01");
+        let since = events_since(0).last_seq;
+        record(diff("events.example.com", at, &[], &[&created]));
+
+        let feed = events_since(since);
+        assert_eq!(feed.events.len(), 1);
+        assert_eq!(feed.events[0].seq, feed.last_seq);
+        assert_eq!(feed.events[0].instance, "events.example.com");
+        assert!(events_since(feed.last_seq).events.is_empty());
+    }
+}
@@ -0,0 +1,457 @@
+//! Metrics about the exporter itself, appended to the tokens metrics on `/metrics`
+
+use anyhow::Context as _;
+use chrono::{DateTime, Utc};
+use core::fmt::Write as _; // To be able to use the `write` macro
+use core::time::Duration;
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, MutexGuard},
+};
+
+use crate::gitlab::group;
+
+/// gitlab API errors, by instance, endpoint and [kind](crate::gitlab::client::ErrorKind)
+static API_ERRORS: Mutex<BTreeMap<(String, &'static str, &'static str), u64>> =
+    Mutex::new(BTreeMap::new());
+
+/// gitlab API requests, by instance, endpoint and status code
+static API_REQUESTS: Mutex<BTreeMap<(String, &'static str, String), ApiRequestsStats>> =
+    Mutex::new(BTreeMap::new());
+
+/// Retries of the gitlab API requests performed by the retry middleware, by instance
+static API_RETRIES: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+/// Bytes of the gitlab API responses downloaded, by instance
+static DOWNLOADED_BYTES: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+/// Lookups in the [group cache](crate::gitlab::group), by instance
+static GROUP_CACHE: Mutex<BTreeMap<String, CacheStats>> = Mutex::new(BTreeMap::new());
+
+/// Lookups in the [HTTP cache](crate::gitlab::cache), by instance
+static HTTP_CACHE: Mutex<BTreeMap<String, CacheStats>> = Mutex::new(BTreeMap::new());
+
+/// Time of the next scheduled scan, by resource type
+static NEXT_SCANS: Mutex<BTreeMap<&'static str, DateTime<Utc>>> = Mutex::new(BTreeMap::new());
+
+/// Projects, groups or users whose tokens have been fetched during the last scan, by instance,
+/// credential and resource type
+static SCANNED_RESOURCES: Mutex<BTreeMap<ResourceKey, usize>> = Mutex::new(BTreeMap::new());
+
+/// Duration of the last scan, by instance, credential and resource type
+static SCAN_DURATIONS: Mutex<BTreeMap<ResourceKey, Duration>> = Mutex::new(BTreeMap::new());
+
+/// Token change events, by instance, token type and [kind](crate::events::EventKind)
+static TOKEN_EVENTS: Mutex<BTreeMap<(String, &'static str, &'static str), u64>> =
+    Mutex::new(BTreeMap::new());
+
+/// Resource type (`project`, `group` or `user`), by instance and credential
+type ResourceKey = (String, String, &'static str);
+
+/// Statistics of the gitlab API requests for an endpoint and a status code
+#[derive(Default)]
+struct ApiRequestsStats {
+    /// Number of requests
+    count: u64,
+    /// Total duration of the requests
+    duration: Duration,
+}
+
+/// Hits and misses of a cache
+#[derive(Default)]
+struct CacheStats {
+    /// Number of lookups answered from the cache
+    hits: u64,
+    /// Number of lookups not answered from the cache
+    misses: u64,
+}
+
+impl CacheStats {
+    /// Records a lookup
+    const fn record(&mut self, hit: bool) {
+        if hit {
+            self.hits = self.hits.saturating_add(1);
+        } else {
+            self.misses = self.misses.saturating_add(1);
+        }
+    }
+}
+
+/// Locks one of our metrics
+#[expect(
+    clippy::unwrap_used,
+    reason = "crashing on a poisoned mutex is ok in our case"
+)]
+fn lock<T>(metric: &Mutex<T>) -> MutexGuard<'_, T> {
+    metric.lock().unwrap()
+}
+
+/// Records a gitlab API request
+pub fn record_api_request(
+    instance: &str,
+    endpoint: &'static str,
+    status_code: String,
+    duration: Duration,
+) {
+    let mut api_requests = lock(&API_REQUESTS);
+    let stats = api_requests
+        .entry((instance.to_owned(), endpoint, status_code))
+        .or_default();
+    stats.count = stats.count.saturating_add(1);
+    stats.duration = stats.duration.saturating_add(duration);
+    drop(api_requests);
+}
+
+/// Records a gitlab API error
+pub fn record_api_error(instance: &str, endpoint: &'static str, kind: &'static str) {
+    let mut api_errors = lock(&API_ERRORS);
+    let count = api_errors
+        .entry((instance.to_owned(), endpoint, kind))
+        .or_default();
+    *count = count.saturating_add(1);
+    drop(api_errors);
+}
+
+/// Records a retry of a gitlab API request
+pub fn record_api_retry(instance: &str) {
+    let mut api_retries = lock(&API_RETRIES);
+    let count = api_retries.entry(instance.to_owned()).or_default();
+    *count = count.saturating_add(1);
+    drop(api_retries);
+}
+
+/// Records `count` bytes downloaded from `instance`
+pub fn record_downloaded_bytes(instance: &str, count: usize) {
+    let mut downloaded_bytes = lock(&DOWNLOADED_BYTES);
+    let total = downloaded_bytes.entry(instance.to_owned()).or_default();
+    *total = total.saturating_add(u64::try_from(count).unwrap_or(u64::MAX));
+    drop(downloaded_bytes);
+}
+
+/// Records a lookup in the group cache of `instance`
+pub fn record_group_cache(instance: &str, hit: bool) {
+    let mut group_cache = lock(&GROUP_CACHE);
+    group_cache
+        .entry(instance.to_owned())
+        .or_default()
+        .record(hit);
+    drop(group_cache);
+}
+
+/// Records a lookup in the HTTP cache of `instance`
+pub fn record_http_cache(instance: &str, hit: bool) {
+    let mut http_cache = lock(&HTTP_CACHE);
+    http_cache
+        .entry(instance.to_owned())
+        .or_default()
+        .record(hit);
+    drop(http_cache);
+}
+
+/// Records the time of the next scheduled scan of `resource_type`
+pub fn record_next_scan(resource_type: &'static str, at: DateTime<Utc>) {
+    lock(&NEXT_SCANS).insert(resource_type, at);
+}
+
+/// Records the duration of the scan of a resource type
+pub fn record_scan_duration(
+    instance: &str,
+    credential: &str,
+    resource_type: &'static str,
+    duration: Duration,
+) {
+    let mut scan_durations = lock(&SCAN_DURATIONS);
+    scan_durations.insert(
+        (instance.to_owned(), credential.to_owned(), resource_type),
+        duration,
+    );
+    drop(scan_durations);
+}
+
+/// Records the number of resources whose tokens are fetched during a scan
+pub fn record_scanned_resources(
+    instance: &str,
+    credential: &str,
+    resource_type: &'static str,
+    count: usize,
+) {
+    let mut scanned_resources = lock(&SCANNED_RESOURCES);
+    scanned_resources.insert(
+        (instance.to_owned(), credential.to_owned(), resource_type),
+        count,
+    );
+    drop(scanned_resources);
+}
+
+/// Records a token change event
+pub fn record_token_event(instance: &str, token_type: &'static str, kind: &'static str) {
+    let mut token_events = lock(&TOKEN_EVENTS);
+    let count = token_events
+        .entry((instance.to_owned(), token_type, kind))
+        .or_default();
+    *count = count.saturating_add(1);
+    drop(token_events);
+}
+
+/// Returns the hit ratio between 0 and 1 (0 if there was no request)
+#[expect(
+    clippy::as_conversions,
+    clippy::cast_precision_loss,
+    clippy::float_arithmetic,
+    reason = "a ratio doesn't need to be exact"
+)]
+fn ratio(hits: u64, misses: u64) -> f64 {
+    match hits.saturating_add(misses) {
+        0 => 0.0,
+        total => hits as f64 / total as f64,
+    }
+}
+
+/// Renders the exporter metrics in the prometheus format
+pub fn render() -> Result<String, anyhow::Error> {
+    let mut res = String::new();
+
+    res.push_str(
+        "# HELP gitlab_tokens_exporter_http_cache_hits_total GitLab API requests answered with 304 Not Modified\n\
+         # TYPE gitlab_tokens_exporter_http_cache_hits_total counter\n\
+         # HELP gitlab_tokens_exporter_http_cache_misses_total GitLab API requests not answered from the cache\n\
+         # TYPE gitlab_tokens_exporter_http_cache_misses_total counter\n\
+         # HELP gitlab_tokens_exporter_http_cache_hit_ratio Ratio of GitLab API requests answered from the cache\n\
+         # TYPE gitlab_tokens_exporter_http_cache_hit_ratio gauge\n",
+    );
+    let http_cache = lock(&HTTP_CACHE);
+    for (instance, stats) in http_cache.iter() {
+        write!(
+            res,
+            "gitlab_tokens_exporter_http_cache_hits_total{{instance=\"{instance}\"}} {}\n\
+             gitlab_tokens_exporter_http_cache_misses_total{{instance=\"{instance}\"}} {}\n\
+             gitlab_tokens_exporter_http_cache_hit_ratio{{instance=\"{instance}\"}} {}\n",
+            stats.hits,
+            stats.misses,
+            ratio(stats.hits, stats.misses)
+        )
+        .context("failed to write http cache metrics")?;
+    }
+    drop(http_cache);
+
+    res.push_str(
+        "# HELP gitlab_tokens_exporter_group_cache_hits_total Group full paths found in the group cache\n\
+         # TYPE gitlab_tokens_exporter_group_cache_hits_total counter\n\
+         # HELP gitlab_tokens_exporter_group_cache_misses_total Group full paths queried from GitLab\n\
+         # TYPE gitlab_tokens_exporter_group_cache_misses_total counter\n",
+    );
+    let group_cache = lock(&GROUP_CACHE);
+    for (instance, stats) in group_cache.iter() {
+        write!(
+            res,
+            "gitlab_tokens_exporter_group_cache_hits_total{{instance=\"{instance}\"}} {}\n\
+             gitlab_tokens_exporter_group_cache_misses_total{{instance=\"{instance}\"}} {}\n",
+            stats.hits, stats.misses,
+        )
+        .context("failed to write group cache metrics")?;
+    }
+    drop(group_cache);
+
+    res.push_str(
+        "# HELP gitlab_tokens_exporter_group_cache_size Number of groups in the group cache\n\
+         # TYPE gitlab_tokens_exporter_group_cache_size gauge\n",
+    );
+    for (instance, size) in group::cache_sizes() {
+        writeln!(
+            res,
+            "gitlab_tokens_exporter_group_cache_size{{instance=\"{instance}\"}} {size}"
+        )
+        .context("failed to write group cache size metrics")?;
+    }
+
+    res.push_str(
+        "# HELP gitlab_tokens_exporter_api_requests_total GitLab API requests by endpoint and status code\n\
+         # TYPE gitlab_tokens_exporter_api_requests_total counter\n",
+    );
+    let api_requests = lock(&API_REQUESTS);
+    for ((instance, endpoint, status), stats) in api_requests.iter() {
+        writeln!(
+            res,
+            "gitlab_tokens_exporter_api_requests_total{{instance=\"{instance}\",endpoint=\"{endpoint}\",status=\"{status}\"}} {}",
+            stats.count
+        )
+        .context("failed to write api requests metrics")?;
+    }
+
+    res.push_str(
+        "# HELP gitlab_tokens_exporter_api_requests_seconds_total Time spent in GitLab API requests by endpoint and status code\n\
+         # TYPE gitlab_tokens_exporter_api_requests_seconds_total counter\n",
+    );
+    for ((instance, endpoint, status), stats) in api_requests.iter() {
+        writeln!(
+            res,
+            "gitlab_tokens_exporter_api_requests_seconds_total{{instance=\"{instance}\",endpoint=\"{endpoint}\",status=\"{status}\"}} {}",
+            stats.duration.as_secs_f64()
+        )
+        .context("failed to write api requests duration metrics")?;
+    }
+    drop(api_requests);
+
+    res.push_str(
+        "# HELP gitlab_tokens_exporter_api_errors_total GitLab API errors by endpoint and kind\n\
+         # TYPE gitlab_tokens_exporter_api_errors_total counter\n",
+    );
+    let api_errors = lock(&API_ERRORS);
+    for ((instance, endpoint, kind), count) in api_errors.iter() {
+        writeln!(
+            res,
+            "gitlab_tokens_exporter_api_errors_total{{instance=\"{instance}\",endpoint=\"{endpoint}\",kind=\"{kind}\"}} {count}"
+        )
+        .context("failed to write api errors metrics")?;
+    }
+    drop(api_errors);
+
+    render_api_traffic(&mut res)?;
+    render_scans(&mut res)?;
+    render_token_events(&mut res)?;
+
+    Ok(res)
+}
+
+/// Renders the retries and downloaded bytes metrics
+fn render_api_traffic(res: &mut String) -> Result<(), anyhow::Error> {
+    res.push_str(
+        "# HELP gitlab_tokens_exporter_api_retries_total GitLab API requests retried after a transient failure\n\
+         # TYPE gitlab_tokens_exporter_api_retries_total counter\n",
+    );
+    let api_retries = lock(&API_RETRIES);
+    for (instance, count) in api_retries.iter() {
+        writeln!(
+            res,
+            "gitlab_tokens_exporter_api_retries_total{{instance=\"{instance}\"}} {count}"
+        )
+        .context("failed to write api retries metrics")?;
+    }
+    drop(api_retries);
+
+    res.push_str(
+        "# HELP gitlab_tokens_exporter_downloaded_bytes_total Bytes of GitLab API responses downloaded\n\
+         # TYPE gitlab_tokens_exporter_downloaded_bytes_total counter\n",
+    );
+    let downloaded_bytes = lock(&DOWNLOADED_BYTES);
+    for (instance, count) in downloaded_bytes.iter() {
+        writeln!(
+            res,
+            "gitlab_tokens_exporter_downloaded_bytes_total{{instance=\"{instance}\"}} {count}"
+        )
+        .context("failed to write downloaded bytes metrics")?;
+    }
+    drop(downloaded_bytes);
+
+    Ok(())
+}
+
+/// Renders the scans metrics, by resource type
+fn render_scans(res: &mut String) -> Result<(), anyhow::Error> {
+    res.push_str(
+        "# HELP gitlab_tokens_exporter_scan_duration_seconds Duration of the last scan by resource type\n\
+         # TYPE gitlab_tokens_exporter_scan_duration_seconds gauge\n",
+    );
+    let scan_durations = lock(&SCAN_DURATIONS);
+    for ((instance, credential, resource_type), duration) in scan_durations.iter() {
+        writeln!(
+            res,
+            "gitlab_tokens_exporter_scan_duration_seconds{{instance=\"{instance}\",credential=\"{credential}\",type=\"{resource_type}\"}} {}",
+            duration.as_secs_f64()
+        )
+        .context("failed to write scan duration metrics")?;
+    }
+    drop(scan_durations);
+
+    res.push_str(
+        "# HELP gitlab_tokens_exporter_scanned_resources Projects, groups or users whose tokens were fetched during the last scan\n\
+         # TYPE gitlab_tokens_exporter_scanned_resources gauge\n",
+    );
+    let scanned_resources = lock(&SCANNED_RESOURCES);
+    for ((instance, credential, resource_type), count) in scanned_resources.iter() {
+        writeln!(
+            res,
+            "gitlab_tokens_exporter_scanned_resources{{instance=\"{instance}\",credential=\"{credential}\",type=\"{resource_type}\"}} {count}"
+        )
+        .context("failed to write scanned resources metrics")?;
+    }
+    drop(scanned_resources);
+
+    res.push_str(
+        "# HELP gitlab_tokens_exporter_next_scan_timestamp_seconds Time of the next scheduled scan of the resource type\n\
+         # TYPE gitlab_tokens_exporter_next_scan_timestamp_seconds gauge\n",
+    );
+    let next_scans = lock(&NEXT_SCANS);
+    for (resource_type, next_scan) in next_scans.iter() {
+        writeln!(
+            res,
+            "gitlab_tokens_exporter_next_scan_timestamp_seconds{{type=\"{resource_type}\"}} {}",
+            next_scan.timestamp()
+        )
+        .context("failed to write next scan metrics")?;
+    }
+    drop(next_scans);
+
+    Ok(())
+}
+
+/// Renders the token change events metrics
+fn render_token_events(res: &mut String) -> Result<(), anyhow::Error> {
+    res.push_str(
+        "# HELP gitlab_tokens_exporter_token_events_total Token changes found between two scans, by token type and event\n\
+         # TYPE gitlab_tokens_exporter_token_events_total counter\n",
+    );
+    let token_events = lock(&TOKEN_EVENTS);
+    for ((instance, token_type, event), count) in token_events.iter() {
+        writeln!(
+            res,
+            "gitlab_tokens_exporter_token_events_total{{instance=\"{instance}\",type=\"{token_type}\",event=\"{event}\"}} {count}"
+        )
+        .context("failed to write token events metrics")?;
+    }
+    drop(token_events);
+
+    Ok(())
+}
+
+//-------------------------------------------
+//
+// Unit tests
+//
+// ------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::exporter_metrics::{
+        ratio, record_api_retry, record_downloaded_bytes, record_scanned_resources, render,
+    };
+
+    #[test]
+    fn ratio_without_requests() {
+        assert!(ratio(0, 0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn ratio_with_requests() {
+        assert!((ratio(3, 1) - 0.75).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn self_instrumentation_is_rendered() {
+        record_api_retry("retried.example.com");
+        record_api_retry("retried.example.com");
+        record_downloaded_bytes("retried.example.com", 1024);
+        record_scanned_resources("retried.example.com", "default", "project", 42);
+
+        let metrics = render().unwrap();
+        assert!(metrics.contains(
+            "gitlab_tokens_exporter_api_retries_total{instance=\"retried.example.com\"} 2\n"
+        ));
+        assert!(metrics.contains(
+            "gitlab_tokens_exporter_downloaded_bytes_total{instance=\"retried.example.com\"} 1024\n"
+        ));
+        assert!(metrics.contains(
+            "gitlab_tokens_exporter_scanned_resources{instance=\"retried.example.com\",credential=\"default\",type=\"project\"} 42\n"
+        ));
+    }
+}
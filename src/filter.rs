@@ -0,0 +1,361 @@
+//! Include and exclude filters on project paths, group paths, usernames, token names and token
+//! scopes, and filters on the token state
+//!
+//! A pattern is a glob (`*` matches any characters but `/`, `**` matches any characters and `?`
+//! matches a single character but `/`), or a regex if it starts with `re:`.
+//!
+//! In the comma separated lists of the environment variables, `\,` is a comma inside a pattern
+//! (for example `re:^v\d{1\,3}$`).
+
+use anyhow::anyhow;
+use chrono::NaiveDate;
+use core::mem;
+use regex::Regex;
+use std::collections::BTreeSet;
+
+use crate::gitlab::token::Token;
+
+/// Prefix of the regex patterns
+const REGEX_PREFIX: &str = "re:";
+
+/// Include and exclude patterns for a kind of names
+#[derive(Clone, Debug, Default)]
+pub struct NameFilter {
+    /// A name matching any of these patterns is filtered out
+    exclude: Vec<Pattern>,
+    /// If not empty, a name must match at least one of these patterns
+    include: Vec<Pattern>,
+}
+
+impl NameFilter {
+    /// Returns the exclude patterns, as configured
+    pub fn exclude_patterns(&self) -> Vec<String> {
+        self.exclude
+            .iter()
+            .map(|pattern| pattern.source.clone())
+            .collect()
+    }
+
+    /// Creates a [`NameFilter`] from lists of patterns
+    pub fn from_patterns(include: &[String], exclude: &[String]) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            exclude: compile_patterns(exclude.iter().map(String::as_str))?,
+            include: compile_patterns(include.iter().map(String::as_str))?,
+        })
+    }
+
+    /// Adds `names` to the include patterns, as exact matches
+    pub fn include_exact(&mut self, names: &BTreeSet<String>) -> Result<(), anyhow::Error> {
+        let exact_patterns: Vec<String> = names
+            .iter()
+            .map(|name| format!("{REGEX_PREFIX}^{}$", regex::escape(name)))
+            .collect();
+        self.include
+            .extend(compile_patterns(exact_patterns.iter().map(String::as_str))?);
+        Ok(())
+    }
+
+    /// Returns the include patterns, as configured
+    pub fn include_patterns(&self) -> Vec<String> {
+        self.include
+            .iter()
+            .map(|pattern| pattern.source.clone())
+            .collect()
+    }
+
+    /// Returns `true` if `names` are kept by the filter: one of them must match the include
+    /// patterns (if any), and none of them may match the exclude patterns
+    pub fn is_any_match(&self, names: &[String]) -> bool {
+        (self.include.is_empty()
+            || names
+                .iter()
+                .any(|name| self.include.iter().any(|pattern| pattern.re.is_match(name))))
+            && !names
+                .iter()
+                .any(|name| self.exclude.iter().any(|pattern| pattern.re.is_match(name)))
+    }
+
+    /// Returns `true` if there is no pattern
+    pub const fn is_empty(&self) -> bool {
+        self.exclude.is_empty() && self.include.is_empty()
+    }
+
+    /// Returns `true` if `name` is kept by the filter
+    pub fn is_match(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|pattern| pattern.re.is_match(name)))
+            && !self.exclude.iter().any(|pattern| pattern.re.is_match(name))
+    }
+
+    /// Creates a [`NameFilter`] from comma separated lists of patterns
+    pub fn new(include: Option<&str>, exclude: Option<&str>) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            exclude: parse_patterns(exclude.unwrap_or_default())?,
+            include: parse_patterns(include.unwrap_or_default())?,
+        })
+    }
+}
+
+/// Filters applied to the tokens of an instance
+#[derive(Clone, Debug, Default)]
+pub struct Filters {
+    /// Filter on group full paths
+    pub group_paths: NameFilter,
+    /// Filter on project full paths
+    pub project_paths: NameFilter,
+    /// Filter on token state and expiration date
+    pub state: StateFilter,
+    /// Filter on token names
+    pub token_names: NameFilter,
+    /// Filter on token scopes
+    pub token_scopes: NameFilter,
+    /// Filter on usernames
+    pub usernames: NameFilter,
+}
+
+impl Filters {
+    /// Returns `true` if `token` is kept, according to the filter of its type, the token names
+    /// and scopes filters and the state filter
+    pub fn is_match(&self, token: &Token) -> bool {
+        let path_filter = match *token {
+            Token::Group { .. } => &self.group_paths,
+            Token::Project { .. } => &self.project_paths,
+            Token::User { .. } => &self.usernames,
+        };
+        path_filter.is_match(token.full_path())
+            && self.token_names.is_match(token.name())
+            && self.token_scopes.is_any_match(&token.scope_names())
+            && self.state.is_match(token, chrono::Utc::now().date_naive())
+    }
+}
+
+/// A compiled pattern (cf [module documentation](self))
+#[derive(Clone, Debug)]
+struct Pattern {
+    /// Regex compiled from `source`
+    re: Regex,
+    /// Pattern, as configured
+    source: String,
+}
+
+/// Filters on the state and the expiration date of the tokens
+#[derive(Clone, Debug, Default)]
+pub struct StateFilter {
+    /// Tokens expired for more than this number of days are filtered out
+    pub expired_for_days_max: Option<u32>,
+    /// Inactive (expired or revoked) tokens are filtered out if set to `true`
+    pub skip_inactive: bool,
+    /// Tokens without expiration date are filtered out if set to `true`
+    pub skip_non_expiring: bool,
+    /// Revoked tokens are filtered out if set to `true`
+    pub skip_revoked: bool,
+}
+
+impl StateFilter {
+    /// Returns `true` if `token` is kept on `today`
+    pub fn is_match(&self, token: &Token, today: NaiveDate) -> bool {
+        (!self.skip_inactive || token.is_active())
+            && (!self.skip_revoked || !token.is_revoked())
+            && token
+                .expires_at()
+                .map_or(!self.skip_non_expiring, |expires_at| {
+                    self.expired_for_days_max.is_none_or(|days| {
+                        today.signed_duration_since(expires_at).num_days() <= i64::from(days)
+                    })
+                })
+    }
+}
+
+/// Compiles patterns (cf [module documentation](self)), ignoring the empty ones
+fn compile_patterns<'pattern, I>(patterns: I) -> Result<Vec<Pattern>, anyhow::Error>
+where
+    I: IntoIterator<Item = &'pattern str>,
+{
+    patterns
+        .into_iter()
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .map(|pattern| {
+            let re = pattern
+                .strip_prefix(REGEX_PREFIX)
+                .map_or_else(|| glob_to_regex(pattern), ToOwned::to_owned);
+            Ok(Pattern {
+                re: Regex::new(&re).map_err(|err| anyhow!("invalid pattern '{pattern}': {err}"))?,
+                source: pattern.to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// Converts a glob pattern into an anchored regex
+fn glob_to_regex(glob: &str) -> String {
+    let mut res = String::from("^");
+    let mut chars = glob.chars().peekable();
+
+    while let Some(glob_char) = chars.next() {
+        match glob_char {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                res.push_str(".*");
+            }
+            '*' => res.push_str("[^/]*"),
+            '?' => res.push_str("[^/]"),
+            _ => res.push_str(&regex::escape(&glob_char.to_string())),
+        }
+    }
+
+    res.push('$');
+    res
+}
+
+/// Parses a comma separated list of patterns (cf [module documentation](self))
+fn parse_patterns(value: &str) -> Result<Vec<Pattern>, anyhow::Error> {
+    let patterns = split_patterns(value);
+    compile_patterns(patterns.iter().map(String::as_str))
+}
+
+/// Splits a comma separated list of patterns, `\,` being a comma inside a pattern
+fn split_patterns(value: &str) -> Vec<String> {
+    let mut patterns = Vec::new();
+    let mut pattern = String::new();
+    let mut chars = value.chars().peekable();
+
+    while let Some(list_char) = chars.next() {
+        match list_char {
+            '\\' if chars.peek() == Some(&',') => {
+                chars.next();
+                pattern.push(',');
+            }
+            ',' => patterns.push(mem::take(&mut pattern)),
+            _ => pattern.push(list_char),
+        }
+    }
+
+    patterns.push(pattern);
+    patterns
+}
+
+//-------------------------------------------
+//
+// Unit tests
+//
+// ------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use crate::{
+        filter::{NameFilter, StateFilter},
+        gitlab::token::{AccessLevel, AccessToken, AccessTokenScope, Token},
+    };
+
+    fn project_token(active: bool, revoked: bool, expires_at: Option<NaiveDate>) -> Token {
+        Token::Project {
+            token: AccessToken {
+                access_level: AccessLevel::Maintainer,
+                active,
+                expires_at,
+                id: 1,
+                name: "ci".to_owned(),
+                revoked,
+                scopes: vec![AccessTokenScope::Api, AccessTokenScope::ReadRepository],
+            },
+            full_path: "business/project".to_owned(),
+            web_url: "https://gitlab.example.com/business/project".to_owned(),
+        }
+    }
+
+    #[test]
+    fn glob_patterns() {
+        let filter = NameFilter::new(Some("business/*, platform/**"), None).unwrap();
+
+        assert!(filter.is_match("business/project"));
+        assert!(!filter.is_match("business/unit/project"));
+        assert!(filter.is_match("platform/infra/project"));
+        assert!(!filter.is_match("other/project"));
+    }
+
+    #[test]
+    fn escaped_commas() {
+        let filter = NameFilter::new(Some(r"re:^v\d{1\,3}$, a\,b"), None).unwrap();
+
+        assert_eq!(filter.include_patterns(), vec![r"re:^v\d{1,3}$", "a,b"]);
+        assert!(filter.is_match("v42"));
+        assert!(!filter.is_match("v1234"));
+        assert!(filter.is_match("a,b"));
+        assert!(!filter.is_match("a"));
+    }
+
+    #[test]
+    fn regex_patterns() {
+        let filter = NameFilter::new(None, Some(r"re:^renovate(-bot)?$,re:_bot_\d+")).unwrap();
+
+        assert!(!filter.is_match("renovate"));
+        assert!(!filter.is_match("project_42_bot_1"));
+        assert!(filter.is_match("renovate-admin"));
+    }
+
+    #[test]
+    fn exclude_wins() {
+        let filter = NameFilter::new(Some("ci-*"), Some("ci-legacy-?")).unwrap();
+
+        assert!(filter.is_match("ci-deploy"));
+        assert!(!filter.is_match("ci-legacy-1"));
+    }
+
+    #[test]
+    fn scope_patterns() {
+        let scopes = vec!["read_api".to_owned(), "write_repository".to_owned()];
+
+        assert!(
+            NameFilter::new(Some("api,write_*"), None)
+                .unwrap()
+                .is_any_match(&scopes)
+        );
+        assert!(
+            !NameFilter::new(Some("api"), None)
+                .unwrap()
+                .is_any_match(&scopes)
+        );
+        assert!(
+            !NameFilter::new(None, Some("write_*"))
+                .unwrap()
+                .is_any_match(&scopes)
+        );
+    }
+
+    #[test]
+    fn state_filter() {
+        let today = NaiveDate::from_ymd_opt(2025, 6, 30).unwrap();
+        let filter = StateFilter {
+            expired_for_days_max: Some(30),
+            skip_inactive: false,
+            skip_non_expiring: true,
+            skip_revoked: true,
+        };
+
+        assert!(filter.is_match(
+            &project_token(true, false, NaiveDate::from_ymd_opt(2025, 7, 1)),
+            today
+        ));
+        assert!(filter.is_match(
+            &project_token(false, false, NaiveDate::from_ymd_opt(2025, 5, 31)),
+            today
+        ));
+        assert!(!filter.is_match(
+            &project_token(false, false, NaiveDate::from_ymd_opt(2025, 5, 30)),
+            today
+        ));
+        assert!(!filter.is_match(
+            &project_token(false, true, NaiveDate::from_ymd_opt(2025, 7, 1)),
+            today
+        ));
+        assert!(!filter.is_match(&project_token(true, false, None), today));
+        assert!(StateFilter::default().is_match(&project_token(false, true, None), today));
+    }
+
+    #[test]
+    fn invalid_regex() {
+        assert!(NameFilter::new(Some("re:("), None).is_err());
+    }
+}
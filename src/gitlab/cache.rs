@@ -0,0 +1,555 @@
+//! HTTP cache layer used by [`Connection`](crate::gitlab::connection::Connection)
+//!
+//! Successful `GET` responses carrying an `ETag` header are kept in memory. The
+//! next request on the same URL is sent with `If-None-Match`, and a `304 Not Modified`
+//! answer is replaced by the cached response, so unchanged pages are not downloaded again.
+//!
+//! The body of a response is copied into the cache while the caller reads it, so the
+//! responses are still decoded as they are downloaded. The cache of a credential is bounded
+//! to [`MAX_CACHE_SIZE`] bytes of bodies: the least recently used responses are evicted first.
+//!
+//! The caches are kept by instance and credential in [`CACHES`]: the connections rebuilt by a
+//! configuration reload reuse them, unless the hostname or the token of the credential changed.
+
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, MutexGuard, Weak},
+};
+
+use bytes::Bytes;
+use http::Extensions;
+use http_body::{Frame, SizeHint};
+use reqwest::{
+    Body, Method, Request, Response, ResponseBuilderExt as _, StatusCode,
+    header::{ETAG, HeaderMap, HeaderValue, IF_NONE_MATCH},
+};
+use reqwest_middleware::{Middleware, Next};
+use tracing::debug;
+
+use crate::exporter_metrics;
+
+/// Maximum size of the bodies cached for a credential, in bytes
+const MAX_CACHE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Maximum size of a cached body, in bytes: larger responses are not cached
+const MAX_ENTRY_SIZE: usize = 4 * 1024 * 1024;
+
+/// Caches of the credentials, by instance and credential name. Used in [`HttpCache::shared`]
+///
+/// A cache is dropped with the last connection using it, the entries of the dropped caches are
+/// removed when a cache is requested
+static CACHES: Mutex<BTreeMap<(String, String), SharedEntries>> = Mutex::new(BTreeMap::new());
+
+/// Marks the responses answered from the cache, whose body hasn't been downloaded
+#[derive(Clone, Copy, Debug)]
+pub struct CacheHit;
+
+/// A response stored in [`HttpCache`]
+struct CachedResponse {
+    /// Response body
+    body: Bytes,
+    /// `ETag` header value sent back in `If-None-Match`
+    etag: HeaderValue,
+    /// Response headers (they contain the pagination `link` header)
+    headers: HeaderMap,
+    /// Position of the entry in [`Entries::uses`]
+    last_use: u64,
+    /// Response status
+    status: StatusCode,
+}
+
+/// Cached responses, with their use order to evict the least recently used ones
+#[derive(Default)]
+struct Entries {
+    /// Cached responses, by request URL
+    by_url: HashMap<String, CachedResponse>,
+    /// Last value of the use counter
+    last_use: u64,
+    /// Total size of the cached bodies, in bytes
+    size: usize,
+    /// URLs of the cached responses by last use, least recently used first
+    uses: BTreeMap<u64, String>,
+}
+
+/// In-memory HTTP cache middleware, keyed by request URL
+pub struct HttpCache {
+    /// Cached responses
+    entries: Arc<Mutex<Entries>>,
+    /// Name of the gitlab instance, used as a metric label
+    instance: String,
+}
+
+/// A cache of [`CACHES`], with the hostname and token of the credential it was created for
+struct SharedEntries {
+    /// Cached responses, dropped with the last connection using them
+    entries: Weak<Mutex<Entries>>,
+    /// Hostname of the instance
+    hostname: String,
+    /// Token of the credential
+    token: String,
+}
+
+/// Body of a response being cached: its chunks are passed on as they are downloaded,
+/// and a copy is stored in the cache once the whole body has been received
+struct TeeBody {
+    /// Copy of the chunks received so far, `None` once the body can't be cached
+    copy: Option<Vec<u8>>,
+    /// Cache the response is stored in
+    entries: Arc<Mutex<Entries>>,
+    /// Response body
+    inner: Body,
+    /// Request URL and response without its body, until it is stored
+    pending: Option<(String, CachedResponse)>,
+}
+
+impl Entries {
+    /// Returns the cached response for `url`, marking it as the most recently used
+    fn get(&mut self, url: &str) -> Option<&CachedResponse> {
+        let cached = self.by_url.get_mut(url)?;
+        self.uses.remove(&cached.last_use);
+        self.last_use = self.last_use.saturating_add(1);
+        cached.last_use = self.last_use;
+        self.uses.insert(self.last_use, url.to_owned());
+        Some(cached)
+    }
+
+    /// Stores `cached` as the response for `url`, evicting the least recently used
+    /// responses to keep the cache under [`MAX_CACHE_SIZE`]
+    fn insert(&mut self, url: String, mut cached: CachedResponse) {
+        self.remove(&url);
+        while self.size.saturating_add(cached.body.len()) > MAX_CACHE_SIZE {
+            let Some((_, oldest)) = self.uses.pop_first() else {
+                break;
+            };
+            self.remove(&oldest);
+        }
+        self.last_use = self.last_use.saturating_add(1);
+        cached.last_use = self.last_use;
+        self.size = self.size.saturating_add(cached.body.len());
+        self.uses.insert(self.last_use, url.clone());
+        self.by_url.insert(url, cached);
+    }
+
+    /// Removes the cached response for `url`, if any
+    fn remove(&mut self, url: &str) {
+        if let Some(cached) = self.by_url.remove(url) {
+            self.uses.remove(&cached.last_use);
+            self.size = self.size.saturating_sub(cached.body.len());
+        }
+    }
+}
+
+impl HttpCache {
+    /// Builds a [`Response`] from a cached entry
+    fn build_response(url: reqwest::Url, cached: &CachedResponse) -> Result<Response, http::Error> {
+        let mut builder = http::Response::builder().status(cached.status).url(url);
+        if let Some(headers) = builder.headers_mut() {
+            headers.clone_from(&cached.headers);
+        }
+        builder.body(cached.body.clone()).map(Response::from)
+    }
+
+    /// Returns the response `resp` to `url`, whose body is stored in the cache with `etag`
+    /// once it has been read
+    fn cache_response(
+        &self,
+        url: reqwest::Url,
+        etag: HeaderValue,
+        resp: Response,
+    ) -> Result<Response, http::Error> {
+        let (parts, body) = http::Response::<Body>::from(resp).into_parts();
+        let pending = CachedResponse {
+            body: Bytes::new(),
+            etag,
+            headers: parts.headers.clone(),
+            last_use: 0,
+            status: parts.status,
+        };
+        let tee = TeeBody {
+            copy: Some(Vec::new()),
+            entries: Arc::clone(&self.entries),
+            inner: body,
+            pending: Some((url.as_str().to_owned(), pending)),
+        };
+        let mut builder = http::Response::builder()
+            .status(parts.status)
+            .version(parts.version)
+            .url(url);
+        if let Some(headers) = builder.headers_mut() {
+            *headers = parts.headers;
+        }
+        builder.body(Body::wrap(tee)).map(Response::from)
+    }
+
+    /// Locks the cached responses
+    #[expect(
+        clippy::unwrap_used,
+        reason = "crashing on a poisoned mutex is ok in our case"
+    )]
+    fn lock(&self) -> MutexGuard<'_, Entries> {
+        self.entries.lock().unwrap()
+    }
+
+    /// Creates an empty [`HttpCache`] for the gitlab `instance`
+    fn new(instance: &str) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(Entries::default())),
+            instance: instance.to_owned(),
+        }
+    }
+
+    /// Returns the cache of the `credential` of `instance`, shared with the previous connections
+    /// of the credential if its `hostname` and `token` are unchanged, or else a new empty one
+    pub fn shared(instance: &str, credential: &str, hostname: &str, token: &str) -> Self {
+        let mut caches = lock_caches();
+        caches.retain(|_, shared_entries| shared_entries.entries.strong_count() > 0);
+        let key = (instance.to_owned(), credential.to_owned());
+        if let Some(entries) = caches
+            .get(&key)
+            .filter(|shared_entries| {
+                shared_entries.hostname == hostname && shared_entries.token == token
+            })
+            .and_then(|shared_entries| shared_entries.entries.upgrade())
+        {
+            debug!("reusing the HTTP cache of the credential '{credential}' of {instance}");
+            return Self {
+                entries,
+                instance: instance.to_owned(),
+            };
+        }
+        let cache = Self::new(instance);
+        caches.insert(
+            key,
+            SharedEntries {
+                entries: Arc::downgrade(&cache.entries),
+                hostname: hostname.to_owned(),
+                token: token.to_owned(),
+            },
+        );
+        cache
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for HttpCache {
+    async fn handle(
+        &self,
+        mut req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        if req.method() != Method::GET {
+            return next.run(req, extensions).await;
+        }
+
+        let url = req.url().clone();
+        let key = url.as_str().to_owned();
+
+        let cached_etag = self
+            .lock()
+            .by_url
+            .get(&key)
+            .map(|cached| cached.etag.clone());
+        // Copy of the request without `If-None-Match`, in case the entry is evicted
+        // before the `304 Not Modified` answer arrives
+        let unconditional_req = cached_etag.as_ref().and_then(|_| req.try_clone());
+        if let Some(etag) = cached_etag {
+            req.headers_mut().insert(IF_NONE_MATCH, etag);
+        }
+
+        let mut resp = next.clone().run(req, extensions).await?;
+
+        if resp.status() == StatusCode::NOT_MODIFIED {
+            let cache_hit = self
+                .lock()
+                .get(&key)
+                .map(|cached| Self::build_response(url.clone(), cached));
+
+            if let Some(cached_resp) = cache_hit {
+                debug!("cache hit for {key}");
+                exporter_metrics::record_http_cache(&self.instance, true);
+                return cached_resp
+                    .map(|mut cached_resp_with_marker| {
+                        cached_resp_with_marker.extensions_mut().insert(CacheHit);
+                        cached_resp_with_marker
+                    })
+                    .map_err(reqwest_middleware::Error::middleware);
+            }
+            if let Some(retried_req) = unconditional_req {
+                debug!("cache entry evicted for {key}, sending the request again");
+                resp = next.run(retried_req, extensions).await?;
+            }
+        }
+
+        exporter_metrics::record_http_cache(&self.instance, false);
+
+        let Some(etag) = resp.headers().get(ETAG).cloned() else {
+            return Ok(resp);
+        };
+        if !resp.status().is_success()
+            || resp.content_length().is_some_and(|length| {
+                usize::try_from(length).unwrap_or(usize::MAX) > MAX_ENTRY_SIZE
+            })
+        {
+            return Ok(resp);
+        }
+
+        self.cache_response(url, etag, resp)
+            .map_err(reqwest_middleware::Error::middleware)
+    }
+}
+
+impl TeeBody {
+    /// Stores the copied body in the cache, once the whole body has been received
+    #[expect(
+        clippy::unwrap_used,
+        reason = "crashing on a poisoned mutex is ok in our case"
+    )]
+    fn store(&mut self) {
+        if let (Some(copy), Some((url, mut cached))) = (self.copy.take(), self.pending.take()) {
+            cached.body = Bytes::from(copy);
+            self.entries.lock().unwrap().insert(url, cached);
+        }
+    }
+}
+
+impl http_body::Body for TeeBody {
+    type Data = Bytes;
+    type Error = reqwest::Error;
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let polled = Pin::new(&mut self.inner).poll_frame(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    let too_large = self
+                        .copy
+                        .as_ref()
+                        .is_some_and(|copy| copy.len().saturating_add(data.len()) > MAX_ENTRY_SIZE);
+                    if too_large {
+                        self.copy = None;
+                    } else if let Some(copy) = self.copy.as_mut() {
+                        copy.extend_from_slice(data);
+                    } else {
+                        // The body is not cached
+                    }
+                }
+            }
+            Poll::Ready(Some(Err(_))) => self.copy = None,
+            Poll::Ready(None) => self.store(),
+            Poll::Pending => {}
+        }
+        polled
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Locks [`CACHES`]
+#[expect(
+    clippy::unwrap_used,
+    reason = "crashing on a poisoned mutex is ok in our case"
+)]
+fn lock_caches() -> MutexGuard<'static, BTreeMap<(String, String), SharedEntries>> {
+    CACHES.lock().unwrap()
+}
+
+//-------------------------------------------
+//
+// Unit tests
+//
+// ------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use bytes::Bytes;
+    use http::Extensions;
+    use reqwest::{
+        Request, Response, ResponseBuilderExt as _, StatusCode,
+        header::{ETAG, HeaderMap, HeaderValue, IF_NONE_MATCH},
+    };
+    use reqwest_middleware::{ClientBuilder, ClientWithMiddleware, Middleware, Next};
+
+    use crate::gitlab::cache::{
+        CacheHit, CachedResponse, Entries, HttpCache, MAX_CACHE_SIZE, lock_caches,
+    };
+
+    const URL: &str = "https://gitlab.example.com/api/v4/projects";
+
+    /// Fake gitlab API, answering `304 Not Modified` when `If-None-Match` matches its `ETag`
+    struct FakeGitlab {
+        /// Cache whose entry is evicted before answering `304 Not Modified`, if any
+        evicted_cache: Option<Arc<HttpCache>>,
+        /// `If-None-Match` header of the received requests
+        requests: Mutex<Vec<Option<HeaderValue>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Middleware for FakeGitlab {
+        async fn handle(
+            &self,
+            req: Request,
+            _extensions: &mut Extensions,
+            _next: Next<'_>,
+        ) -> reqwest_middleware::Result<Response> {
+            let if_none_match = req.headers().get(IF_NONE_MATCH).cloned();
+            self.requests.lock().unwrap().push(if_none_match.clone());
+            let builder = http::Response::builder().url(req.url().clone());
+            let resp = if if_none_match.is_some_and(|etag| etag == "\"v1\"") {
+                if let Some(cache) = &self.evicted_cache {
+                    cache.lock().remove(req.url().as_str());
+                }
+                builder.status(StatusCode::NOT_MODIFIED).body("")
+            } else {
+                builder
+                    .status(StatusCode::OK)
+                    .header(ETAG, "\"v1\"")
+                    .body(r#"[{"id":1}]"#)
+            };
+            Ok(Response::from(resp.unwrap()))
+        }
+    }
+
+    fn client(cache: Arc<HttpCache>, gitlab: Arc<FakeGitlab>) -> ClientWithMiddleware {
+        ClientBuilder::new(reqwest::Client::new())
+            .with_arc(cache)
+            .with_arc(gitlab)
+            .build()
+    }
+
+    fn entry(size: usize) -> CachedResponse {
+        CachedResponse {
+            body: Bytes::from(vec![0; size]),
+            etag: HeaderValue::from_static("\"v1\""),
+            headers: HeaderMap::new(),
+            last_use: 0,
+            status: StatusCode::OK,
+        }
+    }
+
+    #[tokio::test]
+    async fn not_modified_is_answered_from_the_cache() {
+        let cache = Arc::new(HttpCache::new("gitlab.example.com"));
+        let gitlab = Arc::new(FakeGitlab {
+            evicted_cache: None,
+            requests: Mutex::new(Vec::new()),
+        });
+        let client = client(Arc::clone(&cache), Arc::clone(&gitlab));
+
+        let first = client.get(URL).send().await.unwrap();
+        assert!(first.extensions().get::<CacheHit>().is_none());
+        assert_eq!(first.text().await.unwrap(), r#"[{"id":1}]"#);
+
+        let second = client.get(URL).send().await.unwrap();
+        assert_eq!(second.status(), StatusCode::OK);
+        assert!(second.extensions().get::<CacheHit>().is_some());
+        assert_eq!(second.headers().get(ETAG).unwrap(), "\"v1\"");
+        assert_eq!(second.text().await.unwrap(), r#"[{"id":1}]"#);
+
+        assert_eq!(
+            *gitlab.requests.lock().unwrap(),
+            vec![None, Some(HeaderValue::from_static("\"v1\""))]
+        );
+    }
+
+    #[tokio::test]
+    async fn not_modified_without_entry_is_sent_again() {
+        let cache = Arc::new(HttpCache::new("gitlab.example.com"));
+        let gitlab = Arc::new(FakeGitlab {
+            evicted_cache: Some(Arc::clone(&cache)),
+            requests: Mutex::new(Vec::new()),
+        });
+        let client = client(Arc::clone(&cache), Arc::clone(&gitlab));
+
+        client.get(URL).send().await.unwrap().text().await.unwrap();
+        let second = client.get(URL).send().await.unwrap();
+        assert_eq!(second.status(), StatusCode::OK);
+        assert!(second.extensions().get::<CacheHit>().is_none());
+        assert_eq!(second.text().await.unwrap(), r#"[{"id":1}]"#);
+
+        assert_eq!(
+            *gitlab.requests.lock().unwrap(),
+            vec![None, Some(HeaderValue::from_static("\"v1\"")), None]
+        );
+    }
+
+    #[tokio::test]
+    async fn unread_bodies_are_not_cached() {
+        let cache = Arc::new(HttpCache::new("gitlab.example.com"));
+        let gitlab = Arc::new(FakeGitlab {
+            evicted_cache: None,
+            requests: Mutex::new(Vec::new()),
+        });
+        let client = client(Arc::clone(&cache), Arc::clone(&gitlab));
+
+        drop(client.get(URL).send().await.unwrap());
+        assert!(cache.lock().by_url.is_empty());
+    }
+
+    #[test]
+    fn caches_are_shared_until_the_token_changes() {
+        let first = HttpCache::shared("shared.example.com", "default", "gitlab.example.com", "a");
+        let reused = HttpCache::shared("shared.example.com", "default", "gitlab.example.com", "a");
+        assert!(Arc::ptr_eq(&first.entries, &reused.entries));
+
+        let other_token =
+            HttpCache::shared("shared.example.com", "default", "gitlab.example.com", "b");
+        assert!(!Arc::ptr_eq(&first.entries, &other_token.entries));
+        let other_credential =
+            HttpCache::shared("shared.example.com", "ci", "gitlab.example.com", "b");
+        assert!(!Arc::ptr_eq(
+            &other_token.entries,
+            &other_credential.entries
+        ));
+
+        // A cache is dropped with the last connection using it
+        drop((first, reused, other_token, other_credential));
+        drop(HttpCache::shared(
+            "other.example.com",
+            "default",
+            "gitlab.example.com",
+            "a",
+        ));
+        assert!(
+            !lock_caches()
+                .keys()
+                .any(|(instance, _)| instance == "shared.example.com")
+        );
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let mut entries = Entries::default();
+        let third = MAX_CACHE_SIZE / 3;
+        entries.insert("a".to_owned(), entry(third));
+        entries.insert("b".to_owned(), entry(third));
+        entries.insert("c".to_owned(), entry(third));
+        assert!(entries.get("a").is_some());
+
+        entries.insert("d".to_owned(), entry(third));
+        assert!(entries.get("b").is_none());
+        assert!(entries.get("a").is_some());
+        assert!(entries.get("c").is_some());
+        assert!(entries.get("d").is_some());
+        assert_eq!(entries.size, 3 * third);
+
+        entries.insert("d".to_owned(), entry(1));
+        assert_eq!(entries.size, 2 * third + 1);
+        assert_eq!(entries.uses.len(), 3);
+    }
+}
@@ -0,0 +1,682 @@
+//! Typed gitlab API client, built around a [`Connection`]
+//!
+//! Every request to the gitlab API goes through [`Client`], which builds the URL of an
+//! [`Endpoint`], authenticates the request, classifies errors ([`ErrorKind`]) and records
+//! per-endpoint metrics (cf [`exporter_metrics`]).
+
+use core::error;
+use core::fmt::{self, Display, Formatter};
+use core::marker::PhantomData;
+use serde::{Deserialize, de::DeserializeOwned};
+use tokio::time::Instant;
+use tracing::{debug, instrument};
+
+use crate::{
+    exporter_metrics,
+    gitlab::{cache::CacheHit, connection::Connection, json, token::AccessLevel},
+};
+
+/// Number of items requested per page
+const PER_PAGE: &str = "100";
+
+/// The gitlab API endpoints used by the exporter
+#[derive(Clone, Debug)]
+pub enum Endpoint {
+    /// [Current user](https://docs.gitlab.com/api/users/#get-the-current-user)
+    CurrentUser,
+    /// [Descendant groups](https://docs.gitlab.com/api/groups/#list-descendant-groups) of a group
+    DescendantGroups {
+        /// Group id
+        id: usize,
+        /// Only list groups where the current user has at least this access level
+        min_access_level: Option<AccessLevel>,
+    },
+    /// [GraphQL API](https://docs.gitlab.com/api/graphql/)
+    GraphQl,
+    /// [Single group](https://docs.gitlab.com/api/groups/#get-a-single-group), by id
+    Group(usize),
+    /// [Group access tokens](https://docs.gitlab.com/api/group_access_tokens/#list-all-group-access-tokens), by group id
+    GroupAccessTokens(usize),
+    /// [Single group](https://docs.gitlab.com/api/groups/#get-a-single-group), by full path
+    GroupByPath(String),
+    /// [Projects of a group](https://docs.gitlab.com/api/groups/#list-projects), including its subgroups
+    GroupProjects {
+        /// Group id
+        id: usize,
+        /// Only list projects where the current user has at least this access level
+        min_access_level: Option<AccessLevel>,
+    },
+    /// [Groups](https://docs.gitlab.com/api/groups/#list-groups)
+    Groups {
+        /// Only list groups where the current user has at least this access level
+        min_access_level: Option<AccessLevel>,
+    },
+    /// [Personal access tokens](https://docs.gitlab.com/api/personal_access_tokens/#list-all-personal-access-tokens)
+    PersonalAccessTokens,
+    /// [Single project](https://docs.gitlab.com/api/projects/#get-a-single-project), by id
+    Project(usize),
+    /// [Project access tokens](https://docs.gitlab.com/api/project_access_tokens/#list-all-project-access-tokens), by project id
+    ProjectAccessTokens(usize),
+    /// [Single project](https://docs.gitlab.com/api/projects/#get-a-single-project), by full path
+    ProjectByPath(String),
+    /// [Projects](https://docs.gitlab.com/api/projects/#list-all-projects)
+    Projects {
+        /// Only list projects where the current user has at least this access level
+        min_access_level: Option<AccessLevel>,
+    },
+    /// [Single user](https://docs.gitlab.com/api/users/#get-a-single-user), by id
+    User(usize),
+    /// [Personal access tokens](https://docs.gitlab.com/api/personal_access_tokens/#list-all-personal-access-tokens)
+    /// of a user, by user id
+    UserPersonalAccessTokens(usize),
+    /// [Users](https://docs.gitlab.com/api/users/#list-users)
+    Users,
+    /// [Users](https://docs.gitlab.com/api/users/#list-users) with a given username
+    UsersByUsername(String),
+}
+
+impl Endpoint {
+    /// Endpoint name, without ids, used as a metric label
+    pub const fn name(&self) -> &'static str {
+        match *self {
+            Self::CurrentUser => "/user",
+            Self::DescendantGroups { .. } => "/groups/:id/descendant_groups",
+            Self::Group(_) | Self::GroupByPath(_) => "/groups/:id",
+            Self::GroupAccessTokens(_) => "/groups/:id/access_tokens",
+            Self::GroupProjects { .. } => "/groups/:id/projects",
+            Self::Groups { .. } => "/groups",
+            Self::GraphQl => "/graphql",
+            Self::PersonalAccessTokens | Self::UserPersonalAccessTokens(_) => {
+                "/personal_access_tokens"
+            }
+            Self::Project(_) | Self::ProjectByPath(_) => "/projects/:id",
+            Self::ProjectAccessTokens(_) => "/projects/:id/access_tokens",
+            Self::Projects { .. } => "/projects",
+            Self::User(_) => "/users/:id",
+            Self::Users | Self::UsersByUsername(_) => "/users",
+        }
+    }
+
+    /// Path of the endpoint on the gitlab instance
+    fn path(&self) -> String {
+        match self {
+            Self::DescendantGroups { id, .. } => format!("/api/v4/groups/{id}/descendant_groups"),
+            Self::Group(id) => format!("/api/v4/groups/{id}"),
+            Self::GroupAccessTokens(id) => format!("/api/v4/groups/{id}/access_tokens"),
+            // Group paths only contain alphanumeric characters, `_`, `-`, `.` and `/`
+            Self::GroupByPath(full_path) => {
+                format!("/api/v4/groups/{}", full_path.replace('/', "%2F"))
+            }
+            Self::GroupProjects { id, .. } => format!("/api/v4/groups/{id}/projects"),
+            Self::GraphQl => "/api/graphql".to_owned(),
+            Self::Project(id) => format!("/api/v4/projects/{id}"),
+            Self::ProjectAccessTokens(id) => format!("/api/v4/projects/{id}/access_tokens"),
+            // Project paths only contain alphanumeric characters, `_`, `-`, `.` and `/`
+            Self::ProjectByPath(full_path) => {
+                format!("/api/v4/projects/{}", full_path.replace('/', "%2F"))
+            }
+            Self::User(id) => format!("/api/v4/users/{id}"),
+            Self::CurrentUser
+            | Self::Groups { .. }
+            | Self::PersonalAccessTokens
+            | Self::Projects { .. }
+            | Self::UserPersonalAccessTokens(_)
+            | Self::Users
+            | Self::UsersByUsername(_) => format!("/api/v4{}", self.name()),
+        }
+    }
+
+    /// Query parameters specific to this endpoint
+    fn query(&self) -> Vec<(&'static str, String)> {
+        match self {
+            Self::DescendantGroups {
+                min_access_level, ..
+            }
+            | Self::Groups { min_access_level }
+            | Self::Projects { min_access_level } => {
+                let mut query = vec![("archived", "false".to_owned())];
+                if let Some(level) = *min_access_level {
+                    query.push(("min_access_level", level.value().to_string()));
+                }
+                query
+            }
+            Self::GroupProjects {
+                min_access_level, ..
+            } => {
+                let mut query = vec![
+                    ("archived", "false".to_owned()),
+                    ("include_subgroups", "true".to_owned()),
+                ];
+                if let Some(level) = *min_access_level {
+                    query.push(("min_access_level", level.value().to_string()));
+                }
+                query
+            }
+            Self::UserPersonalAccessTokens(user_id) => vec![("user_id", user_id.to_string())],
+            Self::UsersByUsername(username) => vec![("username", username.clone())],
+            Self::CurrentUser
+            | Self::Group(_)
+            | Self::GroupAccessTokens(_)
+            | Self::GroupByPath(_)
+            | Self::GraphQl
+            | Self::PersonalAccessTokens
+            | Self::Project(_)
+            | Self::ProjectAccessTokens(_)
+            | Self::ProjectByPath(_)
+            | Self::User(_)
+            | Self::Users => Vec::new(),
+        }
+    }
+}
+
+/// Classification of [`ApiError`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The token is invalid, expired or revoked (`401`)
+    Auth,
+    /// The resource doesn't exist (`404`)
+    NotFound,
+    /// Any other error, including undecodable responses
+    Other,
+    /// The token doesn't have the necessary rights (`403`)
+    Permission,
+    /// Timeouts, connection errors, `408`, `429` and `5xx` responses, after all retries failed
+    Transient,
+}
+
+impl ErrorKind {
+    /// Classifies an HTTP status code
+    const fn from_status(status: reqwest::StatusCode) -> Self {
+        match status.as_u16() {
+            401 => Self::Auth,
+            403 => Self::Permission,
+            404 => Self::NotFound,
+            408 | 429 | 500..=599 => Self::Transient,
+            _ => Self::Other,
+        }
+    }
+
+    /// Kind name, used as a metric label
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Auth => "auth",
+            Self::NotFound => "not_found",
+            Self::Other => "other",
+            Self::Permission => "permission",
+            Self::Transient => "transient",
+        }
+    }
+}
+
+/// Error returned by [`Client`]
+#[derive(Debug)]
+pub struct ApiError {
+    /// Error classification
+    kind: ErrorKind,
+    /// Underlying error
+    source: anyhow::Error,
+    /// Requested URL
+    url: String,
+}
+
+impl ApiError {
+    /// Error classification
+    pub const fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "request to {} failed ({} error)",
+            self.url,
+            self.kind.name()
+        )
+    }
+}
+
+impl error::Error for ApiError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+/// A [GraphQL response](https://docs.gitlab.com/api/graphql/#errors)
+#[derive(Deserialize)]
+struct GraphQlResponse<T> {
+    /// Query result
+    data: Option<T>,
+    /// Errors, if any
+    #[serde(default)]
+    errors: Vec<GraphQlError>,
+}
+
+/// A GraphQL error
+#[derive(Deserialize)]
+struct GraphQlError {
+    /// Error message
+    message: String,
+}
+
+/// gitlab API client
+#[derive(Clone)]
+pub struct Client {
+    /// Connection used to send requests
+    connection: Connection,
+}
+
+impl Client {
+    /// Returns the instance the body of `resp` is downloaded from, or `None` if it comes from
+    /// the [HTTP cache](crate::gitlab::cache)
+    fn downloaded_from(&self, resp: &reqwest::Response) -> Option<&str> {
+        resp.extensions()
+            .get::<CacheHit>()
+            .is_none()
+            .then_some(self.connection.instance.as_str())
+    }
+
+    /// Builds an [`ApiError`], and records it in the metrics
+    fn error(
+        &self,
+        endpoint: &Endpoint,
+        kind: ErrorKind,
+        url: &str,
+        source: anyhow::Error,
+    ) -> ApiError {
+        exporter_metrics::record_api_error(&self.connection.instance, endpoint.name(), kind.name());
+
+        // Never leak the token, even if it was part of an URL
+        ApiError {
+            kind,
+            source,
+            url: url.replace(&self.connection.token, "[REDACTED]"),
+        }
+    }
+
+    /// Sends a `GET` request to `url`, which must be an URL of `endpoint`
+    async fn get(&self, endpoint: &Endpoint, url: &str) -> Result<reqwest::Response, ApiError> {
+        debug!("trying to GET {url}");
+
+        self.send(endpoint, url, self.connection.http_client.get(url))
+            .await
+    }
+
+    /// Sends a `GET` request to `endpoint` and decodes the response
+    #[instrument(skip(self), err)]
+    pub async fn get_one<T: DeserializeOwned + Send + 'static>(
+        &self,
+        endpoint: &Endpoint,
+    ) -> Result<T, ApiError> {
+        let url = self.url(endpoint, &[])?;
+        let resp = self.get(endpoint, &url).await?;
+
+        let downloaded_from = self.downloaded_from(&resp);
+        json::decode_one(resp, downloaded_from)
+            .await
+            .map_err(|err| self.error(endpoint, ErrorKind::Other, &url, err))
+    }
+
+    /// Returns all the items of `endpoint`, going through all the pages
+    #[instrument(skip(self), err)]
+    pub async fn get_paginated<T: DeserializeOwned>(
+        &self,
+        endpoint: &Endpoint,
+    ) -> Result<Vec<T>, ApiError> {
+        let mut result = Vec::new();
+        let mut pages = self.stream_paginated(endpoint.clone())?;
+
+        while let Some(mut items) = pages.next_page().await? {
+            result.append(&mut items);
+        }
+
+        debug!("done! (endpoint was {})", endpoint.name());
+
+        Ok(result)
+    }
+
+    /// Sends a GraphQL `query` with its `variables`, and returns the `data` of the response
+    #[instrument(skip_all, err)]
+    pub async fn graphql<T: DeserializeOwned + Send + 'static>(
+        &self,
+        query: &str,
+        variables: serde_json::Value,
+    ) -> Result<T, ApiError> {
+        let endpoint = Endpoint::GraphQl;
+        let url = self.url(&endpoint, &[])?;
+
+        debug!("trying to POST a GraphQL query to {url}");
+
+        let body = serde_json::json!({ "query": query, "variables": variables });
+        let request = self.connection.http_client.post(&url).json(&body);
+        let resp = self.send(&endpoint, &url, request).await?;
+
+        let downloaded_from = self.downloaded_from(&resp);
+        let graphql_resp: GraphQlResponse<T> = json::decode_one(resp, downloaded_from)
+            .await
+            .map_err(|err| self.error(&endpoint, ErrorKind::Other, &url, err))?;
+
+        match graphql_resp.data {
+            Some(data) if graphql_resp.errors.is_empty() => Ok(data),
+            _ => {
+                let messages: Vec<_> = graphql_resp
+                    .errors
+                    .into_iter()
+                    .map(|graphql_error| graphql_error.message)
+                    .collect();
+                Err(self.error(
+                    &endpoint,
+                    ErrorKind::Other,
+                    &url,
+                    anyhow::anyhow!("GraphQL errors: {}", messages.join("; ")),
+                ))
+            }
+        }
+    }
+
+    /// Creates a new [`Client`]
+    pub const fn new(connection: Connection) -> Self {
+        Self { connection }
+    }
+
+    /// Authenticates and sends `request` to `url`, which must be an URL of `endpoint`
+    async fn send(
+        &self,
+        endpoint: &Endpoint,
+        url: &str,
+        request: reqwest_middleware::RequestBuilder,
+    ) -> Result<reqwest::Response, ApiError> {
+        let time = Instant::now();
+        let send_result = request
+            .header("PRIVATE-TOKEN", &self.connection.token)
+            .send()
+            .await;
+
+        let status = match &send_result {
+            Ok(resp) => resp.status().as_str().to_owned(),
+            Err(_) => "error".to_owned(),
+        };
+        exporter_metrics::record_api_request(
+            &self.connection.instance,
+            endpoint.name(),
+            status,
+            time.elapsed(),
+        );
+
+        let resp = send_result.map_err(|err| {
+            let kind = match &err {
+                reqwest_middleware::Error::Reqwest(reqwest_err)
+                    if reqwest_err.is_timeout()
+                        || reqwest_err.is_connect()
+                        || reqwest_err.is_request() =>
+                {
+                    ErrorKind::Transient
+                }
+                reqwest_middleware::Error::Reqwest(_)
+                | reqwest_middleware::Error::Middleware(_) => ErrorKind::Other,
+            };
+            self.error(endpoint, kind, url, err.into())
+        })?;
+
+        match resp.error_for_status_ref() {
+            Ok(_) => Ok(resp),
+            Err(err) => Err(self.error(
+                endpoint,
+                ErrorKind::from_status(resp.status()),
+                url,
+                err.into(),
+            )),
+        }
+    }
+
+    /// Returns the [`Pages`] of `endpoint`, streamed one at a time by [`Pages::next_page`]
+    pub fn stream_paginated<T: DeserializeOwned>(
+        &self,
+        endpoint: Endpoint,
+    ) -> Result<Pages<'_, T>, ApiError> {
+        let first_url = self.url(&endpoint, &[("per_page", PER_PAGE.to_owned())])?;
+
+        Ok(Pages {
+            client: self,
+            endpoint,
+            item_type: PhantomData,
+            next_url: Some(first_url),
+        })
+    }
+
+    /// Token used to authenticate, to find out whether a reloaded configuration changed it
+    pub fn token(&self) -> &str {
+        &self.connection.token
+    }
+
+    /// Builds the URL of `endpoint`, with `extra_query` parameters
+    fn url(
+        &self,
+        endpoint: &Endpoint,
+        extra_query: &[(&'static str, String)],
+    ) -> Result<String, ApiError> {
+        let base = format!("https://{}{}", self.connection.hostname, endpoint.path());
+
+        let mut url = reqwest::Url::parse(&base)
+            .map_err(|err| self.error(endpoint, ErrorKind::Other, &base, err.into()))?;
+
+        let query: Vec<_> = endpoint
+            .query()
+            .into_iter()
+            .chain(extra_query.iter().cloned())
+            .collect();
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+
+        Ok(url.into())
+    }
+}
+
+/// Pages of an [`Endpoint`], using gitlab [offset-based pagination](https://docs.gitlab.com/api/rest/#offset-based-pagination)
+pub struct Pages<'client, T> {
+    /// Client used to get the pages
+    client: &'client Client,
+    /// Paginated endpoint
+    endpoint: Endpoint,
+    /// Type of the items
+    item_type: PhantomData<T>,
+    /// URL of the next page, given by the `link` header
+    next_url: Option<String>,
+}
+
+impl<T: DeserializeOwned> Pages<'_, T> {
+    /// Returns the items of the next page, or `None` if there are no more pages
+    pub async fn next_page(&mut self) -> Result<Option<Vec<T>>, ApiError> {
+        let Some(current_url) = self.next_url.take() else {
+            return Ok(None);
+        };
+
+        let resp = self.client.get(&self.endpoint, &current_url).await?;
+
+        debug!("Got a response for {current_url}");
+
+        self.next_url = resp
+            .headers()
+            .get("link")
+            .and_then(|header_value| header_value.to_str().ok())
+            .and_then(|header_value_str| parse_link_header::parse_with_rel(header_value_str).ok())
+            .and_then(|mut links| links.remove("next").map(|link| link.raw_uri));
+
+        debug!(next_url = self.next_url);
+
+        let downloaded_from = self.client.downloaded_from(&resp);
+        json::decode_array(resp, downloaded_from)
+            .await
+            .map(Some)
+            .map_err(|err| {
+                self.client
+                    .error(&self.endpoint, ErrorKind::Other, &current_url, err)
+            })
+    }
+}
+
+//-------------------------------------------
+//
+// Unit tests
+//
+// ------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use reqwest::StatusCode;
+
+    use crate::gitlab::{
+        client::{Client, Endpoint, ErrorKind},
+        connection::{Connection, TlsOptions},
+        token::AccessLevel,
+    };
+
+    fn client() -> Client {
+        let connection = Connection::new(
+            "example".to_owned(),
+            "gitlab.example.com".to_owned(),
+            "secret".to_owned(),
+            &TlsOptions::default(),
+            None,
+            0,
+            Duration::from_millis(1),
+        )
+        .unwrap();
+        Client::new(connection)
+    }
+
+    #[test]
+    fn single_resource_url() {
+        assert_eq!(
+            client().url(&Endpoint::Group(42), &[]).unwrap(),
+            "https://gitlab.example.com/api/v4/groups/42"
+        );
+    }
+
+    #[test]
+    fn group_by_path_url() {
+        assert_eq!(
+            client()
+                .url(&Endpoint::GroupByPath("business/unit".to_owned()), &[])
+                .unwrap(),
+            "https://gitlab.example.com/api/v4/groups/business%2Funit"
+        );
+    }
+
+    #[test]
+    fn subtree_projects_url() {
+        let endpoint = Endpoint::GroupProjects {
+            id: 42,
+            min_access_level: None,
+        };
+        assert_eq!(
+            client()
+                .url(&endpoint, &[("per_page", "100".to_owned())])
+                .unwrap(),
+            "https://gitlab.example.com/api/v4/groups/42/projects?archived=false&include_subgroups=true&per_page=100"
+        );
+    }
+
+    #[test]
+    /// `min_access_level` must be sent as a number, gitlab ignores access level names
+    fn paginated_url_with_min_access_level() {
+        let min_access_level = Some(AccessLevel::Owner);
+        for (endpoint, expected_url) in [
+            (
+                Endpoint::Projects { min_access_level },
+                "https://gitlab.example.com/api/v4/projects?archived=false&min_access_level=50&per_page=100",
+            ),
+            (
+                Endpoint::Groups { min_access_level },
+                "https://gitlab.example.com/api/v4/groups?archived=false&min_access_level=50&per_page=100",
+            ),
+            (
+                Endpoint::GroupProjects {
+                    id: 42,
+                    min_access_level,
+                },
+                "https://gitlab.example.com/api/v4/groups/42/projects?archived=false&include_subgroups=true&min_access_level=50&per_page=100",
+            ),
+            (
+                Endpoint::DescendantGroups {
+                    id: 42,
+                    min_access_level,
+                },
+                "https://gitlab.example.com/api/v4/groups/42/descendant_groups?archived=false&min_access_level=50&per_page=100",
+            ),
+        ] {
+            assert_eq!(
+                client()
+                    .url(&endpoint, &[("per_page", "100".to_owned())])
+                    .unwrap(),
+                expected_url
+            );
+        }
+    }
+
+    #[test]
+    fn project_by_path_url() {
+        assert_eq!(
+            client()
+                .url(
+                    &Endpoint::ProjectByPath("business/unit/app".to_owned()),
+                    &[]
+                )
+                .unwrap(),
+            "https://gitlab.example.com/api/v4/projects/business%2Funit%2Fapp"
+        );
+    }
+
+    #[test]
+    fn user_personal_access_tokens_url() {
+        assert_eq!(
+            client()
+                .url(
+                    &Endpoint::UserPersonalAccessTokens(42),
+                    &[("per_page", "100".to_owned())]
+                )
+                .unwrap(),
+            "https://gitlab.example.com/api/v4/personal_access_tokens?user_id=42&per_page=100"
+        );
+    }
+
+    #[test]
+    fn endpoint_name_has_no_id() {
+        assert_eq!(
+            Endpoint::ProjectAccessTokens(42).name(),
+            "/projects/:id/access_tokens"
+        );
+    }
+
+    #[test]
+    fn error_classification() {
+        assert_eq!(
+            ErrorKind::from_status(StatusCode::UNAUTHORIZED),
+            ErrorKind::Auth
+        );
+        assert_eq!(
+            ErrorKind::from_status(StatusCode::FORBIDDEN),
+            ErrorKind::Permission
+        );
+        assert_eq!(
+            ErrorKind::from_status(StatusCode::NOT_FOUND),
+            ErrorKind::NotFound
+        );
+        assert_eq!(
+            ErrorKind::from_status(StatusCode::TOO_MANY_REQUESTS),
+            ErrorKind::Transient
+        );
+        assert_eq!(
+            ErrorKind::from_status(StatusCode::BAD_GATEWAY),
+            ErrorKind::Transient
+        );
+        assert_eq!(
+            ErrorKind::from_status(StatusCode::BAD_REQUEST),
+            ErrorKind::Other
+        );
+    }
+}
@@ -1,12 +1,39 @@
 //! Defines a connection to gitlab
+use anyhow::Context as _;
 use core::time::Duration;
+use std::{fs, path::PathBuf};
 
-use reqwest_middleware::ClientWithMiddleware;
+use http::Extensions;
+use reqwest::{Request, Response};
+use reqwest_middleware::{ClientWithMiddleware, Middleware, Next};
 use reqwest_retry::{RetryTransientMiddleware, policies::ExponentialBackoff};
 
+use crate::exporter_metrics;
+use crate::gitlab::cache::HttpCache;
+
 /// Caps the maximum retry delay at 64x the base delay
 const MAX_BACKOFF_MULTIPLIER: u32 = 64;
 
+/// Marks a request which has already been sent once, cf [`RetryCounter`]
+#[derive(Clone, Copy)]
+struct Attempted;
+
+/// Middleware counting the retries of the [`RetryTransientMiddleware`], which runs it for every
+/// attempt of a request
+struct RetryCounter {
+    /// Name of the gitlab instance, used as a metric label
+    instance: String,
+}
+
+/// TLS options used to connect to gitlab
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    /// DANGEROUS!!! disables HTTPS certificate validation
+    pub accept_invalid_certs: bool,
+    /// PEM file containing additional CA certificates to trust
+    pub ca_cert_file: Option<PathBuf>,
+}
+
 /// Infos needed to connect to gitlab
 #[derive(Clone)]
 pub struct Connection {
@@ -14,6 +41,8 @@ pub struct Connection {
     pub hostname: String,
     /// [`reqwest`] client, wrapped with a retry middleware
     pub http_client: ClientWithMiddleware,
+    /// Name of the gitlab instance, used as a metric label
+    pub instance: String,
     /// Authentication token
     pub token: String,
 }
@@ -26,19 +55,37 @@ impl Connection {
     /// `max_retries` times starting from the `retry_backoff` base delay.
     /// Setting `max_retries` to `0` disables retrying.
     ///
+    /// If `http_cache` is set, responses are cached in it and revalidated with their
+    /// `ETag` (cf [`HttpCache::shared`]).
+    ///
     /// The set of retried failures is defined by [`reqwest_retry`]'s default
     /// retryable strategy; see
     /// <https://docs.rs/reqwest-retry/0.9.1/src/reqwest_retry/retryable_strategy.rs.html#106>.
     pub fn new(
+        instance: String,
         hostname: String,
         token: String,
-        accept_invalid_certs: bool,
+        tls: &TlsOptions,
+        http_cache: Option<HttpCache>,
         max_retries: u32,
         retry_backoff: Duration,
-    ) -> Result<Self, reqwest::Error> {
-        let inner_client = reqwest::ClientBuilder::new()
-            .tls_danger_accept_invalid_certs(accept_invalid_certs)
-            .build()?;
+    ) -> Result<Self, anyhow::Error> {
+        let mut inner_client_builder =
+            reqwest::ClientBuilder::new().tls_danger_accept_invalid_certs(tls.accept_invalid_certs);
+
+        if let Some(ca_cert_file) = &tls.ca_cert_file {
+            let pem = fs::read(ca_cert_file)
+                .with_context(|| format!("failed to read {}", ca_cert_file.display()))?;
+            for cert in reqwest::Certificate::from_pem_bundle(&pem)
+                .with_context(|| format!("failed to parse {}", ca_cert_file.display()))?
+            {
+                inner_client_builder = inner_client_builder.add_root_certificate(cert);
+            }
+        }
+
+        let inner_client = inner_client_builder
+            .build()
+            .context("failed to build the HTTP client")?;
 
         let retry_policy = ExponentialBackoff::builder()
             .retry_bounds(
@@ -47,14 +94,42 @@ impl Connection {
             )
             .build_with_max_retries(max_retries);
 
-        let http_client = reqwest_middleware::ClientBuilder::new(inner_client)
+        // The cache is the outermost middleware, so that it handles a request only once,
+        // whatever the number of retries
+        let mut client_builder = reqwest_middleware::ClientBuilder::new(inner_client);
+        if let Some(cache) = http_cache {
+            client_builder = client_builder.with(cache);
+        }
+        let http_client = client_builder
             .with(RetryTransientMiddleware::new_with_policy(retry_policy))
+            .with(RetryCounter {
+                instance: instance.clone(),
+            })
             .build();
 
         Ok(Self {
             hostname,
             http_client,
+            instance,
             token,
         })
     }
 }
+
+#[async_trait::async_trait]
+impl Middleware for RetryCounter {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        // The extensions are shared by all the attempts of a request
+        if extensions.get::<Attempted>().is_some() {
+            exporter_metrics::record_api_retry(&self.instance);
+        } else {
+            extensions.insert(Attempted);
+        }
+        next.run(req, extensions).await
+    }
+}
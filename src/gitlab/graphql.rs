@@ -0,0 +1,744 @@
+//! Collects projects and groups tokens with the [gitlab GraphQL API](https://docs.gitlab.com/api/graphql/)
+//!
+//! Projects (or groups) are listed 100 at a time, each one with its access tokens, so this
+//! backend needs one request per page of projects instead of one request per project.
+//! The tokens of a project with more than 100 tokens are fetched with additional queries.
+//!
+//! Users tokens are always collected with the REST API.
+//!
+//! When `GROUP_SUBTREES_INCLUDE` is set, only the projects (or the root and descendant groups)
+//! of the listed groups are queried.
+
+use anyhow::anyhow;
+use core::future::Future;
+use serde::{Deserialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use tracing::{debug, instrument};
+
+use crate::{
+    config::GroupSubtrees,
+    gitlab::{
+        client::Client,
+        token::{self, AccessLevel, AccessToken, AccessTokenScope, Token},
+    },
+};
+
+/// Fields of an access token
+const ACCESS_TOKEN_FIELDS: &str = "
+fragment AccessTokenFields on AccessToken {
+  id
+  name
+  active
+  revoked
+  expiresAt
+  accessLevel { integerValue }
+  scopes
+}";
+
+/// Lists projects with their first 100 access tokens
+const PROJECTS_QUERY: &str = "
+query($after: String) {
+  resources: projects(after: $after, first: 100) {
+    pageInfo { endCursor hasNextPage }
+    nodes {
+      fullPath
+      webUrl
+      archived
+      maxAccessLevel { integerValue }
+      accessTokens(first: 100) {
+        pageInfo { endCursor hasNextPage }
+        nodes { ...AccessTokenFields }
+      }
+    }
+  }
+}";
+
+/// Lists groups with their first 100 access tokens
+const GROUPS_QUERY: &str = "
+query($after: String) {
+  resources: groups(after: $after, first: 100) {
+    pageInfo { endCursor hasNextPage }
+    nodes {
+      fullPath
+      webUrl
+      maxAccessLevel { integerValue }
+      accessTokens(first: 100) {
+        pageInfo { endCursor hasNextPage }
+        nodes { ...AccessTokenFields }
+      }
+    }
+  }
+}";
+
+/// Lists the projects of a group and its subgroups with their first 100 access tokens
+const SUBTREE_PROJECTS_QUERY: &str = "
+query($fullPath: ID!, $after: String) {
+  subtree: group(fullPath: $fullPath) {
+    resources: projects(includeSubgroups: true, after: $after, first: 100) {
+      pageInfo { endCursor hasNextPage }
+      nodes {
+        fullPath
+        webUrl
+        archived
+        maxAccessLevel { integerValue }
+        accessTokens(first: 100) {
+          pageInfo { endCursor hasNextPage }
+          nodes { ...AccessTokenFields }
+        }
+      }
+    }
+  }
+}";
+
+/// Lists the descendant groups of a group with their first 100 access tokens
+const SUBTREE_GROUPS_QUERY: &str = "
+query($fullPath: ID!, $after: String) {
+  subtree: group(fullPath: $fullPath) {
+    resources: descendantGroups(includeParentDescendants: true, after: $after, first: 100) {
+      pageInfo { endCursor hasNextPage }
+      nodes {
+        fullPath
+        webUrl
+        maxAccessLevel { integerValue }
+        accessTokens(first: 100) {
+          pageInfo { endCursor hasNextPage }
+          nodes { ...AccessTokenFields }
+        }
+      }
+    }
+  }
+}";
+
+/// Gets the root group of a subtree with its first 100 access tokens
+const SUBTREE_ROOT_QUERY: &str = "
+query($fullPath: ID!) {
+  root: group(fullPath: $fullPath) {
+    fullPath
+    webUrl
+    maxAccessLevel { integerValue }
+    accessTokens(first: 100) {
+      pageInfo { endCursor hasNextPage }
+      nodes { ...AccessTokenFields }
+    }
+  }
+}";
+
+/// Lists the access tokens of a project, after `$after`
+const PROJECT_TOKENS_QUERY: &str = "
+query($fullPath: ID!, $after: String) {
+  resource: project(fullPath: $fullPath) {
+    accessTokens(after: $after, first: 100) {
+      pageInfo { endCursor hasNextPage }
+      nodes { ...AccessTokenFields }
+    }
+  }
+}";
+
+/// Lists the access tokens of a group, after `$after`
+const GROUP_TOKENS_QUERY: &str = "
+query($fullPath: ID!, $after: String) {
+  resource: group(fullPath: $fullPath) {
+    accessTokens(after: $after, first: 100) {
+      pageInfo { endCursor hasNextPage }
+      nodes { ...AccessTokenFields }
+    }
+  }
+}";
+
+/// Sends GraphQL queries. Implemented by [`Client`], and by fake responders in tests
+pub trait GraphQlTransport: Sync {
+    /// Sends `query` with its `variables`, and returns the `data` of the response
+    fn query<T: DeserializeOwned + Send + 'static>(
+        &self,
+        query: String,
+        variables: Value,
+    ) -> impl Future<Output = Result<T, anyhow::Error>> + Send;
+}
+
+impl GraphQlTransport for Client {
+    async fn query<T: DeserializeOwned + Send + 'static>(
+        &self,
+        query: String,
+        variables: Value,
+    ) -> Result<T, anyhow::Error> {
+        Ok(self.graphql(&query, variables).await?)
+    }
+}
+
+/// Kind of resources owning access tokens
+#[derive(Clone, Copy, Debug)]
+pub enum ResourceKind {
+    /// Gitlab groups
+    Group,
+    /// Gitlab projects
+    Project,
+}
+
+impl ResourceKind {
+    /// Returns the queries used for this kind of resources
+    fn queries(self) -> Queries {
+        let (resources_query, subtree_query, tokens_query) = match self {
+            Self::Group => (GROUPS_QUERY, SUBTREE_GROUPS_QUERY, GROUP_TOKENS_QUERY),
+            Self::Project => (PROJECTS_QUERY, SUBTREE_PROJECTS_QUERY, PROJECT_TOKENS_QUERY),
+        };
+        Queries {
+            resources: format!("{resources_query}{ACCESS_TOKEN_FIELDS}"),
+            subtree: format!("{subtree_query}{ACCESS_TOKEN_FIELDS}"),
+            tokens: format!("{tokens_query}{ACCESS_TOKEN_FIELDS}"),
+        }
+    }
+
+    /// Name of the type ("project" or "group")
+    pub const fn type_name(self) -> &'static str {
+        match self {
+            Self::Group => "group",
+            Self::Project => "project",
+        }
+    }
+}
+
+/// Queries used for a [`ResourceKind`]
+struct Queries {
+    /// Lists all the resources
+    resources: String,
+    /// Lists the resources in the subtree of a group
+    subtree: String,
+    /// Lists the tokens of a single resource
+    tokens: String,
+}
+
+/// A GraphQL [connection](https://docs.gitlab.com/api/graphql/#connection-pagination)
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Connection<T> {
+    /// Items of the page
+    nodes: Vec<T>,
+    /// Pagination infos
+    page_info: PageInfo,
+}
+
+/// Cursor pagination infos
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PageInfo {
+    /// Cursor of the last item of the page
+    end_cursor: Option<String>,
+    /// There is at least one more page
+    has_next_page: bool,
+}
+
+/// An access level, as returned by the GraphQL API
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphQlAccessLevel {
+    /// Numeric value
+    integer_value: AccessLevel,
+}
+
+/// An access token, as returned by the GraphQL API
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphQlAccessToken {
+    /// Access level
+    access_level: GraphQlAccessLevel,
+    /// Active
+    active: bool,
+    /// Expiration date
+    #[serde(deserialize_with = "token::deserialize_optional_date")]
+    expires_at: Option<chrono::NaiveDate>,
+    /// Global id, for example `gid://gitlab/PersonalAccessToken/42`
+    id: String,
+    /// Name
+    name: String,
+    /// Revoked
+    revoked: bool,
+    /// Scopes
+    scopes: Vec<AccessTokenScope>,
+}
+
+impl TryFrom<GraphQlAccessToken> for AccessToken {
+    type Error = anyhow::Error;
+
+    fn try_from(token: GraphQlAccessToken) -> Result<Self, Self::Error> {
+        Ok(Self {
+            access_level: token.access_level.integer_value,
+            active: token.active,
+            expires_at: token.expires_at,
+            id: parse_global_id(&token.id)?,
+            name: token.name,
+            revoked: token.revoked,
+            scopes: token.scopes,
+        })
+    }
+}
+
+/// A project or a group, as returned by the GraphQL API
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Resource {
+    /// First page of access tokens
+    access_tokens: Connection<GraphQlAccessToken>,
+    /// Archived (projects only)
+    #[serde(default)]
+    archived: bool,
+    /// Full path
+    full_path: String,
+    /// Access level of the current user
+    max_access_level: GraphQlAccessLevel,
+    /// URL
+    web_url: String,
+}
+
+/// Response to [`PROJECTS_QUERY`] or [`GROUPS_QUERY`]
+#[derive(Deserialize)]
+struct ResourcesData {
+    /// Projects or groups
+    resources: Connection<Resource>,
+}
+
+/// Response to [`SUBTREE_PROJECTS_QUERY`] or [`SUBTREE_GROUPS_QUERY`]
+#[derive(Deserialize)]
+struct SubtreeData {
+    /// Root group of the subtree (`None` if it doesn't exist or isn't visible)
+    subtree: Option<ResourcesData>,
+}
+
+/// Response to [`SUBTREE_ROOT_QUERY`]
+#[derive(Deserialize)]
+struct SubtreeRootData {
+    /// Root group of the subtree (`None` if it doesn't exist or isn't visible)
+    root: Option<Resource>,
+}
+
+/// Response to [`PROJECT_TOKENS_QUERY`] or [`GROUP_TOKENS_QUERY`]
+#[derive(Deserialize)]
+struct ResourceTokensData {
+    /// Project or group
+    resource: ResourceTokens,
+}
+
+/// Access tokens of a project or a group
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceTokens {
+    /// A page of access tokens
+    access_tokens: Connection<GraphQlAccessToken>,
+}
+
+/// Parameters of a [`get_tokens`] call
+struct Scan<'scan, R> {
+    /// Group subtrees to scan (or not)
+    group_subtrees: &'scan GroupSubtrees,
+    /// Kind of the scanned resources
+    kind: ResourceKind,
+    /// Only handle the resources owned by the current user
+    owned_only: bool,
+    /// Query listing the tokens of a single resource
+    tokens_query: &'scan str,
+    /// Transport used to send the queries
+    transport: &'scan R,
+}
+
+impl<R: GraphQlTransport> Scan<'_, R> {
+    /// Adds the tokens of a page of resources to `res`, and returns the cursor of the next page, if any
+    async fn add_page(
+        &self,
+        resources: Connection<Resource>,
+        res: &mut Vec<Token>,
+    ) -> Result<Option<String>, anyhow::Error> {
+        debug!("got {} {}s", resources.nodes.len(), self.kind.type_name());
+
+        for resource in resources.nodes {
+            self.add_resource(resource, res).await?;
+        }
+
+        Ok(match resources.page_info {
+            PageInfo {
+                has_next_page: true,
+                end_cursor: Some(end_cursor),
+            } => Some(end_cursor),
+            _ => None,
+        })
+    }
+
+    /// Adds the tokens of `resource` to `res`, unless it is skipped
+    async fn add_resource(
+        &self,
+        resource: Resource,
+        res: &mut Vec<Token>,
+    ) -> Result<(), anyhow::Error> {
+        if resource.archived
+            || (self.owned_only
+                && resource.max_access_level.integer_value.value() < AccessLevel::Owner.value())
+            || self.group_subtrees.is_excluded(&resource.full_path)
+        {
+            return Ok(());
+        }
+
+        let access_tokens = get_resource_tokens(
+            self.transport,
+            self.tokens_query,
+            &resource.full_path,
+            resource.access_tokens,
+        )
+        .await?;
+
+        for access_token in access_tokens {
+            let full_path = resource.full_path.clone();
+            let web_url = resource.web_url.clone();
+            res.push(match self.kind {
+                ResourceKind::Group => Token::Group {
+                    token: access_token,
+                    full_path,
+                    web_url,
+                },
+                ResourceKind::Project => Token::Project {
+                    token: access_token,
+                    full_path,
+                    web_url,
+                },
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Extracts the numeric id from a global id (`gid://gitlab/<Type>/<id>`)
+fn parse_global_id(global_id: &str) -> Result<usize, anyhow::Error> {
+    global_id
+        .rsplit('/')
+        .next()
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| anyhow!("invalid GraphQL global id: '{global_id}'"))
+}
+
+/// Returns the tokens of all the projects (or groups) visible with `transport`
+///
+/// If `owned_only` is `true`, only the projects (or groups) owned by the current user are handled.
+/// Only the subtrees listed in `group_subtrees.include` (if any) are queried, and the projects
+/// (or groups) in `group_subtrees.exclude` are skipped.
+#[instrument(skip(transport), err)]
+pub async fn get_tokens<R: GraphQlTransport>(
+    transport: &R,
+    kind: ResourceKind,
+    owned_only: bool,
+    group_subtrees: &GroupSubtrees,
+) -> Result<Vec<Token>, anyhow::Error> {
+    let queries = kind.queries();
+    let scan = Scan {
+        group_subtrees,
+        kind,
+        owned_only,
+        tokens_query: &queries.tokens,
+        transport,
+    };
+
+    let mut res = Vec::new();
+
+    if group_subtrees.include.is_empty() {
+        let mut after: Option<String> = None;
+        loop {
+            let data: ResourcesData = transport
+                .query(queries.resources.clone(), json!({ "after": after }))
+                .await?;
+
+            match scan.add_page(data.resources, &mut res).await? {
+                Some(end_cursor) => after = Some(end_cursor),
+                None => break,
+            }
+        }
+    }
+
+    for group_path in &group_subtrees.include {
+        if matches!(kind, ResourceKind::Group) {
+            let data: SubtreeRootData = transport
+                .query(
+                    format!("{SUBTREE_ROOT_QUERY}{ACCESS_TOKEN_FIELDS}"),
+                    json!({ "fullPath": group_path }),
+                )
+                .await?;
+            let root = data
+                .root
+                .ok_or_else(|| anyhow!("group {group_path} not found"))?;
+            scan.add_resource(root, &mut res).await?;
+        }
+
+        let mut after: Option<String> = None;
+        loop {
+            let data: SubtreeData = transport
+                .query(
+                    queries.subtree.clone(),
+                    json!({ "fullPath": group_path, "after": after }),
+                )
+                .await?;
+            let subtree = data
+                .subtree
+                .ok_or_else(|| anyhow!("group {group_path} not found"))?;
+
+            match scan.add_page(subtree.resources, &mut res).await? {
+                Some(end_cursor) => after = Some(end_cursor),
+                None => break,
+            }
+        }
+    }
+
+    Ok(res)
+}
+
+/// Returns all the access tokens of the project (or group) at `full_path`, starting
+/// from their `first_page` and querying the next pages with `tokens_query` if needed
+async fn get_resource_tokens<R: GraphQlTransport>(
+    transport: &R,
+    tokens_query: &str,
+    full_path: &str,
+    first_page: Connection<GraphQlAccessToken>,
+) -> Result<Vec<AccessToken>, anyhow::Error> {
+    let mut graphql_tokens = first_page.nodes;
+    let mut page_info = first_page.page_info;
+
+    while let PageInfo {
+        has_next_page: true,
+        end_cursor: Some(after),
+    } = page_info
+    {
+        debug!("getting more tokens for {full_path}");
+
+        let data: ResourceTokensData = transport
+            .query(
+                tokens_query.to_owned(),
+                json!({ "fullPath": full_path, "after": after }),
+            )
+            .await?;
+
+        graphql_tokens.extend(data.resource.access_tokens.nodes);
+        page_info = data.resource.access_tokens.page_info;
+    }
+
+    graphql_tokens
+        .into_iter()
+        .map(AccessToken::try_from)
+        .collect()
+}
+
+//-------------------------------------------
+//
+// Unit tests
+//
+// ------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use serde::de::DeserializeOwned;
+    use serde_json::{Value, json};
+    use std::collections::HashMap;
+
+    use crate::{
+        config::GroupSubtrees,
+        gitlab::{
+            graphql::{GraphQlTransport, ResourceKind, get_tokens},
+            token::Token,
+        },
+    };
+
+    /// Answers with recorded responses, indexed by the query variables
+    struct FakeResponder {
+        responses: HashMap<String, Value>,
+    }
+
+    impl FakeResponder {
+        fn new(responses: &[(Value, Value)]) -> Self {
+            Self {
+                responses: responses
+                    .iter()
+                    .map(|(variables, data)| (variables.to_string(), data.clone()))
+                    .collect(),
+            }
+        }
+    }
+
+    impl GraphQlTransport for FakeResponder {
+        async fn query<T: DeserializeOwned + Send + 'static>(
+            &self,
+            _query: String,
+            variables: Value,
+        ) -> Result<T, anyhow::Error> {
+            let data = self
+                .responses
+                .get(&variables.to_string())
+                .unwrap_or_else(|| panic!("unexpected variables: {variables}"));
+            Ok(serde_json::from_value(data.clone())?)
+        }
+    }
+
+    fn access_token(id: usize, expires_at: Option<&str>) -> Value {
+        json!({
+            "id": format!("gid://gitlab/PersonalAccessToken/{id}"),
+            "name": format!("token_{id}"),
+            "active": true,
+            "revoked": false,
+            "expiresAt": expires_at,
+            "accessLevel": { "integerValue": 40 },
+            "scopes": ["api", "read_repository"],
+        })
+    }
+
+    fn project(
+        full_path: &str,
+        access_level: u8,
+        tokens: &[Value],
+        end_cursor: Option<&str>,
+    ) -> Value {
+        json!({
+            "fullPath": full_path,
+            "webUrl": format!("https://gitlab.example.com/{full_path}"),
+            "archived": false,
+            "maxAccessLevel": { "integerValue": access_level },
+            "accessTokens": {
+                "pageInfo": { "endCursor": end_cursor, "hasNextPage": end_cursor.is_some() },
+                "nodes": tokens,
+            },
+        })
+    }
+
+    fn page(nodes: &[Value], end_cursor: Option<&str>) -> Value {
+        json!({
+            "resources": {
+                "pageInfo": { "endCursor": end_cursor, "hasNextPage": end_cursor.is_some() },
+                "nodes": nodes,
+            }
+        })
+    }
+
+    fn full_paths_and_ids(tokens: &[Token]) -> Vec<(String, usize)> {
+        tokens
+            .iter()
+            .map(|token| match token {
+                Token::Project {
+                    token, full_path, ..
+                } => (full_path.clone(), token.id),
+                _ => panic!("expected a project token"),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    /// Projects and tokens are read from all the pages
+    async fn cursor_pagination() {
+        let responder = FakeResponder::new(&[
+            (
+                json!({ "after": null }),
+                page(
+                    &[project(
+                        "group/project_a",
+                        50,
+                        &[access_token(1, Some("2030-01-01"))],
+                        Some("token_cursor"),
+                    )],
+                    Some("project_cursor"),
+                ),
+            ),
+            (
+                json!({ "fullPath": "group/project_a", "after": "token_cursor" }),
+                json!({
+                    "resource": {
+                        "accessTokens": {
+                            "pageInfo": { "endCursor": null, "hasNextPage": false },
+                            "nodes": [access_token(2, None)],
+                        }
+                    }
+                }),
+            ),
+            (
+                json!({ "after": "project_cursor" }),
+                page(
+                    &[project(
+                        "group/project_b",
+                        30,
+                        &[access_token(3, Some("10000-12-31"))],
+                        None,
+                    )],
+                    None,
+                ),
+            ),
+        ]);
+
+        let tokens = get_tokens(
+            &responder,
+            ResourceKind::Project,
+            false,
+            &GroupSubtrees::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            full_paths_and_ids(&tokens),
+            vec![
+                ("group/project_a".to_owned(), 1),
+                ("group/project_a".to_owned(), 2),
+                ("group/project_b".to_owned(), 3),
+            ]
+        );
+        assert!(tokens[1].expires_at().is_none());
+    }
+
+    #[tokio::test]
+    /// Only projects where the current user is owner are kept
+    async fn owned_only() {
+        let responder = FakeResponder::new(&[(
+            json!({ "after": null }),
+            page(
+                &[
+                    project("group/owned", 50, &[access_token(1, None)], None),
+                    project("group/maintained", 40, &[access_token(2, None)], None),
+                ],
+                None,
+            ),
+        )]);
+
+        let tokens = get_tokens(
+            &responder,
+            ResourceKind::Project,
+            true,
+            &GroupSubtrees::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            full_paths_and_ids(&tokens),
+            vec![("group/owned".to_owned(), 1)]
+        );
+    }
+
+    #[tokio::test]
+    /// Only the included subtrees are queried, without the excluded projects
+    async fn group_subtrees() {
+        let responder = FakeResponder::new(&[(
+            json!({ "fullPath": "business", "after": null }),
+            json!({
+                "subtree": page(
+                    &[
+                        project("business/unit/project", 50, &[access_token(1, None)], None),
+                        project("business/sandbox/project", 50, &[access_token(2, None)], None),
+                    ],
+                    None,
+                ),
+            }),
+        )]);
+        let group_subtrees = GroupSubtrees {
+            exclude: vec!["business/sandbox".to_owned()],
+            include: vec!["business".to_owned()],
+        };
+
+        let tokens = get_tokens(&responder, ResourceKind::Project, false, &group_subtrees)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            full_paths_and_ids(&tokens),
+            vec![("business/unit/project".to_owned(), 1)]
+        );
+    }
+}
@@ -3,26 +3,33 @@
 use anyhow::Context as _;
 use serde::Deserialize;
 use std::{
-    collections::HashMap,
-    sync::{LazyLock, Mutex},
+    collections::BTreeMap,
+    sync::{Mutex, MutexGuard},
 };
 use tracing::{debug, instrument};
 
 use crate::{
-    config::CONFIG,
+    config::{Credential, Instance},
+    exporter_metrics,
+    filter::{Filters, NameFilter},
     gitlab::{
+        client::Endpoint,
         pagination::{GitLabResourceLister, TokenFetcher},
         token,
     },
 };
 
-/// Group id cache. Used in [`Group::get_full_path`]
-static GROUP_ID_CACHE: LazyLock<Mutex<HashMap<usize, Group>>> =
-    LazyLock::new(|| Mutex::new(HashMap::new()));
+/// Full paths of the groups, by instance name and id. Used in [`Group::get_full_path`]
+///
+/// The entries of an instance are cleared at the start of each of its scans (cf [`clear_cache`]),
+/// so that moved or renamed groups are reflected on the next scan
+static GROUP_CACHE: Mutex<BTreeMap<(String, usize), String>> = Mutex::new(BTreeMap::new());
 
 /// Defines a [gitlab group](https://docs.gitlab.com/api/groups/)
 #[derive(Clone, Debug, Deserialize)]
 pub struct Group {
+    /// Group full path (`None` if the gitlab API didn't return it)
+    pub full_path: Option<String>,
     /// Group id
     pub id: usize,
     /// Group parent id
@@ -34,120 +41,142 @@ pub struct Group {
 }
 
 impl Group {
-    /// Creates a string containing `group` full path
+    /// Returns `group` full path
     ///
-    /// Because the gitlab API gives us `path_with_namespace` for [`projects`](crate::gitlab::project::Project) but not for [`groups`](crate::gitlab::group::Group)
-    #[expect(
-        clippy::unwrap_used,
-        reason = "
-    this function calls unwrap() if the mutex is poisoned, crashing is ok in our case
-"
-    )]
+    /// The full path is given by the gitlab API. If it isn't, it is built from the full path of
+    /// the parent group, which is queried (with `credential`) at most once per scan thanks to [`GROUP_CACHE`]
     #[instrument(skip_all, err)]
-    pub async fn get_full_path(&self) -> Result<String, anyhow::Error> {
+    pub async fn get_full_path(
+        &self,
+        instance: &Instance,
+        credential: &Credential,
+    ) -> Result<String, anyhow::Error> {
         debug!("group: {self:?}");
 
-        // This variable will contain the String returned by this function
-        let mut res = self.path.clone();
-
-        // This variable will be overwritten in the while loop below
-        let mut tmp_group = GROUP_ID_CACHE
-            .lock()
-            .unwrap()
-            .entry(self.id)
-            .or_insert_with(|| self.clone())
-            .clone();
-
-        while let Some(parent_group_id) = tmp_group.parent_id {
-            if let Some(cached_group) = GROUP_ID_CACHE.lock().unwrap().get(&parent_group_id) {
-                debug!("parent group id {parent_group_id} found in cache");
-                tmp_group = cached_group.clone();
-            } else {
-                // We have to query gitlab
-                debug!("getting group id {parent_group_id} from gitlab");
-
-                let url = format!(
-                    "https://{}/api/v4/groups/{parent_group_id}",
-                    CONFIG.connection.hostname
-                );
-                let resp = CONFIG
-                    .connection
-                    .http_client
-                    .get(&url)
-                    .header("PRIVATE-TOKEN", &CONFIG.connection.token)
-                    .send()
-                    .await
-                    .with_context(|| format!("failed to GET {url}"))?
-                    .error_for_status()
-                    .with_context(|| format!("URL {url} returned an error"))?;
-
-                let raw_json = resp
-                    .text()
-                    .await
-                    .with_context(|| format!("failed to get response text from {url}"))?;
-
-                let group_from_gitlab: Self = serde_json::from_str(&raw_json)
-                    .with_context(|| format!("failed to decode raw_json={raw_json}"))?;
-
-                // Storing the result in the cache
-                tmp_group = GROUP_ID_CACHE
-                    .lock()
-                    .unwrap()
-                    .entry(group_from_gitlab.id)
-                    .or_insert_with(|| group_from_gitlab.clone())
-                    .clone();
-
-                debug!("group id {parent_group_id} inserted in cache");
-            }
-
-            res = format!("{}/{res}", tmp_group.path);
+        if let Some(full_path) = &self.full_path {
+            lock_cache().insert((instance.name.clone(), self.id), full_path.clone());
+            return Ok(full_path.clone());
         }
 
-        Ok(res)
+        match self.parent_id {
+            Some(parent_id) => Ok(format!(
+                "{}/{}",
+                get_cached_full_path(instance, credential, parent_id).await?,
+                self.path
+            )),
+            None => Ok(self.path.clone()),
+        }
     }
 }
 
 impl GitLabResourceLister<Self> for Group {
-    fn first_url() -> String {
-        format!(
-            "https://{}/api/v4/groups?per_page=100&archived=false{}",
-            CONFIG.connection.hostname,
-            if CONFIG.owned_entities_only {
-                format!("&min_access_level={}", token::AccessLevel::Owner)
-            } else {
-                String::new()
-            }
-        )
+    fn endpoint(instance: &Instance) -> Endpoint {
+        Endpoint::Groups {
+            min_access_level: instance
+                .owned_entities_only
+                .then_some(token::AccessLevel::Owner),
+        }
     }
 }
 
 impl TokenFetcher for Group {
     async fn create_generic_token(
         &self,
+        instance: &Instance,
+        credential: &Credential,
         token: token::AccessToken,
     ) -> Result<token::Token, anyhow::Error> {
         Ok(token::Token::Group {
             token,
             full_path: self
-                .get_full_path()
+                .get_full_path(instance, credential)
                 .await
                 .context("failed to get full path")?,
             web_url: self.web_url.clone(),
         })
     }
 
-    fn first_url(&self) -> String {
-        format!(
-            "https://{}/api/v4/groups/{}/access_tokens?per_page=100",
-            CONFIG.connection.hostname, self.id
-        )
+    fn endpoint(&self) -> Endpoint {
+        Endpoint::GroupAccessTokens(self.id)
+    }
+
+    fn from_subtree_root(root: Group) -> Option<Self> {
+        Some(root)
+    }
+
+    fn full_path(&self) -> Option<&str> {
+        self.full_path.as_deref()
+    }
+
+    fn id(&self) -> usize {
+        self.id
     }
 
     fn name(&self) -> String {
         self.path.clone()
     }
 
+    fn path_filter(filters: &Filters) -> &NameFilter {
+        &filters.group_paths
+    }
+
+    fn subtree_endpoint(instance: &Instance, group_id: usize) -> Endpoint {
+        Endpoint::DescendantGroups {
+            id: group_id,
+            min_access_level: instance
+                .owned_entities_only
+                .then_some(token::AccessLevel::Owner),
+        }
+    }
+
     fn type_name() -> &'static str {
         "group"
     }
 }
+
+/// Clears the entries of `instance` in [`GROUP_CACHE`]. Must be called at the start of each scan
+pub fn clear_cache(instance: &Instance) {
+    lock_cache().retain(|(name, _), _| *name != instance.name);
+}
+
+/// Returns the number of groups in [`GROUP_CACHE`], by instance name
+pub fn cache_sizes() -> BTreeMap<String, usize> {
+    let mut sizes = BTreeMap::new();
+    for (name, _) in lock_cache().keys() {
+        let size: &mut usize = sizes.entry(name.clone()).or_default();
+        *size = size.saturating_add(1);
+    }
+    sizes
+}
+
+/// Returns the full path of the group `id` of `instance`, from [`GROUP_CACHE`] or from gitlab
+async fn get_cached_full_path(
+    instance: &Instance,
+    credential: &Credential,
+    id: usize,
+) -> Result<String, anyhow::Error> {
+    let cached_full_path = lock_cache().get(&(instance.name.clone(), id)).cloned();
+
+    if let Some(full_path) = cached_full_path {
+        debug!("group id {id} found in cache");
+        exporter_metrics::record_group_cache(&instance.name, true);
+        return Ok(full_path);
+    }
+
+    debug!("getting group id {id} from gitlab");
+    exporter_metrics::record_group_cache(&instance.name, false);
+
+    let group: Group = credential.client.get_one(&Endpoint::Group(id)).await?;
+
+    // `get_full_path` stores the result in the cache
+    Box::pin(group.get_full_path(instance, credential)).await
+}
+
+/// Locks [`GROUP_CACHE`]
+#[expect(
+    clippy::unwrap_used,
+    reason = "crashing on a poisoned mutex is ok in our case"
+)]
+fn lock_cache() -> MutexGuard<'static, BTreeMap<(String, usize), String>> {
+    GROUP_CACHE.lock().unwrap()
+}
@@ -0,0 +1,491 @@
+//! Decodes gitlab JSON responses while the body is being received
+//!
+//! Arrays are split into their elements as the chunks arrive, and each element is
+//! deserialized as soon as it is complete, so a page is never held as a whole string.
+//! Other values (single resources, GraphQL responses) are deserialized by a blocking task,
+//! reading the chunks as they arrive: at most [`MAX_PENDING_CHUNKS`] chunks wait for it, the
+//! body isn't read further until it catches up.
+//!
+//! Decoding errors contain the JSON path of the offending value (for example `[3].scopes[1]`)
+//! and a bounded excerpt of the body, instead of the whole raw body.
+
+use anyhow::{Context as _, anyhow};
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use std::{
+    collections::VecDeque,
+    io::{self, Read},
+};
+use tokio::{sync::mpsc, task};
+
+use crate::exporter_metrics;
+
+/// Maximum number of bytes of the body shown in error messages
+const EXCERPT_MAX_LEN: usize = 120;
+
+/// Maximum number of chunks received but not read yet by the decoder of [`decode_one`]
+const MAX_PENDING_CHUNKS: usize = 8;
+
+/// Position of an [`ArrayDecoder`] relative to the array
+#[derive(PartialEq, Eq)]
+enum ArrayPosition {
+    /// The closing `]` has been received
+    After,
+    /// The opening `[` has not been received yet
+    Before,
+    /// Between the opening `[` and the closing `]`
+    Inside,
+}
+
+/// Position of an [`ArrayDecoder`] relative to JSON strings
+#[derive(PartialEq, Eq)]
+enum StringPosition {
+    /// The previous byte was a `\` inside a string
+    Escaped,
+    /// Inside a string
+    Inside,
+    /// Outside of any string
+    Outside,
+}
+
+/// Incremental decoder for a top-level JSON array of `T`
+pub struct ArrayDecoder<T> {
+    /// Bytes of the element being received
+    buf: Vec<u8>,
+    /// Nesting depth inside the current element
+    depth: usize,
+    /// Index of the element being received
+    index: usize,
+    /// Decoded elements
+    items: Vec<T>,
+    /// Position relative to the array
+    position: ArrayPosition,
+    /// Position relative to JSON strings
+    string: StringPosition,
+}
+
+/// Reader of the chunks of a body, sent by [`decode_one`] as they arrive
+struct ChunkReader {
+    /// Chunk being read
+    chunk: Bytes,
+    /// Chunks not read yet, the channel is closed at the end of the body
+    chunks: mpsc::Receiver<Bytes>,
+    /// Position in `chunk`
+    position: usize,
+    /// Last bytes read (at most [`EXCERPT_MAX_LEN`]), shown in error messages
+    recent: VecDeque<u8>,
+}
+
+impl<T: DeserializeOwned> ArrayDecoder<T> {
+    /// Decodes the element stored in `buf`
+    fn decode_element(&mut self) -> Result<(), anyhow::Error> {
+        let item = decode(&self.buf, &format!("[{}]", self.index))?;
+        self.items.push(item);
+        self.buf.clear();
+        self.index = self.index.saturating_add(1);
+        Ok(())
+    }
+
+    /// Feeds a chunk of the body, decoding every element completed by this chunk
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<(), anyhow::Error> {
+        for (offset, &byte) in chunk.iter().enumerate() {
+            let between_elements = self.depth == 0 && self.string == StringPosition::Outside;
+
+            match self.position {
+                ArrayPosition::After => {
+                    if !byte.is_ascii_whitespace() {
+                        return Err(anyhow!(
+                            "unexpected data after the end of the array: `{}`",
+                            excerpt(chunk, offset)
+                        ));
+                    }
+                }
+                ArrayPosition::Before => match byte {
+                    b'[' => self.position = ArrayPosition::Inside,
+                    _ if byte.is_ascii_whitespace() => {}
+                    _ => {
+                        return Err(anyhow!(
+                            "expected a JSON array at path `$`, got `{}`",
+                            excerpt(chunk, offset)
+                        ));
+                    }
+                },
+                ArrayPosition::Inside if between_elements && matches!(byte, b',' | b']') => {
+                    if !self.buf.iter().all(u8::is_ascii_whitespace) {
+                        self.decode_element()?;
+                    }
+                    if byte == b']' {
+                        self.position = ArrayPosition::After;
+                    }
+                }
+                ArrayPosition::Inside
+                    if between_elements && self.buf.is_empty() && byte.is_ascii_whitespace() => {}
+                ArrayPosition::Inside => {
+                    self.track(byte);
+                    self.buf.push(byte);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the whole array has been received and returns its elements
+    pub fn finish(self) -> Result<Vec<T>, anyhow::Error> {
+        if self.position == ArrayPosition::After {
+            Ok(self.items)
+        } else {
+            Err(anyhow!(
+                "truncated JSON array after {} element{}",
+                self.index,
+                match self.index {
+                    0 | 1 => "",
+                    _ => "s",
+                }
+            ))
+        }
+    }
+
+    /// Creates an empty [`ArrayDecoder`]
+    pub const fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            depth: 0,
+            index: 0,
+            items: Vec::new(),
+            position: ArrayPosition::Before,
+            string: StringPosition::Outside,
+        }
+    }
+
+    /// Updates the string and nesting state with a byte of the current element
+    const fn track(&mut self, byte: u8) {
+        match (&self.string, byte) {
+            (StringPosition::Escaped, _) | (StringPosition::Outside, b'"') => {
+                self.string = StringPosition::Inside;
+            }
+            (StringPosition::Inside, b'\\') => self.string = StringPosition::Escaped,
+            (StringPosition::Inside, b'"') => self.string = StringPosition::Outside,
+            (StringPosition::Outside, b'{' | b'[') => self.depth = self.depth.saturating_add(1),
+            (StringPosition::Outside, b'}' | b']') => self.depth = self.depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position >= self.chunk.len() {
+            let Some(chunk) = self.chunks.blocking_recv() else {
+                return Ok(0);
+            };
+            self.chunk = chunk;
+            self.position = 0;
+        }
+        let remaining = self.chunk.get(self.position..).unwrap_or_default();
+        let len = buf.len().min(remaining.len());
+        let (read, _) = remaining.split_at(len);
+        if let Some(dest) = buf.get_mut(..len) {
+            dest.copy_from_slice(read);
+        }
+        for &byte in read {
+            if self.recent.len() >= EXCERPT_MAX_LEN {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(byte);
+        }
+        self.position = self.position.saturating_add(len);
+        Ok(len)
+    }
+}
+
+/// Deserializes `raw_json`, reporting the JSON path (prefixed with `path_prefix`)
+/// and a bounded excerpt around the position of the error
+pub fn decode<T: DeserializeOwned>(raw_json: &[u8], path_prefix: &str) -> Result<T, anyhow::Error> {
+    let mut deserializer = serde_json::Deserializer::from_slice(raw_json);
+
+    serde_path_to_error::deserialize(&mut deserializer).map_err(|err| {
+        let inner = err.inner();
+        let offset = byte_offset(raw_json, inner.line(), inner.column());
+        decode_error(&err, path_prefix, &excerpt(raw_json, offset))
+    })
+}
+
+/// Reads `resp` chunk by chunk and decodes it as a JSON array of `T`
+///
+/// The size of the body is recorded as downloaded from `downloaded_from`, if set
+pub async fn decode_array<T: DeserializeOwned>(
+    mut resp: reqwest::Response,
+    downloaded_from: Option<&str>,
+) -> Result<Vec<T>, anyhow::Error> {
+    let mut decoder = ArrayDecoder::new();
+    while let Some(chunk) = resp.chunk().await? {
+        if let Some(instance) = downloaded_from {
+            exporter_metrics::record_downloaded_bytes(instance, chunk.len());
+        }
+        decoder.feed(&chunk)?;
+    }
+    decoder.finish()
+}
+
+/// Returns the error of a failed deserialization, with its JSON path (prefixed with
+/// `path_prefix`) and the `excerpt` of the body around the error
+fn decode_error(
+    err: &serde_path_to_error::Error<serde_json::Error>,
+    path_prefix: &str,
+    excerpt: &str,
+) -> anyhow::Error {
+    // `serde_path_to_error` displays the root as `.` and doesn't prefix the first field with a `.`
+    let path = match err.path().to_string() {
+        root if root == "." => String::new(),
+        index if index.starts_with('[') => index,
+        field => format!(".{field}"),
+    };
+    anyhow!(
+        "failed to decode JSON at path `${path_prefix}{path}`: {}, near `{excerpt}`",
+        err.inner()
+    )
+}
+
+/// Reads `resp` chunk by chunk and decodes it as a single `T`
+///
+/// The chunks are deserialized by a blocking task as they arrive, the body is read no faster
+/// than they are deserialized. The size of the body is recorded as downloaded from
+/// `downloaded_from`, if set
+pub async fn decode_one<T: DeserializeOwned + Send + 'static>(
+    mut resp: reqwest::Response,
+    downloaded_from: Option<&str>,
+) -> Result<T, anyhow::Error> {
+    let (sender, chunks) = mpsc::channel(MAX_PENDING_CHUNKS);
+    let decoder = task::spawn_blocking(move || {
+        let mut reader = ChunkReader {
+            chunk: Bytes::new(),
+            chunks,
+            position: 0,
+            recent: VecDeque::new(),
+        };
+        let mut deserializer = serde_json::Deserializer::from_reader(&mut reader);
+        serde_path_to_error::deserialize(&mut deserializer).map_err(|err| {
+            let (front, back) = reader.recent.as_slices();
+            let excerpt = format!(
+                "{}{}",
+                String::from_utf8_lossy(front),
+                String::from_utf8_lossy(back)
+            );
+            decode_error(&err, "", &excerpt)
+        })
+    });
+
+    while let Some(chunk) = resp.chunk().await? {
+        if let Some(instance) = downloaded_from {
+            exporter_metrics::record_downloaded_bytes(instance, chunk.len());
+        }
+        // The decoder stops reading on the first error, which it returns below
+        if sender.send(chunk).await.is_err() {
+            break;
+        }
+    }
+    drop(sender);
+    decoder.await.context("the JSON decoder failed")?
+}
+
+/// Converts a (1-based) `line` and `column` from a [`serde_json::Error`] into a byte offset
+fn byte_offset(raw_json: &[u8], line: usize, column: usize) -> usize {
+    let line_start: usize = raw_json
+        .split(|byte| *byte == b'\n')
+        .take(line.saturating_sub(1))
+        .map(|line_bytes| line_bytes.len().saturating_add(1))
+        .sum();
+    line_start.saturating_add(column.saturating_sub(1))
+}
+
+/// Returns at most [`EXCERPT_MAX_LEN`] bytes of `raw_json` around `offset`
+fn excerpt(raw_json: &[u8], offset: usize) -> String {
+    let start = offset.saturating_sub(EXCERPT_MAX_LEN.div_euclid(2));
+    let end = start.saturating_add(EXCERPT_MAX_LEN).min(raw_json.len());
+    let bytes = raw_json.get(start..end).unwrap_or_default();
+
+    format!(
+        "{}{}{}",
+        if start > 0 { "..." } else { "" },
+        String::from_utf8_lossy(bytes),
+        if end < raw_json.len() { "..." } else { "" }
+    )
+}
+
+//-------------------------------------------
+//
+// Unit tests
+//
+// ------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use core::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use http_body::Frame;
+    use serde::Deserialize;
+    use std::{collections::VecDeque, convert::Infallible};
+
+    use crate::gitlab::json::{
+        ArrayDecoder, EXCERPT_MAX_LEN, MAX_PENDING_CHUNKS, decode, decode_one,
+    };
+
+    /// Body sent in several chunks
+    struct ChunkedBody(VecDeque<Bytes>);
+
+    impl http_body::Body for ChunkedBody {
+        type Data = Bytes;
+        type Error = Infallible;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+            Poll::Ready(self.0.pop_front().map(|chunk| Ok(Frame::data(chunk))))
+        }
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Item {
+        id: usize,
+        name: String,
+    }
+
+    fn decode_chunks(chunks: &[&str]) -> Result<Vec<Item>, anyhow::Error> {
+        let mut decoder = ArrayDecoder::new();
+        for chunk in chunks {
+            decoder.feed(chunk.as_bytes())?;
+        }
+        decoder.finish()
+    }
+
+    #[test]
+    fn empty_array() {
+        assert!(decode_chunks(&[" [ ] "]).unwrap().is_empty());
+    }
+
+    #[test]
+    /// Elements split across chunks, with brackets and escaped quotes inside strings
+    fn split_elements() {
+        let items = decode_chunks(&[
+            r#"[{"id":1,"name":"a]"#,
+            r#",\"}"},  {"id""#,
+            r#":2,"name":"b{"}]"#,
+        ])
+        .unwrap();
+
+        assert_eq!(
+            items,
+            vec![
+                Item {
+                    id: 1,
+                    name: "a],\"}".to_owned()
+                },
+                Item {
+                    id: 2,
+                    name: "b{".to_owned()
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_array() {
+        let err = decode_chunks(&[r#"[{"id":1,"name":"a"},{"id":2"#]).unwrap_err();
+        assert_eq!(err.to_string(), "truncated JSON array after 1 element");
+    }
+
+    #[test]
+    fn not_an_array() {
+        let err = decode_chunks(&[r#"{"message":"401 Unauthorized"}"#]).unwrap_err();
+        assert!(err.to_string().starts_with("expected a JSON array"));
+    }
+
+    #[test]
+    fn excerpt_starts_at_the_offending_byte() {
+        let padding = " ".repeat(2 * EXCERPT_MAX_LEN);
+        let err = decode_chunks(&[&format!("{padding}x[]")]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "expected a JSON array at path `$`, got `...{}x[]`",
+                " ".repeat(EXCERPT_MAX_LEN / 2)
+            )
+        );
+
+        let err = decode_chunks(&[r#"[{"id":1,"name":"a"}]"#, "  ", " {} "]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unexpected data after the end of the array: ` {} `"
+        );
+        let err = decode_chunks(&[&format!(r#"[]{padding}{{"id":1}}"#)]).unwrap_err();
+        assert!(err.to_string().ends_with(r#" {"id":1}`"#), "{err}");
+    }
+
+    #[tokio::test]
+    async fn decode_single_value() {
+        let resp = |body: &'static str| {
+            reqwest::Response::from(http::Response::builder().body(body).unwrap())
+        };
+
+        let item: Item = decode_one(resp(r#"{"id":1,"name":"a"}"#), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            item,
+            Item {
+                id: 1,
+                name: "a".to_owned()
+            }
+        );
+
+        let err = decode_one::<Item>(resp(r#"{"name":"a","id":"one"}"#), None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"failed to decode JSON at path `$.id`: invalid type: string "one", expected usize at line 1 column 22, near `{"name":"a","id":"one"}`"#
+        );
+    }
+
+    #[tokio::test]
+    async fn decode_single_value_in_many_chunks() {
+        let value = format!(r#"{{"id":7,"name":"{}"}}"#, "x".repeat(100));
+        let chunks: VecDeque<Bytes> = value
+            .as_bytes()
+            .chunks(1)
+            .map(Bytes::copy_from_slice)
+            .collect();
+        assert!(chunks.len() > MAX_PENDING_CHUNKS);
+        let resp = reqwest::Response::from(
+            http::Response::builder()
+                .body(reqwest::Body::wrap(ChunkedBody(chunks)))
+                .unwrap(),
+        );
+
+        let item: Item = decode_one(resp, None).await.unwrap();
+        assert_eq!(item.id, 7);
+        assert_eq!(item.name.len(), 100);
+    }
+
+    #[test]
+    fn error_contains_path() {
+        let err = decode_chunks(&[r#"[{"id":1,"name":"a"},{"id":"two","name":"b"}]"#]).unwrap_err();
+        assert!(err.to_string().contains("`$[1].id`"), "{err}");
+    }
+
+    #[test]
+    fn error_excerpt_is_bounded() {
+        let long_name = "x".repeat(10 * EXCERPT_MAX_LEN);
+        let raw_json = format!(r#"{{"name":"{long_name}","id":-1}}"#);
+
+        let err = decode::<Item>(raw_json.as_bytes(), "").unwrap_err();
+        let msg = err.to_string();
+
+        assert!(msg.contains("`$.id`"), "{msg}");
+        assert!(msg.len() < 3 * EXCERPT_MAX_LEN, "{msg}");
+    }
+}
@@ -1,6 +1,10 @@
 //! Top-level file to include other files
+pub mod cache;
+pub mod client;
 pub mod connection;
+pub mod graphql;
 pub mod group;
+pub mod json;
 pub mod pagination;
 pub mod project;
 pub mod token;
@@ -2,100 +2,115 @@
 
 use anyhow::Context as _;
 use core::future::Future;
-use tracing::{debug, instrument};
+use serde::de::DeserializeOwned;
+use std::collections::HashSet;
 
+#[cfg(doc)]
+use crate::gitlab::{project::Project, user::User};
 use crate::{
-    config::CONFIG,
-    gitlab::token::{AccessToken, Token},
+    config::{Credential, Instance},
+    filter::{Filters, NameFilter},
+    gitlab::{
+        client::Endpoint,
+        group::Group,
+        token::{AccessToken, Token},
+    },
 };
 
-/// Trait used to get [`Project`](crate::gitlab::project::Project), [`Group`](crate::gitlab::group::Group), [`User`](crate::gitlab::user::User) and [`PersonalAccessToken`](crate::gitlab::token)
-pub trait GitLabResourceLister<T: for<'serde> serde::Deserialize<'serde> + GitLabResourceLister<T>>
-{
-    /// This function must return the URL of the first page to get a list of `T`
-    fn first_url() -> String;
-    /// Returns `Vec<T>` using [`get_all_gitlab_items`], starting from [`first_url`](GitLabResourceLister::first_url)
-    async fn get_all() -> Result<Vec<T>, anyhow::Error> {
-        let first_url = T::first_url();
-        get_all_gitlab_items(&first_url).await
+/// Trait used to get [`Project`], [`Group`], [`User`] and [`PersonalAccessToken`](crate::gitlab::token)
+pub trait GitLabResourceLister<T: DeserializeOwned + GitLabResourceLister<T>> {
+    /// This function must return the paginated [`Endpoint`] listing `T` on `instance`
+    fn endpoint(instance: &Instance) -> Endpoint;
+    /// Returns the `Vec<T>` visible by `credential`, using [`Client::get_paginated`](crate::gitlab::client::Client::get_paginated) on [`endpoint`](GitLabResourceLister::endpoint)
+    async fn get_all(
+        instance: &Instance,
+        credential: &Credential,
+    ) -> Result<Vec<T>, anyhow::Error> {
+        Ok(credential
+            .client
+            .get_paginated(&T::endpoint(instance))
+            .await?)
     }
 }
 
-/// Trait used to fetch tokens from a specific [`Project`](crate::gitlab::project::Project) or [`Group`](crate::gitlab::group::Group)
+/// Trait used to fetch tokens from a specific [`Project`] or [`Group`]
 pub trait TokenFetcher: Send + Sync + 'static {
     /// Generates a (common) [`Token`] from an [`AccessToken`]
     fn create_generic_token(
         &self,
+        instance: &Instance,
+        credential: &Credential,
         token: AccessToken,
     ) -> impl Future<Output = Result<Token, anyhow::Error>> + Send;
 
-    /// This function must return the URL of the first page to get a list of [`AccessToken`]
-    fn first_url(&self) -> String;
+    /// This function must return the paginated [`Endpoint`] listing the [`AccessToken`]
+    fn endpoint(&self) -> Endpoint;
+
+    /// Returns the root group of a subtree as a resource, if it is one (i.e. for groups)
+    fn from_subtree_root(root: Group) -> Option<Self>
+    where
+        Self: Sized;
 
-    /// Get tokens for a specific [`Project`](crate::gitlab::project::Project) or [`Group`](crate::gitlab::group::Group), starting from [`first_url`](TokenFetcher::first_url)
+    /// Full path of the resource, if it is known without querying gitlab
+    fn full_path(&self) -> Option<&str>;
+
+    /// Get tokens for a specific [`Project`] or [`Group`], using [`endpoint`](TokenFetcher::endpoint)
     fn get_all_tokens(
         &self,
+        credential: &Credential,
     ) -> impl Future<Output = Result<Vec<AccessToken>, anyhow::Error>> + Send {
-        async {
-            let first_url = self.first_url();
-            get_all_gitlab_items(&first_url).await
-        }
+        async { Ok(credential.client.get_paginated(&self.endpoint()).await?) }
     }
 
-    /// [`Project`](crate::gitlab::project::Project) or [`Group`](crate::gitlab::group::Group) name
+    /// [`Project`] or [`Group`] id
+    fn id(&self) -> usize;
+
+    /// [`Project`] or [`Group`] name
     fn name(&self) -> String;
 
+    /// Returns the filter applied to the full paths of this type of resources
+    fn path_filter(filters: &Filters) -> &NameFilter;
+
+    /// This function must return the paginated [`Endpoint`] listing the resources
+    /// in the subtree of the group `group_id`
+    fn subtree_endpoint(instance: &Instance, group_id: usize) -> Endpoint;
+
     /// Name of the type ("project" or "group")
     fn type_name() -> &'static str;
 }
 
-#[instrument(skip_all, err)]
-/// Starting from `start_url`, get all the items, using the 'link' header to go through all the pages
-/// cf <https://docs.gitlab.com/api/rest/#offset-based-pagination>
-async fn get_all_gitlab_items<T>(start_url: &str) -> Result<Vec<T>, anyhow::Error>
+/// Returns the projects (or groups) in the subtrees of the groups listed in `GROUP_SUBTREES_INCLUDE`,
+/// using [`subtree_endpoint`](TokenFetcher::subtree_endpoint)
+pub async fn get_all_in_subtrees<T>(
+    instance: &Instance,
+    credential: &Credential,
+) -> Result<Vec<T>, anyhow::Error>
 where
-    T: for<'serde> serde::Deserialize<'serde>,
+    T: DeserializeOwned + TokenFetcher,
 {
-    let mut result: Vec<T> = Vec::new();
-    let mut next_url: Option<String> = Some(start_url.to_owned());
-
-    while let Some(current_url) = next_url {
-        debug!("trying to GET {current_url}");
-
-        let resp = CONFIG
-            .connection
-            .http_client
-            .get(&current_url)
-            .header("PRIVATE-TOKEN", &CONFIG.connection.token)
-            .send()
-            .await
-            .with_context(|| format!("failed to GET {current_url}"))?
-            .error_for_status()
-            .with_context(|| format!("URL {current_url} returned an error"))?;
+    let mut res: Vec<T> = Vec::new();
+    let mut ids = HashSet::new();
 
-        debug!("Got a response for {current_url}");
-
-        next_url = resp
-            .headers()
-            .get("link")
-            .and_then(|header_value| header_value.to_str().ok())
-            .and_then(|header_value_str| parse_link_header::parse_with_rel(header_value_str).ok())
-            .and_then(|mut links| links.remove("next").map(|link| link.raw_uri));
-
-        debug!(?next_url);
-
-        let raw_json = resp
-            .text()
+    for group_path in &instance.group_subtrees.include {
+        let root: Group = credential
+            .client
+            .get_one(&Endpoint::GroupByPath(group_path.clone()))
             .await
-            .with_context(|| format!("failed to get response text from {current_url}"))?;
-
-        let mut items: Vec<T> = serde_json::from_str(&raw_json)
-            .with_context(|| format!("failed to decode raw_json={raw_json}"))?;
-
-        result.append(&mut items);
+            .with_context(|| format!("failed to get group {group_path}"))?;
+
+        let items: Vec<T> = credential
+            .client
+            .get_paginated(&T::subtree_endpoint(instance, root.id))
+            .await?;
+
+        // Subtrees never overlap (cf `GROUP_SUBTREES_INCLUDE` parsing), but the ids are
+        // checked anyway in case a group was moved during the scan
+        for item in T::from_subtree_root(root).into_iter().chain(items) {
+            if ids.insert(item.id()) {
+                res.push(item);
+            }
+        }
     }
 
-    debug!("done! (start_url was {start_url})");
-
-    Ok(result)
+    Ok(res)
 }
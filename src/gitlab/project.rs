@@ -3,8 +3,11 @@
 use serde::Deserialize;
 
 use crate::{
-    config::CONFIG,
+    config::{Credential, Instance},
+    filter::{Filters, NameFilter},
     gitlab::{
+        client::Endpoint,
+        group::Group,
         pagination::{GitLabResourceLister, TokenFetcher},
         token,
     },
@@ -22,22 +25,20 @@ pub struct Project {
 }
 
 impl GitLabResourceLister<Self> for Project {
-    fn first_url() -> String {
-        format!(
-            "https://{}/api/v4/projects?per_page=100&archived=false{}",
-            CONFIG.connection.hostname,
-            if CONFIG.owned_entities_only {
-                format!("&min_access_level={}", token::AccessLevel::Owner)
-            } else {
-                String::new()
-            }
-        )
+    fn endpoint(instance: &Instance) -> Endpoint {
+        Endpoint::Projects {
+            min_access_level: instance
+                .owned_entities_only
+                .then_some(token::AccessLevel::Owner),
+        }
     }
 }
 
 impl TokenFetcher for Project {
     async fn create_generic_token(
         &self,
+        _instance: &Instance,
+        _credential: &Credential,
         token: token::AccessToken,
     ) -> Result<token::Token, anyhow::Error> {
         Ok(token::Token::Project {
@@ -47,17 +48,39 @@ impl TokenFetcher for Project {
         })
     }
 
-    fn first_url(&self) -> String {
-        format!(
-            "https://{}/api/v4/projects/{}/access_tokens?per_page=100",
-            CONFIG.connection.hostname, self.id
-        )
+    fn endpoint(&self) -> Endpoint {
+        Endpoint::ProjectAccessTokens(self.id)
+    }
+
+    fn from_subtree_root(_root: Group) -> Option<Self> {
+        None
+    }
+
+    fn full_path(&self) -> Option<&str> {
+        Some(&self.path_with_namespace)
+    }
+
+    fn id(&self) -> usize {
+        self.id
     }
 
     fn name(&self) -> String {
         self.path_with_namespace.clone()
     }
 
+    fn path_filter(filters: &Filters) -> &NameFilter {
+        &filters.project_paths
+    }
+
+    fn subtree_endpoint(instance: &Instance, group_id: usize) -> Endpoint {
+        Endpoint::GroupProjects {
+            id: group_id,
+            min_access_level: instance
+                .owned_entities_only
+                .then_some(token::AccessLevel::Owner),
+        }
+    }
+
     fn type_name() -> &'static str {
         "project"
     }
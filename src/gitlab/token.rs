@@ -3,14 +3,15 @@ use anyhow::Context as _;
 use chrono::NaiveDate;
 use core::fmt::Write as _; // To be able to use the `write` macro
 use core::fmt::{Display, Formatter};
-use serde::Deserialize;
-use serde_repr::Deserialize_repr;
+use serde::{Deserialize, Serialize};
+use serde_repr::{Deserialize_repr, Serialize_repr};
 
-use crate::config::CONFIG;
+use crate::config::Instance;
+use crate::gitlab::client::Endpoint;
 use crate::gitlab::pagination::GitLabResourceLister;
 
 /// cf <https://docs.gitlab.com/api/project_access_tokens/#create-a-project-access-token>
-#[derive(Debug, Deserialize_repr)]
+#[derive(Clone, Copy, Debug, Deserialize_repr, Serialize_repr)]
 #[repr(u8)]
 pub enum AccessLevel {
     Developer = 30,
@@ -20,6 +21,14 @@ pub enum AccessLevel {
     Reporter = 20,
 }
 
+impl AccessLevel {
+    /// Numeric value, as expected by the gitlab API
+    #[expect(clippy::as_conversions, reason = "AccessLevel is a repr(u8) enum")]
+    pub const fn value(self) -> u8 {
+        self as u8
+    }
+}
+
 impl Display for AccessLevel {
     #[expect(clippy::absolute_paths, reason = "use a specific Result type")]
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
@@ -38,7 +47,7 @@ impl Display for AccessLevel {
 }
 
 /// Defines a [gitlab access token](https://docs.gitlab.com/api/project_access_tokens/#list-project-access-tokens)
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct AccessToken {
     /// Access level
     pub access_level: AccessLevel,
@@ -58,7 +67,7 @@ pub struct AccessToken {
 }
 
 /// Scopes used by [`AccessToken`] (for [`Project`](crate::gitlab::project::Project) and [`Group`](crate::gitlab::group::Group))
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum AccessTokenScope {
     /// Grants permission to perform API actions for GitLab Duo
@@ -135,7 +144,7 @@ impl core::fmt::Display for AccessTokenScope {
 }
 
 /// Defines a [gitlab personal access token](https://docs.gitlab.com/api/personal_access_tokens/#list-personal-access-tokens)
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct PersonalAccessToken {
     /// Active
     pub active: bool,
@@ -155,16 +164,13 @@ pub struct PersonalAccessToken {
 }
 
 impl GitLabResourceLister<Self> for PersonalAccessToken {
-    fn first_url() -> String {
-        format!(
-            "https://{}/api/v4/personal_access_tokens?per_page=100",
-            CONFIG.connection.hostname
-        )
+    fn endpoint(_instance: &Instance) -> Endpoint {
+        Endpoint::PersonalAccessTokens
     }
 }
 
 /// Scopes used by [`PersonalAccessToken`] (for [`User`](crate::gitlab::user::User))
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum PersonalAccessTokenScope {
     /// Grants permission to perform API actions when Admin Mode is enabled
@@ -252,7 +258,8 @@ impl core::fmt::Display for PersonalAccessTokenScope {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
 #[expect(clippy::missing_docs_in_private_items, reason = "self documented ;)")]
 /// A common token type
 pub enum Token {
@@ -276,6 +283,65 @@ pub enum Token {
 }
 
 impl Token {
+    /// Expiration date
+    pub const fn expires_at(&self) -> Option<NaiveDate> {
+        match self {
+            Self::Group { token, .. } | Self::Project { token, .. } => token.expires_at,
+            Self::User { token, .. } => token.expires_at,
+        }
+    }
+
+    /// Full path of the project or group, or username of the user owning the token
+    pub fn full_path(&self) -> &str {
+        match self {
+            Self::Group { full_path, .. }
+            | Self::Project { full_path, .. }
+            | Self::User { full_path, .. } => full_path,
+        }
+    }
+
+    /// Token id (unique for a given [type](Token::type_name))
+    pub const fn id(&self) -> usize {
+        match self {
+            Self::Group { token, .. } | Self::Project { token, .. } => token.id,
+            Self::User { token, .. } => token.id,
+        }
+    }
+
+    /// Returns `true` if the token is active
+    pub const fn is_active(&self) -> bool {
+        match self {
+            Self::Group { token, .. } | Self::Project { token, .. } => token.active,
+            Self::User { token, .. } => token.active,
+        }
+    }
+
+    /// Returns `true` if the token has been revoked
+    pub const fn is_revoked(&self) -> bool {
+        match self {
+            Self::Group { token, .. } | Self::Project { token, .. } => token.revoked,
+            Self::User { token, .. } => token.revoked,
+        }
+    }
+
+    /// Token name
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Group { token, .. } | Self::Project { token, .. } => &token.name,
+            Self::User { token, .. } => &token.name,
+        }
+    }
+
+    /// Names of the token scopes (`api`, `read_api`, ...)
+    pub fn scope_names(&self) -> Vec<String> {
+        match self {
+            Self::Group { token, .. } | Self::Project { token, .. } => {
+                token.scopes.iter().map(ToString::to_string).collect()
+            }
+            Self::User { token, .. } => token.scopes.iter().map(ToString::to_string).collect(),
+        }
+    }
+
     /// Convert token scopes ([`AccessTokenScope`] or [`PersonalAccessTokenScope`]) into a String
     pub fn scopes(&self) -> Result<String, anyhow::Error> {
         let mut res = String::from("[");
@@ -300,6 +366,15 @@ impl Token {
         res.push(']');
         Ok(res)
     }
+
+    /// Name of the token type ("group", "project" or "user")
+    pub const fn type_name(&self) -> &'static str {
+        match *self {
+            Self::Group { .. } => "group",
+            Self::Project { .. } => "project",
+            Self::User { .. } => "user",
+        }
+    }
 }
 
 /// Custom date deserialization function to handle years > 9999
@@ -310,7 +385,9 @@ impl Token {
     clippy::indexing_slicing,
     reason = "we check the size of the vec before indexing"
 )]
-fn deserialize_optional_date<'de, D>(deserializer: D) -> Result<Option<chrono::NaiveDate>, D::Error>
+pub fn deserialize_optional_date<'de, D>(
+    deserializer: D,
+) -> Result<Option<chrono::NaiveDate>, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
@@ -1,10 +1,12 @@
 //! gitab user definition and traits/helpers
 
-use anyhow::Context as _;
 use serde::Deserialize;
 use tracing::{debug, instrument};
 
-use crate::{config::CONFIG, gitlab::pagination::GitLabResourceLister};
+use crate::{
+    config::{Credential, Instance},
+    gitlab::{client::Endpoint, pagination::GitLabResourceLister},
+};
 
 /// Defines a [gitlab user](https://docs.gitlab.com/api/users/#list-users)
 #[derive(Debug, Deserialize)]
@@ -19,39 +21,15 @@ pub struct User {
 }
 
 impl GitLabResourceLister<Self> for User {
-    fn first_url() -> String {
-        format!(
-            "https://{}/api/v4/users?per_page=100",
-            CONFIG.connection.hostname
-        )
+    fn endpoint(_instance: &Instance) -> Endpoint {
+        Endpoint::Users
     }
 }
 
-/// Get the current gitlab user
+/// Get the gitlab user authenticated by `credential`
 #[instrument(skip_all, err)]
-pub async fn get_current() -> Result<User, anyhow::Error> {
-    let current_url = format!("https://{}/api/v4/user", CONFIG.connection.hostname);
-
+pub async fn get_current(credential: &Credential) -> Result<User, anyhow::Error> {
     debug!("getting current user");
 
-    let resp = CONFIG
-        .connection
-        .http_client
-        .get(&current_url)
-        .header("PRIVATE-TOKEN", &CONFIG.connection.token)
-        .send()
-        .await
-        .with_context(|| format!("failed to GET {current_url}"))?
-        .error_for_status()
-        .with_context(|| format!("URL {current_url} returned an error"))?;
-
-    let raw_json = resp
-        .text()
-        .await
-        .with_context(|| format!("failed to get response text from {current_url}"))?;
-
-    let user = serde_json::from_str(&raw_json)
-        .with_context(|| format!("failed to decode raw_json={raw_json}"))?;
-
-    Ok(user)
+    Ok(credential.client.get_one(&Endpoint::CurrentUser).await?)
 }
@@ -0,0 +1,186 @@
+//! Health (`/healthz`) and readiness (`/readyz`) endpoints, for the Kubernetes probes
+//!
+//! `/healthz` checks that the state and timer actors answer a ping, `/readyz` that a scan
+//! succeeded since the exporter started. Both answer with a JSON document.
+
+use axum::{
+    extract::State,
+    http::{StatusCode, header},
+    response::{IntoResponse as _, Response},
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use core::time::Duration;
+use serde::Serialize;
+use tokio::{
+    sync::{mpsc, oneshot},
+    time,
+};
+
+use crate::state_actor::Message;
+
+/// Maximum duration of a ping. The Kubernetes probes time out after 1 second by default
+const PING_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Result of the ping of an actor
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Check {
+    /// The actor answered
+    Ok,
+    /// The actor is stopped
+    Stopped,
+    /// The actor didn't answer within [`PING_TIMEOUT`]
+    Timeout,
+}
+
+/// Channels of the actors checked by the endpoints
+#[derive(Clone, Debug)]
+pub struct Checked {
+    /// State actor
+    pub state_actor: mpsc::Sender<Message>,
+    /// Timer actor
+    pub timer_actor: mpsc::Sender<oneshot::Sender<()>>,
+}
+
+/// Response of `/healthz`
+#[derive(Debug, Serialize)]
+struct Health {
+    /// Ping of the state actor
+    state_actor: Check,
+    /// `ok` if all the checks are ok, `failed` otherwise
+    status: &'static str,
+    /// Ping of the timer actor
+    timer_actor: Check,
+}
+
+/// Response of `/readyz`
+#[derive(Debug, Serialize)]
+struct Readiness {
+    /// End of the last successful scan (RFC 3339)
+    last_successful_scan: Option<String>,
+    /// True once a scan succeeded
+    ready: bool,
+    /// Ping of the state actor
+    state_actor: Check,
+}
+
+/// Returns `value` as a JSON response
+pub fn json_response<T: Serialize>(status: StatusCode, value: &T) -> Response {
+    match serde_json::to_string(value) {
+        Ok(body) => (status, [(header::CONTENT_TYPE, "application/json")], body).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+/// Handles `/healthz` requests
+pub async fn healthz_handler(State(checked): State<Checked>) -> Response {
+    let (state_actor, _) = ping_state_actor(&checked.state_actor).await;
+    let timer_actor = ping_timer_actor(&checked.timer_actor).await;
+
+    let healthy = state_actor == Check::Ok && timer_actor == Check::Ok;
+    json_response(
+        if healthy {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        },
+        &Health {
+            state_actor,
+            status: if healthy { "ok" } else { "failed" },
+            timer_actor,
+        },
+    )
+}
+
+/// Pings the state actor, and returns the date of the last successful scan
+async fn ping_state_actor(sender: &mpsc::Sender<Message>) -> (Check, Option<DateTime<Utc>>) {
+    let (respond_to, response) = oneshot::channel();
+    // The timeout includes the send, which waits while the channel is full
+    let ping = async {
+        sender
+            .send(Message::Ping { respond_to })
+            .await
+            .ok()
+            .map(|()| response)?
+            .await
+            .ok()
+    };
+    match time::timeout(PING_TIMEOUT, ping).await {
+        Ok(Some(last_successful_scan)) => (Check::Ok, last_successful_scan),
+        Ok(None) => (Check::Stopped, None),
+        Err(_) => (Check::Timeout, None),
+    }
+}
+
+/// Pings the timer actor
+async fn ping_timer_actor(sender: &mpsc::Sender<oneshot::Sender<()>>) -> Check {
+    let (respond_to, response) = oneshot::channel();
+    let ping = async {
+        sender
+            .send(respond_to)
+            .await
+            .ok()
+            .map(|()| response)?
+            .await
+            .ok()
+    };
+    match time::timeout(PING_TIMEOUT, ping).await {
+        Ok(Some(())) => Check::Ok,
+        Ok(None) => Check::Stopped,
+        Err(_) => Check::Timeout,
+    }
+}
+
+/// Handles `/readyz` requests
+pub async fn readyz_handler(State(checked): State<Checked>) -> Response {
+    let (state_actor, last_successful_scan) = ping_state_actor(&checked.state_actor).await;
+
+    let ready = last_successful_scan.is_some();
+    json_response(
+        if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        },
+        &Readiness {
+            last_successful_scan: last_successful_scan
+                .map(|date| date.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            ready,
+            state_actor,
+        },
+    )
+}
+
+//-------------------------------------------
+//
+// Unit tests
+//
+// ------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::{mpsc, oneshot};
+
+    use crate::health::{Check, ping_state_actor, ping_timer_actor};
+    use crate::state_actor::Message;
+
+    #[tokio::test]
+    async fn actors_are_pinged() {
+        // The actor answers
+        let (sender, mut receiver) = mpsc::channel(1);
+        let actor = tokio::spawn(async move {
+            if let Some(Message::Ping { respond_to }) = receiver.recv().await {
+                respond_to.send(None).unwrap();
+            }
+        });
+        assert_eq!(ping_state_actor(&sender).await, (Check::Ok, None));
+        actor.await.unwrap();
+
+        // The actor is stopped
+        assert_eq!(ping_state_actor(&sender).await, (Check::Stopped, None));
+
+        // The actor is wedged: it never reads its channel
+        let (sender, _receiver) = mpsc::channel::<oneshot::Sender<()>>(1);
+        assert_eq!(ping_timer_actor(&sender).await, Check::Timeout);
+    }
+}
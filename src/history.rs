@@ -0,0 +1,363 @@
+//! History of the tokens, kept in an embedded `SQLite` database (`HISTORY_DATABASE`)
+//!
+//! After each scan, the [state actor](crate::state_actor) saves the scanned tokens: a token is
+//! inserted the first time it is seen, and its state and last-seen time are updated by the next
+//! scans. The tokens which haven't been seen for `HISTORY_RETENTION_DAYS` are deleted, so that the
+//! tokens deleted from GitLab are kept for that long.
+//!
+//! `/history` answers with the tokens matching an instance, a path, a type and a date range.
+
+use anyhow::Context as _;
+use axum::{
+    extract::Query,
+    http::StatusCode,
+    response::{IntoResponse as _, Response},
+};
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeDelta, Utc};
+use core::time::Duration;
+use rusqlite::{Connection, OpenFlags, Row, named_params};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::task;
+use tracing::{debug, error};
+
+use crate::config::CONFIG;
+use crate::gitlab::token::Token;
+use crate::health::json_response;
+use crate::inventory::ResourceType;
+
+/// Time a connection waits for the database to be unlocked by another one
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Creates the tables, if they don't exist yet
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS tokens (
+    instance TEXT NOT NULL,
+    type TEXT NOT NULL,
+    id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    path TEXT NOT NULL,
+    scopes TEXT NOT NULL,
+    expires_at TEXT,
+    active INTEGER NOT NULL,
+    revoked INTEGER NOT NULL,
+    first_seen TEXT NOT NULL,
+    last_seen TEXT NOT NULL,
+    PRIMARY KEY (instance, type, id)
+);
+CREATE INDEX IF NOT EXISTS tokens_path ON tokens (path);
+CREATE INDEX IF NOT EXISTS tokens_last_seen ON tokens (last_seen);
+";
+
+/// Query parameters of `/history`. Without parameter, all the tokens are returned
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HistoryQuery {
+    /// Only returns the tokens seen on or after this day
+    from: Option<NaiveDate>,
+    /// Only returns the tokens of this instance
+    instance: Option<String>,
+    /// Only returns the tokens owned by the project or group at this path, or by the projects and
+    /// groups below it, or by the user with this username
+    path: Option<String>,
+    /// Only returns the tokens seen on or before this day
+    to: Option<NaiveDate>,
+    /// Only returns the tokens of this type
+    #[serde(rename = "type")]
+    token_type: Option<ResourceType>,
+}
+
+/// A token, as saved in the history database
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct HistoryToken {
+    /// Whether the token was active when it was last seen
+    active: bool,
+    /// Expiration date of the token
+    expires_at: Option<NaiveDate>,
+    /// First time a scan found the token
+    first_seen: DateTime<Utc>,
+    /// Token id
+    id: i64,
+    /// Instance name
+    instance: String,
+    /// Last time a scan found the token
+    last_seen: DateTime<Utc>,
+    /// Token name
+    name: String,
+    /// Full path of the project or group, or username of the user owning the token
+    path: String,
+    /// Whether the token was revoked when it was last seen
+    revoked: bool,
+    /// Scopes of the token, separated by commas
+    scopes: String,
+    /// `project`, `group` or `user`
+    #[serde(rename = "type")]
+    token_type: String,
+}
+
+impl HistoryToken {
+    /// Reads a row of the `tokens` table
+    fn from_row(row: &Row<'_>) -> Result<Self, rusqlite::Error> {
+        Ok(Self {
+            active: row.get("active")?,
+            expires_at: row.get("expires_at")?,
+            first_seen: row.get("first_seen")?,
+            id: row.get("id")?,
+            instance: row.get("instance")?,
+            last_seen: row.get("last_seen")?,
+            name: row.get("name")?,
+            path: row.get("path")?,
+            revoked: row.get("revoked")?,
+            scopes: row.get("scopes")?,
+            token_type: row.get("type")?,
+        })
+    }
+
+    /// Returns `token` of `instance`, seen by a scan finished `at`
+    pub fn new(instance: &str, token: &Token, at: DateTime<Utc>) -> Self {
+        Self {
+            active: token.is_active(),
+            expires_at: token.expires_at(),
+            first_seen: at,
+            id: i64::try_from(token.id()).unwrap_or(i64::MAX),
+            instance: instance.to_owned(),
+            last_seen: at,
+            name: token.name().to_owned(),
+            path: token.full_path().to_owned(),
+            revoked: token.is_revoked(),
+            scopes: token.scope_names().join(","),
+            token_type: token.type_name().to_owned(),
+        }
+    }
+}
+
+/// Handles `/history` requests, answering `404 Not Found` if `HISTORY_DATABASE` isn't set
+pub async fn history_handler(Query(query): Query<HistoryQuery>) -> Response {
+    let Some(path) = CONFIG.load().history_database.clone() else {
+        return (
+            StatusCode::NOT_FOUND,
+            "the history database is not enabled (HISTORY_DATABASE)".to_owned(),
+        )
+            .into_response();
+    };
+    match task::spawn_blocking(move || select(&path, &query)).await {
+        Ok(Ok(tokens)) => json_response(StatusCode::OK, &tokens),
+        Ok(Err(err)) => {
+            error!("{err:?}");
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")).into_response()
+        }
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+/// Opens the database at `path`, creating it and its tables if needed
+fn open(path: &Path) -> Result<Connection, anyhow::Error> {
+    let connection = Connection::open(path)
+        .with_context(|| format!("failed to open history database {}", path.display()))?;
+    connection.busy_timeout(BUSY_TIMEOUT)?;
+    connection
+        .execute_batch(SCHEMA)
+        .with_context(|| format!("failed to create the tables of {}", path.display()))?;
+    Ok(connection)
+}
+
+/// Saves the `tokens` seen by a scan finished `at` to the database at `path`, and deletes the
+/// tokens which haven't been seen for `retention_days`
+fn save(
+    path: &Path,
+    tokens: &[HistoryToken],
+    at: DateTime<Utc>,
+    retention_days: u16,
+) -> Result<(), anyhow::Error> {
+    let mut connection = open(path)?;
+    let transaction = connection.transaction()?;
+    {
+        // The scans may be saved out of order: the first and last times are only moved outwards
+        let mut upsert = transaction.prepare(
+            "INSERT INTO tokens (instance, type, id, name, path, scopes, expires_at, active, revoked, first_seen, last_seen)
+             VALUES (:instance, :type, :id, :name, :path, :scopes, :expires_at, :active, :revoked, :first_seen, :last_seen)
+             ON CONFLICT (instance, type, id) DO UPDATE SET
+                 name = excluded.name,
+                 path = excluded.path,
+                 scopes = excluded.scopes,
+                 expires_at = excluded.expires_at,
+                 active = excluded.active,
+                 revoked = excluded.revoked,
+                 first_seen = min(first_seen, excluded.first_seen),
+                 last_seen = max(last_seen, excluded.last_seen)",
+        )?;
+        for token in tokens {
+            upsert.execute(named_params! {
+                ":instance": token.instance,
+                ":type": token.token_type,
+                ":id": token.id,
+                ":name": token.name,
+                ":path": token.path,
+                ":scopes": token.scopes,
+                ":expires_at": token.expires_at,
+                ":active": token.active,
+                ":revoked": token.revoked,
+                ":first_seen": token.first_seen,
+                ":last_seen": token.last_seen,
+            })?;
+        }
+    }
+    let deleted = transaction.execute(
+        "DELETE FROM tokens WHERE last_seen < ?1",
+        [
+            at.checked_sub_signed(TimeDelta::days(i64::from(retention_days)))
+                .unwrap_or(DateTime::<Utc>::MIN_UTC),
+        ],
+    )?;
+    transaction.commit()?;
+    debug!(
+        "{} tokens saved to the history database, {deleted} expired tokens deleted",
+        tokens.len()
+    );
+    Ok(())
+}
+
+/// Saves the `tokens` seen by a scan finished `at` to the history database, if
+/// `HISTORY_DATABASE` is set. The database is written in the background
+pub fn save_scan(tokens: Vec<HistoryToken>, at: DateTime<Utc>) {
+    let config = CONFIG.load();
+    let Some(path) = config.history_database.clone() else {
+        return;
+    };
+    let retention_days = config.history_retention_days;
+    drop(config);
+    task::spawn_blocking(move || {
+        if let Err(err) = save(&path, &tokens, at, retention_days) {
+            error!("failed to save the history of the tokens: {err:?}");
+        }
+    });
+}
+
+/// Returns the tokens of the database at `path` matching `query`, by instance, path, type and id.
+/// The database is created by the first scan: until then, there is no token
+fn select(path: &Path, query: &HistoryQuery) -> Result<Vec<HistoryToken>, anyhow::Error> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("failed to open history database {}", path.display()))?;
+    connection.busy_timeout(BUSY_TIMEOUT)?;
+    let mut statement = connection.prepare(
+        "SELECT * FROM tokens
+         WHERE (:instance IS NULL OR instance = :instance)
+           AND (:type IS NULL OR type = :type)
+           AND (:path IS NULL OR path = :path OR substr(path, 1, length(:path) + 1) = :path || '/')
+           AND (:from IS NULL OR last_seen >= :from)
+           AND (:to IS NULL OR first_seen < :to)
+         ORDER BY instance, path, type, id",
+    )?;
+    let start_of_day = |date: NaiveDate| date.and_time(NaiveTime::MIN).and_utc();
+    let tokens = statement
+        .query_map(
+            named_params! {
+                ":instance": query.instance,
+                ":type": query.token_type.map(ResourceType::name),
+                ":path": query.path.as_deref().map(|full_path| full_path.trim_matches('/')),
+                ":from": query.from.map(start_of_day),
+                // The tokens seen during the day `to` are included
+                ":to": query.to.and_then(|date| date.succ_opt()).map(start_of_day),
+            },
+            HistoryToken::from_row,
+        )?
+        .collect::<Result<Vec<_>, _>>()
+        .context("failed to read the history database")?;
+    Ok(tokens)
+}
+
+//-------------------------------------------
+//
+// Unit tests
+//
+// ------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone as _, Utc};
+    use std::{env, fs, process};
+
+    use crate::gitlab::token::{AccessLevel, AccessToken, Token};
+    use crate::history::{HistoryQuery, HistoryToken, save, select};
+    use crate::inventory::ResourceType;
+
+    fn project_token(id: usize, full_path: &str) -> Token {
+        Token::Project {
+            token: AccessToken {
+                access_level: AccessLevel::Maintainer,
+                active: true,
+                expires_at: None,
+                id,
+                name: "deploy".to_owned(),
+                revoked: false,
+                scopes: Vec::new(),
+            },
+            full_path: full_path.to_owned(),
+            web_url: format!("https://gitlab.example.com/{full_path}"),
+        }
+    }
+
+    #[test]
+    fn history_is_saved_and_queried() {
+        let dir = env::temp_dir().join(format!("gitlab-tokens-exporter-history-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.db");
+        // The database doesn't exist before the first scan
+        assert!(select(&path, &HistoryQuery::default()).unwrap().is_empty());
+        assert!(!path.exists());
+        let march = Utc.with_ymd_and_hms(2026, 3, 10, 14, 30, 0).unwrap();
+        let april = Utc.with_ymd_and_hms(2026, 4, 10, 14, 30, 0).unwrap();
+
+        let app = project_token(1, "business/app");
+        let other = project_token(2, "business_unit/other");
+        save(
+            &path,
+            &[
+                HistoryToken::new("gitlab.example.com", &app, march),
+                HistoryToken::new("gitlab.example.com", &other, march),
+            ],
+            march,
+            365,
+        )
+        .unwrap();
+        // The token 2 was deleted from GitLab in April
+        save(
+            &path,
+            &[HistoryToken::new("gitlab.example.com", &app, april)],
+            april,
+            365,
+        )
+        .unwrap();
+
+        let tokens = select(&path, &HistoryQuery::default()).unwrap();
+        assert_eq!(tokens.len(), 2);
+        assert_eq!((tokens[0].first_seen, tokens[0].last_seen), (march, april));
+
+        let in_april = HistoryQuery {
+            from: "2026-04-01".parse().ok(),
+            to: "2026-04-30".parse().ok(),
+            ..HistoryQuery::default()
+        };
+        let tokens = select(&path, &in_april).unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].path, "business/app");
+
+        let in_business = HistoryQuery {
+            path: Some("business".to_owned()),
+            token_type: Some(ResourceType::Project),
+            ..HistoryQuery::default()
+        };
+        let tokens = select(&path, &in_business).unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].id, 1);
+
+        // The token 2 hasn't been seen for more than 30 days
+        save(&path, &[], april, 30).unwrap();
+        assert_eq!(select(&path, &HistoryQuery::default()).unwrap().len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,790 @@
+//! Tokens found by the scans, kept by the [state actor](crate::state_actor)
+//!
+//! A scan either rescans some resource types (projects, groups or users) of every instance, or
+//! refreshes single resources (cf [`ScanScope`]). Its results replace the matching tokens of the
+//! [`Inventory`], the other tokens are kept, and the metrics are rendered from the whole inventory.
+//!
+//! The tokens replaced by a scan are compared with the scanned ones, to find the
+//! [token change events](crate::events).
+//!
+//! If `INVENTORY_FILE` is set, the inventory is saved as JSON after each successful scan, and
+//! loaded at startup: its tokens are served until the first full scan, flagged as stale.
+
+use anyhow::{Context as _, anyhow};
+use chrono::{DateTime, Utc};
+use core::{fmt, fmt::Write as _, str::FromStr};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+};
+
+use crate::config::{Config, Instance};
+use crate::events::{self, TokenEvent};
+use crate::gitlab::graphql::ResourceKind;
+use crate::gitlab::token::Token;
+use crate::prometheus_metrics::{self, Origin};
+use crate::state_actor::{InstanceScan, ScannedToken};
+use crate::textfile;
+
+/// Tokens of an instance, by type and id
+type InstanceTokens = BTreeMap<(&'static str, usize), ScannedToken>;
+
+/// Tokens found on an instance
+#[derive(Default, Deserialize, Serialize)]
+struct InstanceInventory {
+    /// Whether the last scan of a resource type of the instance succeeded
+    scan_success: bool,
+    /// Resource types whose tokens have been fully scanned: the next scans of these types are
+    /// compared with them, the first one is the baseline
+    #[serde(default)]
+    scanned_types: BTreeSet<ResourceType>,
+    /// Tokens by type and id, saved as a list
+    #[serde(
+        deserialize_with = "deserialize_tokens",
+        serialize_with = "serialize_tokens"
+    )]
+    tokens: InstanceTokens,
+}
+
+/// Tokens found by the scans, by instance name
+#[derive(Default, Deserialize, Serialize)]
+pub struct Inventory {
+    /// Tokens of each instance
+    instances: BTreeMap<String, InstanceInventory>,
+    /// Resource types rescanned successfully since the inventory was loaded, by one or several
+    /// scans, by instance name
+    #[serde(skip)]
+    rescanned_types: BTreeMap<String, BTreeSet<ResourceType>>,
+    /// End of the last successful scan
+    scanned_at: Option<DateTime<Utc>>,
+    /// Whether the tokens were loaded from the file saved by a previous run, and haven't been
+    /// rescanned yet
+    #[serde(skip)]
+    stale: bool,
+}
+
+/// Identifies a project, a group or a user
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum ResourceId {
+    /// Numeric id
+    Id(usize),
+    /// Full path of a project or group, or username of a user
+    Path(String),
+}
+
+/// A single project, group or user of an instance, to refresh
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct ResourceRef {
+    /// Id or path of the resource
+    pub id: ResourceId,
+    /// Name of the instance
+    pub instance: String,
+    /// Type of the resource
+    pub resource_type: ResourceType,
+}
+
+/// Types of the resources owning tokens
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResourceType {
+    /// Groups, owning group access tokens
+    Group,
+    /// Projects, owning project access tokens
+    Project,
+    /// Users, owning personal access tokens
+    User,
+}
+
+/// What a scan refreshes
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ScanScope {
+    /// Resource types rescanned on a single instance, in addition to `types`, by instance name
+    pub instances: BTreeMap<String, BTreeSet<ResourceType>>,
+    /// Single resources refreshed, whose type isn't in `types`
+    pub resources: BTreeSet<ResourceRef>,
+    /// Resource types rescanned on every instance
+    pub types: BTreeSet<ResourceType>,
+}
+
+impl InstanceInventory {
+    /// Returns the changes between the tokens for which `compared` returns `true` and the
+    /// matching `scanned_tokens` of `instance`, found by a scan finished `at`
+    fn diff<F>(
+        &self,
+        instance: &str,
+        at: DateTime<Utc>,
+        scanned_tokens: &[ScannedToken],
+        compared: F,
+    ) -> Vec<TokenEvent>
+    where
+        F: Fn(&Token) -> bool,
+    {
+        let previous: Vec<&Token> = self
+            .tokens
+            .values()
+            .map(|scanned_token| &scanned_token.token)
+            .filter(|token| compared(token))
+            .collect();
+        let current: Vec<&Token> = scanned_tokens
+            .iter()
+            .map(|scanned_token| &scanned_token.token)
+            .filter(|token| compared(token))
+            .collect();
+        events::diff(instance, at, &previous, &current)
+    }
+
+    /// Adds `scanned_tokens`, replacing the tokens with the same type and id
+    fn insert(&mut self, scanned_tokens: Vec<ScannedToken>) {
+        for scanned_token in scanned_tokens {
+            self.tokens.insert(
+                (scanned_token.token.type_name(), scanned_token.token.id()),
+                scanned_token,
+            );
+        }
+    }
+
+    /// Removes the tokens of `resource_type` owned by the resource at `full_path`
+    fn remove_resource(&mut self, resource_type: ResourceType, full_path: &str) {
+        self.tokens.retain(|_, scanned_token| {
+            ResourceType::of(&scanned_token.token) != resource_type
+                || scanned_token.token.full_path() != full_path
+        });
+    }
+}
+
+impl Inventory {
+    /// Returns `true` if the tokens were loaded from the file saved by a previous run, and
+    /// haven't been rescanned yet
+    pub const fn is_stale(&self) -> bool {
+        self.stale
+    }
+
+    /// Loads the inventory saved to `path` by a previous run. It is [stale](Self::is_stale) until
+    /// all the resource types are rescanned
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read inventory file {}", path.display()))?;
+        let mut inventory: Self = serde_json::from_str(&content)
+            .with_context(|| format!("failed to parse inventory file {}", path.display()))?;
+        inventory.stale = true;
+        Ok(inventory)
+    }
+
+    /// Merges the scan of the resource types of an instance (cf [`InstanceScan::types`]), finished
+    /// `at`, and returns the token change events. If it succeeded, its tokens replace the tokens of these types. If it failed, the
+    /// instance is marked as failed, and the tokens of these types are removed so that they are
+    /// missing from the metrics, unless the inventory is stale: the tokens loaded from the
+    /// inventory file are kept until a scan succeeds
+    pub fn merge_instance_scan(
+        &mut self,
+        instance_scan: InstanceScan,
+        at: DateTime<Utc>,
+    ) -> Vec<TokenEvent> {
+        let stale = self.stale;
+        let types = &instance_scan.types;
+        let instance_name = &instance_scan.instance.name;
+        let instance_inventory = self.instances.entry(instance_name.clone()).or_default();
+        let is_replaced = |token: &Token| types.contains(&ResourceType::of(token));
+        let Ok(scanned_tokens) = instance_scan.result else {
+            if !stale {
+                // The next successful scan of these types is a new baseline
+                instance_inventory
+                    .tokens
+                    .retain(|_, scanned_token| !is_replaced(&scanned_token.token));
+                instance_inventory
+                    .scanned_types
+                    .retain(|resource_type| !types.contains(resource_type));
+            }
+            instance_inventory.scan_success = false;
+            return Vec::new();
+        };
+        let token_events = instance_inventory.diff(instance_name, at, &scanned_tokens, |token| {
+            is_replaced(token)
+                && instance_inventory
+                    .scanned_types
+                    .contains(&ResourceType::of(token))
+        });
+        instance_inventory
+            .tokens
+            .retain(|_, scanned_token| !is_replaced(&scanned_token.token));
+        instance_inventory.insert(scanned_tokens);
+        instance_inventory.scan_success = true;
+        instance_inventory.scanned_types.extend(types);
+        token_events
+    }
+
+    /// Merges the refresh of a single resource of `instance`, finished `at`, and returns the token
+    /// change events: `scanned_tokens` replace the tokens of `resource_type` owned by the resource
+    /// at `full_path`
+    pub fn merge_resource(
+        &mut self,
+        instance: &str,
+        resource_type: ResourceType,
+        full_path: &str,
+        scanned_tokens: Vec<ScannedToken>,
+        at: DateTime<Utc>,
+    ) -> Vec<TokenEvent> {
+        let instance_inventory = self.instances.entry(instance.to_owned()).or_default();
+        let token_events = if instance_inventory.scanned_types.contains(&resource_type) {
+            instance_inventory.diff(instance, at, &scanned_tokens, |token| {
+                ResourceType::of(token) == resource_type && token.full_path() == full_path
+            })
+        } else {
+            Vec::new()
+        };
+        instance_inventory.remove_resource(resource_type, full_path);
+        instance_inventory.insert(scanned_tokens);
+        token_events
+    }
+
+    /// Records the end of a scan at `scanned_at`, which rescanned successfully the resource types
+    /// of `rescanned_types`, by instance name. The inventory is no longer stale once every
+    /// resource type of each instance of `instance_names` (the configured ones) has been
+    /// rescanned successfully, by one or several scans: the tokens of an instance which failed
+    /// are still the ones loaded from the inventory file
+    pub fn record_scan(
+        &mut self,
+        rescanned_types: BTreeMap<String, BTreeSet<ResourceType>>,
+        instance_names: &[&str],
+        scanned_at: DateTime<Utc>,
+    ) {
+        self.scanned_at = Some(scanned_at);
+        for (instance_name, types) in rescanned_types {
+            self.rescanned_types
+                .entry(instance_name)
+                .or_default()
+                .extend(types);
+        }
+        let fully_rescanned = instance_names.iter().all(|instance_name| {
+            self.rescanned_types
+                .get(*instance_name)
+                .is_some_and(|types| types.len() == ResourceType::ALL.len())
+        });
+        if fully_rescanned {
+            self.stale = false;
+        }
+    }
+
+    /// Renders the metrics of the instances of `config`, in the order of the configuration.
+    /// The instances which are no longer configured are forgotten
+    pub fn render(&mut self, config: &Config) -> Result<String, anyhow::Error> {
+        self.instances.retain(|name, _| {
+            config
+                .instances
+                .iter()
+                .any(|instance| &instance.name == name)
+        });
+
+        let mut res = String::from(
+            "# HELP gitlab_token_days_remaining Days before Gitlab token expires\n# TYPE gitlab_token_days_remaining gauge\n",
+        );
+        let mut scan_success = String::from(
+            "# HELP gitlab_tokens_exporter_instance_scan_success Whether the last scan of the GitLab instance succeeded\n# TYPE gitlab_tokens_exporter_instance_scan_success gauge\n",
+        );
+        for instance in &config.instances {
+            let Some(instance_inventory) = self.instances.get(&instance.name) else {
+                continue;
+            };
+            render_instance(instance, &instance_inventory.tokens, &mut res)?;
+            writeln!(
+                scan_success,
+                "gitlab_tokens_exporter_instance_scan_success{{instance=\"{}\"}} {}",
+                instance.name,
+                u8::from(instance_inventory.scan_success)
+            )
+            .context("failed to write scan success metric")?;
+        }
+        res.push_str(&scan_success);
+
+        write!(
+            res,
+            "# HELP gitlab_tokens_exporter_inventory_stale Whether the tokens were loaded from the inventory file and haven't been rescanned yet\n\
+             # TYPE gitlab_tokens_exporter_inventory_stale gauge\n\
+             gitlab_tokens_exporter_inventory_stale {}\n",
+            u8::from(self.stale)
+        )
+        .context("failed to write inventory stale metric")?;
+        if let Some(scanned_at) = self.scanned_at {
+            write!(
+                res,
+                "# HELP gitlab_tokens_exporter_last_successful_scan_timestamp_seconds End of the last successful scan\n\
+                 # TYPE gitlab_tokens_exporter_last_successful_scan_timestamp_seconds gauge\n\
+                 gitlab_tokens_exporter_last_successful_scan_timestamp_seconds {}\n",
+                scanned_at.timestamp()
+            )
+            .context("failed to write last successful scan metric")?;
+        }
+        Ok(res)
+    }
+
+    /// Saves the inventory to `path`, replacing the file atomically
+    pub fn save(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let content = serde_json::to_string(self).context("failed to serialize the inventory")?;
+        textfile::write_atomically(path, &content)
+    }
+
+    /// End of the last successful scan, possibly by a previous run
+    pub const fn scanned_at(&self) -> Option<DateTime<Utc>> {
+        self.scanned_at
+    }
+}
+
+impl fmt::Display for ResourceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Id(id) => write!(f, "{id}"),
+            Self::Path(path) => write!(f, "{path}"),
+        }
+    }
+}
+
+impl FromStr for ResourceId {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let trimmed = value.trim().trim_matches('/');
+        if trimmed.is_empty() {
+            return Err(anyhow!("expected an id or a path"));
+        }
+        Ok(trimmed
+            .parse()
+            .map_or_else(|_| Self::Path(trimmed.to_owned()), Self::Id))
+    }
+}
+
+impl fmt::Display for ResourceRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} of instance {}",
+            self.resource_type.name(),
+            self.id,
+            self.instance
+        )
+    }
+}
+
+impl From<ResourceKind> for ResourceType {
+    fn from(kind: ResourceKind) -> Self {
+        match kind {
+            ResourceKind::Group => Self::Group,
+            ResourceKind::Project => Self::Project,
+        }
+    }
+}
+
+impl ResourceType {
+    /// All the resource types
+    pub const ALL: [Self; 3] = [Self::Project, Self::Group, Self::User];
+
+    /// Name of the type, as used by [`Token::type_name`]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Group => "group",
+            Self::Project => "project",
+            Self::User => "user",
+        }
+    }
+
+    /// Returns the type of the resource owning `token`
+    pub const fn of(token: &Token) -> Self {
+        match *token {
+            Token::Group { .. } => Self::Group,
+            Token::Project { .. } => Self::Project,
+            Token::User { .. } => Self::User,
+        }
+    }
+}
+
+impl ScanScope {
+    /// Returns the scope of a full scan
+    pub fn all() -> Self {
+        Self::types(ResourceType::ALL)
+    }
+
+    /// Returns the resource types rescanned on the instance `instance_name`
+    pub fn instance_types(&self, instance_name: &str) -> BTreeSet<ResourceType> {
+        let mut types = self.types.clone();
+        if let Some(instance_types) = self.instances.get(instance_name) {
+            types.extend(instance_types);
+        }
+        types
+    }
+
+    /// Adds `other` to this scope. The single resources whose type is rescanned are dropped
+    pub fn merge(&mut self, other: Self) {
+        self.types.extend(other.types);
+        for (instance_name, instance_types) in other.instances {
+            self.instances
+                .entry(instance_name)
+                .or_default()
+                .extend(instance_types);
+        }
+        self.resources.extend(other.resources);
+        let types = &self.types;
+        self.instances.retain(|_, instance_types| {
+            instance_types.retain(|resource_type| !types.contains(resource_type));
+            !instance_types.is_empty()
+        });
+        let instances = &self.instances;
+        self.resources.retain(|resource| {
+            !types.contains(&resource.resource_type)
+                && instances
+                    .get(&resource.instance)
+                    .is_none_or(|instance_types| !instance_types.contains(&resource.resource_type))
+        });
+    }
+
+    /// Returns the scope of a single resource refresh
+    pub fn resource(resource: ResourceRef) -> Self {
+        Self {
+            instances: BTreeMap::new(),
+            resources: BTreeSet::from([resource]),
+            types: BTreeSet::new(),
+        }
+    }
+
+    /// Returns the scope of a scan of `types`
+    pub fn types<I>(types: I) -> Self
+    where
+        I: IntoIterator<Item = ResourceType>,
+    {
+        Self {
+            instances: BTreeMap::new(),
+            resources: BTreeSet::new(),
+            types: types.into_iter().collect(),
+        }
+    }
+}
+
+impl fmt::Display for ScanScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let items: Vec<String> = self
+            .types
+            .iter()
+            .map(|resource_type| format!("{}s", resource_type.name()))
+            .chain(
+                self.instances
+                    .iter()
+                    .flat_map(|(instance_name, instance_types)| {
+                        instance_types.iter().map(move |resource_type| {
+                            format!("{}s of instance {instance_name}", resource_type.name())
+                        })
+                    }),
+            )
+            .chain(self.resources.iter().map(ToString::to_string))
+            .collect();
+        if items.is_empty() {
+            // Sent after a reload which only changed the rendering of the metrics
+            write!(f, "nothing")
+        } else {
+            write!(f, "{}", items.join(", "))
+        }
+    }
+}
+
+/// Deserializes the list of tokens saved by [`serialize_tokens`]
+fn deserialize_tokens<'de, D>(deserializer: D) -> Result<InstanceTokens, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Vec::<ScannedToken>::deserialize(deserializer)?
+        .into_iter()
+        .map(|scanned_token| {
+            (
+                (scanned_token.token.type_name(), scanned_token.token.id()),
+                scanned_token,
+            )
+        })
+        .collect())
+}
+
+/// Converts the tokens of `instance` to prometheus metrics, written to `res`
+///
+/// The `credentials` label lists all the credentials which saw the token
+fn render_instance(
+    instance: &Instance,
+    tokens: &InstanceTokens,
+    res: &mut String,
+) -> Result<(), anyhow::Error> {
+    for scanned_token in tokens.values() {
+        let credentials = scanned_token
+            .credentials
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(",");
+        let origin = Origin {
+            credentials: &credentials,
+            instance: &instance.name,
+            labels: &instance.labels,
+        };
+        let token_metric_str = prometheus_metrics::build(&scanned_token.token, &origin)
+            .with_context(|| {
+                format!(
+                    "failed to build prometheus metric for token={:?}",
+                    scanned_token.token
+                )
+            })?;
+        res.push_str(&token_metric_str);
+    }
+    Ok(())
+}
+
+/// Serializes `tokens` as a list, their type and id being in the tokens themselves
+fn serialize_tokens<S>(tokens: &InstanceTokens, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_seq(tokens.values())
+}
+
+//-------------------------------------------
+//
+// Unit tests
+//
+// ------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone as _, Utc};
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        env, fs, process,
+    };
+
+    use crate::gitlab::token::{AccessLevel, AccessToken, Token};
+    use crate::inventory::{Inventory, ResourceId, ResourceRef, ResourceType, ScanScope};
+    use crate::state_actor::ScannedToken;
+
+    /// Names of the configured instances
+    const INSTANCES: &[&str] = &["gitlab.example.com"];
+
+    /// Returns every resource type of each of the `instances`, as rescanned by a full scan
+    fn all_types(instances: &[&str]) -> BTreeMap<String, BTreeSet<ResourceType>> {
+        instances
+            .iter()
+            .map(|instance| ((*instance).to_owned(), BTreeSet::from(ResourceType::ALL)))
+            .collect()
+    }
+
+    /// Returns the users of `instance`, as rescanned by a scan of the users
+    fn users_of(instance: &str) -> BTreeMap<String, BTreeSet<ResourceType>> {
+        BTreeMap::from([(instance.to_owned(), BTreeSet::from([ResourceType::User]))])
+    }
+
+    fn project_token(id: usize, full_path: &str) -> ScannedToken {
+        ScannedToken {
+            credentials: BTreeSet::from(["default".to_owned()]),
+            token: Token::Project {
+                token: AccessToken {
+                    access_level: AccessLevel::Maintainer,
+                    active: true,
+                    expires_at: None,
+                    id,
+                    name: "deploy".to_owned(),
+                    revoked: false,
+                    scopes: Vec::new(),
+                },
+                full_path: full_path.to_owned(),
+                web_url: format!("https://gitlab.example.com/{full_path}"),
+            },
+        }
+    }
+
+    #[test]
+    fn resources_are_merged() {
+        let at = Utc.with_ymd_and_hms(2026, 3, 10, 14, 30, 0).unwrap();
+        let mut inventory = Inventory::default();
+        // The projects haven't been scanned yet: no event
+        assert!(
+            inventory
+                .merge_resource(
+                    "gitlab.example.com",
+                    ResourceType::Project,
+                    "business/app",
+                    vec![
+                        project_token(1, "business/app"),
+                        project_token(2, "business/app"),
+                    ],
+                    at,
+                )
+                .is_empty()
+        );
+        inventory
+            .instances
+            .get_mut("gitlab.example.com")
+            .unwrap()
+            .scanned_types
+            .insert(ResourceType::Project);
+        let token_events = inventory.merge_resource(
+            "gitlab.example.com",
+            ResourceType::Project,
+            "business/other",
+            vec![project_token(3, "business/other")],
+            at,
+        );
+        assert_eq!(token_events.len(), 1);
+        // The token 2 was deleted: only the tokens of business/app are replaced
+        let token_events = inventory.merge_resource(
+            "gitlab.example.com",
+            ResourceType::Project,
+            "business/app",
+            vec![project_token(1, "business/app")],
+            at,
+        );
+        assert_eq!(
+            serde_json::to_value(&token_events).unwrap()[0]["event"],
+            "removed"
+        );
+
+        let ids: Vec<usize> = inventory.instances["gitlab.example.com"]
+            .tokens
+            .values()
+            .map(|scanned_token| scanned_token.token.id())
+            .collect();
+        assert_eq!(ids, vec![1, 3]);
+    }
+
+    #[test]
+    fn inventory_is_saved_and_loaded() {
+        let dir = env::temp_dir().join(format!(
+            "gitlab-tokens-exporter-inventory-{}",
+            process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("inventory.json");
+
+        let mut inventory = Inventory::default();
+        inventory.merge_resource(
+            "gitlab.example.com",
+            ResourceType::Project,
+            "business/app",
+            vec![project_token(1, "business/app")],
+            Utc::now(),
+        );
+        let scanned_at = Utc.with_ymd_and_hms(2026, 3, 10, 14, 30, 0).unwrap();
+        inventory.record_scan(all_types(&["gitlab.example.com"]), INSTANCES, scanned_at);
+        assert!(!inventory.is_stale());
+        inventory.save(&path).unwrap();
+
+        let loaded = Inventory::load(&path).unwrap();
+        assert!(loaded.is_stale());
+        assert_eq!(loaded.scanned_at(), Some(scanned_at));
+        let token = &loaded.instances["gitlab.example.com"].tokens[&("project", 1)].token;
+        assert_eq!(token.full_path(), "business/app");
+
+        // The inventory is stale until every resource type is rescanned
+        let mut reloaded = loaded;
+        reloaded.record_scan(users_of("gitlab.example.com"), INSTANCES, scanned_at);
+        assert!(reloaded.is_stale());
+        reloaded.record_scan(all_types(&["gitlab.example.com"]), INSTANCES, scanned_at);
+        assert!(!reloaded.is_stale());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stale_until_every_type_is_rescanned() {
+        let stale_inventory = || Inventory {
+            stale: true,
+            ..Inventory::default()
+        };
+        let both = ["gitlab.example.com", "gitlab.com"];
+        let scanned_at = Utc::now();
+
+        // The resource types are rescanned one at a time, e.g. with their own refresh schedules
+        let mut inventory = stale_inventory();
+        for (index, resource_type) in ResourceType::ALL.into_iter().enumerate() {
+            assert!(inventory.is_stale(), "stale after {index} types");
+            let types = BTreeSet::from([resource_type]);
+            inventory.record_scan(
+                BTreeMap::from([
+                    ("gitlab.example.com".to_owned(), types.clone()),
+                    ("gitlab.com".to_owned(), types),
+                ]),
+                &both,
+                scanned_at,
+            );
+        }
+        assert!(!inventory.is_stale());
+
+        // Rescanning the same type again and again isn't enough
+        let mut inventory = stale_inventory();
+        inventory.record_scan(users_of("gitlab.example.com"), INSTANCES, scanned_at);
+        inventory.record_scan(users_of("gitlab.example.com"), INSTANCES, scanned_at);
+        assert!(inventory.is_stale());
+
+        // An instance failed: its tokens are still the ones loaded from the inventory file
+        let mut inventory = stale_inventory();
+        inventory.record_scan(all_types(&["gitlab.example.com"]), &both, scanned_at);
+        assert!(inventory.is_stale());
+        inventory.record_scan(all_types(&["gitlab.com"]), &both, scanned_at);
+        assert!(!inventory.is_stale());
+    }
+
+    #[test]
+    fn scopes_are_merged() {
+        let project = ResourceRef {
+            id: "/business/app/".parse().unwrap(),
+            instance: "gitlab.example.com".to_owned(),
+            resource_type: ResourceType::Project,
+        };
+        assert_eq!(project.id, ResourceId::Path("business/app".to_owned()));
+        assert_eq!("42".parse::<ResourceId>().unwrap(), ResourceId::Id(42));
+        assert_eq!(
+            project.to_string(),
+            "project business/app of instance gitlab.example.com"
+        );
+
+        let mut scope = ScanScope::resource(project.clone());
+        scope.merge(ScanScope::types([ResourceType::User]));
+        assert_eq!(
+            scope.to_string(),
+            "users, project business/app of instance gitlab.example.com"
+        );
+        scope.merge(ScanScope::types([ResourceType::Project]));
+        assert_eq!(
+            scope,
+            ScanScope::types([ResourceType::Project, ResourceType::User])
+        );
+
+        // Resource types rescanned on a single instance, after a reload
+        let mut reload = ScanScope::default();
+        assert_eq!(reload.to_string(), "nothing");
+        reload.instances.insert(
+            "gitlab.example.com".to_owned(),
+            BTreeSet::from([ResourceType::Group, ResourceType::User]),
+        );
+        reload.merge(ScanScope::resource(ResourceRef {
+            resource_type: ResourceType::Group,
+            ..project.clone()
+        }));
+        reload.merge(ScanScope::resource(ResourceRef {
+            instance: "other.example.com".to_owned(),
+            ..project
+        }));
+        assert_eq!(
+            reload.to_string(),
+            "groups of instance gitlab.example.com, users of instance gitlab.example.com, \
+             project business/app of instance other.example.com"
+        );
+        assert_eq!(
+            reload.instance_types("gitlab.example.com"),
+            BTreeSet::from([ResourceType::Group, ResourceType::User])
+        );
+        assert!(reload.instance_types("other.example.com").is_empty());
+
+        reload.merge(ScanScope::types([ResourceType::User]));
+        assert_eq!(
+            reload.instances["gitlab.example.com"],
+            BTreeSet::from([ResourceType::Group])
+        );
+        assert_eq!(
+            reload.instance_types("gitlab.example.com"),
+            BTreeSet::from([ResourceType::Group, ResourceType::User])
+        );
+    }
+}
@@ -1,26 +1,76 @@
 //! Export the number of days before GitLab tokens expire as Prometheus metrics.
 
+mod cli;
 mod config;
+mod config_file;
+mod events;
+mod exporter_metrics;
+mod filter;
 mod gitlab;
+mod health;
+mod history;
+mod inventory;
+mod progress;
 mod prometheus_metrics;
+mod refresh;
+mod reload;
+mod scan;
+mod schedule;
 mod state_actor;
+mod textfile;
 mod timer;
+mod web;
 
-use std::sync::LazyLock;
+use std::{
+    io,
+    process::ExitCode,
+    sync::{Arc, LazyLock},
+};
 
 use anyhow::{Context as _, anyhow};
-use axum::{Router, extract::State, http::StatusCode, routing::get};
+use axum::{
+    Router,
+    extract::State,
+    http::StatusCode,
+    middleware,
+    routing::{get, post},
+};
+use clap::Parser as _;
 use tokio::{
-    net::TcpListener,
     select,
     signal::unix::{SignalKind, signal},
     sync::{mpsc, oneshot},
 };
-use tracing::{info, instrument};
+use tracing::{error, info, instrument};
 use tracing_subscriber::EnvFilter;
 
+use crate::cli::{Cli, Command, ScanArgs, ServeArgs};
+use crate::config::{self as settings, CONFIG, Config};
+use crate::health::Checked;
+use crate::scan::ScanStatus;
 use crate::state_actor::{ActorState, Message, gitlab_tokens_actor};
-use crate::{config::CONFIG, timer::timer_actor};
+use crate::{
+    reload::reload_actor,
+    textfile::textfile_actor,
+    timer::timer_actor,
+    web::{Authentication, BoundListener},
+};
+use std::path::PathBuf;
+
+/// Validates the configuration and prints it, with the tokens redacted (`check-config` subcommand)
+///
+/// Returns an error listing all the invalid settings if the configuration is invalid
+#[expect(
+    clippy::print_stdout,
+    reason = "the effective configuration is the output of check-config"
+)]
+fn check_config() -> Result<(), anyhow::Error> {
+    let config = Config::new()?;
+    let effective_config = toml::to_string_pretty(&config.to_config_file())
+        .context("failed to serialize the effective configuration")?;
+    println!("{effective_config}");
+    Ok(())
+}
 
 /// Handles `/metrics` requests
 async fn get_gitlab_tokens_handler(
@@ -41,7 +91,13 @@ async fn get_gitlab_tokens_handler(
     match recv.await {
         Ok(res) => match res {
             ActorState::Loading | ActorState::NoToken => (StatusCode::NO_CONTENT, String::new()),
-            ActorState::Loaded(state) => (StatusCode::OK, state),
+            ActorState::Loaded(mut state) => match exporter_metrics::render() {
+                Ok(exporter_metrics) => {
+                    state.push_str(&exporter_metrics);
+                    (StatusCode::OK, state)
+                }
+                Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:?}")),
+            },
             ActorState::Error(err) => (StatusCode::INTERNAL_SERVER_ERROR, err),
         },
         Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
@@ -53,6 +109,41 @@ async fn root_handler() -> &'static str {
     "I'm Alive :D"
 }
 
+/// Returns the routes of the HTTP server. With `authentication`, every route but the probes
+/// requires credentials; without it, `POST /refresh` is forbidden
+fn router(
+    sender: mpsc::Sender<Message>,
+    checked: Checked,
+    authentication: Option<Arc<Authentication>>,
+) -> Router {
+    let refresh_route = if authentication.is_some() {
+        post(refresh::refresh_handler)
+    } else {
+        post(refresh::forbidden_handler)
+    };
+    let mut routes = Router::new()
+        .route("/", get(root_handler))
+        .route("/events", get(events::events_handler))
+        .route("/history", get(history::history_handler))
+        .route("/metrics", get(get_gitlab_tokens_handler))
+        .route("/refresh", refresh_route)
+        .route("/status", get(progress::status_handler))
+        .with_state(sender);
+    if let Some(credentials) = authentication {
+        routes = routes.layer(middleware::from_fn_with_state(
+            credentials,
+            web::authenticate,
+        ));
+    }
+    // Added after the authentication layer, so that the probes don't need credentials
+    routes.merge(
+        Router::new()
+            .route("/healthz", get(health::healthz_handler))
+            .route("/readyz", get(health::readyz_handler))
+            .with_state(checked),
+    )
+}
+
 /// This function waits for a 'SIGTERM' signal
 #[expect(clippy::expect_used, reason = "exit if we can't create a listener")]
 async fn shutdown_signal() {
@@ -62,14 +153,13 @@ async fn shutdown_signal() {
     sigterm_stream.recv().await;
 }
 
-#[expect(
-    clippy::integer_division_remainder_used,
-    reason = "because clippy is not happy with the tokio::select macro #1"
-)]
 #[tokio::main(flavor = "current_thread")]
 #[instrument]
-async fn main() -> Result<(), anyhow::Error> {
-    // Configure tracing_subscriber with a custom formatter
+async fn main() -> Result<ExitCode, anyhow::Error> {
+    let cli = Cli::parse();
+
+    // Configure tracing_subscriber with a custom formatter. The logs are written to stderr,
+    // stdout is kept for the output of the `scan` and `check-config` subcommands
     #[expect(clippy::absolute_paths, reason = "only call to this function")]
     tracing_subscriber::fmt()
         .with_env_filter(
@@ -80,46 +170,193 @@ async fn main() -> Result<(), anyhow::Error> {
                 .with_target(false)
                 .compact(),
         )
+        .with_writer(io::stderr)
         .init();
 
+    // The command line settings override the env variables
+    settings::set_overrides(cli.overrides);
+
+    match cli.command {
+        Some(Command::CheckConfig) => check_config().map(|()| ExitCode::SUCCESS),
+        Some(Command::Scan(scan_args)) => Ok(run_scan(&scan_args).await.exit_code()),
+        Some(Command::Serve(serve_args)) => serve(serve_args).await.map(|()| ExitCode::SUCCESS),
+        Some(Command::Textfile(textfile_args)) => write_textfile(textfile_args.path)
+            .await
+            .map(|()| ExitCode::SUCCESS),
+        None => serve(ServeArgs::default())
+            .await
+            .map(|()| ExitCode::SUCCESS),
+    }
+}
+
+/// Scans the instances once (`scan` subcommand)
+///
+/// Any error, including an invalid configuration, is reported as [`ScanStatus::Failed`]
+/// so that it can be told apart from expiring tokens
+async fn run_scan(scan_args: &ScanArgs) -> ScanStatus {
+    // Validated once and stored in CONFIG, which would crash the program with an invalid
+    // configuration. The errors are logged by Config::new()
+    if settings::init().is_err() {
+        return ScanStatus::Failed;
+    }
+
+    scan::run(scan_args.expires_within_days, scan_args.format)
+        .await
+        .unwrap_or_else(|err| {
+            error!("scan failed: {err:?}");
+            ScanStatus::Failed
+        })
+}
+
+/// Serves the metrics, until a `SIGTERM` is received (`serve` subcommand)
+#[expect(
+    clippy::integer_division_remainder_used,
+    reason = "because clippy is not happy with the tokio::select macro #1"
+)]
+async fn serve(serve_args: ServeArgs) -> Result<(), anyhow::Error> {
+    let listen_address = web::listen_address(serve_args.listen)?;
+    let (authentication, acceptor) = web::web_config(serve_args.web_config_file)?.into_parts()?;
+
     // Forces the evaluation of this lazy value. if Config::new() fails, the program will crash
     LazyLock::force(&CONFIG);
 
     // Create a channel and then our main actor, gitlab_tokens_actor()
     let (sender, receiver) = mpsc::channel(8);
-    let gitlab_tokens_actor_handle = tokio::spawn(gitlab_tokens_actor(receiver, sender.clone()));
+    let gitlab_tokens_actor_handle =
+        tokio::spawn(gitlab_tokens_actor(receiver, sender.clone(), None));
 
-    // Create the timer actor
-    let timer_actor_handle = tokio::spawn(timer_actor(sender.clone()));
+    // Create the timer actor, which answers the health checks on `timer_pings`
+    let (timer_pings, timer_pings_receiver) = mpsc::channel(1);
+    let timer_actor_handle = tokio::spawn(timer_actor(sender.clone(), timer_pings_receiver));
 
-    let app = Router::new()
-        .route("/", get(root_handler))
-        .route("/metrics", get(get_gitlab_tokens_handler))
-        .with_state(sender);
-
-    let listener = TcpListener::bind("0.0.0.0:3000")
-        .await
-        .context("failed to bind to port 3000")?;
+    // Create the reload actor
+    let reload_actor_handle = tokio::spawn(reload_actor(sender.clone()));
 
-    let local_addr = listener
-        .local_addr()
-        .context("failed to get local addr from listener")?;
+    let checked = Checked {
+        state_actor: sender.clone(),
+        timer_actor: timer_pings,
+    };
+    let app = router(sender, checked, authentication);
 
-    info!("listening on {local_addr}");
+    let listener = BoundListener::bind(&listen_address).await?;
+    let scheme = if acceptor.is_some() { "https" } else { "http" };
+    info!("listening on {} ({scheme})", listener.local_address()?);
 
     // We are waiting for one of the following :
     // - the state actor to finish/panic
     // - the timer actor to finish/panic
+    // - the reload actor to finish/panic
     // - the axum server to finish/be interrupted by a SIGTERM
     select! {
-        _ = gitlab_tokens_actor_handle => {
-            return Err(anyhow!("the state actor died!"));
-        },
-        _ = timer_actor_handle => {
-            return Err(anyhow!("the timer actor died!"));
-        },
-        _ = axum::serve(listener, app).with_graceful_shutdown(shutdown_signal()) => {
-            return Err(anyhow!("the server received a SIGTERM or died!"));
+        _ = gitlab_tokens_actor_handle => Err(anyhow!("the state actor died!")),
+        _ = timer_actor_handle => Err(anyhow!("the timer actor died!")),
+        _ = reload_actor_handle => Err(anyhow!("the reload actor died!")),
+        _ = listener.serve(app, acceptor, shutdown_signal()) => Err(anyhow!("the server received a SIGTERM or died!")),
+    }
+}
+
+/// Writes the metrics to `path` after each refresh, until a `SIGTERM` is received
+/// (`textfile` subcommand)
+#[expect(
+    clippy::integer_division_remainder_used,
+    reason = "because clippy is not happy with the tokio::select macro #2"
+)]
+async fn write_textfile(path: PathBuf) -> Result<(), anyhow::Error> {
+    textfile::check_path(&path)?;
+
+    // Forces the evaluation of this lazy value. if Config::new() fails, the program will crash
+    LazyLock::force(&CONFIG);
+
+    // Create a channel and then our main actor, gitlab_tokens_actor(), which sends the refreshed
+    // metrics to the textfile actor
+    let (sender, receiver) = mpsc::channel(8);
+    let (refreshed_sender, refreshed_receiver) = mpsc::channel(1);
+    let gitlab_tokens_actor_handle = tokio::spawn(gitlab_tokens_actor(
+        receiver,
+        sender.clone(),
+        Some(refreshed_sender),
+    ));
+
+    // Create the textfile, timer and reload actors
+    let textfile_actor_handle = tokio::spawn(textfile_actor(refreshed_receiver, path.clone()));
+    // Nothing checks the health of the timer actor without the HTTP server
+    let (_timer_pings, timer_pings_receiver) = mpsc::channel(1);
+    let timer_actor_handle = tokio::spawn(timer_actor(sender.clone(), timer_pings_receiver));
+    let reload_actor_handle = tokio::spawn(reload_actor(sender));
+
+    info!("writing the metrics to {}", path.display());
+
+    select! {
+        _ = gitlab_tokens_actor_handle => Err(anyhow!("the state actor died!")),
+        _ = textfile_actor_handle => Err(anyhow!("the textfile actor died!")),
+        _ = timer_actor_handle => Err(anyhow!("the timer actor died!")),
+        _ = reload_actor_handle => Err(anyhow!("the reload actor died!")),
+        () = shutdown_signal() => {
+            info!("received a SIGTERM");
+            Ok(())
         }
     }
 }
+
+//-------------------------------------------
+//
+// Unit tests
+//
+// ------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use std::{env, fs, process, sync::Arc};
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    use crate::health::Checked;
+    use crate::router;
+    use crate::web::{self, Authentication};
+
+    /// Serves the routes, with `authentication`, and returns their base URL
+    async fn serve_router(authentication: Option<Arc<Authentication>>) -> String {
+        let (sender, _receiver) = mpsc::channel(1);
+        let (timer_pings, _timer_pings_receiver) = mpsc::channel(1);
+        let checked = Checked {
+            state_actor: sender.clone(),
+            timer_actor: timer_pings,
+        };
+        let app = router(sender, checked, authentication);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{address}")
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_refreshes_are_rejected() {
+        let client = reqwest::Client::new();
+
+        // Without credentials in the web configuration, nobody can trigger scans
+        let url = serve_router(None).await;
+        let response = client.post(format!("{url}/refresh")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // With credentials, the requests without them are rejected
+        let dir = env::temp_dir().join(format!("gitlab-tokens-exporter-web-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("web.yml");
+        let token_hash = bcrypt::hash("glpat-token", 4).unwrap();
+        fs::write(&path, format!("bearer_tokens:\n  - {token_hash}\n")).unwrap();
+        let (authentication, _) = web::web_config(Some(path)).unwrap().into_parts().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let url = serve_router(authentication).await;
+        let response = client.post(format!("{url}/refresh")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = client
+            .post(format!("{url}/refresh"))
+            .bearer_auth("wrong")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
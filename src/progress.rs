@@ -0,0 +1,315 @@
+//! Progress of the scans, served as JSON on `/status`
+//!
+//! The scan tasks of [`state_actor`](crate::state_actor) report the phase of each resource type
+//! (projects, groups and users), for each instance and credential, and the number of resources
+//! processed.
+
+use axum::{http::StatusCode, response::Response};
+use chrono::{DateTime, SecondsFormat, Utc};
+use core::time::Duration;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, MutexGuard},
+};
+use tokio::time::Instant;
+
+use crate::health::json_response;
+
+/// Progress of the current scan, and outcome of the previous ones
+static PROGRESS: Mutex<Progress> = Mutex::new(Progress::new());
+
+/// Resource type, by instance and credential
+type ResourceKey = (String, String, &'static str);
+
+/// Error of a scan
+#[derive(Clone, Debug, Serialize)]
+struct LastError {
+    /// End of the scan (RFC 3339)
+    at: String,
+    /// Error message
+    message: String,
+}
+
+/// Phase of the scan of a resource type
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// All the tokens have been fetched
+    Done,
+    /// The scan failed
+    Failed,
+    /// Fetching the tokens of the listed resources
+    FetchingTokens,
+    /// Listing the projects, groups or users
+    Listing,
+}
+
+/// Progress of the scans
+struct Progress {
+    /// Start of the current scan, if a scan is running
+    current_scan: Option<(DateTime<Utc>, Instant)>,
+    /// Error of the last scan which had one
+    last_error: Option<LastError>,
+    /// Duration of the last finished scan
+    last_scan_duration: Option<Duration>,
+    /// End of the last successful scan
+    last_successful_scan: Option<DateTime<Utc>>,
+    /// Progress of each resource type of the current (or last) scan
+    resources: BTreeMap<ResourceKey, ResourceProgress>,
+    /// Identifier of the current (or last) scan
+    scan_id: Option<u64>,
+}
+
+/// Progress of the scan of a resource type
+#[derive(Clone, Copy, Debug)]
+struct ResourceProgress {
+    /// Phase of the scan
+    phase: Phase,
+    /// Number of resources whose tokens have been fetched
+    processed: usize,
+    /// Number of listed resources, once known
+    total: Option<usize>,
+}
+
+/// Response of `/status`
+#[derive(Debug, Serialize)]
+struct Status {
+    /// Seconds since the start of the current scan, or duration of the last one
+    elapsed_seconds: Option<f64>,
+    /// Error of the last scan which had one
+    last_error: Option<LastError>,
+    /// End of the last successful scan (RFC 3339)
+    last_successful_scan: Option<String>,
+    /// `scanning` or `idle`
+    phase: &'static str,
+    /// Progress of each resource type
+    resources: Vec<StatusResource>,
+    /// Identifier of the current (or last) scan
+    scan_id: Option<u64>,
+    /// Start of the current scan (RFC 3339)
+    scan_started_at: Option<String>,
+}
+
+/// Progress of a resource type in the response of `/status`
+#[derive(Debug, Serialize)]
+struct StatusResource {
+    /// Credential used
+    credential: String,
+    /// Instance name
+    instance: String,
+    /// Phase of the scan
+    phase: Phase,
+    /// Number of resources whose tokens have been fetched
+    processed: usize,
+    /// `project`, `group` or `user`
+    #[serde(rename = "type")]
+    resource_type: &'static str,
+    /// Number of listed resources, once known
+    total: Option<usize>,
+}
+
+impl Progress {
+    /// Returns the progress before the first scan
+    const fn new() -> Self {
+        Self {
+            current_scan: None,
+            last_error: None,
+            last_scan_duration: None,
+            last_successful_scan: None,
+            resources: BTreeMap::new(),
+            scan_id: None,
+        }
+    }
+
+    /// Updates the progress of a resource type
+    fn update<F>(
+        &mut self,
+        instance: &str,
+        credential: &str,
+        resource_type: &'static str,
+        update: F,
+    ) where
+        F: FnOnce(&mut ResourceProgress),
+    {
+        update(
+            self.resources
+                .entry((instance.to_owned(), credential.to_owned(), resource_type))
+                .or_insert(ResourceProgress {
+                    phase: Phase::Listing,
+                    processed: 0,
+                    total: None,
+                }),
+        );
+    }
+}
+
+/// Records that the tokens of the listed resources are being fetched.
+/// `total` is the number of listed resources, if the backend lists them
+pub fn fetching_tokens(
+    instance: &str,
+    credential: &str,
+    resource_type: &'static str,
+    total: Option<usize>,
+) {
+    lock().update(instance, credential, resource_type, |progress| {
+        progress.phase = Phase::FetchingTokens;
+        progress.total = total;
+    });
+}
+
+/// Records the end of the scan of a resource type
+pub fn finished(instance: &str, credential: &str, resource_type: &'static str, success: bool) {
+    lock().update(instance, credential, resource_type, |progress| {
+        progress.phase = if success { Phase::Done } else { Phase::Failed };
+    });
+}
+
+/// Records that the resources are being listed
+pub fn listing(instance: &str, credential: &str, resource_type: &'static str) {
+    lock().update(instance, credential, resource_type, |progress| {
+        progress.phase = Phase::Listing;
+    });
+}
+
+/// Locks [`PROGRESS`]
+#[expect(
+    clippy::unwrap_used,
+    reason = "crashing on a poisoned mutex is ok in our case"
+)]
+fn lock() -> MutexGuard<'static, Progress> {
+    PROGRESS.lock().unwrap()
+}
+
+/// Records that the tokens of `count` more resources have been fetched
+pub fn processed(instance: &str, credential: &str, resource_type: &'static str, count: usize) {
+    lock().update(instance, credential, resource_type, |progress| {
+        progress.processed = progress.processed.saturating_add(count);
+    });
+}
+
+/// Returns the progress, as served on `/status`
+fn render(now: Instant) -> Status {
+    let progress = lock();
+    let format_date = |date: DateTime<Utc>| date.to_rfc3339_opts(SecondsFormat::Secs, true);
+
+    let status = Status {
+        elapsed_seconds: progress
+            .current_scan
+            .map(|(_, started)| now.saturating_duration_since(started))
+            .or(progress.last_scan_duration)
+            .map(|duration| duration.as_secs_f64()),
+        last_error: progress.last_error.clone(),
+        last_successful_scan: progress.last_successful_scan.map(format_date),
+        phase: if progress.current_scan.is_some() {
+            "scanning"
+        } else {
+            "idle"
+        },
+        resources: progress
+            .resources
+            .iter()
+            .map(
+                |((instance, credential, resource_type), resource_progress)| StatusResource {
+                    credential: credential.clone(),
+                    instance: instance.clone(),
+                    phase: resource_progress.phase,
+                    processed: resource_progress.processed,
+                    resource_type,
+                    total: resource_progress.total,
+                },
+            )
+            .collect(),
+        scan_id: progress.scan_id,
+        scan_started_at: progress.current_scan.map(|(date, _)| format_date(date)),
+    };
+    drop(progress);
+    status
+}
+
+/// Records the end of a scan. `errors` are the errors of the failed instances, the scan
+/// succeeded unless they all failed
+pub fn scan_finished(errors: &[String], success: bool) {
+    let mut progress = lock();
+    let now = Utc::now();
+    if let Some((_, started)) = progress.current_scan.take() {
+        progress.last_scan_duration = Some(started.elapsed());
+    }
+    if success {
+        progress.last_successful_scan = Some(now);
+    }
+    if !errors.is_empty() {
+        progress.last_error = Some(LastError {
+            at: now.to_rfc3339_opts(SecondsFormat::Secs, true),
+            message: errors.join("\n"),
+        });
+    }
+    drop(progress);
+}
+
+/// Records the start of the scan `scan_id`, forgetting the progress of the previous one
+pub fn scan_started(scan_id: u64) {
+    let mut progress = lock();
+    progress.scan_id = Some(scan_id);
+    progress.current_scan = Some((Utc::now(), Instant::now()));
+    progress.resources.clear();
+    drop(progress);
+}
+
+/// Handles `/status` requests
+pub async fn status_handler() -> Response {
+    json_response(StatusCode::OK, &render(Instant::now()))
+}
+
+//-------------------------------------------
+//
+// Unit tests
+//
+// ------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::Instant;
+
+    use crate::progress::{
+        Phase, fetching_tokens, finished, listing, processed, render, scan_finished, scan_started,
+    };
+
+    #[test]
+    fn scan_progress() {
+        scan_started(1);
+        listing("gitlab.example.com", "default", "project");
+        fetching_tokens("gitlab.example.com", "default", "project", Some(3));
+        processed("gitlab.example.com", "default", "project", 1);
+        processed("gitlab.example.com", "default", "project", 1);
+        listing("gitlab.example.com", "default", "user");
+
+        let status = render(Instant::now());
+        assert_eq!(status.phase, "scanning");
+        assert_eq!(status.scan_id, Some(1));
+        assert!(status.scan_started_at.is_some());
+        let project = status
+            .resources
+            .iter()
+            .find(|resource| resource.resource_type == "project")
+            .unwrap();
+        assert_eq!(project.phase, Phase::FetchingTokens);
+        assert_eq!((project.processed, project.total), (2, Some(3)));
+
+        finished("gitlab.example.com", "default", "project", true);
+        finished("gitlab.example.com", "default", "user", false);
+        scan_finished(&["failed to get users".to_owned()], true);
+
+        let status = render(Instant::now());
+        assert_eq!(status.phase, "idle");
+        assert!(status.scan_started_at.is_none());
+        assert!(status.last_successful_scan.is_some());
+        assert_eq!(status.last_error.unwrap().message, "failed to get users");
+        assert!(
+            status
+                .resources
+                .iter()
+                .any(|resource| resource.resource_type == "user" && resource.phase == Phase::Failed)
+        );
+    }
+}
@@ -1,7 +1,8 @@
 //! Generates the prometheus metrics
 
-use anyhow::Context as _;
+use anyhow::{Context as _, anyhow};
 use core::fmt::Write as _; // To be able to use the `write` macro
+use std::collections::BTreeMap;
 use tracing::{info, instrument};
 
 use crate::gitlab::token::Token;
@@ -9,19 +10,43 @@ use crate::gitlab::token::Token;
 /// Default value when a token has no expiration date
 const DEFAULT_TOKEN_VALIDITY_DAYS: u16 = 9999;
 
+/// Labels set by the exporter, which can't be used as additional labels
+const RESERVED_LABELS: [&str; 14] = [
+    "access_level",
+    "active",
+    "credentials",
+    "expires_at",
+    "group",
+    "id",
+    "instance",
+    "name",
+    "project",
+    "revoked",
+    "scopes",
+    "type",
+    "user",
+    "web_url",
+];
+
+/// Where a token has been found
+pub struct Origin<'origin> {
+    /// Comma separated names of the credentials which can see the token
+    pub credentials: &'origin str,
+    /// Name of the gitlab instance
+    pub instance: &'origin str,
+    /// Additional labels configured for the instance
+    pub labels: &'origin BTreeMap<String, String>,
+}
+
 /// Generates prometheus metrics in the expected format.
-/// The metric name is always `gitlab_token_days_remaining` with labels indicating its name, id, type, ...
+/// The metric name is always `gitlab_token_days_remaining` with labels indicating its origin, name, id, type, ...
 #[expect(clippy::arithmetic_side_effects, reason = "not handled by chrono")]
 #[instrument(err, skip_all)]
-pub fn build(gitlab_token: &Token) -> Result<String, anyhow::Error> {
+pub fn build(gitlab_token: &Token, origin: &Origin<'_>) -> Result<String, anyhow::Error> {
     let mut res = String::new();
     let date_now = chrono::Utc::now().date_naive();
 
-    let token_type = match *gitlab_token {
-        Token::Group { .. } => "group",
-        Token::Project { .. } => "project",
-        Token::User { .. } => "user",
-    };
+    let token_type = gitlab_token.type_name();
 
     let token_scopes = gitlab_token
         .scopes()
@@ -64,7 +89,20 @@ pub fn build(gitlab_token: &Token) -> Result<String, anyhow::Error> {
     write!(
         metric_str,
         "gitlab_token_days_remaining\
-         {{name=\"{name}\",\
+         {{instance=\"{}\",\
+         credentials=\"{}\",",
+        origin.instance, origin.credentials
+    )
+    .context("failed to write token origin to metric_str")?;
+
+    for (label, value) in origin.labels {
+        write!(metric_str, "{label}=\"{value}\",")
+            .context("failed to write additional labels to metric_str")?;
+    }
+
+    write!(
+        metric_str,
+        "name=\"{name}\",\
          id=\"{id}\",\
          type=\"{token_type}\",\
          {token_type}=\"{full_path}\",\
@@ -103,6 +141,29 @@ pub fn build(gitlab_token: &Token) -> Result<String, anyhow::Error> {
     Ok(res)
 }
 
+/// Checks that `labels` can be added to the metrics: their names must be valid prometheus label
+/// names which are not set by the exporter, and their values can't contain `"`, `\` or new lines
+pub fn check_labels(labels: &BTreeMap<String, String>) -> Result<(), anyhow::Error> {
+    for (label, value) in labels {
+        let mut chars = label.chars();
+        let is_valid_name = chars
+            .next()
+            .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+            && chars.all(|next| next.is_ascii_alphanumeric() || next == '_')
+            && !label.starts_with("__");
+        if !is_valid_name {
+            return Err(anyhow!("invalid label name '{label}'"));
+        }
+        if RESERVED_LABELS.contains(&label.as_str()) {
+            return Err(anyhow!("label '{label}' is already set by the exporter"));
+        }
+        if value.contains(['"', '\\', '\n']) {
+            return Err(anyhow!("invalid value for label '{label}': '{value}'"));
+        }
+    }
+    Ok(())
+}
+
 //-------------------------------------------
 //
 // Unit tests
@@ -112,6 +173,7 @@ pub fn build(gitlab_token: &Token) -> Result<String, anyhow::Error> {
 #[cfg(test)]
 mod tests {
 
+    use std::collections::BTreeMap;
     use std::sync::LazyLock;
 
     use chrono::{Days, NaiveDate};
@@ -122,7 +184,13 @@ mod tests {
             AccessLevel, AccessToken, AccessTokenScope, PersonalAccessToken,
             PersonalAccessTokenScope, Token,
         },
-        prometheus_metrics::DEFAULT_TOKEN_VALIDITY_DAYS,
+        prometheus_metrics::{DEFAULT_TOKEN_VALIDITY_DAYS, Origin, check_labels},
+    };
+
+    const ORIGIN: Origin<'static> = Origin {
+        credentials: "team-a,team-b",
+        instance: "gitlab",
+        labels: &BTreeMap::new(),
     };
 
     static RE: LazyLock<Regex> = LazyLock::new(|| {
@@ -130,6 +198,8 @@ mod tests {
             r#"^(?x) # use the x flag to enable insigificant whitespace mode
 gitlab_token_days_remaining
 \{
+instance="(?<instance>[^"]+)",
+credentials="(?<credentials>[^"]+)",
 name="(?<name>[^"]+)",
 id="(?<id>[^"]+)",
 type="(?<type>(project|group|user))",
@@ -252,13 +322,15 @@ revoked="(?<revoked>true|false)",
     #[test]
     fn project_token_metric_match_re() {
         let token = default_token!(Token::Project);
-        let metric = crate::prometheus_metrics::build(&token).unwrap();
+        let metric = crate::prometheus_metrics::build(&token, &ORIGIN).unwrap();
         let captures = get_captures!(&metric);
 
         assert!(metric.ends_with('\n'));
 
         let (project_token, full_path, web_url) = destructure_token!(&token, Token::Project);
 
+        assert_eq!(&captures["instance"], "gitlab");
+        assert_eq!(&captures["credentials"], "team-a,team-b");
         assert_eq!(&captures["name"], project_token.name);
         assert_eq!(&captures["id"], project_token.id.to_string());
         assert_eq!(&captures["type"], "project");
@@ -284,13 +356,15 @@ revoked="(?<revoked>true|false)",
     #[test]
     fn group_token_metric_match_re() {
         let token = default_token!(Token::Group);
-        let metric = crate::prometheus_metrics::build(&token).unwrap();
+        let metric = crate::prometheus_metrics::build(&token, &ORIGIN).unwrap();
         let captures = get_captures!(&metric);
 
         assert!(metric.ends_with('\n'));
 
         let (group_token, full_path, web_url) = destructure_token!(&token, Token::Group);
 
+        assert_eq!(&captures["instance"], "gitlab");
+        assert_eq!(&captures["credentials"], "team-a,team-b");
         assert_eq!(&captures["name"], group_token.name);
         assert_eq!(&captures["id"], group_token.id.to_string());
         assert_eq!(&captures["type"], "group");
@@ -317,13 +391,15 @@ revoked="(?<revoked>true|false)",
     #[test]
     fn user_token_metric_match_re() {
         let token = default_token!(Token::User);
-        let metric = crate::prometheus_metrics::build(&token).unwrap();
+        let metric = crate::prometheus_metrics::build(&token, &ORIGIN).unwrap();
         let captures = get_captures!(&metric);
 
         assert!(metric.ends_with('\n'));
 
         let (user_token, full_path) = destructure_token!(&token, Token::User);
 
+        assert_eq!(&captures["instance"], "gitlab");
+        assert_eq!(&captures["credentials"], "team-a,team-b");
         assert_eq!(&captures["name"], user_token.name);
         assert_eq!(&captures["id"], user_token.id.to_string());
         assert_eq!(&captures["type"], "user");
@@ -365,12 +441,15 @@ revoked="(?<revoked>true|false)",
             web_url,
         };
 
-        let metric = crate::prometheus_metrics::build(&token).unwrap();
+        let metric = crate::prometheus_metrics::build(&token, &ORIGIN).unwrap();
         let captures = get_captures!(&metric);
 
         assert!(metric.ends_with('\n'));
 
-        assert_eq!(&captures["days"].parse().unwrap(), DAYS)
+        assert_eq!(
+            &captures["days"].parse::<serde_json::Value>().unwrap(),
+            DAYS
+        )
     }
 
     #[test]
@@ -397,12 +476,15 @@ revoked="(?<revoked>true|false)",
             web_url,
         };
 
-        let metric = crate::prometheus_metrics::build(&token).unwrap();
+        let metric = crate::prometheus_metrics::build(&token, &ORIGIN).unwrap();
         let captures = get_captures!(&metric);
 
         assert!(metric.ends_with('\n'));
 
-        assert_eq!(&captures["days"].parse().unwrap(), -(DAYS as isize))
+        assert_eq!(
+            &captures["days"].parse::<serde_json::Value>().unwrap(),
+            -(DAYS as isize)
+        )
     }
 
     #[test]
@@ -421,7 +503,7 @@ revoked="(?<revoked>true|false)",
             web_url,
         };
 
-        let metric = crate::prometheus_metrics::build(&token).unwrap();
+        let metric = crate::prometheus_metrics::build(&token, &ORIGIN).unwrap();
         let captures = get_captures!(&metric);
 
         assert!(metric.ends_with('\n'));
@@ -448,7 +530,7 @@ revoked="(?<revoked>true|false)",
             full_path,
         };
 
-        let metric = crate::prometheus_metrics::build(&token).unwrap();
+        let metric = crate::prometheus_metrics::build(&token, &ORIGIN).unwrap();
         let captures = get_captures!(&metric);
 
         assert!(metric.ends_with('\n'));
@@ -472,13 +554,13 @@ revoked="(?<revoked>true|false)",
             web_url,
         };
 
-        let metric = crate::prometheus_metrics::build(&token).unwrap();
+        let metric = crate::prometheus_metrics::build(&token, &ORIGIN).unwrap();
         let captures = get_captures!(&metric);
 
         assert!(metric.ends_with('\n'));
 
         assert_eq!(
-            &captures["days"].parse().unwrap(),
+            &captures["days"].parse::<serde_json::Value>().unwrap(),
             DEFAULT_TOKEN_VALIDITY_DAYS
         );
 
@@ -501,13 +583,13 @@ revoked="(?<revoked>true|false)",
             web_url,
         };
 
-        let metric = crate::prometheus_metrics::build(&token).unwrap();
+        let metric = crate::prometheus_metrics::build(&token, &ORIGIN).unwrap();
         let captures = get_captures!(&metric);
 
         assert!(metric.ends_with('\n'));
 
         assert_eq!(
-            &captures["days"].parse().unwrap(),
+            &captures["days"].parse::<serde_json::Value>().unwrap(),
             DEFAULT_TOKEN_VALIDITY_DAYS
         );
 
@@ -529,13 +611,13 @@ revoked="(?<revoked>true|false)",
             full_path,
         };
 
-        let metric = crate::prometheus_metrics::build(&token).unwrap();
+        let metric = crate::prometheus_metrics::build(&token, &ORIGIN).unwrap();
         let captures = get_captures!(&metric);
 
         assert!(metric.ends_with('\n'));
 
         assert_eq!(
-            &captures["days"].parse().unwrap(),
+            &captures["days"].parse::<serde_json::Value>().unwrap(),
             DEFAULT_TOKEN_VALIDITY_DAYS
         );
 
@@ -558,7 +640,7 @@ revoked="(?<revoked>true|false)",
             web_url,
         };
 
-        let metric = crate::prometheus_metrics::build(&token).unwrap();
+        let metric = crate::prometheus_metrics::build(&token, &ORIGIN).unwrap();
         let captures = get_captures!(&metric);
 
         assert!(metric.ends_with('\n'));
@@ -582,7 +664,7 @@ revoked="(?<revoked>true|false)",
             web_url,
         };
 
-        let metric = crate::prometheus_metrics::build(&token).unwrap();
+        let metric = crate::prometheus_metrics::build(&token, &ORIGIN).unwrap();
         let captures = get_captures!(&metric);
 
         assert!(metric.ends_with('\n'));
@@ -605,7 +687,7 @@ revoked="(?<revoked>true|false)",
             full_path,
         };
 
-        let metric = crate::prometheus_metrics::build(&token).unwrap();
+        let metric = crate::prometheus_metrics::build(&token, &ORIGIN).unwrap();
         let captures = get_captures!(&metric);
 
         assert!(metric.ends_with('\n'));
@@ -629,7 +711,7 @@ revoked="(?<revoked>true|false)",
             web_url,
         };
 
-        let metric = crate::prometheus_metrics::build(&token).unwrap();
+        let metric = crate::prometheus_metrics::build(&token, &ORIGIN).unwrap();
         let captures = get_captures!(&metric);
 
         assert!(metric.ends_with('\n'));
@@ -653,7 +735,7 @@ revoked="(?<revoked>true|false)",
             web_url,
         };
 
-        let metric = crate::prometheus_metrics::build(&token).unwrap();
+        let metric = crate::prometheus_metrics::build(&token, &ORIGIN).unwrap();
         let captures = get_captures!(&metric);
 
         assert!(metric.ends_with('\n'));
@@ -676,11 +758,39 @@ revoked="(?<revoked>true|false)",
             full_path,
         };
 
-        let metric = crate::prometheus_metrics::build(&token).unwrap();
+        let metric = crate::prometheus_metrics::build(&token, &ORIGIN).unwrap();
         let captures = get_captures!(&metric);
 
         assert!(metric.ends_with('\n'));
 
         assert_eq!(&captures["expires_at"], "+250000-12-31");
     }
+
+    #[test]
+    fn additional_labels() {
+        let token = default_token!(Token::Project);
+        let labels = BTreeMap::from([("env".to_owned(), "prod".to_owned())]);
+        let origin = Origin {
+            labels: &labels,
+            ..ORIGIN
+        };
+
+        let metric = crate::prometheus_metrics::build(&token, &origin).unwrap();
+
+        assert!(
+            get_first_non_comment_line(&metric)
+                .starts_with(r#"gitlab_token_days_remaining{instance="gitlab",credentials="team-a,team-b",env="prod",name="#)
+        );
+    }
+
+    #[test]
+    fn invalid_labels() {
+        let label = |name: &str, value: &str| BTreeMap::from([(name.to_owned(), value.to_owned())]);
+
+        assert!(check_labels(&label("team_2", "platform")).is_ok());
+        assert!(check_labels(&label("2team", "platform")).is_err());
+        assert!(check_labels(&label("__team", "platform")).is_err());
+        assert!(check_labels(&label("scopes", "api")).is_err());
+        assert!(check_labels(&label("team", "platform\"")).is_err());
+    }
 }
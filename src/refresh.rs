@@ -0,0 +1,223 @@
+//! On-demand refresh (`POST /refresh`)
+//!
+//! The request sends [`Message::Update`] to the [state actor](crate::state_actor), which runs a
+//! single scan at a time: a refresh requested during a scan is queued, and all the refreshes
+//! requested during the same scan are coalesced into the queued one.
+//!
+//! The query parameters narrow the refresh down to a resource type (`type=user`), or to a single
+//! project, group or user (`type=project&resource=business/unit/app`). The tokens of the other
+//! resources are kept from the previous scans (cf [`inventory`](crate::inventory)).
+//!
+//! Without credentials in the web configuration file, anyone reaching the server could trigger
+//! scans (and the requests to the gitlab instances they send): the refreshes are then forbidden.
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse as _, Response},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::config::CONFIG;
+use crate::health::json_response;
+use crate::inventory::{ResourceRef, ResourceType, ScanScope};
+use crate::state_actor::Message;
+
+/// Answer of the state actor to [`Message::Update`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct Refresh {
+    /// Identifier of the scan which will include the changes made before the request
+    pub scan_id: u64,
+    /// Whether the scan started, or will start when the running one finishes
+    pub status: RefreshStatus,
+}
+
+/// Query parameters of `POST /refresh`. Without parameter, everything is rescanned
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RefreshQuery {
+    /// Instance of `resource`, optional if a single instance is configured
+    instance: Option<String>,
+    /// Id or full path of a project or group, or id or username of a user
+    resource: Option<String>,
+    /// Type of `resource`, or type of the resources rescanned on every instance
+    #[serde(rename = "type")]
+    resource_type: Option<ResourceType>,
+}
+
+/// Status of the scan of a [`Refresh`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RefreshStatus {
+    /// A scan is running: the scan will start when it finishes
+    Queued,
+    /// No scan was running: the scan started
+    Started,
+}
+
+impl RefreshQuery {
+    /// Returns the scope of the requested refresh, or why the request is invalid.
+    /// `instance_names` are the names of the configured instances
+    fn scope(self, instance_names: &[&str]) -> Result<ScanScope, String> {
+        let Some(resource) = self.resource else {
+            if self.instance.is_some() {
+                return Err("the instance parameter requires a resource".to_owned());
+            }
+            return Ok(self
+                .resource_type
+                .map_or_else(ScanScope::all, |resource_type| {
+                    ScanScope::types([resource_type])
+                }));
+        };
+        let resource_type = self
+            .resource_type
+            .ok_or_else(|| "the resource parameter requires a type".to_owned())?;
+        let instance = match (self.instance, instance_names) {
+            (Some(name), _) if instance_names.contains(&name.as_str()) => name,
+            (Some(name), _) => return Err(format!("instance '{name}' is not configured")),
+            (None, &[name]) => name.to_owned(),
+            (None, _) => {
+                return Err(
+                    "the instance parameter is required when several instances are configured"
+                        .to_owned(),
+                );
+            }
+        };
+        Ok(ScanScope::resource(ResourceRef {
+            id: resource.parse().map_err(|err| format!("{err}"))?,
+            instance,
+            resource_type,
+        }))
+    }
+}
+
+/// Handles `POST /refresh` requests when no credentials are configured, answering
+/// `403 Forbidden`
+pub async fn forbidden_handler() -> Response {
+    (
+        StatusCode::FORBIDDEN,
+        "on-demand refreshes require basic_auth_users or bearer_tokens in the web configuration file"
+            .to_owned(),
+    )
+        .into_response()
+}
+
+/// Handles `POST /refresh` requests, answering `202 Accepted` with the [`Refresh`] as JSON, or
+/// `400 Bad Request` if the [`RefreshQuery`] is invalid
+pub async fn refresh_handler(
+    State(sender): State<mpsc::Sender<Message>>,
+    Query(query): Query<RefreshQuery>,
+) -> Response {
+    let config = CONFIG.load();
+    let instance_names: Vec<&str> = config
+        .instances
+        .iter()
+        .map(|instance| instance.name.as_str())
+        .collect();
+    let scope = query.scope(&instance_names);
+    drop(instance_names);
+    drop(config);
+    let requested_scope = match scope {
+        Ok(requested_scope) => requested_scope,
+        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    };
+
+    let (respond_to, response) = oneshot::channel();
+    if sender
+        .send(Message::Update {
+            respond_to: Some(respond_to),
+            scope: requested_scope,
+        })
+        .await
+        .is_err()
+    {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "the state actor is stopped".to_owned(),
+        )
+            .into_response();
+    }
+
+    match response.await {
+        Ok(refresh) => json_response(StatusCode::ACCEPTED, &refresh),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+//-------------------------------------------
+//
+// Unit tests
+//
+// ------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::inventory::{ResourceId, ResourceRef, ResourceType, ScanScope};
+    use crate::refresh::RefreshQuery;
+
+    #[test]
+    fn refresh_scopes() {
+        let query = |instance: Option<&str>, resource: Option<&str>, resource_type| RefreshQuery {
+            instance: instance.map(ToOwned::to_owned),
+            resource: resource.map(ToOwned::to_owned),
+            resource_type,
+        };
+        let single = ["gitlab.example.com"];
+        let several = ["gitlab.example.com", "gitlab.com"];
+
+        assert_eq!(
+            RefreshQuery::default().scope(&several).unwrap(),
+            ScanScope::all()
+        );
+        assert_eq!(
+            query(None, None, Some(ResourceType::User))
+                .scope(&several)
+                .unwrap(),
+            ScanScope::types([ResourceType::User])
+        );
+        assert_eq!(
+            query(None, Some("business/unit/app"), Some(ResourceType::Project))
+                .scope(&single)
+                .unwrap(),
+            ScanScope::resource(ResourceRef {
+                id: ResourceId::Path("business/unit/app".to_owned()),
+                instance: "gitlab.example.com".to_owned(),
+                resource_type: ResourceType::Project,
+            })
+        );
+        assert_eq!(
+            query(Some("gitlab.com"), Some("42"), Some(ResourceType::User))
+                .scope(&several)
+                .unwrap(),
+            ScanScope::resource(ResourceRef {
+                id: ResourceId::Id(42),
+                instance: "gitlab.com".to_owned(),
+                resource_type: ResourceType::User,
+            })
+        );
+
+        // The resource needs a type, and an instance if several are configured
+        assert!(query(None, Some("42"), None).scope(&single).is_err());
+        assert!(
+            query(None, Some("42"), Some(ResourceType::Group))
+                .scope(&several)
+                .is_err()
+        );
+        assert!(
+            query(Some("other"), Some("42"), Some(ResourceType::Group))
+                .scope(&several)
+                .is_err()
+        );
+        assert!(
+            query(Some("gitlab.com"), None, None)
+                .scope(&several)
+                .is_err()
+        );
+        assert!(
+            query(None, Some("/"), Some(ResourceType::Group))
+                .scope(&single)
+                .is_err()
+        );
+    }
+}
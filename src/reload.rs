@@ -0,0 +1,80 @@
+//! The purpose of this actor is to [reload](crate::config::reload) the configuration on `SIGHUP`,
+//! or when the configuration file (`CONFIG_FILE` or `--config-file`) changes, and then to send [`Message::Update`]
+//! to [`gitlab_tokens_actor`](crate::state_actor::gitlab_tokens_actor), with the instances and
+//! resource types whose settings changed
+
+use core::time::Duration;
+use std::{fs, path::Path, time::SystemTime};
+use tokio::{
+    select,
+    signal::unix::{SignalKind, signal},
+    sync::mpsc,
+    time,
+};
+use tracing::{debug, error, info, instrument};
+
+use crate::config;
+use crate::state_actor::Message;
+
+/// Interval between two checks of the configuration file modification time
+const CONFIG_FILE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Returns the modification time of the file at `path`, or `None` if it can't be read
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Reloads the configuration when it changes, and sends [`Message::Update`] if the new one is valid
+/// and different. An empty scope only renders the metrics again (for example when labels changed)
+#[expect(
+    clippy::integer_division_remainder_used,
+    reason = "because clippy is not happy with the tokio::select macro"
+)]
+#[instrument(skip_all)]
+pub async fn reload_actor(sender: mpsc::Sender<Message>) {
+    let mut sighup_stream = match signal(SignalKind::hangup()) {
+        Ok(sighup_stream) => sighup_stream,
+        Err(err) => {
+            error!("failed to create a SIGHUP listener: {err}");
+            return;
+        }
+    };
+
+    let config_file = config::config_file_path();
+    let mut last_modified = config_file.as_deref().and_then(modified);
+    let mut poll_timer = time::interval(CONFIG_FILE_POLL_INTERVAL);
+
+    loop {
+        select! {
+            _ = sighup_stream.recv() => info!("received SIGHUP, reloading the configuration"),
+            _ = poll_timer.tick(), if config_file.is_some() => {
+                let current_modified = config_file.as_deref().and_then(modified);
+                if current_modified == last_modified {
+                    continue;
+                }
+                last_modified = current_modified;
+                info!("the configuration file changed, reloading it");
+            }
+        }
+
+        match config::reload() {
+            Ok(None) => {}
+            Ok(Some(scope)) => {
+                debug!("sending Message::Update");
+                if let Err(err) = sender
+                    .send(Message::Update {
+                        respond_to: None,
+                        scope,
+                    })
+                    .await
+                {
+                    error!("{err}");
+                    return;
+                }
+            }
+            Err(err) => error!("keeping the current configuration: {err:?}"),
+        }
+    }
+}
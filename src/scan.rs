@@ -0,0 +1,500 @@
+//! Scans the gitlab instances once and prints the tokens (`scan` subcommand)
+//!
+//! With `--expires-within-days`, only the tokens expiring within this number of days are printed,
+//! and the exit status tells whether there is any (cf [`ScanStatus`]). It is meant to gate CI
+//! pipelines, with the `junit` format reporting each expiring token as a failed test case.
+
+use anyhow::Context as _;
+use chrono::NaiveDate;
+use clap::ValueEnum;
+use core::{fmt::Write as _, iter};
+use serde::Serialize;
+use std::{collections::BTreeMap, process::ExitCode};
+use tracing::error;
+
+use crate::config::CONFIG;
+use crate::inventory::ScanScope;
+use crate::state_actor::{InstanceScan, scan_instances};
+
+/// Columns of the `table` and `csv` formats
+const COLUMNS: [&str; 11] = [
+    "instance",
+    "type",
+    "path",
+    "name",
+    "id",
+    "active",
+    "revoked",
+    "scopes",
+    "expires_at",
+    "days_remaining",
+    "credentials",
+];
+
+/// Format of the tokens printed by the `scan` subcommand
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum OutputFormat {
+    /// Comma separated values, with a header line
+    Csv,
+    /// Array of JSON objects
+    Json,
+    /// `JUnit` XML report, with one test case per token. The expiring tokens are failures
+    Junit,
+    /// Aligned columns, for humans
+    Table,
+}
+
+/// Outcome of the `scan` subcommand, used as its exit status
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanStatus {
+    /// At least one instance couldn't be scanned, or the configuration is invalid (exit status 2)
+    Failed,
+    /// Every instance has been scanned, and no token expires within the threshold (exit status 0)
+    Ok,
+    /// At least one token expires within the threshold (exit status 1)
+    ThresholdViolated,
+}
+
+/// An instance which couldn't be scanned
+#[derive(Debug)]
+struct FailedInstance {
+    /// Why the scan failed
+    error: String,
+    /// Name of the gitlab instance
+    name: String,
+}
+
+/// A token, as printed by the `scan` subcommand
+#[derive(Debug, Serialize)]
+struct TokenRow {
+    /// `false` if the token is inactive
+    active: bool,
+    /// Comma separated names of the credentials which saw the token
+    credentials: String,
+    /// Days before the token expires, `None` if it never expires
+    days_remaining: Option<i64>,
+    /// Expiration date, `None` if the token never expires
+    expires_at: Option<String>,
+    /// Token id
+    id: usize,
+    /// Name of the gitlab instance
+    instance: String,
+    /// Token name
+    name: String,
+    /// Full path of the project or group, or username of the user owning the token
+    path: String,
+    /// `true` if the token has been revoked
+    revoked: bool,
+    /// Comma separated scopes of the token
+    scopes: String,
+    /// Token type (`group`, `project` or `user`)
+    #[serde(rename = "type")]
+    token_type: &'static str,
+}
+
+impl ScanStatus {
+    /// Returns the exit status of the program
+    pub fn exit_code(self) -> ExitCode {
+        match self {
+            Self::Failed => ExitCode::from(2),
+            Self::Ok => ExitCode::SUCCESS,
+            Self::ThresholdViolated => ExitCode::FAILURE,
+        }
+    }
+}
+
+impl TokenRow {
+    /// Returns the values of the row, in the order of [`COLUMNS`]
+    fn cells(&self) -> [String; 11] {
+        [
+            self.instance.clone(),
+            self.token_type.to_owned(),
+            self.path.clone(),
+            self.name.clone(),
+            self.id.to_string(),
+            self.active.to_string(),
+            self.revoked.to_string(),
+            self.scopes.clone(),
+            self.expires_at.clone().unwrap_or_default(),
+            self.days_remaining
+                .map(|days| days.to_string())
+                .unwrap_or_default(),
+            self.credentials.clone(),
+        ]
+    }
+
+    /// Returns `true` if the token is still usable and expires within `days` days
+    fn is_expiring(&self, days: u32) -> bool {
+        self.active
+            && !self.revoked
+            && self
+                .days_remaining
+                .is_some_and(|days_remaining| days_remaining <= i64::from(days))
+    }
+}
+
+/// Quotes `value` if it contains a comma, a double quote or a line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Renders `rows` in the requested `format`
+///
+/// Only the expiring tokens are rendered if `expires_within_days` is set, except in the `junit`
+/// format where the other tokens are passed test cases
+fn render(
+    rows: &[TokenRow],
+    failed_instances: &[FailedInstance],
+    expires_within_days: Option<u32>,
+    format: OutputFormat,
+) -> Result<String, anyhow::Error> {
+    let is_expiring =
+        |row: &TokenRow| expires_within_days.is_some_and(|days| row.is_expiring(days));
+    let printed_rows: Vec<&TokenRow> = rows
+        .iter()
+        .filter(|row| expires_within_days.is_none() || is_expiring(row))
+        .collect();
+
+    match format {
+        OutputFormat::Csv => Ok(render_csv(&printed_rows)),
+        OutputFormat::Json => {
+            let mut json =
+                serde_json::to_string_pretty(&printed_rows).context("failed to render JSON")?;
+            json.push('\n');
+            Ok(json)
+        }
+        OutputFormat::Junit => render_junit(rows, failed_instances, is_expiring),
+        OutputFormat::Table => Ok(render_table(&printed_rows)),
+    }
+}
+
+/// Renders `rows` as CSV, with a header line
+fn render_csv(rows: &[&TokenRow]) -> String {
+    let mut res = COLUMNS.join(",");
+    res.push('\n');
+    for row in rows {
+        res.push_str(&row.cells().map(|cell| csv_field(&cell)).join(","));
+        res.push('\n');
+    }
+    res
+}
+
+/// Renders `rows` as a `JUnit` XML report, with one test suite per instance and one test case
+/// per token. The tokens for which `is_expiring` returns `true` are failures, and each
+/// instance which couldn't be scanned is an error
+fn render_junit<F>(
+    rows: &[TokenRow],
+    failed_instances: &[FailedInstance],
+    is_expiring: F,
+) -> Result<String, anyhow::Error>
+where
+    F: Fn(&TokenRow) -> bool,
+{
+    let mut rows_by_instance: BTreeMap<&str, Vec<&TokenRow>> = BTreeMap::new();
+    for row in rows {
+        rows_by_instance.entry(&row.instance).or_default().push(row);
+    }
+
+    let mut res = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    writeln!(
+        res,
+        "<testsuites name=\"gitlab-tokens-exporter\" tests=\"{}\" failures=\"{}\" errors=\"{}\">",
+        rows.len().saturating_add(failed_instances.len()),
+        rows.iter().filter(|row| is_expiring(row)).count(),
+        failed_instances.len()
+    )
+    .context("failed to write testsuites")?;
+
+    for (instance, instance_rows) in rows_by_instance {
+        writeln!(
+            res,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"0\">",
+            xml_escape(instance),
+            instance_rows.len(),
+            instance_rows.iter().filter(|row| is_expiring(row)).count()
+        )
+        .context("failed to write testsuite")?;
+
+        for row in instance_rows {
+            write!(
+                res,
+                "    <testcase classname=\"{}.{}\" name=\"{} {} (id {})\"",
+                xml_escape(&row.instance),
+                row.token_type,
+                xml_escape(&row.path),
+                xml_escape(&row.name),
+                row.id
+            )
+            .context("failed to write testcase")?;
+
+            if is_expiring(row) {
+                writeln!(
+                    res,
+                    ">\n      <failure type=\"expiring_token\" message=\"expires on {} ({} days remaining)\"/>\n    </testcase>",
+                    row.expires_at.as_deref().unwrap_or_default(),
+                    row.days_remaining.unwrap_or_default()
+                )
+                .context("failed to write failure")?;
+            } else {
+                res.push_str("/>\n");
+            }
+        }
+        res.push_str("  </testsuite>\n");
+    }
+
+    for failed_instance in failed_instances {
+        let name = xml_escape(&failed_instance.name);
+        writeln!(
+            res,
+            "  <testsuite name=\"{name}\" tests=\"1\" failures=\"0\" errors=\"1\">\n    \
+             <testcase classname=\"{name}\" name=\"scan\">\n      \
+             <error type=\"scan_failed\" message=\"{}\"/>\n    </testcase>\n  </testsuite>",
+            xml_escape(&failed_instance.error)
+        )
+        .context("failed to write error")?;
+    }
+
+    res.push_str("</testsuites>\n");
+    Ok(res)
+}
+
+/// Renders `rows` as a table with aligned columns
+fn render_table(rows: &[&TokenRow]) -> String {
+    let lines: Vec<[String; 11]> = iter::once(COLUMNS.map(str::to_uppercase))
+        .chain(rows.iter().map(|row| row.cells()))
+        .collect();
+
+    let mut widths = [0; 11];
+    for line in &lines {
+        for (width, cell) in widths.iter_mut().zip(line) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut res = String::new();
+    for line in &lines {
+        let padded: Vec<String> = line
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        res.push_str(padded.join("  ").trim_end());
+        res.push('\n');
+    }
+    res
+}
+
+/// Converts the tokens found by `instance_scans` into rows, sorted by expiration date
+///
+/// Returns the instances which couldn't be scanned along with the rows
+fn token_rows(
+    instance_scans: Vec<InstanceScan>,
+    today: NaiveDate,
+) -> (Vec<TokenRow>, Vec<FailedInstance>) {
+    let mut rows = Vec::new();
+    let mut failed_instances = Vec::new();
+
+    for instance_scan in instance_scans {
+        let scanned_tokens = match instance_scan.result {
+            Ok(scanned_tokens) => scanned_tokens,
+            Err(err) => {
+                error!(
+                    "failed to scan instance {}: {err:?}",
+                    instance_scan.instance.name
+                );
+                failed_instances.push(FailedInstance {
+                    error: format!("{err:#}"),
+                    name: instance_scan.instance.name.clone(),
+                });
+                continue;
+            }
+        };
+
+        for scanned_token in scanned_tokens {
+            let token = &scanned_token.token;
+            rows.push(TokenRow {
+                active: token.is_active(),
+                credentials: scanned_token
+                    .credentials
+                    .iter()
+                    .map(String::as_str)
+                    .collect::<Vec<_>>()
+                    .join(","),
+                days_remaining: token
+                    .expires_at()
+                    .map(|expires_at| expires_at.signed_duration_since(today).num_days()),
+                expires_at: token.expires_at().map(|expires_at| expires_at.to_string()),
+                id: token.id(),
+                instance: instance_scan.instance.name.clone(),
+                name: token.name().to_owned(),
+                path: token.full_path().to_owned(),
+                revoked: token.is_revoked(),
+                scopes: token.scope_names().join(","),
+                token_type: token.type_name(),
+            });
+        }
+    }
+
+    // Tokens expiring first come first, non-expiring tokens last
+    rows.sort_by(|row, other| {
+        (
+            row.days_remaining.is_none(),
+            row.days_remaining,
+            &row.instance,
+            &row.path,
+        )
+            .cmp(&(
+                other.days_remaining.is_none(),
+                other.days_remaining,
+                &other.instance,
+                &other.path,
+            ))
+    });
+
+    (rows, failed_instances)
+}
+
+/// Scans all the instances once and prints their tokens in the requested `format`
+///
+/// The tokens of the instances which succeeded are printed even if another instance failed.
+/// If `expires_within_days` is set, only the tokens expiring within this number of days are
+/// printed (except in the `junit` format), and they make the scan fail
+#[expect(clippy::print_stdout, reason = "the tokens are the output of the scan")]
+pub async fn run(
+    expires_within_days: Option<u32>,
+    format: OutputFormat,
+) -> Result<ScanStatus, anyhow::Error> {
+    let config = CONFIG.load_full();
+    let instance_scans = scan_instances(&config, &ScanScope::all()).await;
+    let (rows, failed_instances) = token_rows(instance_scans, chrono::Utc::now().date_naive());
+
+    print!(
+        "{}",
+        render(&rows, &failed_instances, expires_within_days, format)?
+    );
+
+    if !failed_instances.is_empty() {
+        return Ok(ScanStatus::Failed);
+    }
+    let expiring_count = expires_within_days.map_or(0, |days| {
+        rows.iter().filter(|row| row.is_expiring(days)).count()
+    });
+    if expiring_count > 0 {
+        error!("{expiring_count} token(s) expire within {expires_within_days:?} days");
+        return Ok(ScanStatus::ThresholdViolated);
+    }
+    Ok(ScanStatus::Ok)
+}
+
+/// Escapes the XML special characters of `value`
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+//-------------------------------------------
+//
+// Unit tests
+//
+// ------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::scan::{
+        FailedInstance, TokenRow, csv_field, render_csv, render_junit, render_table, xml_escape,
+    };
+
+    fn token_row(name: &str, days_remaining: Option<i64>) -> TokenRow {
+        TokenRow {
+            active: true,
+            credentials: "default".to_owned(),
+            days_remaining,
+            expires_at: days_remaining.map(|_| "2026-11-01".to_owned()),
+            id: 1,
+            instance: "gitlab".to_owned(),
+            name: name.to_owned(),
+            path: "business/project".to_owned(),
+            revoked: false,
+            scopes: "api,read_api".to_owned(),
+            token_type: "project",
+        }
+    }
+
+    #[test]
+    fn csv_fields_are_quoted() {
+        assert_eq!(csv_field("api"), "api");
+        assert_eq!(csv_field("api,read_api"), "\"api,read_api\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+
+        assert_eq!(
+            render_csv(&[&token_row("deploy", Some(14))]),
+            "instance,type,path,name,id,active,revoked,scopes,expires_at,days_remaining,credentials\n\
+             gitlab,project,business/project,deploy,1,true,false,\"api,read_api\",2026-11-01,14,default\n"
+        );
+    }
+
+    #[test]
+    fn table_columns_are_aligned() {
+        let table = render_table(&[&token_row("deploy", Some(14)), &token_row("ci", None)]);
+        let lines: Vec<&str> = table.lines().collect();
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("INSTANCE  TYPE     PATH              NAME    ID"));
+        assert!(lines[1].ends_with("2026-11-01  14              default"));
+        assert!(lines[2].contains("ci      1"));
+        assert!(lines[2].ends_with("default"));
+    }
+
+    #[test]
+    fn expiring_tokens() {
+        let mut revoked = token_row("revoked", Some(3));
+        revoked.revoked = true;
+
+        assert!(token_row("deploy", Some(14)).is_expiring(14));
+        assert!(token_row("deploy", Some(-1)).is_expiring(14));
+        assert!(!token_row("deploy", Some(15)).is_expiring(14));
+        assert!(!token_row("ci", None).is_expiring(14));
+        assert!(!revoked.is_expiring(14));
+    }
+
+    #[test]
+    fn junit_report() {
+        let failed_instances = [FailedInstance {
+            error: "connection refused".to_owned(),
+            name: "other".to_owned(),
+        }];
+        let report = render_junit(
+            &[token_row("deploy", Some(3)), token_row("ci & cd", Some(30))],
+            &failed_instances,
+            |row| row.is_expiring(14),
+        )
+        .unwrap();
+
+        assert!(report.contains(
+            r#"<testsuites name="gitlab-tokens-exporter" tests="3" failures="1" errors="1">"#
+        ));
+        assert!(report.contains(r#"<testsuite name="gitlab" tests="2" failures="1" errors="0">"#));
+        assert!(report.contains(
+            r#"<testcase classname="gitlab.project" name="business/project deploy (id 1)">"#
+        ));
+        assert!(report.contains(
+            r#"<failure type="expiring_token" message="expires on 2026-11-01 (3 days remaining)"/>"#
+        ));
+        assert!(report.contains(
+            r#"<testcase classname="gitlab.project" name="business/project ci &amp; cd (id 1)"/>"#
+        ));
+        assert!(report.contains(r#"<error type="scan_failed" message="connection refused"/>"#));
+        assert_eq!(
+            xml_escape(r#"<a href="x">'"#),
+            "&lt;a href=&quot;x&quot;&gt;&apos;"
+        );
+    }
+}
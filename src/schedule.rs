@@ -0,0 +1,173 @@
+//! Refresh schedules of the [timer](crate::timer) actor (`REFRESH_SCHEDULE`, the schedules of
+//! the resource types such as `USERS_REFRESH_SCHEDULE`, and `REFRESH_JITTER`)
+//!
+//! A schedule is either an interval between two scans (a duration such as `15m` or `6h`), or a
+//! cron expression (such as `0 2 * * *`) evaluated in the local time zone (`TZ`). A random delay,
+//! up to the jitter, is added to each scheduled scan so that several exporters don't hit the same
+//! GitLab instance at the same time.
+
+use anyhow::{Context as _, anyhow};
+use chrono::{DateTime, Local, TimeDelta};
+use core::{fmt, str::FromStr, time::Duration};
+use croner::Cron;
+
+/// Default interval between two scans, the default of `DATA_REFRESH_HOURS`
+const DEFAULT_INTERVAL: Duration = Duration::from_hours(6);
+
+/// Maximum interval between two scans
+const MAX_INTERVAL: Duration = Duration::from_hours(31 * 24);
+
+/// Maximum random delay added to each scan
+const MAX_JITTER: Duration = Duration::from_hours(24);
+
+/// Minimum interval between two scans
+const MIN_INTERVAL: Duration = Duration::from_mins(1);
+
+/// When the scans are run, after the first one
+#[derive(Clone, Debug)]
+pub enum RefreshSchedule {
+    /// At the times matching a cron expression, in the local time zone
+    Cron(Box<Cron>),
+    /// At a fixed interval
+    Interval(Duration),
+}
+
+impl RefreshSchedule {
+    /// Returns a schedule running a scan every `hours` hours (`DATA_REFRESH_HOURS`)
+    pub fn hours(hours: u8) -> Self {
+        Self::Interval(Duration::from_secs(u64::from(hours).saturating_mul(3600)))
+    }
+
+    /// Returns the time of the next scan after `now`, before the [jitter]
+    pub fn next_scan(&self, now: DateTime<Local>) -> Result<DateTime<Local>, anyhow::Error> {
+        match self {
+            Self::Cron(cron) => cron
+                .find_next_occurrence(&now, false)
+                .with_context(|| format!("no time matches the cron expression '{cron}'")),
+            Self::Interval(interval) => now
+                .checked_add_signed(TimeDelta::from_std(*interval)?)
+                .ok_or_else(|| anyhow!("the next scan is out of range")),
+        }
+    }
+}
+
+impl Default for RefreshSchedule {
+    fn default() -> Self {
+        Self::Interval(DEFAULT_INTERVAL)
+    }
+}
+
+impl fmt::Display for RefreshSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cron(cron) => write!(f, "{cron}"),
+            Self::Interval(interval) => {
+                write!(f, "{}", humantime::format_duration(*interval))
+            }
+        }
+    }
+}
+
+impl FromStr for RefreshSchedule {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let trimmed = value.trim();
+        if let Ok(interval) = humantime::parse_duration(trimmed) {
+            return if (MIN_INTERVAL..=MAX_INTERVAL).contains(&interval) {
+                Ok(Self::Interval(interval))
+            } else {
+                Err(anyhow!(
+                    "expected an interval between {} and {}",
+                    humantime::format_duration(MIN_INTERVAL),
+                    humantime::format_duration(MAX_INTERVAL)
+                ))
+            };
+        }
+
+        let cron = Cron::new(trimmed).parse().with_context(|| {
+            format!(
+                "'{trimmed}' is neither a duration (such as '15m' or '6h') nor a cron expression (such as '0 2 * * *')"
+            )
+        })?;
+        // Rejects the expressions which never match, such as February 30th
+        cron.find_next_occurrence(&Local::now(), false)
+            .with_context(|| format!("no time matches the cron expression '{trimmed}'"))?;
+        Ok(Self::Cron(Box::new(cron)))
+    }
+}
+
+/// Returns a random delay up to `max`, added to a scheduled scan
+pub fn jitter(max: Duration) -> Duration {
+    Duration::from_millis(fastrand::u64(
+        0..=u64::try_from(max.as_millis()).unwrap_or(u64::MAX),
+    ))
+}
+
+/// Parses a random delay added to each scan (`REFRESH_JITTER`), such as `5m`
+pub fn parse_jitter(value: &str) -> Result<Duration, anyhow::Error> {
+    let jitter = humantime::parse_duration(value.trim())
+        .with_context(|| format!("'{value}' is not a duration (such as '5m')"))?;
+    if jitter > MAX_JITTER {
+        return Err(anyhow!(
+            "expected a duration up to {}",
+            humantime::format_duration(MAX_JITTER)
+        ));
+    }
+    Ok(jitter)
+}
+
+//-------------------------------------------
+//
+// Unit tests
+//
+// ------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Local, TimeDelta, TimeZone as _, Timelike as _};
+    use core::time::Duration;
+
+    use crate::schedule::{RefreshSchedule, jitter, parse_jitter};
+
+    #[test]
+    fn schedules_are_parsed() {
+        let interval: RefreshSchedule = "15m".parse().unwrap();
+        assert_eq!(interval.to_string(), "15m");
+        assert_eq!(RefreshSchedule::hours(6).to_string(), "6h");
+        assert!("30s".parse::<RefreshSchedule>().is_err());
+        assert!("60days".parse::<RefreshSchedule>().is_err());
+
+        let cron: RefreshSchedule = " 0 2 * * * ".parse().unwrap();
+        assert_eq!(cron.to_string(), "0 2 * * *");
+        assert!("0 25 * * *".parse::<RefreshSchedule>().is_err());
+        assert!("0 0 30 2 *".parse::<RefreshSchedule>().is_err());
+        assert!("every day".parse::<RefreshSchedule>().is_err());
+    }
+
+    #[test]
+    fn next_scans() {
+        let now = Local.with_ymd_and_hms(2026, 3, 10, 14, 30, 0).unwrap();
+
+        let interval: RefreshSchedule = "15m".parse().unwrap();
+        assert_eq!(
+            interval.next_scan(now).unwrap(),
+            now + TimeDelta::minutes(15)
+        );
+
+        let cron: RefreshSchedule = "0 2 * * *".parse().unwrap();
+        let next_scan = cron.next_scan(now).unwrap();
+        assert_eq!((next_scan.hour(), next_scan.minute()), (2, 0));
+        assert_eq!(next_scan.date_naive(), now.date_naive().succ_opt().unwrap());
+    }
+
+    #[test]
+    fn jitters_are_parsed() {
+        assert_eq!(parse_jitter("5m").unwrap(), Duration::from_secs(300));
+        assert!(parse_jitter("2days").is_err());
+        assert!(parse_jitter("soon").is_err());
+
+        assert_eq!(jitter(Duration::ZERO), Duration::ZERO);
+        assert!(jitter(Duration::from_secs(60)) <= Duration::from_secs(60));
+    }
+}
@@ -1,19 +1,31 @@
 //! This is the main actor, it handles all [`Message`]
 
-use anyhow::Context as _;
-use std::collections::HashMap;
+use anyhow::{Context as _, anyhow};
+use chrono::{DateTime, Utc};
+use core::{fmt, mem};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::sync::{mpsc, oneshot};
-use tokio::task::JoinSet;
+use tokio::task::{self, JoinSet};
 use tokio::time::Instant;
 use tracing::{debug, error, info, instrument, warn};
 
-use crate::config::CONFIG;
-use crate::gitlab::group::Group;
-use crate::gitlab::pagination::{GitLabResourceLister, TokenFetcher};
+use crate::config::{CONFIG, CollectionBackend, Config, Credential, Instance, is_in_subtree};
+use crate::events;
+use crate::exporter_metrics;
+use crate::gitlab::client::{ApiError, Endpoint, ErrorKind};
+use crate::gitlab::graphql::{self, ResourceKind};
+use crate::gitlab::group::{self, Group};
+use crate::gitlab::pagination::{self, GitLabResourceLister, TokenFetcher};
 use crate::gitlab::project::Project;
 use crate::gitlab::token::{PersonalAccessToken, Token};
 use crate::gitlab::user::{self, User};
-use crate::prometheus_metrics;
+use crate::history::{self, HistoryToken};
+use crate::inventory::{Inventory, ResourceId, ResourceRef, ResourceType, ScanScope};
+use crate::progress;
+use crate::refresh::{Refresh, RefreshStatus};
 
 /// Defines possible states
 #[derive(Clone, Debug)]
@@ -36,10 +48,196 @@ pub enum Message {
         /// Channel we have to send the state to
         respond_to: oneshot::Sender<ActorState>,
     },
+    /// Health check (cf [`health`](crate::health)): send the date of the last successful scan,
+    /// if any, to `respond_to`
+    Ping {
+        /// Channel we have to send the date to
+        respond_to: oneshot::Sender<Option<DateTime<Utc>>>,
+    },
     /// This message is sent by the update task when it finishes
-    Set(Result<String, String>),
-    /// This message is only send by the [timer](crate::timer) actor
-    Update,
+    Set(Box<ScanResult>),
+    /// Request a scan. This message is sent by the [timer](crate::timer) and
+    /// [reload](crate::reload) actors, and by [`POST /refresh`](crate::refresh)
+    Update {
+        /// Channel we have to send the identifier of the scan to, if any
+        respond_to: Option<oneshot::Sender<Refresh>>,
+        /// What the scan refreshes
+        scope: ScanScope,
+    },
+}
+
+/// Result of the refresh of a single resource (cf [`get_resource_tokens`])
+pub struct ResourceScan {
+    /// Refreshed resource
+    resource: ResourceRef,
+    /// Full path of the resource (username of a user) and its tokens, or the error which made
+    /// the refresh fail
+    result: Result<(String, Vec<ScannedToken>), anyhow::Error>,
+}
+
+/// Result of a scan, merged into the [`Inventory`] by the actor
+pub struct ScanResult {
+    /// Errors of the failed instances and resources
+    errors: Vec<String>,
+    /// Scans of the instances, each for its own resource types (they differ when a
+    /// [reload](crate::reload) only rescans what changed)
+    instances: Vec<InstanceScan>,
+    /// Refreshes of the single resources
+    resources: Vec<ResourceScan>,
+    /// Whether the scan succeeded: not every instance (or single resource) failed
+    success: bool,
+}
+
+/// What becomes of the metrics after a scan (cf [`ScanResult::outcome`])
+#[derive(Debug, Eq, PartialEq)]
+enum ScanOutcome {
+    /// The errors of the scan replace the metrics
+    Error,
+    /// The metrics are rendered from the inventory
+    Render,
+    /// The metrics are left unchanged
+    Unchanged,
+}
+
+/// Scans run by the actor. A single scan runs at a time: the [`Message::Update`] received
+/// during a scan are coalesced into a single queued scan, started when the running one finishes
+#[derive(Debug, Default)]
+struct Scans {
+    /// Identifier of the last scan started or queued
+    last_id: u64,
+    /// Scope of the queued scan, if any
+    queued: Option<ScanScope>,
+    /// Whether a scan is running
+    running: bool,
+}
+
+/// Result of the scan of an [`Instance`] (cf [`scan_instances`])
+pub struct InstanceScan {
+    /// Scanned instance
+    pub instance: Arc<Instance>,
+    /// Tokens found on the instance, or the error which made the scan fail
+    pub result: Result<Vec<ScannedToken>, anyhow::Error>,
+    /// Scanned resource types
+    pub types: BTreeSet<ResourceType>,
+}
+
+/// A token found on an [`Instance`]
+#[derive(Deserialize, Serialize)]
+pub struct ScannedToken {
+    /// Names of the credentials which saw the token
+    pub credentials: BTreeSet<String>,
+    /// The token itself
+    pub token: Token,
+}
+
+impl fmt::Debug for ScanResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScanResult")
+            .field("errors", &self.errors)
+            .field("success", &self.success)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ScanResult {
+    /// Returns what becomes of the metrics in `state` after the scan, `inventory_stale` telling
+    /// whether the inventory is stale
+    ///
+    /// A scan which rescanned resource types of instances (scheduled, requested, or run by a
+    /// reload) replaces the metrics, with its errors if it failed, unless it failed and the
+    /// tokens loaded from the inventory file are still served. A refresh of single resources
+    /// only updates metrics which were rendered: it doesn't replace the result of a failed scan
+    const fn outcome(&self, state: &ActorState, inventory_stale: bool) -> ScanOutcome {
+        if self.instances.is_empty() {
+            if matches!(state, ActorState::Loaded(_) | ActorState::NoToken) {
+                ScanOutcome::Render
+            } else {
+                ScanOutcome::Unchanged
+            }
+        } else if self.success || inventory_stale {
+            ScanOutcome::Render
+        } else {
+            ScanOutcome::Error
+        }
+    }
+
+    /// Returns the resource types rescanned successfully, by instance name
+    fn rescanned_types(&self) -> BTreeMap<String, BTreeSet<ResourceType>> {
+        self.instances
+            .iter()
+            .filter(|instance_scan| instance_scan.result.is_ok())
+            .map(|instance_scan| {
+                (
+                    instance_scan.instance.name.clone(),
+                    instance_scan.types.clone(),
+                )
+            })
+            .collect()
+    }
+}
+
+impl Scans {
+    /// Records the end of the running scan, and returns the identifier and the scope of the
+    /// queued scan to start, if any
+    fn finished(&mut self) -> Option<(u64, ScanScope)> {
+        let queued = self.queued.take();
+        if queued.is_none() {
+            self.running = false;
+        }
+        queued.map(|scope| (self.last_id, scope))
+    }
+
+    /// Records a request of a scan of `scope`. The scan is [started](RefreshStatus::Started) if
+    /// none is running, and its scope is returned. Otherwise it is [queued](RefreshStatus::Queued):
+    /// `scope` is merged into the scope of the queued scan, if any
+    fn request(&mut self, scope: ScanScope) -> (Refresh, Option<ScanScope>) {
+        if self.running {
+            if let Some(queued) = &mut self.queued {
+                queued.merge(scope);
+            } else {
+                self.queued = Some(scope);
+                self.last_id = self.last_id.saturating_add(1);
+            }
+            (
+                Refresh {
+                    scan_id: self.last_id,
+                    status: RefreshStatus::Queued,
+                },
+                None,
+            )
+        } else {
+            self.running = true;
+            self.last_id = self.last_id.saturating_add(1);
+            (
+                Refresh {
+                    scan_id: self.last_id,
+                    status: RefreshStatus::Started,
+                },
+                Some(scope),
+            )
+        }
+    }
+}
+
+/// Tasks getting tokens, with the name of the credential they use
+type CredentialTasks = JoinSet<(String, Result<Vec<Token>, anyhow::Error>)>;
+
+/// Adds the `tokens` seen by the credential `credential_name` to `scanned_tokens`, by type and id
+fn add_scanned_tokens(
+    scanned_tokens: &mut BTreeMap<(&'static str, usize), ScannedToken>,
+    credential_name: &str,
+    tokens: Vec<Token>,
+) {
+    for token in tokens {
+        scanned_tokens
+            .entry((token.type_name(), token.id()))
+            .or_insert_with(|| ScannedToken {
+                credentials: BTreeSet::new(),
+                token,
+            })
+            .credentials
+            .insert(credential_name.to_owned());
+    }
 }
 
 /// Handles [`send()`](mpsc::Sender::send) result by dismissing it ;)
@@ -53,20 +251,54 @@ async fn send_msg(sender: mpsc::Sender<Message>, msg: Message) {
     }
 }
 
-#[instrument(skip_all, err)]
-/// Get tokens from a [`Project`] or a [`Group`] and convert them to prometheus metrics
-async fn get_tokens_metrics<T>() -> Result<String, anyhow::Error>
+#[instrument(skip_all, fields(credential = credential.name), err)]
+/// Get tokens from the [`Project`]s or the [`Group`]s of `instance` visible by `credential`
+async fn get_tokens<T>(
+    instance: Arc<Instance>,
+    credential: Arc<Credential>,
+) -> Result<Vec<Token>, anyhow::Error>
 where
     T: for<'serde> serde::Deserialize<'serde> + GitLabResourceLister<T> + TokenFetcher + Clone,
 {
     info!("getting {}s", T::type_name());
+    progress::listing(&instance.name, &credential.name, T::type_name());
 
     let mut time = Instant::now();
-    let mut res = String::new();
+    let mut res = Vec::new();
 
-    let items = T::get_all()
-        .await
-        .with_context(|| format!("failed to get {}s", T::type_name()))?;
+    let mut items = if instance.group_subtrees.include.is_empty() {
+        T::get_all(&instance, &credential).await
+    } else {
+        pagination::get_all_in_subtrees(&instance, &credential).await
+    }
+    .with_context(|| format!("failed to get {}s", T::type_name()))?;
+
+    let listed_count = items.len();
+    items.retain(|item| {
+        item.full_path()
+            .is_none_or(|full_path| !instance.group_subtrees.is_excluded(full_path))
+    });
+    let unexcluded_count = items.len();
+    if unexcluded_count < listed_count {
+        info!(
+            "{} {}(s) filtered out by GROUP_SUBTREES_EXCLUDE",
+            listed_count.saturating_sub(unexcluded_count),
+            T::type_name()
+        );
+    }
+
+    // Groups without a full path are filtered in `get_access_tokens_task`, once their full path is known
+    items.retain(|item| {
+        item.full_path()
+            .is_none_or(|full_path| T::path_filter(&instance.filters).is_match(full_path))
+    });
+    if items.len() < unexcluded_count {
+        info!(
+            "{} {}(s) filtered out by path filters",
+            unexcluded_count.saturating_sub(items.len()),
+            T::type_name()
+        );
+    }
 
     info!(
         "got {} {}{} in {:?}",
@@ -80,17 +312,40 @@ where
     );
 
     info!("getting {} tokens", T::type_name());
+    progress::fetching_tokens(
+        &instance.name,
+        &credential.name,
+        T::type_name(),
+        Some(items.len()),
+    );
+    exporter_metrics::record_scanned_resources(
+        &instance.name,
+        &credential.name,
+        T::type_name(),
+        items.len(),
+    );
 
     time = Instant::now();
 
-    for chunk in items.chunks(CONFIG.max_concurrent_requests.div_euclid(2).into()) {
+    for chunk in items.chunks(
+        CONFIG
+            .load()
+            .max_concurrent_requests
+            .div_euclid(2)
+            .max(1)
+            .into(),
+    ) {
         // For each chunk, we are going to create a JoinSet, so that we can await the completion all of the tasks
-        let mut set: JoinSet<Result<String, anyhow::Error>> = JoinSet::new();
+        let mut set: JoinSet<Result<Vec<Token>, anyhow::Error>> = JoinSet::new();
         for item in chunk {
             // TODO: I didn't find a way to get a chunk of owned Ts... (maybe with something other that a Vec<T> ?)
             // not possible with a Vec : cf https://github.com/rust-lang/rust/issues/40708
             // maybe using `array_chunks` when it'ss stabilized ? https://doc.rust-lang.org/std/iter/trait.Iterator.html#method.array_chunks
-            set.spawn(get_access_tokens_task(item.clone()));
+            set.spawn(get_access_tokens_task(
+                Arc::clone(&instance),
+                Arc::clone(&credential),
+                item.clone(),
+            ));
         }
 
         // Now that `set` is initialized, we wait for all the tasks to finish
@@ -100,7 +355,10 @@ where
             let task_result = join_result.context("failed to join task")?;
 
             match task_result {
-                Ok(metric_value) => res.push_str(&metric_value),
+                Ok(tokens) => {
+                    res.extend(tokens);
+                    progress::processed(&instance.name, &credential.name, T::type_name(), 1);
+                }
                 Err(err) => return Err(err),
             }
         }
@@ -113,170 +371,690 @@ where
 }
 
 #[instrument(skip_all, err)]
-/// This function is used in [`get_tokens_metrics`] as an async task template
+/// This function is used in [`get_tokens`] as an async task template
 ///
-/// `resource` is a specific [`Project`] or [`Group`]
-async fn get_access_tokens_task<T>(resource: T) -> Result<String, anyhow::Error>
+/// `resource` is a specific [`Project`] or [`Group`] of `instance`
+async fn get_access_tokens_task<T>(
+    instance: Arc<Instance>,
+    credential: Arc<Credential>,
+    resource: T,
+) -> Result<Vec<Token>, anyhow::Error>
 where
     T: TokenFetcher,
 {
-    let mut res = String::new();
+    let mut res = Vec::new();
 
     let tokens = resource
-        .get_all_tokens()
+        .get_all_tokens(&credential)
         .await
         .with_context(|| format!("failed to get tokens for project {}", resource.name()))?;
 
     for token in tokens {
-        if !(CONFIG.skip_non_expiring_tokens && token.expires_at.is_none()) {
-            let generic_token = resource.create_generic_token(token).await?;
-            let token_metric_str =
-                prometheus_metrics::build(&generic_token).with_context(|| {
-                    format!("failed to build prometheus metric for token={generic_token:?}")
-                })?;
-            res.push_str(&token_metric_str);
-        }
+        res.push(
+            resource
+                .create_generic_token(&instance, &credential, token)
+                .await?,
+        );
     }
+
+    retain_matching_tokens(&instance, &mut res);
     Ok(res)
 }
 
-#[instrument(skip_all, err)]
-/// Get users tokens and convert them to prometheus metrics
-async fn get_users_tokens_metrics() -> Result<String, anyhow::Error> {
-    info!("starting");
+/// Returns `None` if the request failed because the resource doesn't exist, or isn't visible by
+/// the credential
+fn visible<T>(result: Result<T, ApiError>) -> Result<Option<T>, anyhow::Error> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if matches!(err.kind(), ErrorKind::NotFound | ErrorKind::Permission) => {
+            debug!("not visible: {err}");
+            Ok(None)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Keeps the tokens matching the [filters](crate::filter::Filters) of `instance`, and logs the
+/// number of tokens filtered out
+fn retain_matching_tokens(instance: &Instance, tokens: &mut Vec<Token>) {
+    let count = tokens.len();
+    tokens.retain(|token| instance.filters.is_match(token));
+    if tokens.len() < count {
+        info!(
+            "{} token(s) filtered out by path, username, token name, scope or state filters",
+            count.saturating_sub(tokens.len())
+        );
+    }
+}
+
+#[instrument(skip_all, fields(credential = credential.name), err)]
+/// Get the projects (or groups) tokens visible by `credential` with the GraphQL API
+async fn get_graphql_tokens(
+    instance: Arc<Instance>,
+    credential: Arc<Credential>,
+    kind: ResourceKind,
+) -> Result<Vec<Token>, anyhow::Error> {
+    info!("getting {} tokens with the GraphQL API", kind.type_name());
+    progress::fetching_tokens(&instance.name, &credential.name, kind.type_name(), None);
 
-    let mut res = String::new();
+    let time = Instant::now();
 
-    // First, we must check that the token we are using have the necessary rights
-    // If not, we return an empty string
+    let mut tokens = graphql::get_tokens(
+        &credential.client,
+        kind,
+        instance.owned_entities_only,
+        &instance.group_subtrees,
+    )
+    .await
+    .with_context(|| format!("failed to get {} tokens", kind.type_name()))?;
 
-    let current_user = user::get_current()
+    info!("got all tokens in {:?}", time.elapsed());
+
+    retain_matching_tokens(&instance, &mut tokens);
+    Ok(tokens)
+}
+
+#[instrument(skip_all, fields(credential = credential.name), err)]
+/// Get the users tokens of `instance` visible by `credential`
+///
+/// An admin credential sees the tokens of all users. Any other credential only sees its own tokens
+async fn get_users_tokens(
+    instance: Arc<Instance>,
+    credential: Arc<Credential>,
+) -> Result<Vec<Token>, anyhow::Error> {
+    info!("starting");
+    progress::listing(&instance.name, &credential.name, "user");
+
+    let current_user = user::get_current(&credential)
         .await
         .context("failed to get current user")?;
-    if current_user.is_admin {
+
+    let users = if current_user.is_admin {
         let time = Instant::now();
         info!("getting users");
 
-        let users = User::get_all().await.context("failed to get users")?;
+        let all_users = User::get_all(&instance, &credential)
+            .await
+            .context("failed to get users")?;
 
         info!(
             "got {} user{} in {:?}",
-            users.len(),
-            match users.len() {
+            all_users.len(),
+            match all_users.len() {
                 0 | 1 => "",
                 _ => "s",
             },
             time.elapsed()
         );
 
-        let user_ids: HashMap<_, _> = users
-            .iter()
-            .filter(|user| !CONFIG.bot_users_re.is_match(&user.username))
-            .filter(|user| match &CONFIG.usernames_filter {
-                Some(filter) => filter.contains(&user.username),
-                None => true,
-            })
-            .map(|user| (user.id, user.username.as_str()))
-            .collect();
+        all_users
+    } else {
+        debug!("current user is not an admin, only getting its own tokens");
+        vec![current_user]
+    };
 
-        let mut personnal_access_tokens = PersonalAccessToken::get_all()
-            .await
-            .context("failed to get personnal access tokens")?;
-        // Retain personnal access tokens of users listed in `user_ids`
-        personnal_access_tokens.retain(|pat| user_ids.contains_key(&pat.user_id));
+    progress::fetching_tokens(&instance.name, &credential.name, "user", Some(users.len()));
+    exporter_metrics::record_scanned_resources(
+        &instance.name,
+        &credential.name,
+        "user",
+        users.len(),
+    );
 
-        if CONFIG.usernames_filter.is_some() && personnal_access_tokens.is_empty() {
-            warn!("no token matched USERNAMES_FILTER");
-        }
+    let config = CONFIG.load();
+    let user_ids: HashMap<_, _> = users
+        .iter()
+        .filter(|user| !config.bot_users_re.is_match(&user.username))
+        .map(|user| (user.id, user.username.as_str()))
+        .collect();
 
-        for personnal_access_token in personnal_access_tokens {
-            if !(CONFIG.skip_non_expiring_tokens && personnal_access_token.expires_at.is_none()) {
-                let username = user_ids
-                    .get(&personnal_access_token.user_id)
-                    .map_or("", |val| val);
-                let token = Token::User {
-                    token: personnal_access_token,
-                    full_path: username.to_owned(),
-                };
-                let token_str = prometheus_metrics::build(&token).with_context(|| {
-                    format!("failed to build prometheus metric from token={token:?}")
-                })?;
-                res.push_str(&token_str);
+    let mut personnal_access_tokens = PersonalAccessToken::get_all(&instance, &credential)
+        .await
+        .context("failed to get personnal access tokens")?;
+    progress::processed(&instance.name, &credential.name, "user", users.len());
+    // Retain personnal access tokens of users listed in `user_ids`
+    personnal_access_tokens.retain(|pat| user_ids.contains_key(&pat.user_id));
+
+    let mut tokens: Vec<Token> = personnal_access_tokens
+        .into_iter()
+        .map(|personnal_access_token| {
+            let username = user_ids
+                .get(&personnal_access_token.user_id)
+                .map_or("", |val| val);
+            Token::User {
+                token: personnal_access_token,
+                full_path: username.to_owned(),
             }
-        }
+        })
+        .collect();
 
-        Ok(res)
-    } else {
-        warn!(
-            "can't get users tokens with the current GITLAB_TOKEN (current_user.is_admin == false)"
-        );
-        Ok(String::new())
+    retain_matching_tokens(&instance, &mut tokens);
+
+    if !instance.filters.usernames.is_empty() && tokens.is_empty() {
+        warn!("no token matched the usernames filters");
     }
+
+    Ok(tokens)
 }
 
-#[instrument(skip_all)]
-/// Handles [`Message::Update`] messages
+/// Spawns `task`, getting the `resource_type` tokens of `instance`, in `set`, with the name of
+/// the `credential` it uses. The end of the task is recorded in the [`progress`], and its
+/// duration in the [`exporter_metrics`]
+fn spawn_credential_task<F>(
+    set: &mut CredentialTasks,
+    instance: &Instance,
+    credential: &Credential,
+    resource_type: &'static str,
+    task: F,
+) where
+    F: Future<Output = Result<Vec<Token>, anyhow::Error>> + Send + 'static,
+{
+    let instance_name = instance.name.clone();
+    let name = credential.name.clone();
+    set.spawn(async move {
+        let time = Instant::now();
+        let result = task.await;
+        exporter_metrics::record_scan_duration(
+            &instance_name,
+            &name,
+            resource_type,
+            time.elapsed(),
+        );
+        progress::finished(&instance_name, &name, resource_type, result.is_ok());
+        (name, result)
+    });
+}
+
+#[instrument(skip_all, fields(instance = instance.name), err)]
+/// Get the tokens of the resources of `types` of `instance`
 ///
-/// When finished, it sends its result by sending [`Message::Set`] to the main actor
-async fn get_gitlab_data(sender: mpsc::Sender<Message>) {
+/// Every credential of the instance is scanned. Tokens seen by several credentials are
+/// deduplicated by type and id.
+///
+/// If *any* task fails, the whole instance fails
+async fn get_instance_tokens(
+    instance: Arc<Instance>,
+    types: BTreeSet<ResourceType>,
+) -> Result<Vec<ScannedToken>, anyhow::Error> {
     info!("starting");
 
-    // This variable will be [`Message::Set`] parameter
-    let mut return_value = String::from(
-        "# HELP gitlab_token_days_remaining Days before Gitlab token expires\n# TYPE gitlab_token_days_remaining gauge\n",
-    );
+    // Groups may have been moved or renamed since the last scan
+    group::clear_cache(&instance);
 
-    // Using a tokio JoinSet to run get_tokens_metrics() twice concurrently
-    let mut set: JoinSet<Result<String, anyhow::Error>> = JoinSet::new();
+    // Using a tokio JoinSet to run the tasks of every credential concurrently
+    let mut set: CredentialTasks = JoinSet::new();
+    let collection_backend = CONFIG.load().collection_backend;
 
-    set.spawn(get_tokens_metrics::<Project>());
-    set.spawn(get_tokens_metrics::<Group>());
+    for credential in &instance.credentials {
+        match collection_backend {
+            CollectionBackend::GraphQl => {
+                for kind in [ResourceKind::Project, ResourceKind::Group] {
+                    if !types.contains(&ResourceType::from(kind)) {
+                        continue;
+                    }
+                    spawn_credential_task(
+                        &mut set,
+                        &instance,
+                        credential,
+                        kind.type_name(),
+                        get_graphql_tokens(Arc::clone(&instance), Arc::clone(credential), kind),
+                    );
+                }
+            }
+            CollectionBackend::Rest => {
+                if types.contains(&ResourceType::Project) {
+                    spawn_credential_task(
+                        &mut set,
+                        &instance,
+                        credential,
+                        "project",
+                        get_tokens::<Project>(Arc::clone(&instance), Arc::clone(credential)),
+                    );
+                }
+                if types.contains(&ResourceType::Group) {
+                    spawn_credential_task(
+                        &mut set,
+                        &instance,
+                        credential,
+                        "group",
+                        get_tokens::<Group>(Arc::clone(&instance), Arc::clone(credential)),
+                    );
+                }
+            }
+        }
 
-    if CONFIG.skip_users_tokens {
-        debug!("skipping users tokens as requested by SKIP_USERS_TOKENS env variable");
-    } else {
-        if CONFIG.usernames_filter.is_some() {
-            debug!("getting users tokens matching USERNAMES_FILTER");
+        if !types.contains(&ResourceType::User) {
+            continue;
+        }
+        if instance.skip_users_tokens {
+            debug!("skipping users tokens as requested by SKIP_USERS_TOKENS env variable");
         } else {
-            debug!("getting all users tokens");
+            if instance.filters.usernames.is_empty() {
+                debug!("getting all users tokens");
+            } else {
+                debug!("getting users tokens matching the usernames filters");
+            }
+            spawn_credential_task(
+                &mut set,
+                &instance,
+                credential,
+                "user",
+                get_users_tokens(Arc::clone(&instance), Arc::clone(credential)),
+            );
         }
-        set.spawn(get_users_tokens_metrics());
     }
 
+    // Tokens by type and id, with the names of the credentials which saw them
+    let mut tokens = BTreeMap::new();
+
     // Now that `set` is initialized, we wait for all the tasks to finish
-    // If we get *any* error, we send an error message
     debug!("waiting for {} tasks to complete", set.len());
     while let Some(join_result) = set.join_next().await {
-        match join_result {
-            Ok(task_result) => match task_result {
-                Ok(metric_value) => return_value.push_str(&metric_value),
-                Err(err) => {
-                    let msg = format!("failed to get tokens: {err:?}");
-                    error!("{msg}");
-                    send_msg(sender, Message::Set(Err(msg))).await;
-                    return;
-                }
-            },
-            Err(err) => {
-                let msg = format!("failed to join a task: {err}");
-                error!("{msg}");
-                send_msg(sender, Message::Set(Err(msg))).await;
-                return;
-            }
+        let (credential_name, task_result) = join_result.context("failed to join a task")?;
+        let task_tokens = task_result
+            .with_context(|| format!("failed to get tokens with credential {credential_name}"))?;
+        add_scanned_tokens(&mut tokens, &credential_name, task_tokens);
+    }
+
+    info!("done");
+    Ok(tokens.into_values().collect())
+}
+
+#[instrument(skip_all, fields(scan_id = scan_id))]
+/// Runs the scan `scan_id` of `scope`, requested by [`Message::Update`]
+///
+/// The resource types of the scope are scanned on their instances concurrently, then the single
+/// resources are refreshed. An instance failing doesn't affect the others: its tokens of these
+/// types are missing from the metrics and its `gitlab_tokens_exporter_instance_scan_success`
+/// metric is set to 0. The scan only fails if every instance failed.
+///
+/// When finished, it sends its result by sending [`Message::Set`] to the main actor
+async fn get_gitlab_data(sender: mpsc::Sender<Message>, scan_id: u64, scope: ScanScope) {
+    info!("starting, refreshing {scope}");
+    progress::scan_started(scan_id);
+
+    let mut errors = Vec::new();
+
+    // The configuration may be reloaded during the scan: we keep using the current one
+    let config = CONFIG.load_full();
+
+    let instances = scan_instances(&config, &scope).await;
+    for instance_scan in &instances {
+        if let Err(err) = &instance_scan.result {
+            let msg = format!(
+                "failed to scan instance {}: {err:?}",
+                instance_scan.instance.name
+            );
+            error!("{msg}");
+            errors.push(msg);
         }
     }
 
-    send_msg(sender, Message::Set(Ok(return_value))).await;
+    let mut resources = Vec::new();
+    for resource in scope.resources {
+        let result = get_resource_tokens(&config, &resource).await;
+        if let Err(err) = &result {
+            let msg = format!("failed to refresh {resource}: {err:?}");
+            error!("{msg}");
+            errors.push(msg);
+        }
+        resources.push(ResourceScan { resource, result });
+    }
+
+    let success = if instances.is_empty() {
+        resources
+            .iter()
+            .any(|resource_scan| resource_scan.result.is_ok())
+    } else {
+        instances
+            .iter()
+            .any(|instance_scan| instance_scan.result.is_ok())
+    };
+    progress::scan_finished(&errors, success && !instances.is_empty());
+    send_msg(
+        sender,
+        Message::Set(Box::new(ScanResult {
+            errors,
+            instances,
+            resources,
+            success,
+        })),
+    )
+    .await;
     info!("done");
 }
 
+#[instrument(skip_all, fields(resource = %resource), err)]
+/// Refreshes the tokens of a single project, group or user, with every credential of its instance
+///
+/// Returns the full path of the resource (the username of a user) and its tokens. The credentials
+/// which can't see the resource are skipped. If none can, a resource given by its path has no
+/// token (it may have been deleted), and a resource given by its id is an error
+async fn get_resource_tokens(
+    config: &Config,
+    resource: &ResourceRef,
+) -> Result<(String, Vec<ScannedToken>), anyhow::Error> {
+    let instance = config
+        .instances
+        .iter()
+        .find(|instance| instance.name == resource.instance)
+        .ok_or_else(|| anyhow!("instance {} is not configured", resource.instance))?;
+
+    let mut full_path = match &resource.id {
+        ResourceId::Id(_) => None,
+        ResourceId::Path(path) => Some(path.clone()),
+    };
+    let mut tokens = BTreeMap::new();
+    for credential in &instance.credentials {
+        let visible = match (resource.resource_type, &resource.id) {
+            (ResourceType::Group, &ResourceId::Id(id)) => {
+                get_single_resource_tokens::<Group>(instance, credential, &Endpoint::Group(id))
+                    .await
+            }
+            (ResourceType::Group, ResourceId::Path(path)) => {
+                get_single_resource_tokens::<Group>(
+                    instance,
+                    credential,
+                    &Endpoint::GroupByPath(path.clone()),
+                )
+                .await
+            }
+            (ResourceType::Project, &ResourceId::Id(id)) => {
+                get_single_resource_tokens::<Project>(instance, credential, &Endpoint::Project(id))
+                    .await
+            }
+            (ResourceType::Project, ResourceId::Path(path)) => {
+                get_single_resource_tokens::<Project>(
+                    instance,
+                    credential,
+                    &Endpoint::ProjectByPath(path.clone()),
+                )
+                .await
+            }
+            (ResourceType::User, id) => get_single_user_tokens(instance, credential, id).await,
+        }
+        .with_context(|| format!("failed to get tokens with credential {}", credential.name))?;
+
+        if let Some((resource_path, credential_tokens)) = visible {
+            full_path = Some(resource_path);
+            add_scanned_tokens(&mut tokens, &credential.name, credential_tokens);
+        } else {
+            debug!(
+                "{resource} is not visible by credential {}",
+                credential.name
+            );
+        }
+    }
+
+    let resource_path = full_path.ok_or_else(|| anyhow!("{resource} not found"))?;
+    info!("got {} token(s)", tokens.len());
+    Ok((resource_path, tokens.into_values().collect()))
+}
+
+#[instrument(skip_all, fields(credential = credential.name), err)]
+/// Get the tokens of the project (or group) at `endpoint` visible by `credential`, with its full
+/// path, or `None` if `credential` can't see it
+///
+/// The project (or group) has no token if it is filtered out by the group subtrees or the path
+/// filters of `instance`
+async fn get_single_resource_tokens<T>(
+    instance: &Arc<Instance>,
+    credential: &Arc<Credential>,
+    endpoint: &Endpoint,
+) -> Result<Option<(String, Vec<Token>)>, anyhow::Error>
+where
+    T: DeserializeOwned + TokenFetcher,
+{
+    let Some(item) = visible(credential.client.get_one::<T>(endpoint).await)? else {
+        return Ok(None);
+    };
+    let full_path = item
+        .full_path()
+        .map_or_else(|| item.name(), ToOwned::to_owned);
+
+    let subtrees = &instance.group_subtrees;
+    if subtrees.is_excluded(&full_path)
+        || (!subtrees.include.is_empty()
+            && !subtrees
+                .include
+                .iter()
+                .any(|group_path| is_in_subtree(&full_path, group_path)))
+        || !T::path_filter(&instance.filters).is_match(&full_path)
+    {
+        info!("{} {full_path} is filtered out", T::type_name());
+        return Ok(Some((full_path, Vec::new())));
+    }
+
+    let tokens = get_access_tokens_task(Arc::clone(instance), Arc::clone(credential), item).await?;
+    Ok(Some((full_path, tokens)))
+}
+
+#[instrument(skip_all, fields(credential = credential.name), err)]
+/// Get the tokens of the user `id` visible by `credential`, with its username, or `None` if
+/// `credential` can't see the user or its tokens
+async fn get_single_user_tokens(
+    instance: &Instance,
+    credential: &Credential,
+    id: &ResourceId,
+) -> Result<Option<(String, Vec<Token>)>, anyhow::Error> {
+    let found = match id {
+        &ResourceId::Id(user_id) => visible(
+            credential
+                .client
+                .get_one::<User>(&Endpoint::User(user_id))
+                .await,
+        )?,
+        ResourceId::Path(username) => credential
+            .client
+            .get_one::<Vec<User>>(&Endpoint::UsersByUsername(username.clone()))
+            .await?
+            .into_iter()
+            .next(),
+    };
+    let Some(found_user) = found else {
+        return Ok(None);
+    };
+    if CONFIG.load().bot_users_re.is_match(&found_user.username) {
+        info!(
+            "user {} is filtered out by BOT_USERS_REGEX",
+            found_user.username
+        );
+        return Ok(Some((found_user.username, Vec::new())));
+    }
+
+    let Some(personal_access_tokens) = visible(
+        credential
+            .client
+            .get_paginated::<PersonalAccessToken>(&Endpoint::UserPersonalAccessTokens(
+                found_user.id,
+            ))
+            .await,
+    )?
+    else {
+        return Ok(None);
+    };
+    let mut tokens: Vec<Token> = personal_access_tokens
+        .into_iter()
+        .filter(|personal_access_token| personal_access_token.user_id == found_user.id)
+        .map(|personal_access_token| Token::User {
+            token: personal_access_token,
+            full_path: found_user.username.clone(),
+        })
+        .collect();
+    retain_matching_tokens(instance, &mut tokens);
+    Ok(Some((found_user.username, tokens)))
+}
+
+/// Scans the resource types of `scope` of the instances of `config` concurrently, and returns the
+/// result of each scanned instance, in the order of the configuration
+///
+/// An instance failing doesn't affect the others
+pub async fn scan_instances(config: &Config, scope: &ScanScope) -> Vec<InstanceScan> {
+    let mut set = JoinSet::new();
+    let mut task_instances = HashMap::new();
+    for instance in &config.instances {
+        let types = scope.instance_types(&instance.name);
+        if types.is_empty() {
+            continue;
+        }
+        let abort_handle = set.spawn(get_instance_tokens(Arc::clone(instance), types.clone()));
+        task_instances.insert(abort_handle.id(), (Arc::clone(instance), types));
+    }
+
+    let mut results = HashMap::new();
+    while let Some(join_result) = set.join_next_with_id().await {
+        let (task_id, result) = match join_result {
+            Ok((task_id, result)) => (task_id, result),
+            Err(err) => (err.id(), Err(anyhow!("failed to join a task: {err}"))),
+        };
+        if let Some((instance, types)) = task_instances.remove(&task_id) {
+            results.insert(
+                instance.name.clone(),
+                InstanceScan {
+                    instance,
+                    result,
+                    types,
+                },
+            );
+        }
+    }
+
+    config
+        .instances
+        .iter()
+        .filter_map(|instance| results.remove(&instance.name))
+        .collect()
+}
+
+/// Loads the inventory saved by the previous run to the `inventory_file` of `config`, if any
+fn load_inventory(config: &Config) -> Inventory {
+    let Some(path) = &config.inventory_file else {
+        return Inventory::default();
+    };
+    if !path.exists() {
+        info!(
+            "no inventory file at {}, waiting for the first scan",
+            path.display()
+        );
+        return Inventory::default();
+    }
+    match Inventory::load(path) {
+        Ok(inventory) => {
+            info!(
+                "serving the tokens of {} until the first scan finishes",
+                path.display()
+            );
+            inventory
+        }
+        Err(err) => {
+            error!("{err:?}, waiting for the first scan");
+            Inventory::default()
+        }
+    }
+}
+
+/// Renders the metrics of `inventory`, and returns the new state. The metrics are sent to
+/// `refreshed`, if any, and the inventory is saved to the `inventory_file`, unless it is stale
+async fn render_inventory(
+    inventory: &mut Inventory,
+    refreshed: Option<&mpsc::Sender<String>>,
+) -> ActorState {
+    let config = CONFIG.load();
+    let data = match inventory.render(&config) {
+        Ok(data) => data,
+        Err(err) => return ActorState::Error(format!("{err:?}")),
+    };
+    let inventory_file = config.inventory_file.clone();
+    drop(config);
+    if let Some(path) = inventory_file
+        && !inventory.is_stale()
+    {
+        save_inventory(inventory, path).await;
+    }
+
+    if let Some(refreshed_sender) = refreshed
+        && let Err(err) = refreshed_sender.send(data.clone()).await
+    {
+        error!("failed to send the refreshed metrics: {err}");
+    }
+    if data.is_empty() {
+        warn!("no token has been found");
+        ActorState::NoToken
+    } else {
+        ActorState::Loaded(data)
+    }
+}
+
+/// Saves `inventory` to `path`. Serializing and writing a large inventory takes a while, it
+/// mustn't block the runtime: the inventory is moved to a blocking task, and back
+async fn save_inventory(inventory: &mut Inventory, path: PathBuf) {
+    let saved_inventory = mem::take(inventory);
+    match task::spawn_blocking(move || {
+        let result = saved_inventory.save(&path);
+        (saved_inventory, result)
+    })
+    .await
+    {
+        Ok((returned_inventory, result)) => {
+            *inventory = returned_inventory;
+            if let Err(err) = result {
+                error!("failed to save the inventory: {err:?}");
+            }
+        }
+        // The inventory is lost, the next scans fill it again
+        Err(err) => error!("failed to save the inventory: {err}"),
+    }
+}
+
+/// Returns the tokens found by the successful scans of the `instances` and `resources`, as saved
+/// in the history database
+fn seen_tokens(
+    instances: &[InstanceScan],
+    resources: &[ResourceScan],
+    scanned_at: DateTime<Utc>,
+) -> Vec<HistoryToken> {
+    let instance_tokens = instances.iter().flat_map(|instance_scan| {
+        instance_scan
+            .result
+            .iter()
+            .flatten()
+            .map(|scanned_token| (instance_scan.instance.name.as_str(), scanned_token))
+    });
+    let resource_tokens = resources.iter().flat_map(|resource_scan| {
+        resource_scan
+            .result
+            .iter()
+            .flat_map(|(_, scanned_tokens)| scanned_tokens)
+            .map(|scanned_token| (resource_scan.resource.instance.as_str(), scanned_token))
+    });
+    instance_tokens
+        .chain(resource_tokens)
+        .map(|(instance, scanned_token)| {
+            HistoryToken::new(instance, &scanned_token.token, scanned_at)
+        })
+        .collect()
+}
+
 #[instrument(skip_all)]
 /// Main actor, receives all [`Message`]
+///
+/// The actor keeps the tokens found by the scans in an [`Inventory`], into which the result of
+/// each scan is merged, and from which the metrics are rendered. If `INVENTORY_FILE` is set, the
+/// inventory saved by the previous run is served until the first scan finishes.
+/// If `refreshed` is set, the metrics are sent to it after each successful refresh
+/// (cf [`textfile_actor`](crate::textfile::textfile_actor))
 pub async fn gitlab_tokens_actor(
     mut receiver: mpsc::Receiver<Message>,
     sender: mpsc::Sender<Message>,
+    refreshed: Option<mpsc::Sender<String>>,
 ) {
-    let mut state = ActorState::Loading;
+    let mut inventory = load_inventory(&CONFIG.load());
+    let mut state = if inventory.is_stale() {
+        render_inventory(&mut inventory, None).await
+    } else {
+        ActorState::Loading
+    };
+    let mut scans = Scans::default();
 
     // wait for some messages
     loop {
@@ -292,27 +1070,224 @@ pub async fn gitlab_tokens_actor(
                     warn!("failed to send reponse : oneshot channel was closed");
                 });
             }
-            Message::Update => {
+            Message::Ping { respond_to } => {
+                debug!("received Message::Ping");
+                respond_to.send(inventory.scanned_at()).unwrap_or_else(|_| {
+                    warn!("failed to send reponse : oneshot channel was closed");
+                });
+            }
+            Message::Update { respond_to, scope } => {
                 // We are going to spawn a async task to get the data from gitlab.
                 // This task will send us Message::Set with the result to
                 // update our 'state' variable
                 debug!("received Message::Update");
-                tokio::spawn(get_gitlab_data(sender.clone()));
+                let (refresh, started) = scans.request(scope);
+                if let Some(started_scope) = started {
+                    tokio::spawn(get_gitlab_data(
+                        sender.clone(),
+                        refresh.scan_id,
+                        started_scope,
+                    ));
+                } else {
+                    info!("a scan is running, scan {} is queued", refresh.scan_id);
+                }
+                if let Some(refresh_sender) = respond_to {
+                    refresh_sender.send(refresh).unwrap_or_else(|_| {
+                        warn!("failed to send reponse : oneshot channel was closed");
+                    });
+                }
             }
-            Message::Set(gitlab_data) => {
+            Message::Set(scan_result) => {
                 debug!("received Message::Set");
-                match gitlab_data {
-                    Ok(data) => {
-                        if data.is_empty() {
-                            warn!("no token has been found");
-                            state = ActorState::NoToken;
-                        } else {
-                            state = ActorState::Loaded(data);
-                        }
+                let outcome = scan_result.outcome(&state, inventory.is_stale());
+                let rescanned_types = scan_result.rescanned_types();
+                let ScanResult {
+                    errors,
+                    instances,
+                    resources,
+                    ..
+                } = *scan_result;
+                let scanned_at = Utc::now();
+                history::save_scan(seen_tokens(&instances, &resources, scanned_at), scanned_at);
+                let mut token_events = Vec::new();
+                for instance_scan in instances {
+                    token_events.extend(inventory.merge_instance_scan(instance_scan, scanned_at));
+                }
+                for resource_scan in resources {
+                    if let Ok((full_path, scanned_tokens)) = resource_scan.result {
+                        token_events.extend(inventory.merge_resource(
+                            &resource_scan.resource.instance,
+                            resource_scan.resource.resource_type,
+                            &full_path,
+                            scanned_tokens,
+                            scanned_at,
+                        ));
+                    }
+                }
+                events::record(token_events);
+
+                if !rescanned_types.is_empty() {
+                    let config = CONFIG.load();
+                    let instance_names: Vec<&str> = config
+                        .instances
+                        .iter()
+                        .map(|instance| instance.name.as_str())
+                        .collect();
+                    inventory.record_scan(rescanned_types, &instance_names, scanned_at);
+                }
+
+                match outcome {
+                    ScanOutcome::Error => state = ActorState::Error(errors.join("\n")),
+                    ScanOutcome::Render => {
+                        state = render_inventory(&mut inventory, refreshed.as_ref()).await;
                     }
-                    Err(err) => state = ActorState::Error(err),
+                    ScanOutcome::Unchanged => {}
+                }
+                if let Some((scan_id, scope)) = scans.finished() {
+                    tokio::spawn(get_gitlab_data(sender.clone(), scan_id, scope));
                 }
             }
         }
     }
 }
+
+//-------------------------------------------
+//
+// Unit tests
+//
+// ------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        sync::Arc,
+    };
+
+    use crate::config::{GroupSubtrees, Instance};
+    use crate::filter::Filters;
+    use crate::gitlab::connection::TlsOptions;
+    use crate::inventory::{ResourceId, ResourceRef, ResourceType, ScanScope};
+    use crate::refresh::{Refresh, RefreshStatus};
+    use crate::state_actor::{
+        ActorState, InstanceScan, ResourceScan, ScanOutcome, ScanResult, ScannedToken, Scans,
+    };
+
+    /// Returns the result of a scan of the users of `gitlab.example.com`, as run by a reload
+    /// which only changed the usernames filter
+    fn reload_scan(result: Result<Vec<ScannedToken>, anyhow::Error>) -> ScanResult {
+        let instance = Instance {
+            credentials: Vec::new(),
+            filters: Filters::default(),
+            group_subtrees: GroupSubtrees::default(),
+            hostname: "gitlab.example.com".to_owned(),
+            labels: BTreeMap::new(),
+            name: "gitlab.example.com".to_owned(),
+            owned_entities_only: false,
+            skip_users_tokens: false,
+            tls: TlsOptions::default(),
+        };
+        ScanResult {
+            errors: Vec::new(),
+            success: result.is_ok(),
+            instances: vec![InstanceScan {
+                instance: Arc::new(instance),
+                result,
+                types: BTreeSet::from([ResourceType::User]),
+            }],
+            resources: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn reload_scans_replace_the_metrics() {
+        // A reload fixing the token which made the last scan fail renders the metrics again
+        let fixed = reload_scan(Ok(Vec::new()));
+        assert_eq!(
+            fixed.outcome(&ActorState::Error("401 Unauthorized".to_owned()), false),
+            ScanOutcome::Render
+        );
+        assert_eq!(
+            fixed.rescanned_types(),
+            BTreeMap::from([(
+                "gitlab.example.com".to_owned(),
+                BTreeSet::from([ResourceType::User])
+            )])
+        );
+
+        // A reload breaking every instance shows the errors
+        let broken = reload_scan(Err(anyhow!("401 Unauthorized")));
+        assert_eq!(
+            broken.outcome(&ActorState::Loaded(String::new()), false),
+            ScanOutcome::Error
+        );
+        assert!(broken.rescanned_types().is_empty());
+        // unless the tokens loaded from the inventory file are still served
+        assert_eq!(
+            broken.outcome(&ActorState::Loaded(String::new()), true),
+            ScanOutcome::Render
+        );
+
+        // A refresh of a single resource doesn't replace the result of a failed scan
+        let refresh = ScanResult {
+            errors: Vec::new(),
+            instances: Vec::new(),
+            resources: vec![ResourceScan {
+                resource: ResourceRef {
+                    id: ResourceId::Id(42),
+                    instance: "gitlab.example.com".to_owned(),
+                    resource_type: ResourceType::User,
+                },
+                result: Ok(("alice".to_owned(), Vec::new())),
+            }],
+            success: true,
+        };
+        assert_eq!(
+            refresh.outcome(&ActorState::Error("401 Unauthorized".to_owned()), false),
+            ScanOutcome::Unchanged
+        );
+        assert_eq!(
+            refresh.outcome(&ActorState::NoToken, false),
+            ScanOutcome::Render
+        );
+    }
+
+    #[test]
+    fn scan_requests_are_coalesced() {
+        let mut scans = Scans::default();
+        let started = |scan_id| Refresh {
+            scan_id,
+            status: RefreshStatus::Started,
+        };
+        let queued = |scan_id| Refresh {
+            scan_id,
+            status: RefreshStatus::Queued,
+        };
+
+        let project = ScanScope::resource(ResourceRef {
+            id: ResourceId::Path("business/unit/app".to_owned()),
+            instance: "gitlab.example.com".to_owned(),
+            resource_type: ResourceType::Project,
+        });
+        let users = ScanScope::types([ResourceType::User]);
+
+        assert_eq!(
+            scans.request(ScanScope::all()),
+            (started(1), Some(ScanScope::all()))
+        );
+        // The requests received during the scan 1 are coalesced into the scan 2
+        assert_eq!(scans.request(project.clone()), (queued(2), None));
+        assert_eq!(scans.request(users.clone()), (queued(2), None));
+        let mut merged = project.clone();
+        merged.merge(users.clone());
+        assert_eq!(scans.finished(), Some((2, merged)));
+        assert_eq!(scans.request(users.clone()), (queued(3), None));
+        // The single projects are dropped once all the projects are rescanned
+        assert_eq!(scans.request(project), (queued(3), None));
+        assert_eq!(scans.request(ScanScope::all()), (queued(3), None));
+        assert_eq!(scans.finished(), Some((3, ScanScope::all())));
+        assert_eq!(scans.finished(), None);
+        assert_eq!(scans.request(users.clone()), (started(4), Some(users)));
+    }
+}
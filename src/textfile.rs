@@ -0,0 +1,142 @@
+//! The purpose of this actor is to write the metrics to a `.prom` file read by the
+//! `node_exporter` textfile collector (`textfile` subcommand), instead of serving them over HTTP
+//!
+//! [`gitlab_tokens_actor`](crate::state_actor::gitlab_tokens_actor) sends the metrics after each
+//! successful refresh. The file is replaced atomically, so that the collector never reads a
+//! partial file, and it is left untouched when a refresh fails.
+
+use anyhow::{Context as _, anyhow};
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::Write as _,
+    path::{Path, PathBuf},
+};
+use tokio::sync::mpsc;
+use tracing::{error, info, instrument};
+
+use crate::exporter_metrics;
+
+/// Returns an error if the `node_exporter` textfile collector wouldn't read the file at `path`
+pub fn check_path(path: &Path) -> Result<(), anyhow::Error> {
+    if path.extension().is_none_or(|extension| extension != "prom") {
+        return Err(anyhow!(
+            "invalid textfile path {}: the file name must end with '.prom'",
+            path.display()
+        ));
+    }
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() && !parent.is_dir() => Err(anyhow!(
+            "invalid textfile path {}: {} is not a directory",
+            path.display(),
+            parent.display()
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the path of the temporary file written before being renamed to `path`.
+/// It is in the same directory, so that the rename is atomic, and it doesn't end with `.prom`,
+/// so that the collector ignores it
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp_path = OsString::from(path.as_os_str());
+    temp_path.push(".tmp");
+    PathBuf::from(temp_path)
+}
+
+/// Writes the metrics received from `receiver` to `path`, with the exporter metrics
+#[instrument(skip_all)]
+pub async fn textfile_actor(mut receiver: mpsc::Receiver<String>, path: PathBuf) {
+    loop {
+        let Some(mut metrics) = receiver.recv().await else {
+            error!("recv failed");
+            return;
+        };
+
+        match exporter_metrics::render() {
+            Ok(exporter_metrics) => metrics.push_str(&exporter_metrics),
+            Err(err) => error!("failed to render the exporter metrics: {err:?}"),
+        }
+
+        match write_atomically(&path, &metrics) {
+            Ok(()) => info!("metrics written to {}", path.display()),
+            Err(err) => error!("{err:?}"),
+        }
+    }
+}
+
+/// Writes `content` to a temporary file, then renames it to `path`.
+/// The temporary file is removed if anything fails
+pub fn write_atomically(path: &Path, content: &str) -> Result<(), anyhow::Error> {
+    let temp_file_path = temp_path(path);
+
+    let result = write_file(&temp_file_path, content).and_then(|()| {
+        fs::rename(&temp_file_path, path).with_context(|| {
+            format!(
+                "failed to rename {} to {}",
+                temp_file_path.display(),
+                path.display()
+            )
+        })
+    });
+
+    if result.is_err() {
+        // The temporary file may not even exist
+        let _res = fs::remove_file(&temp_file_path);
+    }
+    result
+}
+
+/// Writes `content` to the file at `path`, and waits for it to reach the disk
+fn write_file(path: &Path, content: &str) -> Result<(), anyhow::Error> {
+    let mut file =
+        File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    file.write_all(content.as_bytes())
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    file.sync_all()
+        .with_context(|| format!("failed to sync {}", path.display()))
+}
+
+//-------------------------------------------
+//
+// Unit tests
+//
+// ------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::Path, process};
+
+    use crate::textfile::{check_path, temp_path, write_atomically};
+
+    #[test]
+    fn textfile_paths() {
+        assert!(check_path(Path::new("gitlab_tokens.prom")).is_ok());
+        assert!(check_path(&env::temp_dir().join("gitlab_tokens.prom")).is_ok());
+        assert!(check_path(Path::new("gitlab_tokens.txt")).is_err());
+        assert!(check_path(Path::new("/nonexistent/dir/gitlab_tokens.prom")).is_err());
+        assert_eq!(
+            temp_path(Path::new("/textfiles/gitlab_tokens.prom")),
+            Path::new("/textfiles/gitlab_tokens.prom.tmp")
+        );
+    }
+
+    #[test]
+    fn files_are_replaced_atomically() {
+        let dir = env::temp_dir().join(format!("gitlab-tokens-exporter-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("gitlab_tokens.prom");
+
+        write_atomically(&path, "first\n").unwrap();
+        write_atomically(&path, "second\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second\n");
+        assert!(!temp_path(&path).exists());
+
+        // Nothing is left behind when the file can't be written
+        let missing_dir_path = dir.join("missing").join("gitlab_tokens.prom");
+        assert!(write_atomically(&missing_dir_path, "third\n").is_err());
+        assert!(!temp_path(&missing_dir_path).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,36 +1,124 @@
 //! The purpose of this actor is to send [`Message::Update`] to [`gitlab_tokens_actor`](crate::state_actor::gitlab_tokens_actor)
 
 use crate::config::CONFIG;
+use crate::exporter_metrics;
+use crate::inventory::{ResourceType, ScanScope};
+use crate::schedule::{self, RefreshSchedule};
 use crate::state_actor::Message;
-use core::time::Duration;
-use tokio::{sync::mpsc, time};
-use tracing::{error, info, instrument};
+use chrono::{DateTime, Local, SecondsFormat, TimeDelta, Utc};
+use std::collections::BTreeMap;
+use tokio::{
+    select,
+    sync::{mpsc, oneshot},
+    time,
+};
+use tracing::{debug, error, info, instrument};
 
-/// Sends [`Message::Update`] messages at a regular interval
+/// Sends [`Message::Update`] messages according to the [refresh schedules](crate::schedule)
+///
+/// The first message, sent immediately, scans all the resource types. Then each resource type is
+/// scanned according to its own schedule, and the types due at the same time are scanned
+/// together. The schedule of a type is read from the current [`CONFIG`] after each of its scans,
+/// so that a [reloaded](crate::config::reload) schedule is used for its next scan. While waiting,
+/// the health checks received from `pings` are answered
 #[instrument(skip_all)]
-pub async fn timer_actor(sender: mpsc::Sender<Message>) {
-    let mut timer = time::interval(Duration::from_secs(
-        u64::from(CONFIG.data_refresh_hours).saturating_mul(3600),
-    ));
-
-    info!(
-        "refresh interval is {} hour{}",
-        timer.period().as_secs().wrapping_div(3600),
-        match CONFIG.data_refresh_hours {
-            1 => "",
-            _ => "s",
-        }
-    );
-
+#[expect(
+    clippy::integer_division_remainder_used,
+    reason = "because clippy is not happy with the tokio::select macro #4"
+)]
+pub async fn timer_actor(
+    sender: mpsc::Sender<Message>,
+    mut pings: mpsc::Receiver<oneshot::Sender<()>>,
+) {
+    let mut scope = ScanScope::all();
+    // Time of the next scan of each resource type, before the jitter
+    let mut next_scans: BTreeMap<ResourceType, DateTime<Local>> = BTreeMap::new();
     loop {
-        // The first call to timer.tick().await returns immediately
-        timer.tick().await;
-        match sender.send(Message::Update).await {
+        let scanned_types = scope.types.clone();
+        match sender
+            .send(Message::Update {
+                respond_to: None,
+                scope,
+            })
+            .await
+        {
             Ok(()) => {}
             Err(err) => {
                 error!("{err}");
                 return;
             }
         }
+
+        let config = CONFIG.load();
+        let now = Local::now();
+        for resource_type in scanned_types {
+            // Intervals start from the previous scheduled scan, so that the types with the same
+            // interval stay scanned together
+            let previous = next_scans
+                .get(&resource_type)
+                .copied()
+                .filter(|previous| *previous <= now)
+                .unwrap_or(now);
+            let refresh_schedule = config.refresh_schedule_of(resource_type);
+            let next_scan = refresh_schedule
+                .next_scan(previous)
+                .ok()
+                .filter(|next_scan| *next_scan > now)
+                .map_or_else(|| refresh_schedule.next_scan(now), Ok)
+                .unwrap_or_else(|err| {
+                    error!("{err:?}, using the default schedule");
+                    RefreshSchedule::default().next_scan(now).unwrap_or(now)
+                });
+            info!(
+                "{}s refresh schedule is '{refresh_schedule}', next scan at {}",
+                resource_type.name(),
+                next_scan.to_rfc3339_opts(SecondsFormat::Secs, false)
+            );
+            next_scans.insert(resource_type, next_scan);
+        }
+
+        // The types due first are scanned together, after a random delay
+        let Some(earliest) = next_scans.values().min().copied() else {
+            error!("no resource type is scheduled");
+            return;
+        };
+        scope = ScanScope::types(
+            next_scans
+                .iter()
+                .filter(|&(_, next_scan)| *next_scan == earliest)
+                .map(|(resource_type, _)| *resource_type),
+        );
+        let wake_up = TimeDelta::from_std(schedule::jitter(config.refresh_jitter))
+            .ok()
+            .and_then(|delay| earliest.checked_add_signed(delay))
+            .unwrap_or(earliest);
+        drop(config);
+        for (resource_type, next_scan) in &next_scans {
+            let at = if scope.types.contains(resource_type) {
+                wake_up
+            } else {
+                *next_scan
+            };
+            exporter_metrics::record_next_scan(resource_type.name(), at.with_timezone(&Utc));
+        }
+
+        let sleep = time::sleep(
+            wake_up
+                .signed_duration_since(Local::now())
+                .to_std()
+                .unwrap_or_default(),
+        );
+        tokio::pin!(sleep);
+        loop {
+            select! {
+                () = &mut sleep => break,
+                Some(respond_to) = pings.recv() => {
+                    debug!("received a ping");
+                    respond_to.send(()).unwrap_or_else(|()| {
+                        debug!("failed to answer a ping: oneshot channel was closed");
+                    });
+                }
+            }
+        }
     }
 }
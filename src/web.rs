@@ -0,0 +1,941 @@
+//! Settings of the HTTP server: listen address, TLS and authentication (`serve` subcommand)
+//!
+//! TLS and authentication are configured with a web configuration file, in the format used by the
+//! Prometheus exporters (<https://prometheus.io/docs/prometheus/latest/configuration/https/>).
+//! It is read at startup. The settings of the format which aren't supported (`http_server_config`,
+//! `cipher_suites`, `curve_preferences` and `prefer_server_cipher_suites`) are ignored with a
+//! warning.
+
+use anyhow::{Context as _, anyhow};
+use axum::{
+    Router,
+    extract::{Request, State},
+    http::{HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse as _, Response},
+    serve::Listener,
+};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use core::{
+    fmt,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    str::FromStr,
+    time::Duration,
+};
+use rustls::{
+    CertificateError, DigitallySignedStruct, DistinguishedName, RootCertStore, ServerConfig,
+    SignatureScheme, SupportedProtocolVersion,
+    client::danger::HandshakeSignatureValid,
+    crypto::{self, WebPkiSupportedAlgorithms},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject as _},
+    server::{
+        WebPkiClientVerifier,
+        danger::{ClientCertVerified, ClientCertVerifier},
+    },
+    version::{TLS12, TLS13},
+};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashSet},
+    env, fs, io,
+    os::unix::fs::FileTypeExt as _,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+use tokio::{
+    net::{TcpListener, UnixListener},
+    select,
+    task::{self, JoinSet},
+    time,
+};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+use tracing::{debug, error, info, warn};
+use webpki::EndEntityCert;
+
+/// Address the HTTP server listens on by default
+const DEFAULT_LISTEN_ADDRESS: ListenAddress = ListenAddress::Tcp(SocketAddr::V4(
+    SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 3000),
+));
+
+/// Maximum duration of a TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of credentials whose successful verification is cached
+const MAX_VERIFIED_CREDENTIALS: usize = 100;
+
+/// Prefix of the Unix sockets listen addresses
+const UNIX_PREFIX: &str = "unix:";
+
+/// Result of a TLS handshake, with the address of the client
+type Handshake<L> = (
+    Result<TlsStream<<L as Listener>::Io>, anyhow::Error>,
+    <L as Listener>::Addr,
+);
+
+/// Credentials accepted by the HTTP server
+#[derive(Default)]
+pub struct Authentication {
+    /// bcrypt hashes of the passwords, by username
+    basic_auth_users: BTreeMap<String, String>,
+    /// bcrypt hashes of the bearer tokens
+    bearer_tokens: Vec<String>,
+    /// `Authorization` headers already verified, so that bcrypt (slow by design) doesn't run on
+    /// every request. At most [`MAX_VERIFIED_CREDENTIALS`] are kept
+    verified: Mutex<HashSet<String>>,
+}
+
+/// Listener bound to a [`ListenAddress`]
+#[derive(Debug)]
+pub enum BoundListener {
+    /// TCP socket
+    Tcp(TcpListener),
+    /// Unix socket
+    Unix(UnixListener),
+}
+
+/// TLS client certificates policies, named like the Go `tls.ClientAuthType` values
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+pub enum ClientAuthType {
+    /// Client certificates aren't requested
+    #[default]
+    NoClientCert,
+    /// Client certificates are requested, but optional and not verified
+    RequestClientCert,
+    /// A valid client certificate is required
+    RequireAndVerifyClientCert,
+    /// A client certificate is required, but not verified
+    RequireAnyClientCert,
+    /// Client certificates are optional, but must be valid
+    VerifyClientCertIfGiven,
+}
+
+/// Verifies the client certificates according to the `client_auth_type` and the
+/// `client_allowed_sans` of the [`TlsServerConfig`]
+#[derive(Debug)]
+struct ClientVerifier {
+    /// Subject alternative names one of which the client certificates must have, if not empty
+    allowed_sans: Vec<String>,
+    /// Verifies the certificate chains with the client CA, `None` if the certificates aren't
+    /// verified
+    chain_verifier: Option<Arc<dyn ClientCertVerifier>>,
+    /// Whether a client certificate is required
+    mandatory: bool,
+    /// Algorithms verifying the signatures of the handshakes
+    signature_algorithms: WebPkiSupportedAlgorithms,
+}
+
+/// Address the HTTP server listens on
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ListenAddress {
+    /// IPv4 or IPv6 address and port, for instance `0.0.0.0:3000` or `[::]:3000`
+    Tcp(SocketAddr),
+    /// Path of a Unix socket, written `unix:<path>`
+    Unix(PathBuf),
+}
+
+/// Listener wrapping the connections accepted by `listener` in TLS.
+///
+/// The handshakes are performed concurrently, so that a slow client doesn't block the others
+pub struct TlsListener<L: Listener> {
+    /// TLS acceptor
+    acceptor: TlsAcceptor,
+    /// Pending handshakes
+    handshakes: JoinSet<Handshake<L>>,
+    /// Underlying listener
+    listener: L,
+}
+
+/// `tls_server_config` section of the web configuration file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsServerConfig {
+    /// Certificate chain of the server, PEM encoded
+    pub cert_file: PathBuf,
+    /// Cipher suites, not supported: the default ones of rustls are used
+    #[serde(default)]
+    pub cipher_suites: Vec<String>,
+    /// Subject alternative names (DNS names, IP addresses or URIs) one of which the client
+    /// certificates must have, if not empty
+    #[serde(default)]
+    pub client_allowed_sans: Vec<String>,
+    /// Policy for the client certificates
+    #[serde(default)]
+    pub client_auth_type: ClientAuthType,
+    /// CA certificates validating the client certificates, PEM encoded
+    pub client_ca_file: Option<PathBuf>,
+    /// Elliptic curves, not supported: the default ones of rustls are used
+    #[serde(default)]
+    pub curve_preferences: Vec<String>,
+    /// Private key of the server, PEM encoded
+    pub key_file: PathBuf,
+    /// Maximum TLS version
+    pub max_version: Option<TlsVersion>,
+    /// Minimum TLS version
+    pub min_version: Option<TlsVersion>,
+    /// Not supported: rustls always prefers the cipher suites of the server
+    pub prefer_server_cipher_suites: Option<bool>,
+}
+
+/// TLS versions supported by the server
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd)]
+pub enum TlsVersion {
+    /// TLS 1.2
+    #[serde(rename = "TLS12")]
+    Tls12,
+    /// TLS 1.3
+    #[serde(rename = "TLS13")]
+    Tls13,
+}
+
+/// Web configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebConfig {
+    /// bcrypt hashes of the passwords, by username
+    #[serde(default)]
+    pub basic_auth_users: BTreeMap<String, String>,
+    /// bcrypt hashes of the bearer tokens. This setting is specific to this exporter
+    #[serde(default)]
+    pub bearer_tokens: Vec<String>,
+    /// HTTP settings (`http2` and `headers`), not supported
+    pub http_server_config: Option<serde_norway::Value>,
+    /// TLS settings, the server uses plain HTTP if not set
+    pub tls_server_config: Option<TlsServerConfig>,
+}
+
+impl Authentication {
+    /// Returns true if the `Authorization` header `value` holds valid credentials. The successful
+    /// verifications are cached
+    fn check(&self, value: &str) -> bool {
+        if self.is_verified(value) {
+            return true;
+        }
+        let valid = self.verify(value);
+        if valid {
+            let mut verified = self.lock_verified();
+            if verified.len() >= MAX_VERIFIED_CREDENTIALS {
+                verified.clear();
+            }
+            verified.insert(value.to_owned());
+        }
+        valid
+    }
+
+    /// Returns true if no credentials are configured
+    fn is_empty(&self) -> bool {
+        self.basic_auth_users.is_empty() && self.bearer_tokens.is_empty()
+    }
+
+    /// Returns true if the `Authorization` header `value` has already been verified
+    fn is_verified(&self, value: &str) -> bool {
+        self.lock_verified().contains(value)
+    }
+
+    /// Locks the cache of the verified `Authorization` headers
+    #[expect(
+        clippy::unwrap_used,
+        reason = "crashing on a poisoned mutex is ok in our case"
+    )]
+    fn lock_verified(&self) -> MutexGuard<'_, HashSet<String>> {
+        self.verified.lock().unwrap()
+    }
+
+    /// Returns true if the `Authorization` header `value` holds valid credentials, checking them
+    /// against the bcrypt hashes
+    fn verify(&self, value: &str) -> bool {
+        let Some((scheme, credentials)) = value.split_once(' ') else {
+            return false;
+        };
+        if scheme.eq_ignore_ascii_case("basic") {
+            let Some((username, password)) = STANDARD
+                .decode(credentials.trim())
+                .ok()
+                .and_then(|decoded| String::from_utf8(decoded).ok())
+                .and_then(|decoded| {
+                    decoded
+                        .split_once(':')
+                        .map(|(username, password)| (username.to_owned(), password.to_owned()))
+                })
+            else {
+                return false;
+            };
+            self.basic_auth_users
+                .get(&username)
+                .is_some_and(|hash| bcrypt::verify(password, hash).unwrap_or(false))
+        } else if scheme.eq_ignore_ascii_case("bearer") {
+            let token = credentials.trim();
+            self.bearer_tokens
+                .iter()
+                .any(|hash| bcrypt::verify(token, hash).unwrap_or(false))
+        } else {
+            false
+        }
+    }
+}
+
+impl fmt::Debug for Authentication {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The verified headers contain credentials, they are never printed
+        f.debug_struct("Authentication")
+            .field("basic_auth_users", &self.basic_auth_users)
+            .field("bearer_tokens", &self.bearer_tokens)
+            .finish_non_exhaustive()
+    }
+}
+
+impl BoundListener {
+    /// Binds a listener to `address`. An existing Unix socket is replaced
+    pub async fn bind(address: &ListenAddress) -> Result<Self, anyhow::Error> {
+        match address {
+            ListenAddress::Tcp(socket_addr) => TcpListener::bind(socket_addr)
+                .await
+                .map(Self::Tcp)
+                .with_context(|| format!("failed to bind to {address}")),
+            ListenAddress::Unix(path) => {
+                if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket())
+                {
+                    fs::remove_file(path)
+                        .with_context(|| format!("failed to remove {}", path.display()))?;
+                }
+                UnixListener::bind(path)
+                    .map(Self::Unix)
+                    .with_context(|| format!("failed to bind to {address}"))
+            }
+        }
+    }
+
+    /// Returns the address the listener is bound to
+    pub fn local_address(&self) -> Result<ListenAddress, anyhow::Error> {
+        match self {
+            Self::Tcp(listener) => listener
+                .local_addr()
+                .map(ListenAddress::Tcp)
+                .context("failed to get local addr from listener"),
+            Self::Unix(listener) => listener
+                .local_addr()
+                .context("failed to get local addr from listener")?
+                .as_pathname()
+                .map(|path| ListenAddress::Unix(path.to_path_buf()))
+                .ok_or_else(|| anyhow!("the Unix socket has no path")),
+        }
+    }
+
+    /// Serves `app`, with TLS if `acceptor` is set, until `shutdown` completes
+    pub async fn serve<F>(
+        self,
+        app: Router,
+        acceptor: Option<TlsAcceptor>,
+        shutdown: F,
+    ) -> io::Result<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        match (self, acceptor) {
+            (Self::Tcp(listener), None) => {
+                axum::serve(listener, app)
+                    .with_graceful_shutdown(shutdown)
+                    .await
+            }
+            (Self::Tcp(listener), Some(tls_acceptor)) => {
+                axum::serve(TlsListener::new(listener, tls_acceptor), app)
+                    .with_graceful_shutdown(shutdown)
+                    .await
+            }
+            (Self::Unix(listener), None) => {
+                axum::serve(listener, app)
+                    .with_graceful_shutdown(shutdown)
+                    .await
+            }
+            (Self::Unix(listener), Some(tls_acceptor)) => {
+                axum::serve(TlsListener::new(listener, tls_acceptor), app)
+                    .with_graceful_shutdown(shutdown)
+                    .await
+            }
+        }
+    }
+}
+
+impl ClientVerifier {
+    /// Returns true if `end_entity` has one of the allowed subject alternative names, or if any is
+    /// allowed. DNS names and URIs are compared exactly, email addresses aren't supported
+    fn allows(&self, end_entity: &CertificateDer<'_>) -> bool {
+        if self.allowed_sans.is_empty() {
+            return true;
+        }
+        let Ok(cert) = EndEntityCert::try_from(end_entity) else {
+            return false;
+        };
+        self.allowed_sans.iter().any(|allowed_san| {
+            if let Ok(ip) = allowed_san.parse::<IpAddr>() {
+                cert.verify_is_valid_for_subject_name(&ServerName::IpAddress(ip.into()))
+                    .is_ok()
+            } else {
+                cert.valid_dns_names()
+                    .chain(cert.valid_uri_names())
+                    .any(|name| name == allowed_san)
+            }
+        })
+    }
+}
+
+impl ClientCertVerifier for ClientVerifier {
+    #[inline]
+    fn client_auth_mandatory(&self) -> bool {
+        self.mandatory
+    }
+
+    #[inline]
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        self.chain_verifier
+            .as_ref()
+            .map_or(&[], |chain_verifier| chain_verifier.root_hint_subjects())
+    }
+
+    #[inline]
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.signature_algorithms.supported_schemes()
+    }
+
+    #[inline]
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        if let Some(chain_verifier) = &self.chain_verifier {
+            chain_verifier.verify_client_cert(end_entity, intermediates, now)?;
+        }
+        if self.allows(end_entity) {
+            Ok(ClientCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::NotValidForName,
+            ))
+        }
+    }
+
+    #[inline]
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.signature_algorithms)
+    }
+
+    #[inline]
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.signature_algorithms)
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(socket_addr) => write!(f, "{socket_addr}"),
+            Self::Unix(path) => write!(f, "{UNIX_PREFIX}{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for ListenAddress {
+    type Err = anyhow::Error;
+
+    #[inline]
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = value.strip_prefix(UNIX_PREFIX) {
+            if path.is_empty() {
+                return Err(anyhow!("the path of the Unix socket is empty"));
+            }
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+        value.parse().map(Self::Tcp).with_context(|| {
+            format!("invalid listen address '{value}', expected <ip>:<port> or {UNIX_PREFIX}<path>")
+        })
+    }
+}
+
+impl<L: Listener> TlsListener<L> {
+    /// Wraps the connections accepted by `listener` in TLS
+    fn new(listener: L, acceptor: TlsAcceptor) -> Self {
+        Self {
+            acceptor,
+            handshakes: JoinSet::new(),
+            listener,
+        }
+    }
+}
+
+impl<L> Listener for TlsListener<L>
+where
+    L: Listener,
+    L::Addr: 'static,
+{
+    type Addr = L::Addr;
+    type Io = TlsStream<L::Io>;
+
+    #[inline]
+    #[expect(
+        clippy::integer_division_remainder_used,
+        reason = "because clippy is not happy with the tokio::select macro #3"
+    )]
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            select! {
+                (io, addr) = self.listener.accept() => {
+                    let acceptor = self.acceptor.clone();
+                    self.handshakes.spawn(async move {
+                        let stream = time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(io))
+                            .await
+                            .context("TLS handshake timed out")
+                            .and_then(|result| result.context("TLS handshake failed"));
+                        (stream, addr)
+                    });
+                }
+                Some(handshake) = self.handshakes.join_next() => match handshake {
+                    Ok((Ok(stream), addr)) => return (stream, addr),
+                    Ok((Err(err), _)) => debug!("{err:?}"),
+                    Err(err) => error!("TLS handshake task failed: {err}"),
+                },
+            }
+        }
+    }
+
+    #[inline]
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.listener.local_addr()
+    }
+}
+
+impl TlsServerConfig {
+    /// Loads the certificates and the key, and returns the TLS acceptor
+    fn acceptor(&self) -> Result<TlsAcceptor, anyhow::Error> {
+        let certs = CertificateDer::pem_file_iter(&self.cert_file)
+            .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+            .with_context(|| format!("failed to read {}", self.cert_file.display()))?;
+        let key = PrivateKeyDer::from_pem_file(&self.key_file)
+            .with_context(|| format!("failed to read {}", self.key_file.display()))?;
+
+        let versions: Vec<&'static SupportedProtocolVersion> =
+            [(TlsVersion::Tls12, &TLS12), (TlsVersion::Tls13, &TLS13)]
+                .into_iter()
+                .filter(|&(version, _)| {
+                    self.min_version
+                        .is_none_or(|min_version| version >= min_version)
+                        && self
+                            .max_version
+                            .is_none_or(|max_version| version <= max_version)
+                })
+                .map(|(_, supported_version)| supported_version)
+                .collect();
+        if versions.is_empty() {
+            return Err(anyhow!("min_version is greater than max_version"));
+        }
+        let versions_builder = ServerConfig::builder_with_protocol_versions(&versions);
+
+        let chain_verifier = match (&self.client_ca_file, self.client_auth_type) {
+            (Some(_), ClientAuthType::NoClientCert) => {
+                return Err(anyhow!(
+                    "client_ca_file is set but client_auth_type is NoClientCert"
+                ));
+            }
+            (
+                None,
+                client_auth_type @ (ClientAuthType::RequireAndVerifyClientCert
+                | ClientAuthType::VerifyClientCertIfGiven),
+            ) => {
+                return Err(anyhow!(
+                    "client_ca_file must be set with client_auth_type {client_auth_type:?}"
+                ));
+            }
+            (
+                Some(client_ca_file),
+                ClientAuthType::RequireAndVerifyClientCert
+                | ClientAuthType::VerifyClientCertIfGiven,
+            ) => {
+                let roots = load_roots(client_ca_file)?;
+                let verifier = WebPkiClientVerifier::builder_with_provider(
+                    Arc::new(roots),
+                    Arc::clone(versions_builder.crypto_provider()),
+                )
+                // Whether a certificate is required is decided by `ClientVerifier`
+                .allow_unauthenticated()
+                .build()
+                .context("failed to build the client certificates verifier")?;
+                Some(verifier)
+            }
+            // The CA isn't used by the policies which don't verify the certificates
+            _ => None,
+        };
+        let builder = if self.client_auth_type == ClientAuthType::NoClientCert {
+            if !self.client_allowed_sans.is_empty() {
+                return Err(anyhow!(
+                    "client_allowed_sans is set but client_auth_type is NoClientCert"
+                ));
+            }
+            versions_builder.with_no_client_auth()
+        } else {
+            let signature_algorithms = versions_builder
+                .crypto_provider()
+                .signature_verification_algorithms;
+            versions_builder.with_client_cert_verifier(Arc::new(ClientVerifier {
+                allowed_sans: self.client_allowed_sans.clone(),
+                chain_verifier,
+                mandatory: self.client_auth_type == ClientAuthType::RequireAndVerifyClientCert
+                    || self.client_auth_type == ClientAuthType::RequireAnyClientCert,
+                signature_algorithms,
+            }))
+        };
+
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .context("invalid certificate or key")?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+impl WebConfig {
+    /// Reads the web configuration file at `path`
+    pub fn from_file(path: &Path) -> Result<Self, anyhow::Error> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let web_config = Self::parse(&content)
+            .with_context(|| format!("invalid web configuration {}", path.display()))?;
+        for setting in web_config.ignored_settings() {
+            warn!("{setting} isn't supported and is ignored");
+        }
+        Ok(web_config)
+    }
+
+    /// Returns the settings which are set but not supported
+    fn ignored_settings(&self) -> Vec<&'static str> {
+        let mut ignored_settings = Vec::new();
+        if self.http_server_config.is_some() {
+            ignored_settings.push("http_server_config");
+        }
+        if let Some(tls_server_config) = &self.tls_server_config {
+            if !tls_server_config.cipher_suites.is_empty() {
+                ignored_settings.push("tls_server_config.cipher_suites");
+            }
+            if !tls_server_config.curve_preferences.is_empty() {
+                ignored_settings.push("tls_server_config.curve_preferences");
+            }
+            if tls_server_config.prefer_server_cipher_suites.is_some() {
+                ignored_settings.push("tls_server_config.prefer_server_cipher_suites");
+            }
+        }
+        ignored_settings
+    }
+
+    /// Returns the credentials, if any, and the TLS acceptor, if TLS is enabled
+    pub fn into_parts(
+        self,
+    ) -> Result<(Option<Arc<Authentication>>, Option<TlsAcceptor>), anyhow::Error> {
+        let acceptor = self
+            .tls_server_config
+            .as_ref()
+            .map(TlsServerConfig::acceptor)
+            .transpose()
+            .context("invalid tls_server_config")?;
+        let authentication = Authentication {
+            basic_auth_users: self.basic_auth_users,
+            bearer_tokens: self.bearer_tokens,
+            verified: Mutex::default(),
+        };
+        Ok((
+            (!authentication.is_empty()).then(|| Arc::new(authentication)),
+            acceptor,
+        ))
+    }
+
+    /// Parses a web configuration and checks the bcrypt hashes
+    fn parse(content: &str) -> Result<Self, anyhow::Error> {
+        // An empty file is a valid configuration
+        let web_config: Self = if content.trim().is_empty() {
+            Self::default()
+        } else {
+            serde_norway::from_str(content)?
+        };
+
+        for (username, hash) in &web_config.basic_auth_users {
+            hash.parse::<bcrypt::HashParts>()
+                .with_context(|| format!("invalid bcrypt hash for the user '{username}'"))?;
+        }
+        for (index, hash) in web_config.bearer_tokens.iter().enumerate() {
+            hash.parse::<bcrypt::HashParts>()
+                .with_context(|| format!("invalid bcrypt hash for the bearer token #{index}"))?;
+        }
+
+        Ok(web_config)
+    }
+}
+
+/// Middleware rejecting the requests without valid credentials
+pub async fn authenticate(
+    State(authentication): State<Arc<Authentication>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+
+    // bcrypt is slow by design, it mustn't block the runtime
+    let checked_authentication = Arc::clone(&authentication);
+    let authenticated = match authorization {
+        Some(value) if authentication.is_verified(&value) => true,
+        Some(value) => task::spawn_blocking(move || checked_authentication.check(&value))
+            .await
+            .unwrap_or(false),
+        None => false,
+    };
+
+    if authenticated {
+        next.run(request).await
+    } else {
+        let challenge = if authentication.basic_auth_users.is_empty() {
+            "Bearer"
+        } else {
+            "Basic realm=\"gitlab-tokens-exporter\""
+        };
+        let mut response = StatusCode::UNAUTHORIZED.into_response();
+        response.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            HeaderValue::from_static(challenge),
+        );
+        response
+    }
+}
+
+/// Returns the listen address: `cli_value`, else `LISTEN_ADDRESS`, else `0.0.0.0:3000`
+pub fn listen_address(cli_value: Option<ListenAddress>) -> Result<ListenAddress, anyhow::Error> {
+    match cli_value {
+        Some(address) => Ok(address),
+        None => match env::var("LISTEN_ADDRESS") {
+            Ok(value) => value.parse().context("invalid LISTEN_ADDRESS"),
+            Err(_) => Ok(DEFAULT_LISTEN_ADDRESS),
+        },
+    }
+}
+
+/// Loads the CA certificates in `path`
+fn load_roots(path: &Path) -> Result<RootCertStore, anyhow::Error> {
+    let mut roots = RootCertStore::empty();
+    for pem_cert in CertificateDer::pem_file_iter(path)
+        .with_context(|| format!("failed to read {}", path.display()))?
+    {
+        let cert = pem_cert.with_context(|| format!("failed to read {}", path.display()))?;
+        roots
+            .add(cert)
+            .with_context(|| format!("invalid CA certificate in {}", path.display()))?;
+    }
+    Ok(roots)
+}
+
+/// Returns the web configuration: read from `cli_value`, else from `WEB_CONFIG_FILE`,
+/// else the default one (plain HTTP, no authentication)
+pub fn web_config(cli_value: Option<PathBuf>) -> Result<WebConfig, anyhow::Error> {
+    match cli_value.or_else(|| env::var_os("WEB_CONFIG_FILE").map(PathBuf::from)) {
+        Some(path) => {
+            info!("reading the web configuration from {}", path.display());
+            WebConfig::from_file(&path)
+        }
+        None => Ok(WebConfig::default()),
+    }
+}
+
+//-------------------------------------------
+//
+// Unit tests
+//
+// ------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use base64::{Engine as _, engine::general_purpose::STANDARD};
+    use rustls::{
+        crypto::aws_lc_rs,
+        pki_types::{CertificateDer, pem::PemObject as _},
+    };
+    use std::path::PathBuf;
+
+    use crate::web::{ClientAuthType, ClientVerifier, ListenAddress, TlsVersion, WebConfig};
+
+    /// Self-signed client certificate with the subject alternative names
+    /// `DNS:prometheus.example.com`, `IP:10.0.0.1` and `URI:spiffe://example.com/prometheus`
+    const CLIENT_CERT: &str = "-----BEGIN CERTIFICATE-----
+MIIBzDCCAXGgAwIBAgIUSqM3C9wg29Y0JWyzCAwP+NCLQT8wCgYIKoZIzj0EAwIw
+FTETMBEGA1UEAwwKcHJvbWV0aGV1czAeFw0yNjEwMTgxNjQ2NTRaFw0zNjEwMTUx
+NjQ2NTRaMBUxEzARBgNVBAMMCnByb21ldGhldXMwWTATBgcqhkjOPQIBBggqhkjO
+PQMBBwNCAAQJ07yq2JazW1GV9TeGhZR+u2/N8t0X5/avXdsotGle1RFi5THVi6Zx
+kRZrasDDzovMnbAo90iTAuHopO0Yl/U0o4GeMIGbMB0GA1UdDgQWBBS7eUzzkZk5
+LSuUK+3vQe/Uq1/C0TAfBgNVHSMEGDAWgBS7eUzzkZk5LSuUK+3vQe/Uq1/C0TAP
+BgNVHRMBAf8EBTADAQH/MEgGA1UdEQRBMD+CFnByb21ldGhldXMuZXhhbXBsZS5j
+b22HBAoAAAGGH3NwaWZmZTovL2V4YW1wbGUuY29tL3Byb21ldGhldXMwCgYIKoZI
+zj0EAwIDSQAwRgIhAPgyFIMQPyNOn/+GNDAaypOwGiuJVzdva39xO4mMyDi3AiEA
+iA9IRa6i06Ynmsx1GOi5+rq60kDvtOxVHYRJOTgq1Rk=
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn listen_addresses() {
+        assert_eq!(
+            "0.0.0.0:3000".parse::<ListenAddress>().unwrap(),
+            ListenAddress::Tcp("0.0.0.0:3000".parse().unwrap())
+        );
+        assert_eq!(
+            "[::1]:9090".parse::<ListenAddress>().unwrap().to_string(),
+            "[::1]:9090"
+        );
+        assert_eq!(
+            "unix:/run/exporter.sock".parse::<ListenAddress>().unwrap(),
+            ListenAddress::Unix(PathBuf::from("/run/exporter.sock"))
+        );
+        assert!("unix:".parse::<ListenAddress>().is_err());
+        assert!("localhost".parse::<ListenAddress>().is_err());
+    }
+
+    #[test]
+    fn web_config_files() {
+        let web_config = WebConfig::parse(
+            "
+tls_server_config:
+  cert_file: server.crt
+  key_file: server.key
+  client_auth_type: RequireAndVerifyClientCert
+  client_ca_file: ca.crt
+  min_version: TLS13
+",
+        )
+        .unwrap();
+        let tls_server_config = web_config.tls_server_config.unwrap();
+        assert_eq!(
+            tls_server_config.client_auth_type,
+            ClientAuthType::RequireAndVerifyClientCert
+        );
+        assert_eq!(tls_server_config.min_version, Some(TlsVersion::Tls13));
+
+        assert!(WebConfig::parse("").unwrap().tls_server_config.is_none());
+        assert!(WebConfig::parse("basic_auth_users:\n  alice: not-a-hash\n").is_err());
+        assert!(
+            WebConfig::parse(
+                "tls_server_config:\n  cert_file: a\n  key_file: b\n  client_auth_type: Unknown\n"
+            )
+            .is_err()
+        );
+        assert!(WebConfig::parse("unknown_setting: true\n").is_err());
+    }
+
+    #[test]
+    fn exporter_toolkit_settings_are_accepted() {
+        let web_config = WebConfig::parse(
+            "
+tls_server_config:
+  cert_file: server.crt
+  key_file: server.key
+  client_auth_type: RequireAnyClientCert
+  client_allowed_sans:
+    - prometheus.example.com
+  min_version: TLS12
+  max_version: TLS12
+  cipher_suites:
+    - TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256
+  curve_preferences:
+    - X25519
+  prefer_server_cipher_suites: true
+http_server_config:
+  http2: false
+  headers:
+    X-Frame-Options: deny
+",
+        )
+        .unwrap();
+        assert_eq!(
+            web_config.ignored_settings(),
+            [
+                "http_server_config",
+                "tls_server_config.cipher_suites",
+                "tls_server_config.curve_preferences",
+                "tls_server_config.prefer_server_cipher_suites"
+            ]
+        );
+        let tls_server_config = web_config.tls_server_config.unwrap();
+        assert_eq!(
+            tls_server_config.client_auth_type,
+            ClientAuthType::RequireAnyClientCert
+        );
+        assert_eq!(
+            tls_server_config.client_allowed_sans,
+            ["prometheus.example.com"]
+        );
+        assert_eq!(tls_server_config.max_version, Some(TlsVersion::Tls12));
+
+        let web_config = WebConfig::parse(
+            "tls_server_config:\n  cert_file: a\n  key_file: b\n  client_auth_type: RequestClientCert\n",
+        )
+        .unwrap();
+        assert!(web_config.ignored_settings().is_empty());
+        assert_eq!(
+            web_config.tls_server_config.unwrap().client_auth_type,
+            ClientAuthType::RequestClientCert
+        );
+    }
+
+    #[test]
+    fn client_allowed_sans() {
+        let cert = CertificateDer::from_pem_slice(CLIENT_CERT.as_bytes()).unwrap();
+        let verifier = |allowed_sans: &[&str]| ClientVerifier {
+            allowed_sans: allowed_sans.iter().map(ToString::to_string).collect(),
+            chain_verifier: None,
+            mandatory: true,
+            signature_algorithms: aws_lc_rs::default_provider().signature_verification_algorithms,
+        };
+        assert!(verifier(&[]).allows(&cert));
+        assert!(verifier(&["other.example.com", "prometheus.example.com"]).allows(&cert));
+        assert!(verifier(&["10.0.0.1"]).allows(&cert));
+        assert!(verifier(&["spiffe://example.com/prometheus"]).allows(&cert));
+        assert!(!verifier(&["example.com"]).allows(&cert));
+        assert!(!verifier(&["10.0.0.2"]).allows(&cert));
+        assert!(!verifier(&["spiffe://example.com/other"]).allows(&cert));
+    }
+
+    #[test]
+    fn credentials_are_checked() {
+        let password_hash = bcrypt::hash("s3cret", 4).unwrap();
+        let token_hash = bcrypt::hash("glpat-token", 4).unwrap();
+        let (authentication, acceptor) = WebConfig::parse(&format!(
+            "basic_auth_users:\n  alice: {password_hash}\nbearer_tokens:\n  - {token_hash}\n"
+        ))
+        .unwrap()
+        .into_parts()
+        .unwrap();
+        assert!(acceptor.is_none());
+        let authentication = authentication.unwrap();
+
+        let basic = |credentials: &str| format!("Basic {}", STANDARD.encode(credentials));
+        assert!(authentication.check(&basic("alice:s3cret")));
+        assert!(authentication.is_verified(&basic("alice:s3cret")));
+        assert!(authentication.check(&basic("alice:s3cret")));
+        assert!(!authentication.check(&basic("alice:wrong")));
+        assert!(!authentication.is_verified(&basic("alice:wrong")));
+        assert!(!authentication.check(&basic("bob:s3cret")));
+        assert!(authentication.check("Bearer glpat-token"));
+        assert!(!authentication.check("Bearer s3cret"));
+        assert!(!authentication.check("glpat-token"));
+
+        let (no_authentication, _) = WebConfig::parse("").unwrap().into_parts().unwrap();
+        assert!(no_authentication.is_none());
+    }
+}